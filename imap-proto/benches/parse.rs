@@ -0,0 +1,75 @@
+//! Parsing the responses a server sends while a mailbox is synchronized.
+//! Run with `cargo bench -p imap-proto`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use imap_proto::parser::scan_line;
+use imap_proto::parse_response;
+
+// FETCH responses with the envelope, flags and size of each message.
+fn envelopes(count: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for seq in 1..=count {
+        buf.extend(format!(
+            "* {seq} FETCH (UID {uid} RFC822.SIZE {size} FLAGS (\\Seen $Forwarded) \
+             INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" \
+             ENVELOPE (\"Wed, 17 Jul 1996 02:23:25 -0700 (PDT)\" \"Re: IMAP4rev1 WG mtg summary and minutes #{seq}\" \
+             ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) \
+             ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) ((NIL NIL \"imap\" \"cac.washington.edu\")) \
+             ((NIL NIL \"minutes\" \"CNRI.Reston.VA.US\")(\"John Klensin\" NIL \"KLENSIN\" \"MIT.EDU\")) NIL NIL \
+             \"<B27397-0100000@cac.washington.edu>\"))\r\n",
+            seq = seq, uid = seq + 1000, size = 4000 + seq,
+        ).as_bytes());
+    }
+    buf
+}
+
+// FETCH responses with a header section as a literal.
+fn headers(count: u32) -> Vec<u8> {
+    let header = "From: Terry Gray <gray@cac.washington.edu>\r\n\
+                  To: imap@cac.washington.edu\r\n\
+                  Subject: Re: IMAP4rev1 WG mtg summary and minutes\r\n\
+                  Message-ID: <B27397-0100000@cac.washington.edu>\r\n\r\n";
+    let mut buf = Vec::new();
+    for seq in 1..=count {
+        buf.extend(format!(
+            "* {} FETCH (UID {} BODY[HEADER.FIELDS (FROM TO SUBJECT MESSAGE-ID)] {{{}}}\r\n{})\r\n",
+            seq, seq + 1000, header.len(), header,
+        ).as_bytes());
+    }
+    buf
+}
+
+fn parse_all(mut buf: &[u8]) -> usize {
+    let mut count = 0;
+    while !buf.is_empty() {
+        let (rest, _) = parse_response(buf).expect("invalid response");
+        buf = rest;
+        count += 1;
+    }
+    count
+}
+
+fn scan_all(mut buf: &[u8]) -> usize {
+    let mut count = 0;
+    while let imap_proto::parser::Line::Complete(len) = scan_line(buf) {
+        buf = &buf[len..];
+        count += 1;
+    }
+    count
+}
+
+fn fetch(c: &mut Criterion) {
+    let envelopes = envelopes(1000);
+    let headers = headers(1000);
+    let mut group = c.benchmark_group("fetch");
+    group.throughput(Throughput::Bytes(envelopes.len() as u64));
+    group.bench_function("envelopes", |b| b.iter(|| parse_all(black_box(&envelopes))));
+    group.bench_function("scan_line", |b| b.iter(|| scan_all(black_box(&envelopes))));
+    group.throughput(Throughput::Bytes(headers.len() as u64));
+    group.bench_function("headers", |b| b.iter(|| parse_all(black_box(&headers))));
+    group.finish();
+}
+
+criterion_group!(benches, fetch);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Reads raw server output on stdin and prints every response as a line of
+//! JSON, with lines that cannot be parsed reported as `{"error": line}`:
+//!
+//! ```text
+//! cargo run -p imap-proto --features serde --example imap-parse < session.log | jq .
+//! ```
+
+use std::io::{self, Read, Write};
+
+use imap_proto::parse_response;
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buf = vec![];
+    let mut chunk = [0; 8192];
+    loop {
+        let mut start = 0;
+        loop {
+            match parse_response(&buf[start..]) {
+                Ok((rest, rsp)) => {
+                    writeln!(stdout, "{}", serde_json::to_string(&rsp)?)?;
+                    start = buf.len() - rest.len();
+                },
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(_) => match buf[start..].windows(2).position(|w| w == b"\r\n") {
+                    Some(end) => {
+                        let line = String::from_utf8_lossy(&buf[start..start + end]);
+                        writeln!(stdout, "{}", serde_json::json!({ "error": line }))?;
+                        start += end + 2;
+                    },
+                    None => break,
+                },
+            }
+        }
+        buf.drain(..start);
+        let read = stdin.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        buf.extend(&chunk[..read]);
+    }
+    if !buf.is_empty() {
+        writeln!(stdout, "{}", serde_json::json!({ "error": String::from_utf8_lossy(&buf) }))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+/* C API of the imap-proto response parser, built with the `ffi` feature. */
+
+#ifndef IMAP_PROTO_H
+#define IMAP_PROTO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define IMAP_PARSE_OK 0
+#define IMAP_PARSE_INCOMPLETE 1
+#define IMAP_PARSE_ERROR (-1)
+
+typedef enum {
+    IMAP_RESPONSE_NONE,
+    IMAP_RESPONSE_BODY_CHUNK,
+    IMAP_RESPONSE_CAPABILITIES,
+    IMAP_RESPONSE_CONTINUE,
+    IMAP_RESPONSE_DONE,
+    IMAP_RESPONSE_DATA,
+    IMAP_RESPONSE_ENABLED,
+    IMAP_RESPONSE_ESEARCH,
+    IMAP_RESPONSE_EXPUNGE,
+    IMAP_RESPONSE_FETCH,
+    IMAP_RESPONSE_GENURLAUTH,
+    IMAP_RESPONSE_LANGUAGE,
+    IMAP_RESPONSE_LIST,
+    IMAP_RESPONSE_MAILBOX_DATA,
+    IMAP_RESPONSE_METADATA,
+    IMAP_RESPONSE_SEARCH,
+    IMAP_RESPONSE_SORT,
+    IMAP_RESPONSE_STATUS,
+    IMAP_RESPONSE_THREAD,
+    IMAP_RESPONSE_UIDFETCH,
+    IMAP_RESPONSE_URLFETCH,
+    IMAP_RESPONSE_VANISHED,
+} ImapResponseKind;
+
+typedef enum {
+    IMAP_STATUS_NONE,
+    IMAP_STATUS_OK,
+    IMAP_STATUS_NO,
+    IMAP_STATUS_BAD,
+    IMAP_STATUS_PREAUTH,
+    IMAP_STATUS_BYE,
+} ImapStatus;
+
+/* A parsed response. Fields that do not apply to `kind` are zero or NULL. */
+typedef struct {
+    ImapResponseKind kind;
+    /* The status of DONE (tagged) and DATA (untagged status) responses. */
+    ImapStatus status;
+    /* The count of EXISTS and RECENT, the sequence number of EXPUNGE and
+     * FETCH, the UID of UIDFETCH, or the value of a numeric response code
+     * such as UIDNEXT. */
+    uint64_t number;
+    /* The tag of DONE and ESEARCH responses. */
+    char *tag;
+    /* The name of the response code, such as "UIDNEXT". */
+    char *code;
+    /* The human-readable text of status responses and continuations. */
+    char *text;
+    /* The mailbox of LIST, METADATA and STATUS responses. */
+    char *mailbox;
+    /* The capabilities, flags, languages, metadata entries or URLs of the
+     * response, or of its code for BADCHARSET, CAPABILITY and
+     * PERMANENTFLAGS. */
+    char **items;
+    size_t item_count;
+    /* The numbers returned by SEARCH and SORT. */
+    uint32_t *ids;
+    size_t id_count;
+} ImapResponse;
+
+/* Parse one response from the `len` bytes at `buf` into `out`, storing the
+ * number of bytes used in `consumed`. On IMAP_PARSE_OK, `out` must be
+ * released with imap_response_free(); otherwise it is left empty. */
+int imap_parse_response(const uint8_t *buf, size_t len, ImapResponse *out, size_t *consumed);
+
+/* Release the strings and arrays of `rsp` and reset it to be empty. */
+void imap_response_free(ImapResponse *rsp);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,353 @@
+//! A C API for the response parser, declared in `include/imap_proto.h`.
+//!
+//! Build the crate with the `ffi` feature as a `cdylib` or `staticlib`, for
+//! example with `cargo rustc --features ffi --crate-type cdylib`. A parsed
+//! response is returned as an `ImapResponse`, whose `kind` says which of its
+//! fields are set; strings and arrays in it are owned by the library until
+//! released with `imap_response_free()`.
+//!
+//! ```ignore
+//! ImapResponse rsp;
+//! size_t used;
+//! if (imap_parse_response(buf, len, &rsp, &used) == IMAP_PARSE_OK) {
+//!     if (rsp.kind == IMAP_RESPONSE_DONE && rsp.status == IMAP_STATUS_OK) { ... }
+//!     imap_response_free(&rsp);
+//! }
+//! ```
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use crate::parser::parse_response;
+use crate::types::*;
+
+/// The response was parsed, and `consumed` bytes of the buffer were used.
+pub const IMAP_PARSE_OK: c_int = 0;
+/// The buffer does not yet hold a complete response.
+pub const IMAP_PARSE_INCOMPLETE: c_int = 1;
+/// The buffer does not start with a valid response, an argument is NULL, or
+/// the parser failed unexpectedly.
+pub const IMAP_PARSE_ERROR: c_int = -1;
+
+/// The kind of an `ImapResponse`, matching `ResponseKind`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImapResponseKind {
+    None,
+    BodyChunk,
+    Capabilities,
+    Continue,
+    Done,
+    Data,
+    Enabled,
+    Esearch,
+    Expunge,
+    Fetch,
+    GenUrlAuth,
+    Language,
+    List,
+    MailboxData,
+    Metadata,
+    Search,
+    Sort,
+    Status,
+    Thread,
+    UidFetch,
+    UrlFetch,
+    Vanished,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImapStatus {
+    None,
+    Ok,
+    No,
+    Bad,
+    PreAuth,
+    Bye,
+}
+
+/// A parsed response. Fields that do not apply to `kind` are zero or NULL.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ImapResponse {
+    pub kind: ImapResponseKind,
+    /// The status of `Done` and `Data` responses.
+    pub status: ImapStatus,
+    /// The count of EXISTS and RECENT, the sequence number of EXPUNGE and
+    /// FETCH, the UID of UIDFETCH, or the value of a numeric response code
+    /// such as UIDNEXT.
+    pub number: u64,
+    /// The tag of `Done` and `Esearch` responses.
+    pub tag: *mut c_char,
+    /// The name of the response code, such as `UIDNEXT`.
+    pub code: *mut c_char,
+    /// The human-readable text of status responses and continuations.
+    pub text: *mut c_char,
+    /// The mailbox of LIST, METADATA and STATUS responses.
+    pub mailbox: *mut c_char,
+    /// The capabilities, flags, languages, metadata entries or URLs of the
+    /// response, or of its code for BADCHARSET, CAPABILITY and
+    /// PERMANENTFLAGS.
+    pub items: *mut *mut c_char,
+    pub item_count: usize,
+    /// The numbers returned by SEARCH and SORT.
+    pub ids: *mut u32,
+    pub id_count: usize,
+}
+
+impl ImapResponse {
+    fn empty() -> ImapResponse {
+        ImapResponse {
+            kind: ImapResponseKind::None,
+            status: ImapStatus::None,
+            number: 0,
+            tag: ptr::null_mut(),
+            code: ptr::null_mut(),
+            text: ptr::null_mut(),
+            mailbox: ptr::null_mut(),
+            items: ptr::null_mut(),
+            item_count: 0,
+            ids: ptr::null_mut(),
+            id_count: 0,
+        }
+    }
+
+    fn set_items(&mut self, items: &[&str]) -> Result<(), ()> {
+        let mut converted = Vec::with_capacity(items.len());
+        for item in items {
+            match to_c_string(item) {
+                Ok(item) => converted.push(item),
+                Err(()) => {
+                    for item in converted {
+                        drop(unsafe { CString::from_raw(item) });
+                    }
+                    return Err(());
+                },
+            }
+        }
+        let items = converted;
+        self.item_count = items.len();
+        self.items = Box::into_raw(items.into_boxed_slice()) as *mut *mut c_char;
+        Ok(())
+    }
+
+    fn set_ids(&mut self, ids: &[u32]) {
+        self.id_count = ids.len();
+        self.ids = Box::into_raw(ids.to_vec().into_boxed_slice()) as *mut u32;
+    }
+
+    fn set_code(&mut self, code: &ResponseCode) -> Result<(), ()> {
+        self.code = to_c_string(code_name(code))?;
+        match *code {
+            ResponseCode::BadCharset(ref charsets) => self.set_items(charsets)?,
+            ResponseCode::Capabilities(ref caps) => self.set_items(caps)?,
+            ResponseCode::HighestModSeq(seq) => self.number = seq,
+            ResponseCode::PermanentFlags(ref permanent) => {
+                let mut flags = permanent.flags.iter().map(|flag| flag.as_str()).collect::<Vec<_>>();
+                if permanent.new_keywords {
+                    flags.push("\\*");
+                }
+                self.set_items(&flags)?;
+            },
+            ResponseCode::UidNext(n) | ResponseCode::UidValidity(n) | ResponseCode::Unseen(n) => {
+                self.number = u64::from(n);
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn fill_status(&mut self, status: &Status, code: &Option<ResponseCode>, text: Option<&str>)
+                   -> Result<(), ()> {
+        self.status = match *status {
+            Status::Ok => ImapStatus::Ok,
+            Status::No => ImapStatus::No,
+            Status::Bad => ImapStatus::Bad,
+            Status::PreAuth => ImapStatus::PreAuth,
+            Status::Bye => ImapStatus::Bye,
+        };
+        if let Some(ref code) = *code {
+            self.set_code(code)?;
+        }
+        self.text = to_c_string(text.unwrap_or(""))?;
+        Ok(())
+    }
+
+    fn fill(&mut self, rsp: &Response) -> Result<(), ()> {
+        self.kind = kind(rsp.kind());
+        match *rsp {
+            Response::Capabilities(ref caps) | Response::Enabled(ref caps) | Response::GenUrlAuth(ref caps) |
+            Response::Language(ref caps) => {
+                self.set_items(caps)?;
+            },
+            Response::Continue { ref code, text } => {
+                if let Some(ref code) = *code {
+                    self.set_code(code)?;
+                }
+                self.text = to_c_string(text.unwrap_or(""))?;
+            },
+            Response::Done(ref tag, ref status, ref code, text) => {
+                self.tag = to_c_string(&tag.0)?;
+                self.fill_status(status, code, text)?;
+            },
+            Response::Data(ref status, ref code, text) => self.fill_status(status, code, text)?,
+            Response::Esearch { tag: Some(tag), .. } => self.tag = to_c_string(tag)?,
+            Response::Expunge(seq) | Response::Fetch(seq, _) | Response::UidFetch(seq, _) => {
+                self.number = u64::from(seq);
+            },
+            Response::MailboxData(MailboxDatum::Exists(n)) | Response::MailboxData(MailboxDatum::Recent(n)) => {
+                self.number = u64::from(n);
+            },
+            Response::MailboxData(MailboxDatum::Flags(ref flags)) => self.set_items(flags)?,
+            Response::MailboxData(MailboxDatum::List { ref flags, name, .. }) => {
+                self.set_items(flags)?;
+                self.mailbox = to_c_string(name)?;
+            },
+            Response::MailboxData(MailboxDatum::Status { mailbox, .. }) => self.mailbox = to_c_string(mailbox)?,
+            Response::Metadata { mailbox, ref values } => {
+                self.set_items(&values.iter().map(|&(entry, _)| entry).collect::<Vec<_>>())?;
+                self.mailbox = to_c_string(mailbox)?;
+            },
+            Response::Search(ref ids) | Response::Sort(ref ids) => self.set_ids(ids),
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+fn kind(kind: ResponseKind) -> ImapResponseKind {
+    match kind {
+        ResponseKind::BodyChunk => ImapResponseKind::BodyChunk,
+        ResponseKind::Capabilities => ImapResponseKind::Capabilities,
+        ResponseKind::Continue => ImapResponseKind::Continue,
+        ResponseKind::Done => ImapResponseKind::Done,
+        ResponseKind::Data => ImapResponseKind::Data,
+        ResponseKind::Enabled => ImapResponseKind::Enabled,
+        ResponseKind::Esearch => ImapResponseKind::Esearch,
+        ResponseKind::Expunge => ImapResponseKind::Expunge,
+        ResponseKind::Fetch => ImapResponseKind::Fetch,
+        ResponseKind::GenUrlAuth => ImapResponseKind::GenUrlAuth,
+        ResponseKind::Language => ImapResponseKind::Language,
+        ResponseKind::List => ImapResponseKind::List,
+        ResponseKind::MailboxData => ImapResponseKind::MailboxData,
+        ResponseKind::Metadata => ImapResponseKind::Metadata,
+        ResponseKind::Search => ImapResponseKind::Search,
+        ResponseKind::Sort => ImapResponseKind::Sort,
+        ResponseKind::Status => ImapResponseKind::Status,
+        ResponseKind::Thread => ImapResponseKind::Thread,
+        ResponseKind::UidFetch => ImapResponseKind::UidFetch,
+        ResponseKind::UrlFetch => ImapResponseKind::UrlFetch,
+        ResponseKind::Vanished => ImapResponseKind::Vanished,
+    }
+}
+
+fn code_name(code: &ResponseCode) -> &'static str {
+    match *code {
+        ResponseCode::Alert => "ALERT",
+        ResponseCode::AppendUid(..) => "APPENDUID",
+        ResponseCode::BadCharset(_) => "BADCHARSET",
+        ResponseCode::BadUrl(_) => "BADURL",
+        ResponseCode::Capabilities(_) => "CAPABILITY",
+        ResponseCode::CompressionActive => "COMPRESSIONACTIVE",
+        ResponseCode::CopyUid(..) => "COPYUID",
+        ResponseCode::HighestModSeq(_) => "HIGHESTMODSEQ",
+        ResponseCode::MailboxId(_) => "MAILBOXID",
+        ResponseCode::Modified(_) => "MODIFIED",
+        ResponseCode::NoModSeq => "NOMODSEQ",
+        ResponseCode::OverQuota => "OVERQUOTA",
+        ResponseCode::PermanentFlags(_) => "PERMANENTFLAGS",
+        ResponseCode::PrivacyRequired => "PRIVACYREQUIRED",
+        ResponseCode::ReadOnly => "READ-ONLY",
+        ResponseCode::ReadWrite => "READ-WRITE",
+        ResponseCode::Referral(_) => "REFERRAL",
+        ResponseCode::TooBig => "TOOBIG",
+        ResponseCode::TryCreate => "TRYCREATE",
+        ResponseCode::UidNext(_) => "UIDNEXT",
+        ResponseCode::UidRequired => "UIDREQUIRED",
+        ResponseCode::UidValidity(_) => "UIDVALIDITY",
+        ResponseCode::Unseen(_) => "UNSEEN",
+        ResponseCode::UseAttr => "USEATTR",
+    }
+}
+
+// Fails for strings containing NUL, which C cannot represent.
+fn to_c_string(s: &str) -> Result<*mut c_char, ()> {
+    CString::new(s).map(CString::into_raw).map_err(|_| ())
+}
+
+/// Parse one response from the `len` bytes at `buf` into `out`, storing the
+/// number of bytes used in `consumed`. On success, `out` must be released
+/// with `imap_response_free()`; otherwise it is left empty.
+///
+/// # Safety
+///
+/// `buf` must point to `len` readable bytes, and `out` and `consumed` must
+/// be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn imap_parse_response(buf: *const u8, len: usize, out: *mut ImapResponse,
+                                             consumed: *mut usize) -> c_int {
+    if buf.is_null() || out.is_null() || consumed.is_null() {
+        return IMAP_PARSE_ERROR;
+    }
+    ptr::write(out, ImapResponse::empty());
+    ptr::write(consumed, 0);
+    let input = slice::from_raw_parts(buf, len);
+    // A panic must not unwind into the caller, which would abort it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (rest, rsp) = match parse_response(input) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Incomplete(_)) => return IMAP_PARSE_INCOMPLETE,
+            Err(_) => return IMAP_PARSE_ERROR,
+        };
+        if (*out).fill(&rsp).is_err() {
+            return IMAP_PARSE_ERROR;
+        }
+        ptr::write(consumed, len - rest.len());
+        IMAP_PARSE_OK
+    }));
+    match result {
+        Ok(IMAP_PARSE_OK) => IMAP_PARSE_OK,
+        Ok(status) => {
+            imap_response_free(out);
+            status
+        },
+        Err(_) => {
+            imap_response_free(out);
+            IMAP_PARSE_ERROR
+        },
+    }
+}
+
+/// Release the strings and arrays of `rsp` and reset it to be empty. Does
+/// nothing if `rsp` is NULL.
+///
+/// # Safety
+///
+/// `rsp` must have been filled by `imap_parse_response()` and not been
+/// modified since, or be empty.
+#[no_mangle]
+pub unsafe extern "C" fn imap_response_free(rsp: *mut ImapResponse) {
+    if rsp.is_null() {
+        return;
+    }
+    let rsp = &mut *rsp;
+    for s in [rsp.tag, rsp.code, rsp.text, rsp.mailbox] {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    }
+    if !rsp.items.is_null() {
+        let items = Box::from_raw(ptr::slice_from_raw_parts_mut(rsp.items, rsp.item_count));
+        for &item in items.iter() {
+            drop(CString::from_raw(item));
+        }
+    }
+    if !rsp.ids.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(rsp.ids, rsp.id_count)));
+    }
+    *rsp = ImapResponse::empty();
+}
@@ -0,0 +1,319 @@
+//! Conversions to and from the types of the `imap-types` crate, for mixing
+//! this crate with tooling built on those.
+//!
+//! Only values with a counterpart on the other side are converted; the
+//! others fail with `Unconvertible` rather than losing information. As the
+//! types of this crate borrow strings, converting from `imap-types` fails
+//! for values that cannot be borrowed as they are, such as system flags
+//! unknown to either crate or non-UTF-8 envelope fields.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::str;
+
+use imap_types::core::{Atom, Charset, NString, NonEmptyVec, Tag, Text};
+use imap_types::response::{Code, CommandContinuationRequest, Data};
+use imap_types::{envelope, flag, response};
+
+use crate::types::*;
+
+/// A value without a counterpart in the other crate's types.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Unconvertible;
+
+impl fmt::Display for Unconvertible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("value cannot be converted between imap-proto and imap-types")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Unconvertible {}
+
+/// Fails for `\Recent`, which is not a flag that can be stored.
+impl<'a> TryFrom<Flag<'a>> for flag::Flag<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: Flag<'a>) -> Result<flag::Flag<'a>, Unconvertible> {
+        match value {
+            Flag::Recent => Err(Unconvertible),
+            flag => flag::Flag::try_from(flag.as_str()).map_err(|_| Unconvertible),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a flag::Flag<'_>> for Flag<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &'a flag::Flag<'_>) -> Result<Flag<'a>, Unconvertible> {
+        flag_str(value).map(Flag::from)
+    }
+}
+
+fn flag_str<'a>(value: &'a flag::Flag<'_>) -> Result<&'a str, Unconvertible> {
+    Ok(match *value {
+        flag::Flag::Answered => Flag::Answered.as_str(),
+        flag::Flag::Deleted => Flag::Deleted.as_str(),
+        flag::Flag::Draft => Flag::Draft.as_str(),
+        flag::Flag::Flagged => Flag::Flagged.as_str(),
+        flag::Flag::Seen => Flag::Seen.as_str(),
+        flag::Flag::Keyword(ref atom) => atom.as_ref(),
+        flag::Flag::Extension(_) => return Err(Unconvertible),
+    })
+}
+
+impl<'a> TryFrom<&Address<'a>> for envelope::Address<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &Address<'a>) -> Result<envelope::Address<'a>, Unconvertible> {
+        Ok(envelope::Address {
+            name: to_nstring(value.name)?,
+            adl: to_nstring(value.adl)?,
+            mailbox: to_nstring(value.mailbox)?,
+            host: to_nstring(value.host)?,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a envelope::Address<'_>> for Address<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &'a envelope::Address<'_>) -> Result<Address<'a>, Unconvertible> {
+        Ok(Address {
+            name: from_nstring(&value.name)?,
+            adl: from_nstring(&value.adl)?,
+            mailbox: from_nstring(&value.mailbox)?,
+            host: from_nstring(&value.host)?,
+        })
+    }
+}
+
+impl<'a> TryFrom<&Envelope<'a>> for envelope::Envelope<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &Envelope<'a>) -> Result<envelope::Envelope<'a>, Unconvertible> {
+        Ok(envelope::Envelope {
+            date: to_nstring(value.date)?,
+            subject: to_nstring(value.subject)?,
+            from: to_addresses(&value.from)?,
+            sender: to_addresses(&value.sender)?,
+            reply_to: to_addresses(&value.reply_to)?,
+            to: to_addresses(&value.to)?,
+            cc: to_addresses(&value.cc)?,
+            bcc: to_addresses(&value.bcc)?,
+            in_reply_to: to_nstring(value.in_reply_to)?,
+            message_id: to_nstring(value.message_id)?,
+        })
+    }
+}
+
+/// Empty address lists become `None`, as NIL and `()` are equivalent.
+impl<'a> TryFrom<&'a envelope::Envelope<'_>> for Envelope<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &'a envelope::Envelope<'_>) -> Result<Envelope<'a>, Unconvertible> {
+        Ok(Envelope {
+            date: from_nstring(&value.date)?,
+            subject: from_nstring(&value.subject)?,
+            from: from_addresses(&value.from)?,
+            sender: from_addresses(&value.sender)?,
+            reply_to: from_addresses(&value.reply_to)?,
+            to: from_addresses(&value.to)?,
+            cc: from_addresses(&value.cc)?,
+            bcc: from_addresses(&value.bcc)?,
+            in_reply_to: from_nstring(&value.in_reply_to)?,
+            message_id: from_nstring(&value.message_id)?,
+        })
+    }
+}
+
+fn to_nstring(value: Option<&str>) -> Result<NString<'_>, Unconvertible> {
+    match value {
+        Some(value) => NString::try_from(value).map_err(|_| Unconvertible),
+        None => Ok(NString(None)),
+    }
+}
+
+fn from_nstring<'a>(value: &'a NString<'_>) -> Result<Option<&'a str>, Unconvertible> {
+    match value.0 {
+        Some(ref value) => str::from_utf8(value.as_ref()).map(Some).map_err(|_| Unconvertible),
+        None => Ok(None),
+    }
+}
+
+fn to_addresses<'a>(value: &Option<Vec<Address<'a>>>) -> Result<Vec<envelope::Address<'a>>, Unconvertible> {
+    value.iter().flatten().map(envelope::Address::try_from).collect()
+}
+
+fn from_addresses<'a>(value: &'a [envelope::Address<'_>]) -> Result<Option<Vec<Address<'a>>>, Unconvertible> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => value.iter().map(Address::try_from).collect::<Result<_, _>>().map(Some),
+    }
+}
+
+/// Converts status responses, continuation requests, and the CAPABILITY,
+/// EXISTS, RECENT, EXPUNGE, FLAGS and SEARCH data.
+impl<'a> TryFrom<&Response<'a>> for response::Response<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &Response<'a>) -> Result<response::Response<'a>, Unconvertible> {
+        let data = match *value {
+            Response::Continue { ref code, text } => {
+                let code = code.as_ref().map(to_code).transpose()?;
+                let text = text.ok_or(Unconvertible)?;
+                let continuation = CommandContinuationRequest::basic(code, text).map_err(|_| Unconvertible)?;
+                return Ok(response::Response::CommandContinuationRequest(continuation));
+            },
+            Response::Done(ref tag, ref status, ref code, text) => {
+                let tag = Tag::try_from(String::from(&*tag.0)).map_err(|_| Unconvertible)?;
+                return to_status(Some(tag), status, code, text).map(response::Response::Status);
+            },
+            Response::Data(ref status, ref code, text) => {
+                return to_status(None, status, code, text).map(response::Response::Status);
+            },
+            Response::Capabilities(ref caps) => Data::Capability(to_capabilities(caps)?),
+            Response::Expunge(seq) => Data::expunge(seq).map_err(|_| Unconvertible)?,
+            Response::MailboxData(MailboxDatum::Exists(count)) => Data::Exists(count),
+            Response::MailboxData(MailboxDatum::Recent(count)) => Data::Recent(count),
+            Response::MailboxData(MailboxDatum::Flags(ref flags)) => Data::Flags(
+                flags.iter().map(|&flag| flag::Flag::try_from(Flag::from(flag))).collect::<Result<_, _>>()?
+            ),
+            Response::Search(ref ids) => Data::Search(
+                ids.iter().map(|&id| NonZeroU32::new(id).ok_or(Unconvertible)).collect::<Result<_, _>>()?
+            ),
+            _ => return Err(Unconvertible),
+        };
+        Ok(response::Response::Data(data))
+    }
+}
+
+/// Converts the same responses as the opposite conversion, except for
+/// CAPABILITY data and codes, whose names `imap-types` does not keep.
+impl<'a> TryFrom<&'a response::Response<'_>> for Response<'a> {
+    type Error = Unconvertible;
+
+    fn try_from(value: &'a response::Response<'_>) -> Result<Response<'a>, Unconvertible> {
+        let data = match *value {
+            response::Response::CommandContinuationRequest(CommandContinuationRequest::Basic(ref basic)) => {
+                return Ok(Response::Continue {
+                    code: basic.code().map(from_code).transpose()?,
+                    text: Some(basic.text().as_ref()),
+                });
+            },
+            response::Response::CommandContinuationRequest(CommandContinuationRequest::Base64(_)) => {
+                return Err(Unconvertible);
+            },
+            response::Response::Status(ref status) => return from_status(status),
+            response::Response::Data(ref data) => data,
+        };
+        Ok(match *data {
+            Data::Exists(count) => Response::MailboxData(MailboxDatum::Exists(count)),
+            Data::Recent(count) => Response::MailboxData(MailboxDatum::Recent(count)),
+            Data::Expunge(seq) => Response::Expunge(seq.get()),
+            Data::Flags(ref flags) => Response::MailboxData(MailboxDatum::Flags(
+                flags.iter().map(flag_str).collect::<Result<_, _>>()?
+            )),
+            Data::Search(ref ids) => Response::Search(ids.iter().map(|id| id.get()).collect()),
+            _ => return Err(Unconvertible),
+        })
+    }
+}
+
+fn to_status<'a>(tag: Option<Tag<'a>>, status: &Status, code: &Option<ResponseCode<'a>>, text: Option<&'a str>)
+                 -> Result<response::Status<'a>, Unconvertible> {
+    let code = code.as_ref().map(to_code).transpose()?;
+    let text = Text::try_from(text.ok_or(Unconvertible)?).map_err(|_| Unconvertible)?;
+    Ok(match (status, tag) {
+        (Status::Ok, tag) => response::Status::Ok { tag, code, text },
+        (Status::No, tag) => response::Status::No { tag, code, text },
+        (Status::Bad, tag) => response::Status::Bad { tag, code, text },
+        (Status::Bye, None) => response::Status::Bye { code, text },
+        _ => return Err(Unconvertible),
+    })
+}
+
+fn from_status<'a>(value: &'a response::Status<'_>) -> Result<Response<'a>, Unconvertible> {
+    let (status, tag) = match *value {
+        response::Status::Ok { ref tag, .. } => (Status::Ok, tag.as_ref()),
+        response::Status::No { ref tag, .. } => (Status::No, tag.as_ref()),
+        response::Status::Bad { ref tag, .. } => (Status::Bad, tag.as_ref()),
+        response::Status::Bye { .. } => (Status::Bye, None),
+    };
+    let code = value.code().map(from_code).transpose()?;
+    let text = Some(value.text().as_ref());
+    Ok(match tag {
+        Some(tag) => Response::Done(RequestId(Arc::from(tag.as_ref())), status, code, text),
+        None => Response::Data(status, code, text),
+    })
+}
+
+fn to_capabilities<'a>(caps: &[&'a str]) -> Result<NonEmptyVec<response::Capability<'a>>, Unconvertible> {
+    let caps = caps.iter()
+        .map(|&cap| Atom::try_from(cap).map(response::Capability::from).map_err(|_| Unconvertible))
+        .collect::<Result<Vec<_>, _>>()?;
+    NonEmptyVec::try_from(caps).map_err(|_| Unconvertible)
+}
+
+fn to_code<'a>(value: &ResponseCode<'a>) -> Result<Code<'a>, Unconvertible> {
+    Ok(match *value {
+        ResponseCode::Alert => Code::Alert,
+        ResponseCode::BadCharset(ref charsets) => Code::BadCharset {
+            allowed: charsets.iter().map(|&charset| Charset::try_from(charset)).collect::<Result<_, _>>()
+                .map_err(|_| Unconvertible)?,
+        },
+        ResponseCode::Capabilities(ref caps) => Code::Capability(to_capabilities(caps)?),
+        ResponseCode::CompressionActive => Code::CompressionActive,
+        ResponseCode::OverQuota => Code::OverQuota,
+        ResponseCode::PermanentFlags(ref permanent) => {
+            let mut flags = permanent.flags.iter()
+                .map(|&flag| flag::Flag::try_from(flag).map(flag::FlagPerm::Flag))
+                .collect::<Result<Vec<_>, _>>()?;
+            if permanent.new_keywords {
+                flags.push(flag::FlagPerm::Asterisk);
+            }
+            Code::PermanentFlags(flags)
+        },
+        ResponseCode::ReadOnly => Code::ReadOnly,
+        ResponseCode::ReadWrite => Code::ReadWrite,
+        ResponseCode::TooBig => Code::TooBig,
+        ResponseCode::TryCreate => Code::TryCreate,
+        ResponseCode::UidNext(uid) => Code::uidnext(uid).map_err(|_| Unconvertible)?,
+        ResponseCode::UidValidity(uid_validity) => Code::uidvalidity(uid_validity).map_err(|_| Unconvertible)?,
+        ResponseCode::Unseen(seq) => Code::unseen(seq).map_err(|_| Unconvertible)?,
+        _ => return Err(Unconvertible),
+    })
+}
+
+fn from_code<'a>(value: &'a Code<'_>) -> Result<ResponseCode<'a>, Unconvertible> {
+    Ok(match *value {
+        Code::Alert => ResponseCode::Alert,
+        Code::BadCharset { ref allowed } => {
+            ResponseCode::BadCharset(allowed.iter().map(|charset| charset.as_ref()).collect())
+        },
+        Code::CompressionActive => ResponseCode::CompressionActive,
+        Code::OverQuota => ResponseCode::OverQuota,
+        Code::PermanentFlags(ref perms) => {
+            let mut permanent = PermanentFlags { flags: Vec::new(), new_keywords: false };
+            for perm in perms {
+                match *perm {
+                    flag::FlagPerm::Flag(ref flag) => permanent.flags.push(Flag::try_from(flag)?),
+                    flag::FlagPerm::Asterisk => permanent.new_keywords = true,
+                }
+            }
+            ResponseCode::PermanentFlags(permanent)
+        },
+        Code::ReadOnly => ResponseCode::ReadOnly,
+        Code::ReadWrite => ResponseCode::ReadWrite,
+        Code::TooBig => ResponseCode::TooBig,
+        Code::TryCreate => ResponseCode::TryCreate,
+        Code::UidNext(uid) => ResponseCode::UidNext(uid.get()),
+        Code::UidValidity(uid_validity) => ResponseCode::UidValidity(uid_validity.get()),
+        Code::Unseen(seq) => ResponseCode::Unseen(seq.get()),
+        _ => return Err(Unconvertible),
+    })
+}
@@ -0,0 +1,25 @@
+//! Types and parsers for the IMAP protocol, shared by clients and servers.
+//!
+//! This crate does no I/O. Without the default `std` feature, it only
+//! depends on `core` and `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[macro_use]
+extern crate nom;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "imap-types")]
+pub mod interop;
+pub mod parser;
+pub mod types;
+pub mod url;
+pub mod utf7;
+
+pub use smallvec;
+
+pub use crate::parser::{parse_response, ParseResult};
+pub use crate::types::*;
+pub use crate::url::ImapUrl;
@@ -0,0 +1,1317 @@
+//! Parsers for the server responses and client commands of the IMAP
+//! protocol. This module only depends on `core` and `alloc`.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::cmp;
+use core::convert::TryFrom;
+use core::ops::RangeInclusive;
+use core::str;
+
+use memchr::{memchr2, memchr3};
+
+use nom::character::is_digit;
+use nom::character::streaming::digit1;
+use nom::error::{make_error, ErrorKind};
+use nom::{IResult, Needed};
+
+use smallvec::{Array, SmallVec};
+
+use crate::types::{Address, Annotation, AttributeValue, BodyFields, BodyStructure, ContentDisposition};
+use crate::types::{ContextUpdate, Envelope, Flag, MailboxDatum, PartialResults, PermanentFlags, Quirks};
+use crate::types::{Request, RequestId, Response, ResponseCode, SearchResults, Status, StatusAttributeValue, Thread};
+
+const fn list_wildcards(c: u8) -> bool {
+    c == b'%' || c == b'*'
+}
+
+const fn quoted_specials(c: u8) -> bool {
+    c == b'"' || c == b'\\'
+}
+
+fn section_end(c: u8) -> bool {
+    c == b']'
+}
+
+const fn resp_specials(c: u8) -> bool {
+    c == b']'
+}
+
+const fn atom_specials(c: u8) -> bool {
+    c == b'(' || c == b')' || c == b'{' || c == b' ' || c < 32 ||
+    list_wildcards(c) || quoted_specials(c) || resp_specials(c)
+}
+
+// Atoms make up most of a response, so their characters are looked up
+// rather than compared one by one.
+const ATOM_CHARS: [bool; 256] = {
+    let mut table = [false; 256];
+    let mut c = 0;
+    while c < table.len() {
+        table[c] = !atom_specials(c as u8);
+        c += 1;
+    }
+    table
+};
+
+fn atom_char(c: u8) -> bool {
+    ATOM_CHARS[c as usize]
+}
+
+fn astring_char(c: u8) -> bool {
+    atom_char(c) || resp_specials(c)
+}
+
+fn tag_char(c: u8) -> bool {
+    c != b'+' && astring_char(c)
+}
+
+// Ideally this should use nom's `escaped` macro, but it suffers from broken
+// type inference unless compiled with the verbose-errors feature enabled.
+fn quoted_data(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let mut len = 0;
+    loop {
+        match memchr2(b'"', b'\\', &i[len..]) {
+            Some(pos) if i[len + pos] == b'"' => {
+                len += pos;
+                break;
+            },
+            // Skip the escaped character along with the backslash.
+            Some(pos) => len = cmp::min(len + pos + 2, i.len()),
+            None => {
+                len = i.len();
+                break;
+            },
+        }
+    }
+    Ok((&i[len..], &i[..len]))
+}
+
+named!(quoted_bytes<&[u8]>, do_parse!(
+    tag!("\"") >>
+    data: quoted_data >>
+    tag!("\"") >>
+    (data)
+));
+
+named!(quoted<&str>, map_res!(quoted_bytes, str::from_utf8));
+
+// Also accepts the literal8 syntax used with UTF8=ACCEPT (RFC 6855).
+named!(literal<&[u8]>, do_parse!(
+    opt!(tag!("~")) >>
+    tag!("{") >>
+    len: number >>
+    tag!("}") >>
+    tag!("\r\n") >>
+    data: take!(len) >>
+    (data)
+));
+
+// Message data, which need not be UTF-8.
+named!(string_bytes<&[u8]>, alt!(quoted_bytes | literal));
+
+// Strings that are not valid UTF-8 fail to parse.
+named!(string<&str>, map_res!(string_bytes, str::from_utf8));
+
+named!(astring<&str>, alt!(
+    map_res!(take_while1!(astring_char), str::from_utf8) |
+    string
+));
+
+named!(status_ok<Status>, map!(tag_no_case!("OK"),
+    |_| Status::Ok
+));
+named!(status_no<Status>, map!(tag_no_case!("NO"),
+    |_| Status::No
+));
+named!(status_bad<Status>, map!(tag_no_case!("BAD"),
+    |_| Status::Bad
+));
+named!(status_preauth<Status>, map!(tag_no_case!("PREAUTH"),
+    |_| Status::PreAuth
+));
+named!(status_bye<Status>, map!(tag_no_case!("BYE"),
+    |_| Status::Bye
+));
+
+named!(status<Status>, alt!(
+    status_ok |
+    status_no |
+    status_bad |
+    status_preauth |
+    status_bye
+));
+
+// Numbers too large for their type fail to parse.
+named!(number<u32>, map_res!(map_res!(digit1, str::from_utf8), str::parse::<u32>));
+
+named!(number_64<u64>, map_res!(map_res!(digit1, str::from_utf8), str::parse::<u64>));
+
+// Text localized through LANGUAGE (RFC 5255) is UTF-8. Servers that get
+// this wrong have the text cut at the first invalid byte.
+fn text(i: &[u8]) -> IResult<&[u8], &str> {
+    match memchr2(b'\r', b'\n', i) {
+        Some(pos) => {
+            let text = match str::from_utf8(&i[..pos]) {
+                Ok(text) => text,
+                Err(err) => str::from_utf8(&i[..err.valid_up_to()]).unwrap(),
+            };
+            Ok((&i[pos..], text))
+        },
+        None => Err(nom::Err::Incomplete(Needed::new(1))),
+    }
+}
+
+named!(atom<&str>, map_res!(take_while1!(atom_char), str::from_utf8));
+
+fn flag_extension(i: &[u8]) -> IResult<&[u8], &str> {
+    if i.is_empty() || i[0] != b'\\' {
+        return Err(nom::Err::Error(make_error(i, ErrorKind::Char)));
+    }
+    let mut last = 0;
+    for (idx, c) in i[1..].iter().enumerate() {
+        last = idx;
+        if !atom_char(*c) {
+            break;
+        }
+    }
+    match str::from_utf8(&i[..last + 1]) {
+        Ok(flag) => Ok((&i[last + 1..], flag)),
+        Err(_) => Err(nom::Err::Error(make_error(i, ErrorKind::MapRes))),
+    }
+}
+
+named!(flag<&str>, alt!(flag_extension | atom));
+
+// Parses the items in `i`, separated by spaces, into a `SmallVec`, so that
+// short lists are not allocated. Stops before the first space not followed
+// by an item.
+fn space_separated<'a, A, F>(mut i: &'a [u8], item: F) -> IResult<&'a [u8], SmallVec<A>>
+        where A: Array, F: Fn(&'a [u8]) -> IResult<&'a [u8], A::Item> {
+    let mut items = SmallVec::new();
+    let (rest, first) = item(i)?;
+    items.push(first);
+    i = rest;
+    loop {
+        match i.first() {
+            None => return Err(nom::Err::Incomplete(Needed::new(1))),
+            Some(b' ') => {},
+            Some(_) => return Ok((i, items)),
+        }
+        match item(&i[1..]) {
+            Ok((rest, next)) => {
+                items.push(next);
+                i = rest;
+            },
+            Err(nom::Err::Error(_)) => return Ok((i, items)),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+named!(flag_perm<&str>, alt!(
+    map!(tag!("\\*"), |_| "\\*") |
+    flag
+));
+
+// Gmail sends keywords which are not atoms.
+named!(flag_loose<&str>, alt!(
+    map!(tag!("\\*"), |_| "\\*") |
+    flag_extension |
+    map_res!(take_while1!(astring_char), str::from_utf8) |
+    quoted
+));
+
+named_args!(flag_list(quirks: Quirks)<SmallVec<[&str; 2]>>, do_parse!(
+    tag!("(") >>
+    elements: opt!(call!(space_separated, match quirks.contains(Quirks::LOOSE_FLAGS) {
+        true => flag_loose,
+        false => flag,
+    })) >>
+    tag!(")") >>
+    (elements.unwrap_or_default())
+));
+
+named!(resp_text_code_permanent_flags<ResponseCode<'_>>, do_parse!(
+    tag!("PERMANENTFLAGS (") >>
+    elements: opt!(do_parse!(
+        flag0: flag_perm >>
+        flags: many0!(do_parse!(
+            tag!(" ") >>
+            flag: flag_perm >>
+            (flag)
+        )) >> ({
+            let mut res = vec![flag0];
+            res.extend(flags);
+            res
+        })
+    )) >>
+    tag!(")") >> ({
+        let elements = elements.unwrap_or_default();
+        ResponseCode::PermanentFlags(PermanentFlags {
+            new_keywords: elements.contains(&"\\*"),
+            flags: elements.into_iter().filter(|flag| *flag != "\\*").map(Flag::from).collect(),
+        })
+    })
+));
+
+named!(resp_text_code_highest_mod_seq<ResponseCode<'_>>, do_parse!(
+    tag!("HIGHESTMODSEQ ") >>
+    num: number_64 >>
+    (ResponseCode::HighestModSeq(num))
+));
+
+named!(resp_text_code_modified<ResponseCode<'_>>, do_parse!(
+    tag!("MODIFIED ") >>
+    set: map_res!(take_while1!(atom_char), str::from_utf8) >>
+    (ResponseCode::Modified(set))
+));
+
+named!(resp_text_code_no_mod_seq<ResponseCode<'_>>, do_parse!(
+    tag!("NOMODSEQ") >>
+    (ResponseCode::NoModSeq)
+));
+
+named!(resp_text_code_mailbox_id<ResponseCode<'_>>, do_parse!(
+    tag!("MAILBOXID (") >>
+    id: map_res!(take_while1!(atom_char), str::from_utf8) >>
+    tag!(")") >>
+    (ResponseCode::MailboxId(id))
+));
+
+named!(resp_text_code_alert<ResponseCode<'_>>, do_parse!(
+    tag!("ALERT") >>
+    (ResponseCode::Alert)
+));
+
+named!(resp_text_code_compression_active<ResponseCode<'_>>, do_parse!(
+    tag!("COMPRESSIONACTIVE") >>
+    (ResponseCode::CompressionActive)
+));
+
+named!(resp_text_code_privacy_required<ResponseCode<'_>>, do_parse!(
+    tag!("PRIVACYREQUIRED") >>
+    (ResponseCode::PrivacyRequired)
+));
+
+named!(resp_text_code_too_big<ResponseCode<'_>>, do_parse!(
+    tag!("TOOBIG") >>
+    (ResponseCode::TooBig)
+));
+
+named!(resp_text_code_over_quota<ResponseCode<'_>>, do_parse!(
+    tag!("OVERQUOTA") >>
+    (ResponseCode::OverQuota)
+));
+
+named!(resp_text_code_read_only<ResponseCode<'_>>, do_parse!(
+    tag!("READ-ONLY") >>
+    (ResponseCode::ReadOnly)
+));
+
+named!(resp_text_code_read_write<ResponseCode<'_>>, do_parse!(
+    tag!("READ-WRITE") >>
+    (ResponseCode::ReadWrite)
+));
+
+named!(resp_text_code_try_create<ResponseCode<'_>>, do_parse!(
+    tag!("TRYCREATE") >>
+    (ResponseCode::TryCreate)
+));
+
+named!(resp_text_code_uid_validity<ResponseCode<'_>>, do_parse!(
+    tag!("UIDVALIDITY ") >>
+    num: number >>
+    (ResponseCode::UidValidity(num))
+));
+
+named!(resp_text_code_uid_next<ResponseCode<'_>>, do_parse!(
+    tag!("UIDNEXT ") >>
+    num: number >>
+    (ResponseCode::UidNext(num))
+));
+
+named!(resp_text_code_unseen<ResponseCode<'_>>, do_parse!(
+    tag!("UNSEEN ") >>
+    num: number >>
+    (ResponseCode::Unseen(num))
+));
+
+named!(resp_text_code_append_uid<ResponseCode<'_>>, do_parse!(
+    tag!("APPENDUID ") >>
+    uid_validity: number >>
+    tag!(" ") >>
+    uids: sequence_set >>
+    (ResponseCode::AppendUid(uid_validity, uids))
+));
+
+named!(resp_text_code_copy_uid<ResponseCode<'_>>, do_parse!(
+    tag!("COPYUID ") >>
+    uid_validity: number >>
+    tag!(" ") >>
+    source: sequence_set >>
+    tag!(" ") >>
+    destination: sequence_set >>
+    (ResponseCode::CopyUid(uid_validity, source, destination))
+));
+
+named!(resp_text_code_bad_charset<ResponseCode<'_>>, do_parse!(
+    tag!("BADCHARSET") >>
+    charsets: opt!(do_parse!(
+        tag!(" (") >>
+        charsets: separated_list1!(tag!(" "), astring) >>
+        tag!(")") >>
+        (charsets)
+    )) >>
+    (ResponseCode::BadCharset(charsets.unwrap_or_default()))
+));
+
+named!(resp_text_code_bad_url<ResponseCode<'_>>, do_parse!(
+    tag!("BADURL ") >>
+    url: map_res!(take_till1!(section_end), str::from_utf8) >>
+    (ResponseCode::BadUrl(url))
+));
+
+named!(resp_text_code_uid_required<ResponseCode<'_>>, do_parse!(
+    tag!("UIDREQUIRED") >>
+    (ResponseCode::UidRequired)
+));
+
+named!(resp_text_code_use_attr<ResponseCode<'_>>, do_parse!(
+    tag!("USEATTR") >>
+    (ResponseCode::UseAttr)
+));
+
+named!(resp_text_code_referral<ResponseCode<'_>>, do_parse!(
+    tag!("REFERRAL ") >>
+    url: map_res!(take_till1!(section_end), str::from_utf8) >>
+    (ResponseCode::Referral(url))
+));
+
+named!(resp_text_code_capability<ResponseCode<'_>>, do_parse!(
+    tag!("CAPABILITY") >>
+    capabilities: many1!(capability) >>
+    (ResponseCode::Capabilities(capabilities))
+));
+
+named!(resp_text_code<ResponseCode<'_>>, do_parse!(
+    tag!("[") >>
+    coded: alt!(
+        resp_text_code_alert |
+        resp_text_code_append_uid |
+        resp_text_code_bad_charset |
+        resp_text_code_bad_url |
+        resp_text_code_capability |
+        resp_text_code_compression_active |
+        resp_text_code_copy_uid |
+        resp_text_code_permanent_flags |
+        resp_text_code_privacy_required |
+        resp_text_code_uid_validity |
+        resp_text_code_uid_next |
+        resp_text_code_uid_required |
+        resp_text_code_unseen |
+        resp_text_code_over_quota |
+        resp_text_code_read_only |
+        resp_text_code_read_write |
+        resp_text_code_referral |
+        resp_text_code_too_big |
+        resp_text_code_try_create |
+        resp_text_code_use_attr |
+        resp_text_code_highest_mod_seq |
+        resp_text_code_mailbox_id |
+        resp_text_code_modified |
+        resp_text_code_no_mod_seq
+    ) >>
+    // Per the spec, the closing tag should be "] ".
+    // See `resp_text` for more on why this is done differently.
+    tag!("]") >>
+    (coded)
+));
+
+named!(capability<&str>, do_parse!(
+    tag!(" ") >>
+    atom: map_res!(take_till1!(atom_specials), str::from_utf8) >>
+    (atom)
+));
+
+named!(capability_data<Response<'_>>, do_parse!(
+    tag!("CAPABILITY") >>
+    capabilities: many1!(capability) >>
+    (Response::Capabilities(capabilities))
+));
+
+named!(enabled_data<Response<'_>>, do_parse!(
+    tag!("ENABLED") >>
+    capabilities: many0!(capability) >>
+    (Response::Enabled(capabilities))
+));
+
+// Some servers send a trailing space after the last number.
+named!(search_data<Response<'_>>, do_parse!(
+    tag!("SEARCH") >>
+    ids: many0!(do_parse!(
+        tag!(" ") >>
+        id: number >>
+        (id)
+    )) >>
+    opt!(tag!(" ")) >>
+    (Response::Search(ids))
+));
+
+named!(sort_data<Response<'_>>, do_parse!(
+    tag!("SORT") >>
+    ids: many0!(do_parse!(
+        tag!(" ") >>
+        id: number >>
+        (id)
+    )) >>
+    opt!(tag!(" ")) >>
+    (Response::Sort(ids))
+));
+
+// A thread whose first messages form a chain, as in `(3 6 (4 23)(44 7 96))`.
+named!(thread_members<Thread>, do_parse!(
+    ids: separated_list1!(tag!(" "), number) >>
+    nested: opt!(preceded!(tag!(" "), thread_nested)) >>
+    ({
+        let mut thread = Thread { id: None, children: nested.unwrap_or_default() };
+        for id in ids.into_iter().rev() {
+            thread = match thread.id {
+                None => Thread { id: Some(id), children: thread.children },
+                Some(_) => Thread { id: Some(id), children: vec![thread] },
+            };
+        }
+        thread
+    })
+));
+
+named!(thread_nested<Vec<Thread>>, many1!(thread_list));
+
+named!(thread_list<Thread>, do_parse!(
+    tag!("(") >>
+    thread: alt!(
+        thread_members |
+        map!(thread_nested, |children| Thread { id: None, children })
+    ) >>
+    tag!(")") >>
+    (thread)
+));
+
+named!(thread_data<Response<'_>>, do_parse!(
+    tag!("THREAD") >>
+    opt!(tag!(" ")) >>
+    threads: many0!(thread_list) >>
+    opt!(tag!(" ")) >>
+    (Response::Thread(threads))
+));
+
+enum SearchReturnData {
+    AddTo(Vec<ContextUpdate>),
+    All(Vec<RangeInclusive<u32>>),
+    Count(u32),
+    Max(u32),
+    Min(u32),
+    Partial(PartialResults),
+    Relevancy(Vec<u8>),
+    RemoveFrom(Vec<ContextUpdate>),
+}
+
+// Positions beyond the range of `i32` are rejected.
+named!(partial_position<i32>, do_parse!(
+    negative: opt!(tag!("-")) >>
+    num: map_res!(number, i32::try_from) >>
+    (if negative.is_some() { -num } else { num })
+));
+
+named!(partial_results<PartialResults>, do_parse!(
+    tag!(" PARTIAL (") >>
+    first: partial_position >>
+    tag!(":") >>
+    last: partial_position >>
+    tag!(" ") >>
+    set: alt!(
+        map!(tag!("NIL"), |_| vec![]) |
+        sequence_set
+    ) >>
+    tag!(")") >>
+    (PartialResults { first, last, set })
+));
+
+named!(context_update<ContextUpdate>, do_parse!(
+    position: number >>
+    tag!(" ") >>
+    set: sequence_set >>
+    (ContextUpdate { position, set })
+));
+
+named!(context_updates<Vec<ContextUpdate>>, do_parse!(
+    tag!("(") >>
+    updates: separated_list1!(tag!(" "), context_update) >>
+    tag!(")") >>
+    (updates)
+));
+
+named!(search_return_data<SearchReturnData>, alt!(
+    do_parse!(tag!(" MIN ") >> num: number >> (SearchReturnData::Min(num))) |
+    do_parse!(tag!(" MAX ") >> num: number >> (SearchReturnData::Max(num))) |
+    do_parse!(tag!(" ALL ") >> set: sequence_set >> (SearchReturnData::All(set))) |
+    do_parse!(tag!(" COUNT ") >> num: number >> (SearchReturnData::Count(num))) |
+    map!(partial_results, SearchReturnData::Partial) |
+    do_parse!(tag!(" ADDTO ") >> updates: context_updates >> (SearchReturnData::AddTo(updates))) |
+    do_parse!(tag!(" REMOVEFROM ") >> updates: context_updates >> (SearchReturnData::RemoveFrom(updates))) |
+    do_parse!(
+        tag!(" RELEVANCY (") >>
+        scores: separated_list0!(tag!(" "), map!(number, |score| cmp::min(score, 100) as u8)) >>
+        tag!(")") >>
+        (SearchReturnData::Relevancy(scores))
+    )
+));
+
+named!(search_correlator<(&str, Option<(&str, u32)>)>, do_parse!(
+    tag!(" (TAG ") >>
+    tag: string >>
+    mailbox: opt!(do_parse!(
+        tag!(" MAILBOX ") >>
+        mailbox: astring >>
+        tag!(" UIDVALIDITY ") >>
+        uid_validity: number >>
+        ((mailbox, uid_validity))
+    )) >>
+    tag!(")") >>
+    ((tag, mailbox))
+));
+
+named!(esearch_data<Response<'_>>, do_parse!(
+    tag!("ESEARCH") >>
+    correlator: opt!(search_correlator) >>
+    uid: opt!(tag!(" UID")) >>
+    data: many0!(search_return_data) >> ({
+        let mut results = SearchResults::default();
+        for item in data {
+            match item {
+                SearchReturnData::AddTo(updates) => results.add_to.extend(updates),
+                SearchReturnData::All(set) => results.all = Some(set),
+                SearchReturnData::Count(num) => results.count = Some(num),
+                SearchReturnData::Max(num) => results.max = Some(num),
+                SearchReturnData::Min(num) => results.min = Some(num),
+                SearchReturnData::Partial(partial) => results.partial = Some(partial),
+                SearchReturnData::Relevancy(scores) => results.relevancy = Some(scores),
+                SearchReturnData::RemoveFrom(updates) => results.remove_from.extend(updates),
+            }
+        }
+        let (tag, mailbox) = match correlator {
+            Some((tag, mailbox)) => (Some(tag), mailbox),
+            None => (None, None),
+        };
+        Response::Esearch { tag, mailbox, uid: uid.is_some(), results }
+    })
+));
+
+named!(genurlauth_data<Response<'_>>, do_parse!(
+    tag!("GENURLAUTH") >>
+    urls: many1!(do_parse!(
+        tag!(" ") >>
+        url: astring >>
+        (url)
+    )) >>
+    (Response::GenUrlAuth(urls))
+));
+
+named!(language_data<Response<'_>>, do_parse!(
+    tag!("LANGUAGE (") >>
+    languages: separated_list1!(tag!(" "), astring) >>
+    tag!(")") >>
+    (Response::Language(languages))
+));
+
+// Unsolicited responses only list the entries that changed, without values.
+named!(metadata_data<Response<'_>>, do_parse!(
+    tag!("METADATA ") >>
+    mailbox: astring >>
+    tag!(" ") >>
+    values: alt!(
+        delimited!(tag!("("), separated_list1!(tag!(" "), entry_value), tag!(")")) |
+        map!(separated_list1!(tag!(" "), astring), |entries| entries.into_iter().map(|entry| (entry, None)).collect())
+    ) >>
+    (Response::Metadata { mailbox, values })
+));
+
+named!(urlfetch_data<Response<'_>>, do_parse!(
+    tag!("URLFETCH") >>
+    results: many1!(do_parse!(
+        tag!(" ") >>
+        url: astring >>
+        tag!(" ") >>
+        data: nstring_bytes >>
+        ((url, data))
+    )) >>
+    (Response::UrlFetch(results))
+));
+
+named_args!(mailbox_data_flags(quirks: Quirks)<Response<'_>>, do_parse!(
+    tag!("FLAGS ") >>
+    flags: call!(flag_list, quirks) >>
+    (Response::MailboxData(MailboxDatum::Flags(flags.into_vec())))
+));
+
+named!(mailbox_data_exists<Response<'_>>, do_parse!(
+    num: number >>
+    tag!(" EXISTS") >>
+    (Response::MailboxData(MailboxDatum::Exists(num)))
+));
+
+named!(mailbox_data_recent<Response<'_>>, do_parse!(
+    num: number >>
+    tag!(" RECENT") >>
+    (Response::MailboxData(MailboxDatum::Recent(num)))
+));
+
+named_args!(mailbox_data_list(quirks: Quirks)<Response<'_>>, do_parse!(
+    tag!("LIST ") >>
+    flags: call!(flag_list, quirks) >>
+    tag!(" ") >>
+    delimiter: nstring >>
+    tag!(" ") >>
+    name: astring >>
+    (Response::MailboxData(MailboxDatum::List { flags: flags.into_vec(), delimiter, name }))
+));
+
+named!(status_att<StatusAttributeValue>, alt!(
+    map!(preceded!(tag!("HIGHESTMODSEQ "), number_64), StatusAttributeValue::HighestModSeq) |
+    map!(preceded!(tag!("MESSAGES "), number), StatusAttributeValue::Messages) |
+    map!(preceded!(tag!("RECENT "), number), StatusAttributeValue::Recent) |
+    map!(preceded!(tag!("UIDNEXT "), number), StatusAttributeValue::UidNext) |
+    map!(preceded!(tag!("UIDVALIDITY "), number), StatusAttributeValue::UidValidity) |
+    map!(preceded!(tag!("UNSEEN "), number), StatusAttributeValue::Unseen)
+));
+
+named!(mailbox_data_status<Response<'_>>, do_parse!(
+    tag!("STATUS ") >>
+    mailbox: astring >>
+    tag!(" (") >>
+    status: separated_list0!(tag!(" "), status_att) >>
+    tag!(")") >>
+    opt!(tag!(" ")) >>
+    (Response::MailboxData(MailboxDatum::Status { mailbox, status }))
+));
+
+named_args!(mailbox_data(quirks: Quirks)<Response<'_>>, alt!(
+    call!(mailbox_data_flags, quirks) |
+    mailbox_data_exists |
+    call!(mailbox_data_list, quirks) |
+    mailbox_data_recent |
+    mailbox_data_status
+));
+
+named!(nstring<Option<&str>>, map!(
+    alt!(
+        map!(tag!("NIL"), |_| "NIL") |
+        string
+    ),
+    |s| if s == "NIL" { None } else { Some(s) }
+));
+
+named!(nstring_bytes<Option<&[u8]>>, map!(
+    alt!(
+        map!(tag!("NIL"), |_| &b"NIL"[..]) |
+        string_bytes
+    ),
+    |s| if s == b"NIL" { None } else { Some(s) }
+));
+
+named!(address<Address<'_>>, do_parse!(
+    tag!("(") >>
+    name: nstring >>
+    tag!(" ") >>
+    adl: nstring >>
+    tag!(" ") >>
+    mailbox: nstring >>
+    tag!(" ") >>
+    host: nstring >>
+    tag!(")") >>
+    (Address { name, adl, mailbox, host })
+));
+
+named!(opt_addresses<Option<Vec<Address<'_>>>>, alt!(
+    map!(tag!("NIL"), |_| None) |
+    do_parse!(
+        tag!("(") >>
+        addrs: many1!(address) >>
+        tag!(")") >>
+        (Some(addrs))
+    )
+));
+
+// Exchange sends empty address lists, and NIL in place of addresses.
+named!(opt_addresses_nils<Option<Vec<Address<'_>>>>, alt!(
+    map!(tag!("NIL"), |_| None) |
+    do_parse!(
+        tag!("(") >>
+        addrs: many0!(alt!(
+            map!(terminated!(address, opt!(tag!(" "))), Some) |
+            map!(terminated!(tag!("NIL"), opt!(tag!(" "))), |_| None)
+        )) >>
+        tag!(")") >>
+        ({
+            let addrs = addrs.into_iter().flatten().collect::<Vec<_>>();
+            if addrs.is_empty() { None } else { Some(addrs) }
+        })
+    )
+));
+
+fn addresses(i: &[u8], quirks: Quirks) -> IResult<&[u8], Option<Vec<Address<'_>>>> {
+    match quirks.contains(Quirks::ENVELOPE_NILS) {
+        true => opt_addresses_nils(i),
+        false => opt_addresses(i),
+    }
+}
+
+named!(msg_att_body_section<AttributeValue<'_>>, do_parse!(
+    tag!("BODY[") >>
+    section: map_res!(take_till!(section_end), str::from_utf8) >>
+    tag!("]") >>
+    index: opt!(do_parse!(
+        tag!("<") >>
+        num: number >>
+        tag!(">") >>
+        (num)
+    )) >>
+    tag!(" ") >>
+    data: nstring_bytes >>
+    (AttributeValue::BodySection {
+        section: if !section.is_empty() { Some(section) } else { None },
+        index,
+        data,
+    })
+));
+
+named_args!(envelope(quirks: Quirks)<Envelope<'_>>, do_parse!(
+    tag!("(") >>
+    date: nstring >>
+    tag!(" ") >>
+    subject: nstring >>
+    tag!(" ") >>
+    from: call!(addresses, quirks) >>
+    tag!(" ") >>
+    sender: call!(addresses, quirks) >>
+    tag!(" ") >>
+    reply_to: call!(addresses, quirks) >>
+    tag!(" ") >>
+    to: call!(addresses, quirks) >>
+    tag!(" ") >>
+    cc: call!(addresses, quirks) >>
+    tag!(" ") >>
+    bcc: call!(addresses, quirks) >>
+    tag!(" ") >>
+    in_reply_to: nstring >>
+    tag!(" ") >>
+    message_id: nstring >>
+    tag!(")") >>
+    (Envelope { date, subject, from, sender, reply_to, to, cc, bcc, in_reply_to, message_id })
+));
+
+named_args!(msg_att_envelope(quirks: Quirks)<AttributeValue<'_>>, do_parse!(
+    tag!("ENVELOPE ") >>
+    envelope: call!(envelope, quirks) >>
+    (AttributeValue::Envelope(Box::new(envelope)))
+));
+
+named!(body_fld_param<Vec<(&str, &str)>>, alt!(
+    map!(tag!("NIL"), |_| vec![]) |
+    do_parse!(
+        tag!("(") >>
+        params: separated_list1!(tag!(" "), do_parse!(
+            key: string >>
+            tag!(" ") >>
+            value: string >>
+            ((key, value))
+        )) >>
+        tag!(")") >>
+        (params)
+    )
+));
+
+named!(body_fld_dsp<Option<ContentDisposition<'_>>>, alt!(
+    map!(tag!("NIL"), |_| None) |
+    do_parse!(
+        tag!("(") >>
+        kind: string >>
+        tag!(" ") >>
+        params: body_fld_param >>
+        tag!(")") >>
+        (Some(ContentDisposition { kind, params }))
+    )
+));
+
+// The language, location and future extensions, which are skipped.
+named!(body_extension<()>, alt!(
+    map!(nstring, |_| ()) |
+    map!(number, |_| ()) |
+    do_parse!(
+        tag!("(") >>
+        separated_list1!(tag!(" "), body_extension) >>
+        tag!(")") >>
+        (())
+    )
+));
+
+named!(body_fields<BodyFields<'_>>, do_parse!(
+    media_type: string >>
+    tag!(" ") >>
+    media_subtype: string >>
+    tag!(" ") >>
+    params: body_fld_param >>
+    tag!(" ") >>
+    id: nstring >>
+    tag!(" ") >>
+    description: nstring >>
+    tag!(" ") >>
+    encoding: string >>
+    tag!(" ") >>
+    size: number >>
+    (BodyFields { media_type, media_subtype, params, id, description, encoding, size })
+));
+
+named!(body_ext_1part<(Option<&str>, Option<ContentDisposition<'_>>)>, do_parse!(
+    md5: nstring >>
+    disposition: opt!(preceded!(tag!(" "), body_fld_dsp)) >>
+    many0!(preceded!(tag!(" "), body_extension)) >>
+    ((md5, disposition.flatten()))
+));
+
+// Text parts are followed by their size in lines, and message/rfc822 parts
+// by the envelope and structure of the message they contain as well.
+fn body_type_1part(i: &[u8], quirks: Quirks) -> IResult<&[u8], BodyStructure<'_>> {
+    let (i, fields) = body_fields(i)?;
+    let is_message = fields.media_type.eq_ignore_ascii_case("MESSAGE") &&
+        (fields.media_subtype.eq_ignore_ascii_case("RFC822") ||
+         fields.media_subtype.eq_ignore_ascii_case("GLOBAL"));
+    let (i, message) = if is_message {
+        let (i, (envelope, body)) = do_parse!(i,
+            tag!(" ") >>
+            envelope: call!(envelope, quirks) >>
+            tag!(" ") >>
+            body: call!(body, quirks) >>
+            ((envelope, body))
+        )?;
+        (i, Some((Box::new(envelope), Box::new(body))))
+    } else {
+        (i, None)
+    };
+    let (i, lines) = if is_message || fields.media_type.eq_ignore_ascii_case("TEXT") {
+        let (i, lines) = preceded!(i, tag!(" "), number)?;
+        (i, Some(lines))
+    } else {
+        (i, None)
+    };
+    let (i, ext) = opt!(i, preceded!(tag!(" "), body_ext_1part))?;
+    let (md5, disposition) = ext.unwrap_or((None, None));
+    Ok((i, BodyStructure::Single { fields, lines, message, md5, disposition }))
+}
+
+named_args!(body_type_mpart(quirks: Quirks)<BodyStructure<'_>>, do_parse!(
+    parts: many1!(call!(body, quirks)) >>
+    tag!(" ") >>
+    subtype: string >>
+    ext: opt!(do_parse!(
+        tag!(" ") >>
+        params: body_fld_param >>
+        disposition: opt!(preceded!(tag!(" "), body_fld_dsp)) >>
+        many0!(preceded!(tag!(" "), body_extension)) >>
+        ((params, disposition.flatten()))
+    )) >>
+    ({
+        let (params, disposition) = ext.unwrap_or((vec![], None));
+        BodyStructure::Multipart { parts, subtype, params, disposition }
+    })
+));
+
+named_args!(body(quirks: Quirks)<BodyStructure<'_>>, do_parse!(
+    tag!("(") >>
+    body: alt!(call!(body_type_mpart, quirks) | call!(body_type_1part, quirks)) >>
+    tag!(")") >>
+    (body)
+));
+
+// An annotation attribute or a metadata entry, with its value.
+named!(entry_value<(&str, Option<&str>)>, do_parse!(
+    name: astring >>
+    tag!(" ") >>
+    value: nstring >>
+    ((name, value))
+));
+
+// Unsolicited responses only list the entries that changed, without values.
+named!(annotation_entry<Annotation<'_>>, do_parse!(
+    entry: astring >>
+    values: opt!(do_parse!(
+        tag!(" (") >>
+        values: separated_list1!(tag!(" "), entry_value) >>
+        tag!(")") >>
+        (values)
+    )) >>
+    (Annotation { entry, values: values.unwrap_or_default() })
+));
+
+named!(msg_att_annotation<AttributeValue<'_>>, do_parse!(
+    tag!("ANNOTATION (") >>
+    entries: separated_list1!(tag!(" "), annotation_entry) >>
+    tag!(")") >>
+    (AttributeValue::Annotation(entries))
+));
+
+named_args!(msg_att_body(quirks: Quirks)<AttributeValue<'_>>, do_parse!(
+    tag!("BODY ") >>
+    body: call!(body, quirks) >>
+    (AttributeValue::Body(Box::new(body)))
+));
+
+named_args!(msg_att_body_structure(quirks: Quirks)<AttributeValue<'_>>, do_parse!(
+    tag!("BODYSTRUCTURE ") >>
+    body: call!(body, quirks) >>
+    (AttributeValue::BodyStructure(Box::new(body)))
+));
+
+named!(msg_att_internal_date<AttributeValue<'_>>, do_parse!(
+    tag!("INTERNALDATE ") >>
+    date: string >>
+    (AttributeValue::InternalDate(date))
+));
+
+named_args!(msg_att_flags(quirks: Quirks)<AttributeValue<'_>>, do_parse!(
+    tag!("FLAGS ") >>
+    flags: call!(flag_list, quirks) >>
+    (AttributeValue::Flags(flags))
+));
+
+named!(msg_att_rfc822<AttributeValue<'_>>, do_parse!(
+    tag!("RFC822 ") >>
+    raw: nstring_bytes >>
+    (AttributeValue::Rfc822(raw))
+));
+
+named!(msg_att_rfc822_size<AttributeValue<'_>>, do_parse!(
+    tag!("RFC822.SIZE ") >>
+    num: number >>
+    (AttributeValue::Rfc822Size(num))
+));
+
+named!(msg_att_mod_seq<AttributeValue<'_>>, do_parse!(
+    tag!("MODSEQ (") >>
+    num: number_64 >>
+    tag!(")") >>
+    (AttributeValue::ModSeq(num))
+));
+
+named!(msg_att_uid<AttributeValue<'_>>, do_parse!(
+    tag!("UID ") >>
+    num: number >>
+    (AttributeValue::Uid(num))
+));
+
+named_args!(msg_att(quirks: Quirks)<AttributeValue<'_>>, alt!(
+    msg_att_annotation |
+    call!(msg_att_body, quirks) |
+    msg_att_body_section |
+    call!(msg_att_body_structure, quirks) |
+    call!(msg_att_envelope, quirks) |
+    msg_att_internal_date |
+    call!(msg_att_flags, quirks) |
+    msg_att_mod_seq |
+    msg_att_rfc822 |
+    msg_att_rfc822_size |
+    msg_att_uid
+));
+
+named_args!(msg_att_list(quirks: Quirks)<SmallVec<[AttributeValue<'_>; 4]>>, do_parse!(
+    tag!("(") >>
+    elements: call!(space_separated, |i| msg_att(i, quirks)) >>
+    tag!(")") >>
+    (elements)
+));
+
+// Domino pads the attribute list with extra spaces.
+named_args!(msg_att_list_spaced(quirks: Quirks)<SmallVec<[AttributeValue<'_>; 4]>>, do_parse!(
+    tag!("(") >>
+    many0!(tag!(" ")) >>
+    elements: many1!(terminated!(call!(msg_att, quirks), many0!(tag!(" ")))) >>
+    tag!(")") >>
+    (elements.into_iter().collect())
+));
+
+named_args!(message_data_fetch(quirks: Quirks)<Response<'_>>, do_parse!(
+    num: number >>
+    tag!(" FETCH ") >>
+    attrs: call!(match quirks.contains(Quirks::FETCH_SPACING) {
+        true => msg_att_list_spaced,
+        false => msg_att_list,
+    }, quirks) >>
+    (Response::Fetch(num, attrs))
+));
+
+named_args!(message_data_uidfetch(quirks: Quirks)<Response<'_>>, do_parse!(
+    uid: number >>
+    tag!(" UIDFETCH ") >>
+    attrs: call!(match quirks.contains(Quirks::FETCH_SPACING) {
+        true => msg_att_list_spaced,
+        false => msg_att_list,
+    }, quirks) >>
+    (Response::UidFetch(uid, attrs))
+));
+
+named!(message_data_expunge<Response<'_>>, do_parse!(
+    num: number >>
+    tag!(" EXPUNGE") >>
+    (Response::Expunge(num))
+));
+
+named!(seq_range<RangeInclusive<u32>>, do_parse!(
+    start: number >>
+    stop: opt!(do_parse!(
+        tag!(":") >>
+        num: number >>
+        (num)
+    )) >>
+    (start..=stop.unwrap_or(start))
+));
+
+named!(sequence_set<Vec<RangeInclusive<u32>>>, separated_list1!(
+    tag!(","),
+    seq_range
+));
+
+named!(message_data_vanished<Response<'_>>, do_parse!(
+    tag!("VANISHED ") >>
+    earlier: opt!(tag!("(EARLIER) ")) >>
+    uids: sequence_set >>
+    (Response::Vanished { earlier: earlier.is_some(), uids })
+));
+
+named!(tag<RequestId>, map!(map_res!(take_while1!(tag_char), str::from_utf8),
+    |s| RequestId(s.into())
+));
+
+// This is not quite according to spec, which mandates the following:
+//     ["[" resp-text-code "]" SP] text
+// However, examples in RFC 4551 (Conditional STORE) counteract this by giving
+// examples of `resp-text` that do not include the trailing space and text.
+// Yahoo leaves out the space after the code.
+named_args!(resp_text(quirks: Quirks)<(Option<ResponseCode<'_>>, Option<&str>)>, do_parse!(
+    code: opt!(resp_text_code) >>
+    text: text >>
+    ({
+        let res = if text.is_empty() {
+            None
+        } else if code.is_some() && quirks.contains(Quirks::CODE_SPACE) {
+            Some(text.strip_prefix(' ').unwrap_or(text))
+        } else if code.is_some() {
+            let mut chars = text.chars();
+            chars.next();
+            Some(chars.as_str())
+        } else {
+            Some(text)
+        };
+        (code, res)
+    })
+));
+
+named_args!(response_tagged(quirks: Quirks)<Response<'_>>, do_parse!(
+    tag: tag >>
+    tag!(" ") >>
+    status: status >>
+    tag!(" ") >>
+    text: call!(resp_text, quirks) >>
+    (Response::Done(tag, status, text.0, text.1))
+));
+
+named_args!(resp_cond(quirks: Quirks)<Response<'_>>, do_parse!(
+    status: status >>
+    tag!(" ") >>
+    text: call!(resp_text, quirks) >>
+    (Response::Data(status, text.0, text.1))
+));
+
+named_args!(response_data(quirks: Quirks)<Response<'_>>, do_parse!(
+    tag!("* ") >>
+    contents: alt!(
+        call!(resp_cond, quirks) |
+        call!(mailbox_data, quirks) |
+        message_data_expunge |
+        call!(message_data_fetch, quirks) |
+        call!(message_data_uidfetch, quirks) |
+        message_data_vanished |
+        enabled_data |
+        capability_data |
+        esearch_data |
+        search_data |
+        sort_data |
+        thread_data |
+        genurlauth_data |
+        language_data |
+        metadata_data |
+        urlfetch_data
+    ) >>
+    (contents)
+));
+
+// Some servers omit the space when sending no text.
+named_args!(continue_req(quirks: Quirks)<Response<'_>>, do_parse!(
+    tag!("+") >>
+    opt!(tag!(" ")) >>
+    text: call!(resp_text, quirks) >>
+    (Response::Continue { code: text.0, text: text.1 })
+));
+
+// A response without its line terminator.
+named_args!(response_line(quirks: Quirks)<Response<'_>>, alt!(
+    call!(continue_req, quirks) |
+    call!(response_data, quirks) |
+    call!(response_tagged, quirks)
+));
+
+named_args!(response(quirks: Quirks)<Response<'_>>, terminated!(
+    call!(response_line, quirks),
+    call!(match quirks.contains(Quirks::BARE_LF) {
+        true => line_end_lenient,
+        false => line_end,
+    })
+));
+
+named!(line_end, tag!("\r\n"));
+
+// Some servers and middleboxes end lines with a bare LF.
+named!(line_end_lenient, alt!(tag!("\r\n") | tag!("\n")));
+
+/// Result of scanning for the end of the first line in a buffer, taking
+/// quoted strings and literals into account.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Line {
+    /// The line is complete; contains its length including the CRLF.
+    Complete(usize),
+    /// A literal's data extends beyond the end of the buffer. Contains the
+    /// offset of the literal's `{`, the offset at which its data starts, and
+    /// the length of the data.
+    OpenLiteral(usize, usize, usize),
+    Incomplete,
+    /// A literal announces a length too large to address.
+    Invalid,
+}
+
+pub fn scan_line(buf: &[u8]) -> Line {
+    let mut i = 0;
+    let mut quoted = false;
+    while i < buf.len() {
+        // Skip to the next byte that matters.
+        let next = match quoted {
+            true => memchr2(b'\\', b'"', &buf[i..]),
+            false => memchr3(b'"', b'\r', b'{', &buf[i..]),
+        };
+        i += match next {
+            Some(pos) => pos,
+            None => break,
+        };
+        let c = buf[i];
+        if quoted {
+            if c == b'\\' {
+                i += 1;
+            } else if c == b'"' {
+                quoted = false;
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            b'"' => {
+                quoted = true;
+            },
+            b'\r' if buf.get(i + 1) == Some(&b'\n') => {
+                return Line::Complete(i + 2);
+            },
+            b'{' => {
+                let digits = buf[i + 1..].iter().take_while(|c| is_digit(**c)).count();
+                let mut close = i + 1 + digits;
+                // Non-synchronizing literals (RFC 7888) can only be sent by clients.
+                if buf.get(close) == Some(&b'+') {
+                    close += 1;
+                }
+                if digits > 0 && buf.len() >= close + 3 && &buf[close..close + 3] == b"}\r\n" {
+                    let len = str::from_utf8(&buf[i + 1..i + 1 + digits]).unwrap().parse::<usize>();
+                    let len = match len {
+                        Ok(len) => len,
+                        Err(_) => return Line::Invalid,
+                    };
+                    let data = close + 3;
+                    let end = match data.checked_add(len) {
+                        Some(end) => end,
+                        None => return Line::Invalid,
+                    };
+                    if end > buf.len() {
+                        return Line::OpenLiteral(i, data, len);
+                    }
+                    i = end;
+                    continue;
+                }
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+    Line::Incomplete
+}
+
+/// Locates a literal whose data extends beyond the end of `buf`, inside the
+/// first response line in `buf`. See `Line::OpenLiteral`.
+pub fn open_literal(buf: &[u8]) -> Option<(usize, usize, usize)> {
+    match scan_line(buf) {
+        Line::OpenLiteral(header, data, len) => Some((header, data, len)),
+        _ => None,
+    }
+}
+
+/// Splits a complete command line (including CRLF) into its tag and the
+/// remainder of the command.
+pub fn parse_command(line: &[u8]) -> Option<Request> {
+    match tag(line) {
+        Ok((rest, id)) => {
+            if rest.len() < 3 || rest[0] != b' ' || !rest.ends_with(b"\r\n") {
+                return None;
+            }
+            Some(Request(id, rest[1..rest.len() - 2].to_vec()))
+        },
+        _ => None,
+    }
+}
+
+pub type ParseResult<'a> = IResult<&'a [u8], Response<'a>>;
+
+pub fn parse_response(msg: &[u8]) -> ParseResult<'_> {
+    response(msg, Quirks::default())
+}
+
+/// Like `parse_response()`, but also accepts a bare LF as the terminator of
+/// the response line, as sent by some broken servers. Literals still have to
+/// be announced with CRLF.
+pub fn parse_response_lenient(msg: &[u8]) -> ParseResult<'_> {
+    response(msg, Quirks::BARE_LF)
+}
+
+/// Like `parse_response()`, but tolerating `quirks`.
+pub fn parse_response_quirks(msg: &[u8], quirks: Quirks) -> ParseResult<'_> {
+    response(msg, quirks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan_line, Line};
+
+    #[test]
+    fn scan_line_literals() {
+        assert_eq!(scan_line(b"* 1 FETCH (BODY[] {3}\r\nabc)\r\n"), Line::Complete(29));
+        assert_eq!(scan_line(b"* 1 FETCH (BODY[] {5}\r\nabc"), Line::OpenLiteral(18, 23, 5));
+        // A length that overflows when added to the offset of the data.
+        assert_eq!(scan_line(b"* 1 FETCH (BODY[] {18446744073709551610}\r\nabc)\r\n"), Line::Invalid);
+        assert_eq!(scan_line(b"A1 APPEND INBOX {99999999999999999999999}\r\n"), Line::Invalid);
+    }
+}
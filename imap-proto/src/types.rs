@@ -0,0 +1,1191 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::fmt;
+use core::ops::{BitOr, BitOrAssign, RangeInclusive};
+
+use smallvec::SmallVec;
+
+#[derive(Debug)]
+pub struct Request(pub RequestId, pub Vec<u8>);
+
+impl Request {
+    /// Append the command as sent to the server to `dst`: the tag, the
+    /// command with the contents of its literals, and the final CRLF.
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(self.0 .0.as_bytes());
+        dst.push(b' ');
+        dst.extend_from_slice(&self.1);
+        dst.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Shows the command line without the final CRLF, replacing invalid UTF-8.
+/// Credentials are shown as well.
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.0, String::from_utf8_lossy(&self.1))
+    }
+}
+
+#[derive(Debug)]
+pub enum AttrMacro {
+    All,
+    Fast,
+    Full,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Response<'a> {
+    BodyChunk(&'a [u8]),
+    Capabilities(Vec<&'a str>),
+    Continue {
+        code: Option<ResponseCode<'a>>,
+        text: Option<&'a str>,
+    },
+    Done(RequestId, Status, Option<ResponseCode<'a>>, Option<&'a str>),
+    Data(Status, Option<ResponseCode<'a>>, Option<&'a str>),
+    Enabled(Vec<&'a str>), // RFC 5161
+    Esearch { // RFC 4731
+        tag: Option<&'a str>,
+        /// The mailbox and its UIDVALIDITY, for the results of a search of
+        /// several mailboxes (RFC 7377).
+        mailbox: Option<(&'a str, u32)>,
+        uid: bool,
+        results: SearchResults,
+    },
+    Expunge(u32),
+    Fetch(u32, SmallVec<[AttributeValue<'a>; 4]>),
+    GenUrlAuth(Vec<&'a str>), // RFC 4467, section 7
+    Language(Vec<&'a str>), // RFC 5255, section 3.3
+    MailboxData(MailboxDatum<'a>),
+    /// Metadata entries of `mailbox`, or of the server if it is empty,
+    /// paired with their values (RFC 5464, section 4.4). Unsolicited
+    /// responses only list the changed entries, with `None` as values.
+    Metadata {
+        mailbox: &'a str,
+        values: Vec<(&'a str, Option<&'a str>)>,
+    },
+    Search(Vec<u32>),
+    Sort(Vec<u32>), // RFC 5256
+    Thread(Vec<Thread>), // RFC 5256
+    /// The attributes of the message with this UID, sent instead of FETCH
+    /// responses once UIDONLY is enabled (RFC 9586, section 3.3).
+    UidFetch(u32, SmallVec<[AttributeValue<'a>; 4]>),
+    UrlFetch(Vec<(&'a str, Option<&'a [u8]>)>), // RFC 4467, section 7
+    Vanished { // RFC 5162, section 3.6
+        earlier: bool,
+        uids: Vec<RangeInclusive<u32>>,
+    },
+}
+
+impl<'a> Response<'a> {
+    pub fn kind(&self) -> ResponseKind {
+        match *self {
+            Response::BodyChunk(_) => ResponseKind::BodyChunk,
+            Response::Capabilities(_) => ResponseKind::Capabilities,
+            Response::Continue { .. } => ResponseKind::Continue,
+            Response::Done(..) => ResponseKind::Done,
+            Response::Data(..) => ResponseKind::Data,
+            Response::Enabled(_) => ResponseKind::Enabled,
+            Response::Esearch { .. } => ResponseKind::Esearch,
+            Response::Expunge(_) => ResponseKind::Expunge,
+            Response::Fetch(..) => ResponseKind::Fetch,
+            Response::GenUrlAuth(_) => ResponseKind::GenUrlAuth,
+            Response::Language(_) => ResponseKind::Language,
+            Response::MailboxData(MailboxDatum::List { .. }) => ResponseKind::List,
+            Response::MailboxData(MailboxDatum::Status { .. }) => ResponseKind::Status,
+            Response::MailboxData(_) => ResponseKind::MailboxData,
+            Response::Metadata { .. } => ResponseKind::Metadata,
+            Response::Search(_) => ResponseKind::Search,
+            Response::Sort(_) => ResponseKind::Sort,
+            Response::Thread(_) => ResponseKind::Thread,
+            Response::UidFetch(..) => ResponseKind::UidFetch,
+            Response::UrlFetch(_) => ResponseKind::UrlFetch,
+            Response::Vanished { .. } => ResponseKind::Vanished,
+        }
+    }
+}
+
+/// Discriminant of `Response`, used to declare which untagged responses a
+/// command solicits. LIST and STATUS responses have kinds of their own, so
+/// that commands returning them do not claim mailbox updates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResponseKind {
+    BodyChunk,
+    Capabilities,
+    Continue,
+    Done,
+    Data,
+    Enabled,
+    Esearch,
+    Expunge,
+    Fetch,
+    GenUrlAuth,
+    Language,
+    List,
+    MailboxData,
+    Metadata,
+    Search,
+    Sort,
+    Status,
+    Thread,
+    UidFetch,
+    UrlFetch,
+    Vanished,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Status {
+    Ok,
+    No,
+    Bad,
+    PreAuth,
+    Bye,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ResponseCode<'a> {
+    Alert,
+    AppendUid(u32, Vec<RangeInclusive<u32>>), // RFC 4315, section 3
+    BadCharset(Vec<&'a str>), // RFC 3501, section 7.1; empty if not listed
+    BadUrl(&'a str), // RFC 4469, section 6
+    Capabilities(Vec<&'a str>),
+    CompressionActive, // RFC 4978, section 4
+    CopyUid(u32, Vec<RangeInclusive<u32>>, Vec<RangeInclusive<u32>>), // RFC 4315, section 3
+    HighestModSeq(u64), // RFC 4551, section 3.1.1
+    MailboxId(&'a str), // RFC 8474, section 4.1
+    Modified(&'a str), // RFC 4551, section 3.2
+    NoModSeq, // RFC 4551, section 3.1.2
+    OverQuota, // RFC 5530, section 3
+    PermanentFlags(PermanentFlags<'a>),
+    PrivacyRequired, // RFC 5530, section 3
+    ReadOnly,
+    ReadWrite,
+    Referral(&'a str), // RFC 2221, RFC 2193
+    TooBig, // RFC 4469, section 5
+    TryCreate,
+    UidNext(u32),
+    UidRequired, // RFC 9586, section 3
+    UidValidity(u32),
+    Unseen(u32),
+    UseAttr, // RFC 6154, section 6
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Capability {
+    Imap4rev1,
+    Imap4rev2, // RFC 9051
+    Annotate, // RFC 5257, as ANNOTATE-EXPERIMENT-1
+    AppendLimit(Option<u64>), // RFC 7889; without a limit for all mailboxes
+    Auth(String),
+    Catenate, // RFC 4469
+    CompressDeflate, // RFC 4978
+    Condstore, // RFC 4551
+    ContextSearch, // RFC 5267
+    ContextSort, // RFC 5267
+    CreateSpecialUse, // RFC 6154
+    Enable, // RFC 5161
+    Esearch, // RFC 4731
+    Esort, // RFC 5267
+    Id, // RFC 2971
+    Idle, // RFC 2177
+    Language, // RFC 5255
+    ListStatus, // RFC 5819
+    LiteralPlus, // RFC 7888
+    LoginDisabled,
+    Metadata, // RFC 5464
+    MetadataServer, // RFC 5464
+    Move, // RFC 6851
+    MultiAppend, // RFC 3502
+    MultiSearch, // RFC 7377
+    Namespace, // RFC 2342
+    Partial, // RFC 9394
+    Qresync, // RFC 5162
+    Replace, // RFC 8508
+    SaslIr, // RFC 4959
+    SearchFuzzy, // RFC 6203
+    Sort, // RFC 5256
+    SortDisplay, // RFC 5957
+    SpecialUse, // RFC 6154
+    StartTls,
+    Thread(String), // RFC 5256
+    UidOnly, // RFC 9586
+    UidPlus, // RFC 4315
+    UrlAuth, // RFC 4467
+    Utf8Accept, // RFC 6855
+    Utf8Only, // RFC 6855
+    Unauthenticate, // RFC 8437
+    Within, // RFC 5032
+    Other(String),
+}
+
+impl<'a> From<&'a str> for Capability {
+    fn from(s: &'a str) -> Capability {
+        let upper = s.to_ascii_uppercase();
+        match upper.as_str() {
+            "IMAP4REV1" => Capability::Imap4rev1,
+            "IMAP4REV2" => Capability::Imap4rev2,
+            "ANNOTATE-EXPERIMENT-1" => Capability::Annotate,
+            "CATENATE" => Capability::Catenate,
+            "COMPRESS=DEFLATE" => Capability::CompressDeflate,
+            "CONDSTORE" => Capability::Condstore,
+            "CONTEXT=SEARCH" => Capability::ContextSearch,
+            "CONTEXT=SORT" => Capability::ContextSort,
+            "CREATE-SPECIAL-USE" => Capability::CreateSpecialUse,
+            "ENABLE" => Capability::Enable,
+            "ESEARCH" => Capability::Esearch,
+            "ESORT" => Capability::Esort,
+            "ID" => Capability::Id,
+            "IDLE" => Capability::Idle,
+            "LANGUAGE" => Capability::Language,
+            "LIST-STATUS" => Capability::ListStatus,
+            "LITERAL+" => Capability::LiteralPlus,
+            "LOGINDISABLED" => Capability::LoginDisabled,
+            "METADATA" => Capability::Metadata,
+            "METADATA-SERVER" => Capability::MetadataServer,
+            "MOVE" => Capability::Move,
+            "MULTIAPPEND" => Capability::MultiAppend,
+            "MULTISEARCH" => Capability::MultiSearch,
+            "NAMESPACE" => Capability::Namespace,
+            "PARTIAL" => Capability::Partial,
+            "QRESYNC" => Capability::Qresync,
+            "REPLACE" => Capability::Replace,
+            "SASL-IR" => Capability::SaslIr,
+            "SEARCH=FUZZY" => Capability::SearchFuzzy,
+            "SORT" => Capability::Sort,
+            "SORT=DISPLAY" => Capability::SortDisplay,
+            "SPECIAL-USE" => Capability::SpecialUse,
+            "STARTTLS" => Capability::StartTls,
+            "UIDONLY" => Capability::UidOnly,
+            "UIDPLUS" => Capability::UidPlus,
+            "URLAUTH" => Capability::UrlAuth,
+            "UTF8=ACCEPT" => Capability::Utf8Accept,
+            "UTF8=ONLY" => Capability::Utf8Only,
+            "UNAUTHENTICATE" => Capability::Unauthenticate,
+            "WITHIN" => Capability::Within,
+            "APPENDLIMIT" => Capability::AppendLimit(None),
+            _ if upper.starts_with("APPENDLIMIT=") => match upper[12..].parse() {
+                Ok(limit) => Capability::AppendLimit(Some(limit)),
+                Err(_) => Capability::Other(upper),
+            },
+            _ if upper.starts_with("AUTH=") => Capability::Auth(upper[5..].to_string()),
+            _ if upper.starts_with("THREAD=") => Capability::Thread(upper[7..].to_string()),
+            _ => Capability::Other(upper),
+        }
+    }
+}
+
+/// Attributes marking mailboxes with a special purpose (RFC 6154).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecialUse {
+    All,
+    Archive,
+    Drafts,
+    Flagged,
+    Junk,
+    Sent,
+    Trash,
+}
+
+impl SpecialUse {
+    pub fn as_flag(&self) -> &'static str {
+        match *self {
+            SpecialUse::All => "\\All",
+            SpecialUse::Archive => "\\Archive",
+            SpecialUse::Drafts => "\\Drafts",
+            SpecialUse::Flagged => "\\Flagged",
+            SpecialUse::Junk => "\\Junk",
+            SpecialUse::Sent => "\\Sent",
+            SpecialUse::Trash => "\\Trash",
+        }
+    }
+
+    /// The special use denoted by the LIST attribute `flag`, if any.
+    pub fn from_flag(flag: &str) -> Option<SpecialUse> {
+        [
+            SpecialUse::All, SpecialUse::Archive, SpecialUse::Drafts, SpecialUse::Flagged,
+            SpecialUse::Junk, SpecialUse::Sent, SpecialUse::Trash,
+        ].iter().find(|usage| usage.as_flag().eq_ignore_ascii_case(flag)).cloned()
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MailboxDatum<'a> {
+    Exists(u32),
+    Flags(Vec<&'a str>),
+    List {
+        flags: Vec<&'a str>,
+        delimiter: Option<&'a str>,
+        name: &'a str,
+    },
+    Recent(u32),
+    Status {
+        mailbox: &'a str,
+        status: Vec<StatusAttributeValue>,
+    },
+}
+
+/// Items that can be requested with the STATUS command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatusAttribute {
+    HighestModSeq, // RFC 4551, section 3.6
+    Messages,
+    Recent,
+    UidNext,
+    UidValidity,
+    Unseen,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum StatusAttributeValue {
+    HighestModSeq(u64), // RFC 4551, section 3.6
+    Messages(u32),
+    Recent(u32),
+    UidNext(u32),
+    UidValidity(u32),
+    Unseen(u32),
+}
+
+/// A message flag. System flags and common keywords are matched
+/// case-insensitively; all other flags are kept as sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Flag<'a> {
+    Answered,
+    Deleted,
+    Draft,
+    Flagged,
+    Recent,
+    Seen,
+    Forwarded, // $Forwarded, RFC 5550
+    Junk, // $Junk, RFC 9051
+    MdnSent, // $MDNSent, RFC 3503
+    NotJunk, // $NotJunk, RFC 9051
+    Phishing, // $Phishing, RFC 9051
+    Custom(&'a str),
+}
+
+impl<'a> Flag<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match *self {
+            Flag::Answered => "\\Answered",
+            Flag::Deleted => "\\Deleted",
+            Flag::Draft => "\\Draft",
+            Flag::Flagged => "\\Flagged",
+            Flag::Recent => "\\Recent",
+            Flag::Seen => "\\Seen",
+            Flag::Forwarded => "$Forwarded",
+            Flag::Junk => "$Junk",
+            Flag::MdnSent => "$MDNSent",
+            Flag::NotJunk => "$NotJunk",
+            Flag::Phishing => "$Phishing",
+            Flag::Custom(flag) => flag,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Flag<'a> {
+    fn from(s: &'a str) -> Flag<'a> {
+        match s.to_ascii_lowercase().as_str() {
+            "\\answered" => Flag::Answered,
+            "\\deleted" => Flag::Deleted,
+            "\\draft" => Flag::Draft,
+            "\\flagged" => Flag::Flagged,
+            "\\recent" => Flag::Recent,
+            "\\seen" => Flag::Seen,
+            "$forwarded" => Flag::Forwarded,
+            "$junk" => Flag::Junk,
+            "$mdnsent" => Flag::MdnSent,
+            "$notjunk" => Flag::NotJunk,
+            "$phishing" => Flag::Phishing,
+            _ => Flag::Custom(s),
+        }
+    }
+}
+
+impl fmt::Display for Flag<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The flags which can be changed permanently in the selected mailbox.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PermanentFlags<'a> {
+    pub flags: Vec<Flag<'a>>,
+    /// Whether new keywords can be created by storing them, which the
+    /// server indicates with `\\*`.
+    pub new_keywords: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoreType {
+    Replace,
+    Add,
+    Remove,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Attribute {
+    /// The `attributes` of the per-message annotation `entries` (RFC 5257),
+    /// such as `/comment` and `value.priv`. Entries may use the `*` and `%`
+    /// wildcards.
+    Annotation {
+        entries: Vec<String>,
+        attributes: Vec<String>,
+    },
+    Body,
+    BodySection(String),
+    /// A body section, without setting the `\\Seen` flag.
+    BodyPeek(String),
+    BodyStructure,
+    /// `len` octets of a body section from `offset` on, without setting the
+    /// `\\Seen` flag.
+    BodyPeekPartial {
+        section: String,
+        offset: u32,
+        len: u32,
+    },
+    Envelope,
+    Flags,
+    InternalDate,
+    ModSeq, // RFC 4551, section 3.3.2
+    Rfc822,
+    Rfc822Size,
+    Uid,
+}
+
+/// A part of a message composed by the server with APPEND CATENATE
+/// (RFC 4469).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CatenatePart {
+    /// An IMAP URL (RFC 5092) referring to a message or part of one.
+    Url(String),
+    Text(Vec<u8>),
+}
+
+/// Search keys for the SEARCH command (RFC 3501, section 6.4.4). A list of
+/// keys matches the messages matching all of them; use `And` to build one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SearchCriteria {
+    All,
+    And(Vec<SearchCriteria>),
+    Answered,
+    Bcc(String),
+    /// Messages with an internal date before this day.
+    Before(Date),
+    Body(String),
+    Cc(String),
+    Deleted,
+    Draft,
+    Flagged,
+    From(String),
+    /// Match the key approximately, as decided by the server (RFC 6203).
+    /// Requires the SEARCH=FUZZY capability.
+    Fuzzy(Box<SearchCriteria>),
+    Header(String, String),
+    Keyword(String),
+    Larger(u32),
+    New,
+    Not(Box<SearchCriteria>),
+    Old,
+    /// Messages with an internal date more than this many seconds ago
+    /// (RFC 5032).
+    Older(u32),
+    /// Messages with an internal date on this day.
+    On(Date),
+    Or(Box<SearchCriteria>, Box<SearchCriteria>),
+    Recent,
+    Seen,
+    /// Messages with a `Date:` header before this day.
+    SentBefore(Date),
+    /// Messages with a `Date:` header on this day.
+    SentOn(Date),
+    /// Messages with a `Date:` header on or after this day.
+    SentSince(Date),
+    /// A sequence set, such as `1:4,7`.
+    Sequence(String),
+    /// Messages with an internal date on or after this day.
+    Since(Date),
+    Smaller(u32),
+    Subject(String),
+    Text(String),
+    To(String),
+    /// A UID set, such as `1:4,7`.
+    Uid(String),
+    Unanswered,
+    Undeleted,
+    Undraft,
+    Unflagged,
+    Unkeyword(String),
+    Unseen,
+    /// Messages with an internal date at most this many seconds ago
+    /// (RFC 5032).
+    Younger(u32),
+}
+
+/// A day of the calendar, as used by the date search keys. It is always
+/// formatted as `d-Mon-yyyy`, with English month names.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Date {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+impl Date {
+    /// The given day, with `month` from 1 to 12, if it exists and `year`
+    /// has four digits.
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Date> {
+        let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+        let days = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return None,
+        };
+        match (1000..=9999).contains(&year) && (1..=days).contains(&day) {
+            true => Some(Date { year, month, day }),
+            false => None,
+        }
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.day, MONTHS[usize::from(self.month - 1)], self.year)
+    }
+}
+
+/// Fails for years outside 1000 to 9999.
+#[cfg(feature = "chrono")]
+impl core::convert::TryFrom<chrono::NaiveDate> for Date {
+    type Error = chrono::NaiveDate;
+
+    fn try_from(date: chrono::NaiveDate) -> Result<Date, chrono::NaiveDate> {
+        use chrono::Datelike;
+        let year = u16::try_from(date.year()).map_err(|_| date)?;
+        Date::new(year, date.month() as u8, date.day() as u8).ok_or(date)
+    }
+}
+
+/// A point in time in the IMAP date-time format used by INTERNALDATE and
+/// APPEND, such as `17-Jul-1996 02:44:25 -0700`. The offset from UTC is
+/// kept, since servers report it as given when the message was stored.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DateTime {
+    date: Date,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    offset: i16,
+}
+
+impl DateTime {
+    /// The given time on `date`, with `offset` in minutes east of UTC, if
+    /// the time exists and the offset is less than a day.
+    pub fn new(date: Date, hour: u8, minute: u8, second: u8, offset: i16) -> Option<DateTime> {
+        match hour < 24 && minute < 60 && second < 60 && offset.abs() < 24 * 60 {
+            true => Some(DateTime { date, hour, minute, second, offset }),
+            false => None,
+        }
+    }
+
+    /// Parses an INTERNALDATE value, such as ` 7-Jul-1996 02:44:25 -0700`.
+    pub fn parse(text: &str) -> Option<DateTime> {
+        let mut fields = text.trim_start().split(' ');
+        let (date, time, zone) = (fields.next()?, fields.next()?, fields.next()?);
+        if fields.next().is_some() {
+            return None;
+        }
+        let mut fields = date.split('-');
+        let (day, month, year) = (fields.next()?, fields.next()?, fields.next()?);
+        let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u8 + 1;
+        let day = number(day, 1).or_else(|| number(day, 2))?;
+        let date = Date::new(number(year, 4)?, month, day)?;
+        let mut fields = time.split(':');
+        let hour = number(fields.next()?, 2)?;
+        let minute = number(fields.next()?, 2)?;
+        let second = number(fields.next()?, 2)?;
+        let sign = match zone.get(..1)? {
+            "+" => 1,
+            "-" => -1,
+            _ => return None,
+        };
+        let offset = number::<i16>(zone.get(1..3)?, 2)? * 60 + number::<i16>(zone.get(3..)?, 2)?;
+        DateTime::new(date, hour, minute, second, sign * offset)
+    }
+
+    pub fn date(&self) -> Date {
+        self.date
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    /// The offset from UTC in minutes, positive east of Greenwich.
+    pub fn offset(&self) -> i16 {
+        self.offset
+    }
+}
+
+// Parses exactly `len` ASCII digits.
+fn number<T: core::str::FromStr>(text: &str, len: usize) -> Option<T> {
+    match text.len() == len && text.bytes().all(|c| c.is_ascii_digit()) {
+        true => text.parse().ok(),
+        false => None,
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let date = self.date;
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        write!(f, "{:>2}-{}-{} {:02}:{:02}:{:02} {}{:02}{:02}",
+               date.day, MONTHS[usize::from(date.month - 1)], date.year, self.hour, self.minute,
+               self.second, sign, self.offset.abs() / 60, self.offset.abs() % 60)
+    }
+}
+
+/// Fails for years outside 1000 to 9999. Leap seconds become second 59.
+#[cfg(feature = "chrono")]
+impl core::convert::TryFrom<chrono::DateTime<chrono::FixedOffset>> for DateTime {
+    type Error = chrono::DateTime<chrono::FixedOffset>;
+
+    fn try_from(time: chrono::DateTime<chrono::FixedOffset>)
+                -> Result<DateTime, chrono::DateTime<chrono::FixedOffset>> {
+        use chrono::{Offset, Timelike};
+        let date = Date::try_from(time.date_naive()).map_err(|_| time)?;
+        let offset = (time.offset().fix().local_minus_utc() / 60) as i16;
+        DateTime::new(date, time.hour() as u8, time.minute() as u8, time.second() as u8, offset).ok_or(time)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<DateTime> for chrono::DateTime<chrono::FixedOffset> {
+    fn from(time: DateTime) -> chrono::DateTime<chrono::FixedOffset> {
+        use chrono::{NaiveDate, TimeZone};
+        let date = time.date;
+        let offset = chrono::FixedOffset::east_opt(i32::from(time.offset) * 60).expect("offset below a day");
+        let local = NaiveDate::from_ymd_opt(i32::from(date.year), u32::from(date.month), u32::from(date.day))
+            .and_then(|day| day.and_hms_opt(u32::from(time.hour), u32::from(time.minute), u32::from(time.second)))
+            .expect("valid date and time");
+        offset.from_local_datetime(&local).unwrap()
+    }
+}
+
+/// Result options for extended SEARCH (RFC 4731) and SORT (RFC 5267).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SearchReturn {
+    All,
+    Count,
+    Max,
+    Min,
+    /// A page of the results, by position: `Partial(1, 100)` for the
+    /// first 100, or `Partial(-1, -100)` for the last 100 (RFC 9394).
+    Partial(i32, i32),
+    /// The relevancy scores of the results of a fuzzy search, in
+    /// `SearchResults::relevancy` (RFC 6203).
+    Relevancy,
+    /// Keep the results up to date until CANCELUPDATE, reporting changes
+    /// in `SearchResults::add_to` and `remove_from` (RFC 5267, section 4).
+    Update,
+}
+
+/// The results of an extended SEARCH or SORT, as requested with
+/// `SearchReturn` options. For SORT, `all` is in sort order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SearchResults {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub count: Option<u32>,
+    pub all: Option<Vec<RangeInclusive<u32>>>,
+    pub partial: Option<PartialResults>, // RFC 9394
+    pub add_to: Vec<ContextUpdate>, // RFC 5267, section 4.3
+    pub remove_from: Vec<ContextUpdate>, // RFC 5267, section 4.4
+    /// Scores from 1 to 100, in the order of the messages in `all` (RFC
+    /// 6203, section 4).
+    pub relevancy: Option<Vec<u8>>,
+}
+
+/// Messages that joined or left the results of a search kept up to date
+/// with `SearchReturn::Update`, at `position` in the results of a SORT, or
+/// with a position of 0 for SEARCH.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ContextUpdate {
+    pub position: u32,
+    pub set: Vec<RangeInclusive<u32>>,
+}
+
+/// The page of results requested with `SearchReturn::Partial`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PartialResults {
+    pub first: i32,
+    pub last: i32,
+    pub set: Vec<RangeInclusive<u32>>,
+}
+
+/// The mailboxes to search with a multi-mailbox search (RFC 7377, section
+/// 2), named as for LIST.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SearchSource {
+    Selected,
+    /// The selected mailbox, with the results delayed until the client
+    /// is no longer using its sequence numbers.
+    SelectedDelayed,
+    Inboxes,
+    Personal,
+    Subscribed,
+    /// The mailboxes, with all the mailboxes below them.
+    Subtree(Vec<String>),
+    /// The mailboxes, with the mailboxes one level below them.
+    SubtreeOne(Vec<String>),
+    Mailboxes(Vec<String>),
+}
+
+/// Sort keys for the SORT command (RFC 5256).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SortCriterion {
+    Arrival,
+    Cc,
+    Date,
+    /// The display name of the first From address, falling back to the
+    /// mailbox (RFC 5957).
+    DisplayFrom,
+    /// The display name of the first To address, falling back to the
+    /// mailbox (RFC 5957).
+    DisplayTo,
+    From,
+    /// The relevancy score of a fuzzy search (RFC 6203).
+    Relevancy,
+    Reverse(Box<SortCriterion>),
+    Size,
+    Subject,
+    To,
+}
+
+/// Threading algorithms for the THREAD command (RFC 5256). Each requires
+/// the matching `Capability::Thread`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThreadAlgorithm {
+    OrderedSubject,
+    References,
+    /// Like `References`, but ordering threads by their latest message
+    /// (RFC 5957).
+    Refs,
+}
+
+impl ThreadAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ThreadAlgorithm::OrderedSubject => "ORDEREDSUBJECT",
+            ThreadAlgorithm::References => "REFERENCES",
+            ThreadAlgorithm::Refs => "REFS",
+        }
+    }
+}
+
+/// A message of a THREAD response and its replies. The message is `None`
+/// when it is not present, but its replies are.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Thread {
+    pub id: Option<u32>,
+    pub children: Vec<Thread>,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AttributeValue<'a> {
+    Annotation(Vec<Annotation<'a>>), // RFC 5257, section 4.4
+    /// The structure fetched with BODY, which lacks the extension data, so
+    /// that the MD5 and the disposition are always `None`.
+    Body(Box<BodyStructure<'a>>),
+    BodySection {
+        section: Option<&'a str>,
+        index: Option<u32>,
+        data: Option<&'a [u8]>,
+    },
+    BodyStructure(Box<BodyStructure<'a>>),
+    Envelope(Box<Envelope<'a>>),
+    Flags(SmallVec<[&'a str; 2]>),
+    InternalDate(&'a str),
+    ModSeq(u64), // RFC 4551, section 3.3.2
+    Rfc822(Option<&'a [u8]>),
+    Rfc822Size(u32),
+    Uid(u32),
+}
+
+/// A per-message annotation entry (RFC 5257), with its attributes such as
+/// `value.priv` paired with their values. The values are `None` if the
+/// attribute is not set, and the attributes are left out when the server
+/// only reports that the entry changed.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Annotation<'a> {
+    pub entry: &'a str,
+    pub values: Vec<(&'a str, Option<&'a str>)>,
+}
+
+/// The MIME structure of a message (RFC 3501, section 7.4.2). Of the
+/// extension data, only the MD5 and the disposition are kept.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum BodyStructure<'a> {
+    Multipart {
+        parts: Vec<BodyStructure<'a>>,
+        subtype: &'a str,
+        params: Vec<(&'a str, &'a str)>,
+        disposition: Option<ContentDisposition<'a>>,
+    },
+    Single {
+        fields: BodyFields<'a>,
+        /// The size in lines of text and message/rfc822 parts.
+        lines: Option<u32>,
+        /// The envelope and structure of an encapsulated message/rfc822 part.
+        message: Option<(Box<Envelope<'a>>, Box<BodyStructure<'a>>)>,
+        md5: Option<&'a str>,
+        disposition: Option<ContentDisposition<'a>>,
+    },
+}
+
+impl<'a> BodyStructure<'a> {
+    /// The value of the parameter `name` of the Content-Type.
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        let params = match *self {
+            BodyStructure::Multipart { ref params, .. } => params,
+            BodyStructure::Single { ref fields, .. } => &fields.params,
+        };
+        params.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|&(_, value)| value)
+    }
+
+    pub fn disposition(&self) -> Option<&ContentDisposition<'a>> {
+        match *self {
+            BodyStructure::Multipart { ref disposition, .. } => disposition.as_ref(),
+            BodyStructure::Single { ref disposition, .. } => disposition.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BodyFields<'a> {
+    pub media_type: &'a str,
+    pub media_subtype: &'a str,
+    pub params: Vec<(&'a str, &'a str)>,
+    pub id: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub encoding: &'a str,
+    /// The size of the encoded contents, in octets.
+    pub size: u32,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ContentDisposition<'a> {
+    /// Usually `inline` or `attachment`.
+    pub kind: &'a str,
+    pub params: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> ContentDisposition<'a> {
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.params.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|&(_, value)| value)
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Envelope<'a> {
+    pub date: Option<&'a str>,
+    pub subject: Option<&'a str>,
+    pub from: Option<Vec<Address<'a>>>,
+    pub sender: Option<Vec<Address<'a>>>,
+    pub reply_to: Option<Vec<Address<'a>>>,
+    pub to: Option<Vec<Address<'a>>>,
+    pub cc: Option<Vec<Address<'a>>>,
+    pub bcc: Option<Vec<Address<'a>>>,
+    pub in_reply_to: Option<&'a str>,
+    pub message_id: Option<&'a str>,
+}
+
+impl<'a> Envelope<'a> {
+    /// The addresses of the From field, formatted for display as with
+    /// `Address::to_rfc5322()` and separated by commas.
+    pub fn from_display(&self) -> Option<String> {
+        self.from.as_ref().map(|from| display_addresses(from))
+    }
+}
+
+fn display_addresses(addrs: &[Address]) -> String {
+    let mut out = String::new();
+    for addr in addrs {
+        let group_end = addr.mailbox.is_none() && addr.host.is_none();
+        if !group_end && !out.is_empty() {
+            out.push_str(if out.ends_with(':') { " " } else { ", " });
+        }
+        out.push_str(&addr.to_rfc5322());
+    }
+    out
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Address<'a> {
+    pub name: Option<&'a str>,
+    pub adl: Option<&'a str>,
+    pub mailbox: Option<&'a str>,
+    pub host: Option<&'a str>,
+}
+
+impl<'a> Address<'a> {
+    /// The address as written in a header, such as `"Doe, Jane" <jane@x.org>`
+    /// or `jane@x.org`, quoting the name and the local part where needed.
+    /// Encoded words and escapes in the name are kept as they are. The
+    /// markers of a group (RFC 3501, section 7.4.2) become `name:` and `;`.
+    pub fn to_rfc5322(&self) -> String {
+        let mut out = String::new();
+        match (self.mailbox, self.host) {
+            (Some(group), None) => {
+                push_phrase(&mut out, group);
+                out.push(':');
+            },
+            (None, None) => out.push(';'),
+            (mailbox, Some(host)) => {
+                let addr_spec = match self.name {
+                    Some(name) if !name.is_empty() => {
+                        push_phrase(&mut out, name);
+                        out.push_str(" <");
+                        true
+                    },
+                    _ => false,
+                };
+                match mailbox {
+                    Some(mailbox) if is_dot_atom(mailbox) => out.push_str(mailbox),
+                    mailbox => push_quoted_string(&mut out, mailbox.unwrap_or("")),
+                }
+                out.push('@');
+                out.push_str(host);
+                if addr_spec {
+                    out.push('>');
+                }
+            },
+        }
+        out
+    }
+}
+
+// RFC 5322, section 3.2.3
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+fn is_dot_atom(s: &str) -> bool {
+    !s.is_empty() && s.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+fn push_phrase(out: &mut String, phrase: &str) {
+    match phrase.split(' ').all(|word| !word.is_empty() && word.chars().all(is_atext)) {
+        true => out.push_str(phrase),
+        false => push_quoted_string(out, phrase),
+    }
+}
+
+// Values from quoted strings in responses keep their escapes, which are
+// the same in RFC 5322, so only unescaped quotes are escaped.
+fn push_quoted_string(out: &mut String, s: &str) {
+    out.push('"');
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push('\\');
+                out.push(chars.next().unwrap_or('\\'));
+            },
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// The tag of a command. It is shared rather than copied when cloned, as
+/// every response to the command is delivered with it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RequestId(pub Arc<str>);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Deviations from the protocol which the parser tolerates, collecting the
+/// workarounds for known servers in one place. The default is strict;
+/// combine quirks with `|`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Quirks(u32);
+
+impl Quirks {
+    /// Accept lines ending in a bare LF instead of CRLF.
+    pub const BARE_LF: Quirks = Quirks(1);
+    /// Accept `()` and `NIL` entries in envelope address lists.
+    pub const ENVELOPE_NILS: Quirks = Quirks(1 << 1);
+    /// Accept text directly following the `]` of a response code.
+    pub const CODE_SPACE: Quirks = Quirks(1 << 2);
+    /// Accept extra spaces in the attribute list of a FETCH response.
+    pub const FETCH_SPACING: Quirks = Quirks(1 << 3);
+    /// Accept flags containing `]`, quoted flags and `\*` in flag lists.
+    pub const LOOSE_FLAGS: Quirks = Quirks(1 << 4);
+
+    /// Microsoft Exchange.
+    pub const EXCHANGE: Quirks = Quirks::ENVELOPE_NILS;
+    /// Yahoo Mail.
+    pub const YAHOO: Quirks = Quirks::CODE_SPACE;
+    /// HCL (formerly IBM/Lotus) Domino.
+    pub const DOMINO: Quirks = Quirks::FETCH_SPACING;
+    /// Gmail.
+    pub const GMAIL: Quirks = Quirks::LOOSE_FLAGS;
+
+    /// Whether all of `other` is tolerated.
+    pub const fn contains(self, other: Quirks) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Guess the quirks of a server from its greeting text or the name it
+    /// gives in its ID response (RFC 2971).
+    pub fn detect(text: &str) -> Quirks {
+        let text = text.to_ascii_lowercase();
+        let profiles = [
+            ("exchange", Quirks::EXCHANGE),
+            ("yahoo", Quirks::YAHOO),
+            ("domino", Quirks::DOMINO),
+            ("gimap", Quirks::GMAIL),
+        ];
+        profiles.iter()
+            .filter(|(name, _)| text.contains(name))
+            .fold(Quirks::default(), |quirks, (_, profile)| quirks | *profile)
+    }
+}
+
+impl BitOr for Quirks {
+    type Output = Quirks;
+
+    fn bitor(self, other: Quirks) -> Quirks {
+        Quirks(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Quirks {
+    fn bitor_assign(&mut self, other: Quirks) {
+        self.0 |= other.0;
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    NotAuthenticated,
+    Authenticated,
+    Selected,
+    Logout,
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::{AttributeValue, DateTime, Quirks, Response};
+    use crate::parser::{parse_response, parse_response_quirks};
+
+    // Dates as sent in INTERNALDATE survive parsing and formatting as is.
+    #[test]
+    fn date_time_round_trip() {
+        for date in [" 7-Jul-1996 02:44:25 -0700", "17-Jul-1996 02:44:25 +0000", " 1-Jan-2024 23:59:59 -0130",
+                     "29-Feb-2000 00:00:00 +0545", "31-Dec-1999 12:00:00 -0030"] {
+            let line = alloc::format!("* 1 FETCH (INTERNALDATE \"{}\")\r\n", date);
+            let parsed = match parse_response(line.as_bytes()) {
+                Ok((_, Response::Fetch(1, attrs))) => match attrs[0] {
+                    AttributeValue::InternalDate(parsed) => parsed.to_string(),
+                    ref attr => panic!("unexpected attribute {:?}", attr),
+                },
+                rsp => panic!("unexpected response {:?}", rsp),
+            };
+            assert_eq!(parsed, date);
+            let time = DateTime::parse(&parsed).unwrap();
+            assert_eq!(time.to_string(), date);
+        }
+        assert_eq!(DateTime::parse(" 1-Jan-2024 23:59:59 -0130").unwrap().offset(), -90);
+        assert_eq!(DateTime::parse("31-Dec-1999 12:00:00 -0030").unwrap().offset(), -30);
+    }
+
+    // PARTIAL positions that do not fit in an i32 are rejected.
+    #[test]
+    fn partial_position_range() {
+        match parse_response(b"* ESEARCH (TAG \"A1\") UID PARTIAL (-2147483647:2147483647 NIL)\r\n") {
+            Ok((_, Response::Esearch { results, .. })) => {
+                let partial = results.partial.unwrap();
+                assert_eq!((partial.first, partial.last), (-i32::MAX, i32::MAX));
+            },
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+        for line in [&b"* ESEARCH (TAG \"A1\") UID PARTIAL (-2147483648:-1 NIL)\r\n"[..],
+                     &b"* ESEARCH (TAG \"A1\") UID PARTIAL (1:4294967295 NIL)\r\n"[..]] {
+            assert!(parse_response(line).is_err());
+        }
+    }
+
+    // Quirks also apply to the envelope of a message/rfc822 body part.
+    #[test]
+    fn body_structure_quirks() {
+        let line = b"* 1 FETCH (BODYSTRUCTURE (\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 300 \
+                     (NIL \"Hi\" () NIL NIL (NIL) NIL NIL NIL NIL) \
+                     (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 20 1) 10))\r\n";
+        assert!(parse_response(line).is_err());
+        match parse_response_quirks(line, Quirks::EXCHANGE) {
+            Ok((rest, Response::Fetch(1, attrs))) => {
+                assert!(rest.is_empty());
+                assert!(matches!(attrs[0], AttributeValue::BodyStructure(_)));
+            },
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! IMAP URLs (RFC 5092), as used by referrals (RFC 2221, RFC 2193), URLAUTH
+//! (RFC 4467) and BURL (RFC 4468).
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use core::fmt;
+use core::str;
+
+/// An `imap://` URL referring to a server, and optionally to a mailbox on
+/// it, a message in that mailbox, or a part of that message. `Display`
+/// formats it back into a URL in normalized form; since URLAUTH tokens are
+/// computed over the exact URL, authorized URLs should be passed on as they
+/// were received.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImapUrl {
+    user: Option<String>,
+    auth: Option<String>,
+    host: String,
+    port: Option<u16>,
+    mailbox: Option<String>,
+    uid_validity: Option<u32>,
+    uid: Option<u32>,
+    section: Option<String>,
+    partial: Option<(u32, Option<u32>)>,
+    search: Option<String>,
+    expire: Option<String>,
+    urlauth: Option<String>,
+}
+
+// The parameters following the mailbox name, in the order they appear.
+const MARKERS: [&str; 7] = [";UIDVALIDITY=", "/;UID=", "/;SECTION=", "/;PARTIAL=", "?", ";EXPIRE=", ";URLAUTH="];
+
+impl ImapUrl {
+    /// A URL referring to the server `host`.
+    pub fn new(host: &str) -> ImapUrl {
+        ImapUrl {
+            user: None,
+            auth: None,
+            host: host.to_string(),
+            port: None,
+            mailbox: None,
+            uid_validity: None,
+            uid: None,
+            section: None,
+            partial: None,
+            search: None,
+            expire: None,
+            urlauth: None,
+        }
+    }
+
+    /// Parse `url`, returning `None` if it is not a valid IMAP URL.
+    pub fn parse(url: &str) -> Option<ImapUrl> {
+        let rest = strip_prefix_ignore_case(url, "imap://")?;
+        let (server, path) = match rest.find('/') {
+            Some(end) => (&rest[..end], &rest[end + 1..]),
+            None => (rest, ""),
+        };
+        let (user_info, host_port) = match server.rfind('@') {
+            Some(at) => (Some(&server[..at]), &server[at + 1..]),
+            None => (None, server),
+        };
+        let port_start = match host_port.rfind(':') {
+            Some(colon) if !host_port[colon..].contains(']') => Some(colon),
+            _ => None,
+        };
+        let (host, port) = match port_start {
+            Some(colon) => (&host_port[..colon], Some(host_port[colon + 1..].parse().ok()?)),
+            None => (host_port, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let mut parsed = ImapUrl::new(host);
+        parsed.port = port;
+        if let Some(info) = user_info {
+            let user = match find_ignore_case(info, ";AUTH=") {
+                Some(start) => {
+                    parsed.auth = Some(percent_decode(&info[start + 6..])?);
+                    &info[..start]
+                },
+                None => info,
+            };
+            if !user.is_empty() {
+                parsed.user = Some(percent_decode(user)?);
+            }
+        }
+        let (mailbox, mut params) = split_at_marker(path, 0);
+        if !mailbox.is_empty() {
+            parsed.mailbox = Some(percent_decode(mailbox)?);
+        }
+        while !params.is_empty() {
+            let marker = MARKERS.iter().find(|m| strip_prefix_ignore_case(params, m).is_some())?;
+            let (value, rest) = match *marker {
+                // The search program may contain anything but URLAUTH.
+                "?" => split_at_marker(&params[1..], 5),
+                _ => split_at_marker(&params[marker.len()..], 0),
+            };
+            match *marker {
+                ";UIDVALIDITY=" => parsed.uid_validity = Some(value.parse().ok()?),
+                "/;UID=" => parsed.uid = Some(value.parse().ok()?),
+                "/;SECTION=" => parsed.section = Some(percent_decode(value)?),
+                "/;PARTIAL=" => {
+                    parsed.partial = Some(match value.find('.') {
+                        Some(dot) => (value[..dot].parse().ok()?, Some(value[dot + 1..].parse().ok()?)),
+                        None => (value.parse().ok()?, None),
+                    });
+                },
+                "?" => parsed.search = Some(percent_decode(value)?),
+                ";EXPIRE=" => parsed.expire = Some(value.to_string()),
+                _ => parsed.urlauth = Some(value.to_string()),
+            }
+            params = rest;
+        }
+        Some(parsed)
+    }
+
+    pub fn with_user(mut self, user: &str) -> ImapUrl {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Set the authentication mechanism to use, or `*` for any.
+    pub fn with_auth(mut self, auth: &str) -> ImapUrl {
+        self.auth = Some(auth.to_string());
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> ImapUrl {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn with_mailbox(mut self, mailbox: &str) -> ImapUrl {
+        self.mailbox = Some(mailbox.to_string());
+        self
+    }
+
+    pub fn with_uid_validity(mut self, uid_validity: u32) -> ImapUrl {
+        self.uid_validity = Some(uid_validity);
+        self
+    }
+
+    pub fn with_uid(mut self, uid: u32) -> ImapUrl {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Refer to a body section of the message, such as `1.2` or `HEADER`.
+    pub fn with_section(mut self, section: &str) -> ImapUrl {
+        self.section = Some(section.to_string());
+        self
+    }
+
+    /// Refer to `len` octets starting at `offset`, or to everything from
+    /// `offset` on.
+    pub fn with_partial(mut self, offset: u32, len: Option<u32>) -> ImapUrl {
+        self.partial = Some((offset, len));
+        self
+    }
+
+    /// Refer to the messages of the mailbox matching the search `program`,
+    /// such as `SUBJECT "hello"`.
+    pub fn with_search(mut self, program: &str) -> ImapUrl {
+        self.search = Some(program.to_string());
+        self
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// The authentication mechanism to use, or `*` for any.
+    pub fn auth(&self) -> Option<&str> {
+        self.auth.as_deref()
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn mailbox(&self) -> Option<&str> {
+        self.mailbox.as_deref()
+    }
+
+    pub fn uid_validity(&self) -> Option<u32> {
+        self.uid_validity
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn section(&self) -> Option<&str> {
+        self.section.as_deref()
+    }
+
+    pub fn partial(&self) -> Option<(u32, Option<u32>)> {
+        self.partial
+    }
+
+    pub fn search(&self) -> Option<&str> {
+        self.search.as_deref()
+    }
+
+    /// The expiry time of a URLAUTH-authorized URL (RFC 4467).
+    pub fn expire(&self) -> Option<&str> {
+        self.expire.as_deref()
+    }
+
+    /// The `access:mechanism:token` authorization of a URLAUTH-authorized
+    /// URL (RFC 4467).
+    pub fn urlauth(&self) -> Option<&str> {
+        self.urlauth.as_deref()
+    }
+}
+
+impl fmt::Display for ImapUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("imap://")?;
+        if self.user.is_some() || self.auth.is_some() {
+            if let Some(ref user) = self.user {
+                f.write_str(&percent_encode(user, false))?;
+            }
+            if let Some(ref auth) = self.auth {
+                write!(f, ";AUTH={}", percent_encode(auth, false))?;
+            }
+            f.write_str("@")?;
+        }
+        f.write_str(&self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        f.write_str("/")?;
+        if let Some(ref mailbox) = self.mailbox {
+            f.write_str(&percent_encode(mailbox, true))?;
+        }
+        if let Some(uid_validity) = self.uid_validity {
+            write!(f, ";UIDVALIDITY={}", uid_validity)?;
+        }
+        if let Some(uid) = self.uid {
+            write!(f, "/;UID={}", uid)?;
+        }
+        if let Some(ref section) = self.section {
+            write!(f, "/;SECTION={}", percent_encode(section, true))?;
+        }
+        if let Some((offset, len)) = self.partial {
+            let len = len.map(|len| format!(".{}", len)).unwrap_or_default();
+            write!(f, "/;PARTIAL={}{}", offset, len)?;
+        }
+        if let Some(ref search) = self.search {
+            write!(f, "?{}", percent_encode(search, true))?;
+        }
+        if let Some(ref expire) = self.expire {
+            write!(f, ";EXPIRE={}", expire)?;
+        }
+        if let Some(ref urlauth) = self.urlauth {
+            write!(f, ";URLAUTH={}", urlauth)?;
+        }
+        Ok(())
+    }
+}
+
+// Splits `s` at the first of `MARKERS[first..]`, so that the marker starts
+// the second half.
+fn split_at_marker(s: &str, first: usize) -> (&str, &str) {
+    let end = MARKERS[first..].iter()
+        .filter_map(|m| find_ignore_case(s, m))
+        .min()
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.len() >= prefix.len() && s.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes()) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+fn find_ignore_case(s: &str, needle: &str) -> Option<usize> {
+    s.as_bytes().windows(needle.len()).position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Encodes everything but `achar`, or `bchar` if `path` is set (RFC 5092,
+// section 11).
+fn percent_encode(s: &str, path: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        let allowed = b.is_ascii_alphanumeric() || b"-._~!$'()*+,&=".contains(&b) ||
+            path && b":@/".contains(&b);
+        if allowed {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::ImapUrl;
+
+    // Examples from RFC 5092, with the parameter names in upper case as
+    // they are formatted.
+    #[test]
+    fn round_trip() {
+        for url in [
+            "imap://psicorp.example.org/~peter/%E6%97%A5%E6%9C%AC%E8%AA%9E/%E5%8F%B0%E5%8C%97",
+            "imap://;AUTH=*@minbari.example.org/gray%20council?SUBJECT%20shadows",
+            "imap://;AUTH=GSSAPI@minbari.example.org/gray-council;UIDVALIDITY=385759045/;UID=20/;PARTIAL=0.1024",
+            "imap://michael@minbari.example.org/users.*",
+            "imap://joe@example.com/INBOX/;UID=20/;SECTION=1.2;URLAUTH=anonymous:internal:91354a473744909de610943775f92038",
+        ] {
+            assert_eq!(ImapUrl::parse(url).unwrap().to_string(), url);
+        }
+    }
+
+    #[test]
+    fn parse_parts() {
+        let url = "imap://joe@example.com:143/INBOX/;uid=20/;section=1.2/;partial=10;urlauth=anonymous:internal:91354a4";
+        let url = ImapUrl::parse(url).unwrap();
+        assert_eq!(url.user(), Some("joe"));
+        assert_eq!(url.port(), Some(143));
+        assert_eq!(url.mailbox(), Some("INBOX"));
+        assert_eq!(url.uid(), Some(20));
+        assert_eq!(url.section(), Some("1.2"));
+        assert_eq!(url.partial(), Some((10, None)));
+        assert_eq!(url.urlauth(), Some("anonymous:internal:91354a4"));
+
+        let url = "imap://psicorp.example.org/~peter/%E6%97%A5%E6%9C%AC%E8%AA%9E/%E5%8F%B0%E5%8C%97";
+        let url = ImapUrl::parse(url).unwrap();
+        assert_eq!(url.mailbox(), Some("~peter/日本語/台北"));
+        let built = ImapUrl::new("minbari.example.org").with_mailbox("gray council").with_search("SUBJECT shadows");
+        assert_eq!(built.to_string(), "imap://minbari.example.org/gray%20council?SUBJECT%20shadows");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for url in [
+            "http://minbari.example.org/INBOX",
+            "imap:///INBOX",
+            "imap://minbari.example.org:imap/INBOX",
+            "imap://minbari.example.org/INBOX/;UID=twenty",
+            "imap://minbari.example.org/INBOX/;PARTIAL=0.",
+            "imap://minbari.example.org/gray%2",
+            "imap://minbari.example.org/%FF",
+        ] {
+            assert_eq!(ImapUrl::parse(url), None, "{}", url);
+        }
+    }
+}
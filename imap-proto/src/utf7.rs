@@ -0,0 +1,122 @@
+//! The modified UTF-7 encoding of mailbox names (RFC 3501, section 5.1.3),
+//! which is used unless UTF8=ACCEPT has been enabled (RFC 6855).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encode the mailbox name `name` in modified UTF-7.
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let mut pending = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut pending, &mut encoded);
+            if c == '&' {
+                encoded.push_str("&-");
+            } else {
+                encoded.push(c);
+            }
+        } else {
+            let mut units = [0; 2];
+            pending.extend(c.encode_utf16(&mut units).iter());
+        }
+    }
+    flush(&mut pending, &mut encoded);
+    encoded
+}
+
+// Appends the base64-encoded UTF-16 code units in `pending` to `dst`.
+fn flush(pending: &mut Vec<u16>, dst: &mut String) {
+    if pending.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = pending.drain(..).flat_map(|unit| unit.to_be_bytes()).collect();
+    dst.push('&');
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..chunk.len() + 1 {
+            dst.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+        }
+    }
+    dst.push('-');
+}
+
+/// Decode the modified UTF-7 mailbox name `name`, or return `None` if it is
+/// not valid modified UTF-7.
+pub fn decode(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let end = start + rest[start..].find('-')?;
+        let shifted = &rest[start + 1..end];
+        if shifted.is_empty() {
+            decoded.push('&');
+        } else {
+            decode_shifted(shifted, &mut decoded)?;
+        }
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+fn decode_shifted(shifted: &str, dst: &mut String) -> Option<()> {
+    let mut bytes = Vec::with_capacity(shifted.len() * 3 / 4);
+    let (mut bits, mut len) = (0u32, 0);
+    for c in shifted.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = bits << 6 | value;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len) as u8);
+        }
+    }
+    // The bits left over must be zero padding.
+    if bytes.len() % 2 != 0 || bits & ((1 << len) - 1) != 0 {
+        return None;
+    }
+    let units = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+    for c in core::char::decode_utf16(units) {
+        // Printable ASCII must be represented as itself.
+        match c.ok()? {
+            ' '..='~' => return None,
+            c => dst.push(c),
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn round_trip() {
+        // The example of RFC 3501, section 5.1.3.
+        let name = "~peter/mail/\u{53f0}\u{5317}/\u{65e5}\u{672c}\u{8a9e}";
+        assert_eq!(encode(name), "~peter/mail/&U,BTFw-/&ZeVnLIqe-");
+        assert_eq!(decode("~peter/mail/&U,BTFw-/&ZeVnLIqe-").as_deref(), Some(name));
+        assert_eq!(encode("Tom & Jerry"), "Tom &- Jerry");
+        assert_eq!(decode("Tom &- Jerry").as_deref(), Some("Tom & Jerry"));
+        // Characters outside the BMP are encoded as surrogate pairs.
+        assert_eq!(decode(&encode("\u{1f4e7} mail")).as_deref(), Some("\u{1f4e7} mail"));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        for name in [
+            "&U,BTFw",   // unterminated
+            "&U,BTF-",   // incomplete code unit
+            "&U,BTFx-",  // non-zero padding bits
+            "&AGEAYg-",  // printable ASCII ("ab") in a shifted run
+            "&U.BTFw-",  // not in the base64 alphabet
+            "&2D0-",     // lone surrogate
+        ] {
+            assert_eq!(decode(name), None, "{}", name);
+        }
+    }
+}
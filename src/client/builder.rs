@@ -1,70 +1,730 @@
-use proto::{Attribute, AttrMacro, State};
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::RangeInclusive;
+
+use crate::proto::{Attribute, AttrMacro, CatenatePart, DateTime, Flag, ResponseKind, SearchCriteria, SearchReturn};
+use crate::proto::SearchSource;
+use crate::proto::{Request, RequestId};
+use crate::metrics;
+use crate::sasl;
+use crate::proto::{SortCriterion, SpecialUse, State, StatusAttribute, StoreType, ThreadAlgorithm};
 
 pub struct CommandBuilder { }
 
 impl CommandBuilder {
+    pub fn append(mailbox: &str, message: &[u8]) -> AppendCommand {
+        AppendCommand {
+            mailbox: mailbox.to_string(),
+            messages: vec![AppendMessage::new(AppendContent::Message(message.to_vec()))],
+        }
+    }
+
+    /// APPEND a message composed by the server from `parts` (RFC 4469),
+    /// such as parts of existing messages referred to by URL.
+    pub fn append_catenate(mailbox: &str, parts: &[CatenatePart]) -> AppendCommand {
+        AppendCommand {
+            mailbox: mailbox.to_string(),
+            messages: vec![AppendMessage::new(AppendContent::Catenate(parts.to_vec()))],
+        }
+    }
+
+    /// AUTHENTICATE with the SASL `mechanism` (RFC 4422), sending the
+    /// `initial` response along if given, which requires the SASL-IR
+    /// capability (RFC 4959). The rest of the exchange is up to the caller;
+    /// see `Session::authenticate()`.
+    pub fn authenticate(mechanism: &str, initial: Option<&[u8]>) -> Command {
+        let mut args = vec![];
+        args.extend(b"AUTHENTICATE ");
+        args.extend(mechanism.as_bytes());
+        match initial {
+            Some(b"") => args.extend(b" ="),
+            Some(initial) => {
+                args.push(b' ');
+                args.extend(sasl::encode(initial).as_bytes());
+            },
+            None => {},
+        }
+        Command {
+            args,
+            next_state: Some(State::Authenticated),
+            solicits: vec![ResponseKind::Capabilities],
+            literals: vec![],
+        }
+    }
+
+    pub fn capability() -> Command {
+        let args = b"CAPABILITY".to_vec();
+        Command {
+            args,
+            next_state: None,
+            solicits: vec![ResponseKind::Capabilities],
+            literals: vec![],
+        }
+    }
+
+    /// Stop keeping the results of the searches started with `tags` up to
+    /// date; see `SearchReturn::Update` (RFC 5267, section 4.2).
+    pub fn cancel_update(tags: &[&str]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"CANCELUPDATE");
+        for tag in tags {
+            args.push(b' ');
+            push_quoted(&mut args, &mut literals, tag);
+        }
+        Command { args, next_state: None, solicits: vec![], literals }
+    }
+
     pub fn check() -> Command {
         let mut args = vec![];
         args.extend(b"CHECK");
         Command {
-            args: args,
+            args,
             next_state: None,
+            solicits: vec![],
+            literals: vec![],
         }
     }
 
     pub fn close() -> Command {
         let args = b"CLOSE".to_vec();
-        Command { args, next_state: Some(State::Authenticated) }
+        Command { args, next_state: Some(State::Authenticated), solicits: vec![], literals: vec![] }
+    }
+
+    pub fn compress() -> Command {
+        let args = b"COMPRESS DEFLATE".to_vec();
+        Command { args, next_state: None, solicits: vec![], literals: vec![] }
+    }
+
+    pub fn copy(set: &str, mailbox: &str) -> Command {
+        copy_command(b"COPY ", set, mailbox)
+    }
+
+    /// Like `copy()`, but for the messages in the UID set `set`.
+    pub fn uid_copy(set: &str, mailbox: &str) -> Command {
+        copy_command(b"UID COPY ", set, mailbox)
+    }
+
+    pub fn create(mailbox: &str) -> Command {
+        CommandBuilder::create_with_use(mailbox, &[])
+    }
+
+    /// CREATE `mailbox` for the special `uses` (RFC 6154). Requires the
+    /// CREATE-SPECIAL-USE capability when `uses` is not empty.
+    pub fn create_with_use(mailbox: &str, uses: &[SpecialUse]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"CREATE ");
+        push_quoted(&mut args, &mut literals, mailbox);
+        if !uses.is_empty() {
+            let flags: Vec<_> = uses.iter().map(|usage| usage.as_flag()).collect();
+            args.extend(b" (USE (");
+            args.extend(flags.join(" ").as_bytes());
+            args.extend(b"))");
+        }
+        Command { args, next_state: None, solicits: vec![], literals }
+    }
+
+    pub fn enable(capabilities: &[&str]) -> Command {
+        let mut args = vec![];
+        args.extend(b"ENABLE");
+        for cap in capabilities {
+            args.push(b' ');
+            args.extend(cap.as_bytes());
+        }
+        Command { args, next_state: None, solicits: vec![ResponseKind::Enabled], literals: vec![] }
     }
 
     pub fn examine(mailbox: &str) -> Command {
         let mut args = vec![];
+        let mut literals = vec![];
         args.extend(b"EXAMINE ");
-        args.extend(mailbox.as_bytes());
+        push_quoted(&mut args, &mut literals, mailbox);
         Command {
-            args: args,
+            args,
             next_state: Some(State::Selected),
+            solicits: vec![ResponseKind::MailboxData],
+            literals,
+        }
+    }
+
+    /// Remove the messages marked `\Deleted` from the selected mailbox.
+    pub fn expunge() -> Command {
+        let args = b"EXPUNGE".to_vec();
+        Command { args, next_state: None, solicits: vec![ResponseKind::Expunge], literals: vec![] }
+    }
+
+    /// Like `expunge()`, but only for the messages in the UID set `set`
+    /// (RFC 4315, section 2.1).
+    pub fn uid_expunge(set: &str) -> Command {
+        let mut args = vec![];
+        args.extend(b"UID EXPUNGE ");
+        args.extend(set.as_bytes());
+        Command {
+            args,
+            next_state: None,
+            solicits: vec![ResponseKind::Expunge, ResponseKind::Vanished],
+            literals: vec![],
         }
     }
 
     pub fn fetch() -> FetchCommandEmpty {
         let mut args = vec![];
         args.extend(b"FETCH ");
-        FetchCommandEmpty { args: args }
+        FetchCommandEmpty { args }
+    }
+
+    /// FETCH messages by UID rather than by sequence number.
+    pub fn uid_fetch() -> FetchCommandEmpty {
+        let mut args = vec![];
+        args.extend(b"UID FETCH ");
+        FetchCommandEmpty { args }
+    }
+
+    /// Generate URLAUTH-authorized URLs (RFC 4467) from `urls`, each given
+    /// with its authorization mechanism, usually `INTERNAL`.
+    pub fn genurlauth(urls: &[(&str, &str)]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"GENURLAUTH");
+        for &(url, mechanism) in urls {
+            args.push(b' ');
+            push_quoted(&mut args, &mut literals, url);
+            args.push(b' ');
+            args.extend(mechanism.as_bytes());
+        }
+        Command { args, next_state: None, solicits: vec![ResponseKind::GenUrlAuth], literals }
+    }
+
+    /// GETMETADATA `entries` of `mailbox`, or of the server if `mailbox` is
+    /// empty (RFC 5464, section 4.2), such as `/shared/comment`. Requires
+    /// the METADATA or METADATA-SERVER capability.
+    pub fn getmetadata(mailbox: &str, entries: &[&str]) -> GetMetadataCommand {
+        GetMetadataCommand {
+            mailbox: mailbox.to_string(),
+            entries: entries.iter().map(|s| s.to_string()).collect(),
+            depth: None,
+            max_size: None,
+        }
+    }
+
+    /// Wait for changes to the selected mailbox (RFC 2177), which arrive as
+    /// unsolicited responses, until `Connection::idle_done()` is called.
+    pub fn idle() -> Command {
+        let args = b"IDLE".to_vec();
+        Command { args, next_state: None, solicits: vec![], literals: vec![] }
+    }
+
+    /// Have the server use the first of the language `ranges` it supports
+    /// for its human-readable text (RFC 5255), such as `de` or `i-default`.
+    /// Without ranges, the server lists the languages it supports instead.
+    pub fn language(ranges: &[&str]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"LANGUAGE");
+        for range in ranges {
+            args.push(b' ');
+            push_quoted(&mut args, &mut literals, range);
+        }
+        Command { args, next_state: None, solicits: vec![ResponseKind::Language], literals }
+    }
+
+    /// LIST the mailboxes matching `pattern`, relative to `reference`.
+    pub fn list(reference: &str, pattern: &str) -> ListCommand {
+        ListCommand {
+            reference: reference.to_string(),
+            pattern: pattern.to_string(),
+            select_special_use: false,
+            return_special_use: false,
+            status: vec![],
+        }
     }
 
     pub fn login(user_name: &str, password: &str) -> Command {
         let mut args = vec![];
+        let mut literals = vec![];
         args.extend(b"LOGIN ");
-        args.extend(user_name.as_bytes());
+        push_quoted(&mut args, &mut literals, user_name);
         args.push(b' ');
-        args.extend(password.as_bytes());
+        push_quoted(&mut args, &mut literals, password);
         Command {
-            args: args,
+            args,
             next_state: Some(State::Authenticated),
+            solicits: vec![ResponseKind::Capabilities],
+            literals,
+        }
+    }
+
+    /// MOVE the messages in the UID set `set` to `mailbox` (RFC 6851).
+    pub fn uid_move(set: &str, mailbox: &str) -> Command {
+        let mut cmd = copy_command(b"UID MOVE ", set, mailbox);
+        cmd.solicits = vec![ResponseKind::Expunge, ResponseKind::Vanished];
+        cmd
+    }
+
+    /// End the session; the server closes the connection once it has
+    /// confirmed.
+    pub fn logout() -> Command {
+        let args = b"LOGOUT".to_vec();
+        Command { args, next_state: Some(State::Logout), solicits: vec![], literals: vec![] }
+    }
+
+    /// Do nothing, giving the server a chance to report changes to the
+    /// selected mailbox.
+    pub fn noop() -> Command {
+        let args = b"NOOP".to_vec();
+        Command { args, next_state: None, solicits: vec![], literals: vec![] }
+    }
+
+    /// Select `mailbox`, enabling CONDSTORE so that the server reports the
+    /// HIGHESTMODSEQ of the mailbox, or NOMODSEQ (RFC 7162, section 3.1.8).
+    pub fn select_condstore(mailbox: &str) -> Command {
+        let mut cmd = CommandBuilder::select(mailbox);
+        cmd.args.extend(b" (CONDSTORE)");
+        cmd
+    }
+
+    /// SELECT with quick mailbox resynchronization (RFC 5162, section 3.1).
+    /// `known_uids` is the set of UIDs known to the client, if any.
+    pub fn select_qresync(mailbox: &str, uid_validity: u32, mod_seq: u64,
+                          known_uids: Option<&str>) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"SELECT ");
+        push_quoted(&mut args, &mut literals, mailbox);
+        args.extend(b" (QRESYNC (");
+        args.extend(uid_validity.to_string().as_bytes());
+        args.push(b' ');
+        args.extend(mod_seq.to_string().as_bytes());
+        if let Some(uids) = known_uids {
+            args.push(b' ');
+            args.extend(uids.as_bytes());
+        }
+        args.extend(b"))");
+        Command {
+            args,
+            next_state: Some(State::Selected),
+            solicits: vec![ResponseKind::MailboxData, ResponseKind::Fetch, ResponseKind::UidFetch, ResponseKind::Vanished],
+            literals,
+        }
+    }
+
+    pub fn search(criteria: &SearchCriteria) -> SearchCommand {
+        SearchCommand { criteria: criteria.clone(), returns: vec![], charset: None, uid: false, sources: vec![] }
+    }
+
+    /// Like `search()`, but returning UIDs rather than sequence numbers.
+    pub fn uid_search(criteria: &SearchCriteria) -> SearchCommand {
+        SearchCommand { uid: true, ..CommandBuilder::search(criteria) }
+    }
+
+    /// Search the mailboxes of `sources` with a single ESEARCH command
+    /// (RFC 7377), which returns UIDs in an ESEARCH response per mailbox.
+    /// Requires the MULTISEARCH capability.
+    pub fn multisearch(sources: &[SearchSource], criteria: &SearchCriteria) -> SearchCommand {
+        SearchCommand { sources: sources.to_vec(), ..CommandBuilder::search(criteria) }
+    }
+
+    /// SORT the messages matching `search` by `criteria` (RFC 5256).
+    pub fn sort(criteria: &[SortCriterion], search: &SearchCriteria) -> SortCommand {
+        SortCommand {
+            criteria: criteria.to_vec(),
+            search: search.clone(),
+            returns: vec![],
+            uid: false,
+        }
+    }
+
+    /// Like `sort()`, but returning UIDs rather than sequence numbers.
+    pub fn uid_sort(criteria: &[SortCriterion], search: &SearchCriteria) -> SortCommand {
+        SortCommand { uid: true, ..CommandBuilder::sort(criteria, search) }
+    }
+
+    /// THREAD the messages matching `search` using `algorithm` (RFC 5256).
+    pub fn thread(algorithm: ThreadAlgorithm, search: &SearchCriteria) -> Command {
+        thread_command(b"THREAD ", algorithm, search)
+    }
+
+    /// Like `thread()`, but identifying messages by UID rather than by
+    /// sequence number.
+    pub fn uid_thread(algorithm: ThreadAlgorithm, search: &SearchCriteria) -> Command {
+        thread_command(b"UID THREAD ", algorithm, search)
+    }
+
+    /// SETMETADATA `values` of the entries of `mailbox`, or of the server if
+    /// `mailbox` is empty (RFC 5464, section 4.3). A value of `None` removes
+    /// the entry.
+    pub fn setmetadata(mailbox: &str, values: &[(&str, Option<&str>)]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"SETMETADATA ");
+        push_quoted(&mut args, &mut literals, mailbox);
+        args.extend(b" (");
+        for (i, &(entry, value)) in values.iter().enumerate() {
+            if i > 0 {
+                args.push(b' ');
+            }
+            push_quoted(&mut args, &mut literals, entry);
+            args.push(b' ');
+            push_nstring(&mut args, &mut literals, value);
+        }
+        args.push(b')');
+        Command { args, next_state: None, solicits: vec![], literals }
+    }
+
+    pub fn status(mailbox: &str, items: &[StatusAttribute]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"STATUS ");
+        push_quoted(&mut args, &mut literals, mailbox);
+        args.push(b' ');
+        push_status_attributes(&mut args, items);
+        Command { args, next_state: None, solicits: vec![ResponseKind::Status], literals }
+    }
+
+    pub fn store(set: &str, store_type: StoreType, flags: &[&str]) -> StoreCommand {
+        StoreCommand {
+            set: set.to_string(),
+            store_type,
+            flags: flags.iter().map(|s| s.to_string()).collect(),
+            silent: false,
+            unchanged_since: None,
+            uid: false,
+        }
+    }
+
+    /// Add `flags` to the messages in the sequence set `set`.
+    pub fn set_flags(set: &str, flags: &[Flag]) -> StoreCommand {
+        let flags = flags.iter().map(Flag::as_str).collect::<Vec<_>>();
+        CommandBuilder::store(set, StoreType::Add, &flags)
+    }
+
+    /// Remove `flags` from the messages in the sequence set `set`.
+    pub fn clear_flags(set: &str, flags: &[Flag]) -> StoreCommand {
+        let flags = flags.iter().map(Flag::as_str).collect::<Vec<_>>();
+        CommandBuilder::store(set, StoreType::Remove, &flags)
+    }
+
+    /// Like `store()`, but for the messages in the UID set `set`.
+    pub fn uid_store(set: &str, store_type: StoreType, flags: &[&str]) -> StoreCommand {
+        StoreCommand { uid: true, ..CommandBuilder::store(set, store_type, flags) }
+    }
+
+    /// Set attributes of the per-message annotation `entry` (RFC 5257) on
+    /// the messages in the sequence set `set`, for example
+    /// `("value.priv", Some("To do"))`. A value of `None` removes the
+    /// attribute. Requires the ANNOTATE-EXPERIMENT-1 capability.
+    pub fn store_annotation(set: &str, entry: &str, values: &[(&str, Option<&str>)]) -> Command {
+        store_annotation_command(b"STORE ", set, entry, values)
+    }
+
+    /// Like `store_annotation()`, but for the messages in the UID set `set`.
+    pub fn uid_store_annotation(set: &str, entry: &str, values: &[(&str, Option<&str>)]) -> Command {
+        store_annotation_command(b"UID STORE ", set, entry, values)
+    }
+
+    /// Replace message `seq` in the selected mailbox with `message`, which
+    /// is appended to `mailbox` (RFC 8508). The old message is expunged
+    /// only once the new one has been stored.
+    pub fn replace(seq: u32, mailbox: &str, message: &[u8]) -> Command {
+        replace_command(b"REPLACE ", seq, mailbox, message)
+    }
+
+    /// Like `replace()`, but for the message with UID `uid`.
+    pub fn uid_replace(uid: u32, mailbox: &str, message: &[u8]) -> Command {
+        replace_command(b"UID REPLACE ", uid, mailbox, message)
+    }
+
+    /// Invalidate the URLAUTH access keys of `mailbox`, or of all
+    /// mailboxes if `None`, for `mechanisms`, or for all of them if empty.
+    pub fn resetkey(mailbox: Option<&str>, mechanisms: &[&str]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"RESETKEY");
+        if let Some(mailbox) = mailbox {
+            args.push(b' ');
+            push_quoted(&mut args, &mut literals, mailbox);
+            for mechanism in mechanisms {
+                args.push(b' ');
+                args.extend(mechanism.as_bytes());
+            }
+        }
+        Command { args, next_state: None, solicits: vec![], literals }
+    }
+
+    /// End the authenticated session, keeping the connection open so that
+    /// another user can log in over it (RFC 8437).
+    pub fn unauthenticate() -> Command {
+        let args = b"UNAUTHENTICATE".to_vec();
+        Command {
+            args,
+            next_state: Some(State::NotAuthenticated),
+            solicits: vec![],
+            literals: vec![],
+        }
+    }
+
+    /// Fetch the contents of URLAUTH-authorized `urls`.
+    pub fn urlfetch(urls: &[&str]) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"URLFETCH");
+        for url in urls {
+            args.push(b' ');
+            push_quoted(&mut args, &mut literals, url);
         }
+        Command { args, next_state: None, solicits: vec![ResponseKind::UrlFetch], literals }
     }
 
     pub fn select(mailbox: &str) -> Command {
         let mut args = vec![];
+        let mut literals = vec![];
         args.extend(b"SELECT ");
-        args.extend(mailbox.as_bytes());
+        push_quoted(&mut args, &mut literals, mailbox);
         Command {
-            args: args,
+            args,
             next_state: Some(State::Selected),
+            solicits: vec![ResponseKind::MailboxData],
+            literals,
         }
     }
 }
 
+// Adds `key`, with strings that cannot be quoted as literals, encoded in
+// ISO-8859-1 if `latin1` is set and in UTF-8 otherwise.
+fn push_search_key(args: &mut Vec<u8>, literals: &mut Vec<usize>, key: &SearchCriteria, latin1: bool) {
+    match *key {
+        SearchCriteria::All => args.extend(b"ALL"),
+        SearchCriteria::And(ref keys) if keys.is_empty() => args.extend(b"ALL"),
+        SearchCriteria::And(ref keys) => {
+            args.push(b'(');
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    args.push(b' ');
+                }
+                push_search_key(args, literals, key, latin1);
+            }
+            args.push(b')');
+        },
+        SearchCriteria::Answered => args.extend(b"ANSWERED"),
+        SearchCriteria::Bcc(ref s) => push_search_string(args, literals, "BCC", s, latin1),
+        SearchCriteria::Before(date) => args.extend(format!("BEFORE {}", date).as_bytes()),
+        SearchCriteria::Body(ref s) => push_search_string(args, literals, "BODY", s, latin1),
+        SearchCriteria::Cc(ref s) => push_search_string(args, literals, "CC", s, latin1),
+        SearchCriteria::Deleted => args.extend(b"DELETED"),
+        SearchCriteria::Draft => args.extend(b"DRAFT"),
+        SearchCriteria::Flagged => args.extend(b"FLAGGED"),
+        SearchCriteria::From(ref s) => push_search_string(args, literals, "FROM", s, latin1),
+        SearchCriteria::Fuzzy(ref key) => {
+            args.extend(b"FUZZY ");
+            push_search_key(args, literals, key, latin1);
+        },
+        SearchCriteria::Header(ref name, ref value) => {
+            push_search_string(args, literals, "HEADER", name, latin1);
+            args.push(b' ');
+            push_search_value(args, literals, value, latin1);
+        },
+        SearchCriteria::Keyword(ref flag) => {
+            args.extend(b"KEYWORD ");
+            args.extend(flag.as_bytes());
+        },
+        SearchCriteria::Larger(size) => args.extend(format!("LARGER {}", size).as_bytes()),
+        SearchCriteria::New => args.extend(b"NEW"),
+        SearchCriteria::Not(ref key) => {
+            args.extend(b"NOT ");
+            push_search_key(args, literals, key, latin1);
+        },
+        SearchCriteria::Old => args.extend(b"OLD"),
+        SearchCriteria::Older(secs) => args.extend(format!("OLDER {}", secs).as_bytes()),
+        SearchCriteria::On(date) => args.extend(format!("ON {}", date).as_bytes()),
+        SearchCriteria::Or(ref a, ref b) => {
+            args.extend(b"OR ");
+            push_search_key(args, literals, a, latin1);
+            args.push(b' ');
+            push_search_key(args, literals, b, latin1);
+        },
+        SearchCriteria::Recent => args.extend(b"RECENT"),
+        SearchCriteria::Seen => args.extend(b"SEEN"),
+        SearchCriteria::SentBefore(date) => args.extend(format!("SENTBEFORE {}", date).as_bytes()),
+        SearchCriteria::SentOn(date) => args.extend(format!("SENTON {}", date).as_bytes()),
+        SearchCriteria::SentSince(date) => args.extend(format!("SENTSINCE {}", date).as_bytes()),
+        SearchCriteria::Sequence(ref set) => args.extend(set.as_bytes()),
+        SearchCriteria::Since(date) => args.extend(format!("SINCE {}", date).as_bytes()),
+        SearchCriteria::Smaller(size) => args.extend(format!("SMALLER {}", size).as_bytes()),
+        SearchCriteria::Subject(ref s) => push_search_string(args, literals, "SUBJECT", s, latin1),
+        SearchCriteria::Text(ref s) => push_search_string(args, literals, "TEXT", s, latin1),
+        SearchCriteria::To(ref s) => push_search_string(args, literals, "TO", s, latin1),
+        SearchCriteria::Uid(ref set) => {
+            args.extend(b"UID ");
+            args.extend(set.as_bytes());
+        },
+        SearchCriteria::Unanswered => args.extend(b"UNANSWERED"),
+        SearchCriteria::Undeleted => args.extend(b"UNDELETED"),
+        SearchCriteria::Undraft => args.extend(b"UNDRAFT"),
+        SearchCriteria::Unflagged => args.extend(b"UNFLAGGED"),
+        SearchCriteria::Unkeyword(ref flag) => {
+            args.extend(b"UNKEYWORD ");
+            args.extend(flag.as_bytes());
+        },
+        SearchCriteria::Unseen => args.extend(b"UNSEEN"),
+        SearchCriteria::Younger(secs) => args.extend(format!("YOUNGER {}", secs).as_bytes()),
+    }
+}
+
+fn push_search_string(args: &mut Vec<u8>, literals: &mut Vec<usize>, key: &str, value: &str, latin1: bool) {
+    args.extend(key.as_bytes());
+    args.push(b' ');
+    push_search_value(args, literals, value, latin1);
+}
+
+fn push_search_value(args: &mut Vec<u8>, literals: &mut Vec<usize>, value: &str, latin1: bool) {
+    if !needs_literal(value) {
+        push_quoted(args, literals, value);
+    } else if latin1 {
+        let data = value.chars().map(|c| std::convert::TryFrom::try_from(u32::from(c)).unwrap_or(b'?')).collect::<Vec<_>>();
+        push_literal(args, literals, &data);
+    } else {
+        push_literal(args, literals, value.as_bytes());
+    }
+}
+
+// Quoted strings cannot contain CR or LF, and only contain ASCII unless
+// UTF8=ACCEPT has been enabled.
+fn needs_literal(value: &str) -> bool {
+    value.bytes().any(|c| c == b'\r' || c == b'\n' || c >= 0x80)
+}
+
+// Whether any string in `key` matches `pred`.
+pub(crate) fn any_search_string(key: &SearchCriteria, pred: &dyn Fn(&str) -> bool) -> bool {
+    match *key {
+        SearchCriteria::And(ref keys) => keys.iter().any(|key| any_search_string(key, pred)),
+        SearchCriteria::Fuzzy(ref key) | SearchCriteria::Not(ref key) => any_search_string(key, pred),
+        SearchCriteria::Or(ref a, ref b) => any_search_string(a, pred) || any_search_string(b, pred),
+        SearchCriteria::Bcc(ref s) | SearchCriteria::Body(ref s) | SearchCriteria::Cc(ref s) |
+        SearchCriteria::From(ref s) | SearchCriteria::Subject(ref s) | SearchCriteria::Text(ref s) |
+        SearchCriteria::To(ref s) => pred(s),
+        SearchCriteria::Header(ref name, ref value) => pred(name) || pred(value),
+        _ => false,
+    }
+}
+
+fn push_status_attributes(args: &mut Vec<u8>, items: &[StatusAttribute]) {
+    args.push(b'(');
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            args.push(b' ');
+        }
+        args.extend(match *item {
+            StatusAttribute::HighestModSeq => &b"HIGHESTMODSEQ"[..],
+            StatusAttribute::Messages => &b"MESSAGES"[..],
+            StatusAttribute::Recent => &b"RECENT"[..],
+            StatusAttribute::UidNext => &b"UIDNEXT"[..],
+            StatusAttribute::UidValidity => &b"UIDVALIDITY"[..],
+            StatusAttribute::Unseen => &b"UNSEEN"[..],
+        });
+    }
+    args.push(b')');
+}
+
+// Adds `value` as a quoted string, or as a literal if it contains CR or LF,
+// which quoted strings cannot. Mailbox names are always sent this way, so
+// that names with spaces or, once UTF8=ACCEPT is enabled, non-ASCII
+// characters are not taken for atoms.
+fn push_quoted(args: &mut Vec<u8>, literals: &mut Vec<usize>, value: &str) {
+    if value.bytes().any(|c| c == b'\r' || c == b'\n') {
+        push_literal(args, literals, value.as_bytes());
+        return;
+    }
+    args.push(b'"');
+    for &c in value.as_bytes() {
+        if c == b'"' || c == b'\\' {
+            args.push(b'\\');
+        }
+        args.push(c);
+    }
+    args.push(b'"');
+}
+
+fn push_catenate_parts(args: &mut Vec<u8>, literals: &mut Vec<usize>, parts: &[CatenatePart]) {
+    args.extend(b"CATENATE (");
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            args.push(b' ');
+        }
+        match *part {
+            CatenatePart::Url(ref url) => {
+                args.extend(b"URL ");
+                push_quoted(args, literals, url);
+            },
+            CatenatePart::Text(ref text) => {
+                args.extend(b"TEXT ");
+                push_literal(args, literals, text);
+            },
+        }
+    }
+    args.push(b')');
+}
+
+// A single entry or attribute is sent as is, several as a list.
+fn push_annotation_list(args: &mut Vec<u8>, items: &[String]) {
+    match items {
+        [item] => args.extend(item.as_bytes()),
+        _ => {
+            args.push(b'(');
+            args.extend(items.join(" ").as_bytes());
+            args.push(b')');
+        },
+    }
+}
+
+// Adds `value` as a quoted string where possible, and as NIL if `None`.
+fn push_nstring(args: &mut Vec<u8>, literals: &mut Vec<usize>, value: Option<&str>) {
+    match value {
+        Some(value) if needs_literal(value) => push_literal(args, literals, value.as_bytes()),
+        Some(value) => push_quoted(args, literals, value),
+        None => args.extend(b"NIL"),
+    }
+}
+
+// Adds `data` as a synchronizing literal, recording the position of the
+// closing brace of its header in `literals`.
+fn push_literal(args: &mut Vec<u8>, literals: &mut Vec<usize>, data: &[u8]) {
+    args.extend(format!("{{{}", data.len()).as_bytes());
+    literals.push(args.len());
+    args.extend(b"}\r\n");
+    args.extend(data);
+}
+
 pub struct Command {
     args: Vec<u8>,
     next_state: Option<State>,
+    // Untagged responses (beyond status responses) that belong to this
+    // command; anything else received while it runs is unsolicited.
+    solicits: Vec<ResponseKind>,
+    // Positions in `args` of the closing braces of literal headers, after
+    // which the server has to be waited for.
+    literals: Vec<usize>,
 }
 
 impl Command {
-    pub fn to_parts(self) -> (Vec<u8>, Option<State>) {
-        let Command { args, next_state } = self;
-        (args, next_state)
+    pub fn to_parts(self) -> (Vec<u8>, Option<State>, Vec<ResponseKind>, Vec<usize>) {
+        let Command { args, next_state, solicits, literals } = self;
+        (args, next_state, solicits, literals)
+    }
+
+    /// The request for this command with the tag `tag`, for encoding it
+    /// without a connection; see `Request::encode_to()`.
+    pub fn into_request(self, tag: RequestId) -> Request {
+        Request(tag, self.args)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub(crate) fn name(&self) -> String {
+        metrics::command_name(&self.args)
     }
 }
 
@@ -100,7 +760,7 @@ impl FetchCommandMessages {
             AttrMacro::Fast => { args.extend(b"FAST"); },
             AttrMacro::Full => { args.extend(b"FULL"); },
         }
-        FetchCommand { args }
+        FetchCommand { args, modifiers: vec![] }
     }
 }
 
@@ -121,6 +781,13 @@ pub trait FetchBuilderMessages where Self: Sized {
         FetchCommandMessages { args }
     }
 
+    /// Add a raw sequence set, such as `1:4,7` or `3:*`.
+    fn set(self, set: &str) -> FetchCommandMessages {
+        let FetchCommandMessages { mut args } = self.prepare();
+        args.extend(set.as_bytes());
+        FetchCommandMessages { args }
+    }
+
     fn all_after(self, start: u32) -> FetchCommandMessages {
         let FetchCommandMessages { mut args } = self.prepare();
         args.extend(start.to_string().as_bytes());
@@ -153,36 +820,71 @@ pub trait FetchBuilderAttributes where Self: Sized {
     fn prepare(self) -> FetchCommandAttributes;
     fn attr(self, attr: Attribute) -> FetchCommandAttributes {
         let FetchCommandAttributes { mut args } = self.prepare();
-        args.extend(match attr {
-            Attribute::Body => "BODY",
-            Attribute::Envelope => "ENVELOPE",
-            Attribute::Flags => "FLAGS",
-            Attribute::InternalDate => "INTERNALDATE",
-            Attribute::ModSeq => "MODSEQ",
-            Attribute::Rfc822 => "RFC822",
-            Attribute::Rfc822Size => "RFC822.SIZE",
-            Attribute::Uid => "UID",
-        }.as_bytes());
+        match attr {
+            Attribute::Annotation { entries, attributes } => {
+                args.extend(b"ANNOTATION (");
+                push_annotation_list(&mut args, &entries);
+                args.push(b' ');
+                push_annotation_list(&mut args, &attributes);
+                args.push(b')');
+            },
+            Attribute::Body => { args.extend(b"BODY"); },
+            Attribute::BodySection(section) => {
+                args.extend(b"BODY[");
+                args.extend(section.as_bytes());
+                args.push(b']');
+            },
+            Attribute::BodyPeek(section) => {
+                args.extend(b"BODY.PEEK[");
+                args.extend(section.as_bytes());
+                args.push(b']');
+            },
+            Attribute::BodyStructure => { args.extend(b"BODYSTRUCTURE"); },
+            Attribute::BodyPeekPartial { section, offset, len } => {
+                args.extend(b"BODY.PEEK[");
+                args.extend(section.as_bytes());
+                args.extend(format!("]<{}.{}>", offset, len).as_bytes());
+            },
+            Attribute::Envelope => { args.extend(b"ENVELOPE"); },
+            Attribute::Flags => { args.extend(b"FLAGS"); },
+            Attribute::InternalDate => { args.extend(b"INTERNALDATE"); },
+            Attribute::ModSeq => { args.extend(b"MODSEQ"); },
+            Attribute::Rfc822 => { args.extend(b"RFC822"); },
+            Attribute::Rfc822Size => { args.extend(b"RFC822.SIZE"); },
+            Attribute::Uid => { args.extend(b"UID"); },
+        }
         FetchCommandAttributes { args }
     }
 }
 
 pub struct FetchCommand {
     args: Vec<u8>,
+    modifiers: Vec<String>,
 }
 
 pub trait FetchBuilderModifiers where Self: Sized {
     fn prepare(self) -> FetchCommand;
     fn build(self) -> Command {
-        let FetchCommand { args } = self.prepare();
-        Command { args, next_state: None }
+        let FetchCommand { mut args, modifiers } = self.prepare();
+        if !modifiers.is_empty() {
+            args.extend(b" (");
+            args.extend(modifiers.join(" ").as_bytes());
+            args.push(b')');
+        }
+        Command { args, next_state: None, solicits: vec![ResponseKind::Fetch, ResponseKind::UidFetch], literals: vec![] }
     }
     fn changed_since(self, seq: u64) -> FetchCommand {
-        let FetchCommand { mut args } = self.prepare();
-        args.extend(b" (CHANGEDSINCE ");
-        args.extend(seq.to_string().as_bytes());
-        args.push(b')');
-        FetchCommand { args }
+        let FetchCommand { args, mut modifiers } = self.prepare();
+        modifiers.push(format!("CHANGEDSINCE {}", seq));
+        FetchCommand { args, modifiers }
+    }
+    /// Only return the messages at positions `first` to `last` within the
+    /// requested set (RFC 9394). Negative positions count from the end, so
+    /// `partial(-1, -50)` returns the last 50 messages.
+    fn partial(self, first: i32, last: i32) -> FetchCommand {
+        let FetchCommand { args, mut modifiers } = self.prepare();
+        modifiers.push(format!("PARTIAL {}:{}", first, last));
+        FetchCommand { args, modifiers }
     }
 }
 
@@ -190,7 +892,7 @@ impl FetchBuilderModifiers for FetchCommandAttributes {
     fn prepare(self) -> FetchCommand {
         let FetchCommandAttributes { mut args, .. } = self;
         args.push(b')');
-        FetchCommand { args }
+        FetchCommand { args, modifiers: vec![] }
     }
 }
 
@@ -198,3 +900,804 @@ impl FetchBuilderModifiers for FetchCommand {
     fn prepare(self) -> FetchCommand { self }
 }
 
+
+enum AppendContent {
+    Message(Vec<u8>),
+    Catenate(Vec<CatenatePart>),
+}
+
+struct AppendMessage {
+    utf8: bool,
+    flags: Vec<String>,
+    date: Option<String>,
+    content: AppendContent,
+}
+
+impl AppendMessage {
+    fn new(content: AppendContent) -> AppendMessage {
+        AppendMessage { utf8: false, flags: vec![], date: None, content }
+    }
+}
+
+/// An APPEND command, which stores one or more messages in a mailbox.
+/// Appending several messages in one command requires the MULTIAPPEND
+/// capability (RFC 3502); the server then stores all of them or none.
+pub struct AppendCommand {
+    mailbox: String,
+    messages: Vec<AppendMessage>,
+}
+
+impl AppendCommand {
+    /// Add another `message` to the command.
+    pub fn message(mut self, message: &[u8]) -> AppendCommand {
+        self.messages.push(AppendMessage::new(AppendContent::Message(message.to_vec())));
+        self
+    }
+
+    /// Add another message, composed by the server from `parts`.
+    pub fn catenate(mut self, parts: &[CatenatePart]) -> AppendCommand {
+        self.messages.push(AppendMessage::new(AppendContent::Catenate(parts.to_vec())));
+        self
+    }
+
+    /// Set `flags` on the message added last.
+    pub fn flags(mut self, flags: &[&str]) -> AppendCommand {
+        self.last().flags = flags.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Set the internal date of the message added last, given in the IMAP
+    /// date-time format (`"17-Jul-1996 02:44:25 -0700"`).
+    pub fn date(mut self, date: &str) -> AppendCommand {
+        self.last().date = Some(date.to_string());
+        self
+    }
+
+    /// Like `date()`, but from a `DateTime`, for example to preserve the
+    /// INTERNALDATE of a message copied from another server.
+    pub fn internal_date(self, date: DateTime) -> AppendCommand {
+        self.date(&date.to_string())
+    }
+
+    /// Mark the message added last as containing UTF-8 headers (RFC 6855).
+    /// Requires UTF8=ACCEPT to have been enabled.
+    pub fn utf8(mut self) -> AppendCommand {
+        self.last().utf8 = true;
+        self
+    }
+
+    /// The number of messages to be appended.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn last(&mut self) -> &mut AppendMessage {
+        self.messages.last_mut().expect("an APPEND command has at least one message")
+    }
+
+    pub fn build(self) -> Command {
+        self.build_indexed().0
+    }
+
+    // Builds the command, along with the index among its literals of the
+    // first literal of each message, or of the next literal if the message
+    // has none.
+    pub(crate) fn build_indexed(self) -> (Command, Vec<usize>) {
+        let mut args = vec![];
+        let mut literals = vec![];
+        let mut starts = vec![];
+        args.extend(b"APPEND ");
+        push_quoted(&mut args, &mut literals, &self.mailbox);
+        for message in self.messages {
+            starts.push(literals.len());
+            if !message.flags.is_empty() {
+                args.extend(b" (");
+                args.extend(message.flags.join(" ").as_bytes());
+                args.push(b')');
+            }
+            if let Some(ref date) = message.date {
+                args.push(b' ');
+                push_quoted(&mut args, &mut literals, date);
+            }
+            args.push(b' ');
+            match message.content {
+                AppendContent::Message(ref data) if message.utf8 => {
+                    args.extend(b"UTF8 (~");
+                    push_literal(&mut args, &mut literals, data);
+                    args.push(b')');
+                },
+                AppendContent::Message(ref data) => push_literal(&mut args, &mut literals, data),
+                AppendContent::Catenate(ref parts) => push_catenate_parts(&mut args, &mut literals, parts),
+            }
+        }
+        (Command { args, next_state: None, solicits: vec![], literals }, starts)
+    }
+}
+
+pub struct ListCommand {
+    reference: String,
+    pattern: String,
+    select_special_use: bool,
+    return_special_use: bool,
+    status: Vec<StatusAttribute>,
+}
+
+impl ListCommand {
+    /// Only list mailboxes with a special use, and return their special-use
+    /// attributes (RFC 6154). Requires the SPECIAL-USE capability.
+    pub fn select_special_use(mut self) -> ListCommand {
+        self.select_special_use = true;
+        self.return_special_use = true;
+        self
+    }
+
+    /// Return the special-use attributes of the listed mailboxes, which
+    /// servers may otherwise omit (RFC 6154).
+    pub fn return_special_use(mut self) -> ListCommand {
+        self.return_special_use = true;
+        self
+    }
+
+    /// Also return the STATUS `items` of each listed mailbox (RFC 5819).
+    /// Requires the LIST-STATUS capability.
+    pub fn status(mut self, items: &[StatusAttribute]) -> ListCommand {
+        self.status = items.to_vec();
+        self
+    }
+
+    pub fn build(self) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"LIST ");
+        if self.select_special_use {
+            args.extend(b"(SPECIAL-USE) ");
+        }
+        push_quoted(&mut args, &mut literals, &self.reference);
+        args.push(b' ');
+        push_quoted(&mut args, &mut literals, &self.pattern);
+        let mut returns = vec![];
+        if self.return_special_use {
+            returns.extend(b"SPECIAL-USE");
+        }
+        if !self.status.is_empty() {
+            if !returns.is_empty() {
+                returns.push(b' ');
+            }
+            returns.extend(b"STATUS ");
+            push_status_attributes(&mut returns, &self.status);
+        }
+        if !returns.is_empty() {
+            args.extend(b" RETURN (");
+            args.extend(returns);
+            args.push(b')');
+        }
+        Command { args, next_state: None, solicits: vec![ResponseKind::List, ResponseKind::Status], literals }
+    }
+}
+
+pub struct GetMetadataCommand {
+    mailbox: String,
+    entries: Vec<String>,
+    depth: Option<&'static str>,
+    max_size: Option<u32>,
+}
+
+impl GetMetadataCommand {
+    /// Also return the entries directly below the requested ones.
+    pub fn children(mut self) -> GetMetadataCommand {
+        self.depth = Some("1");
+        self
+    }
+
+    /// Also return all entries below the requested ones.
+    pub fn descendants(mut self) -> GetMetadataCommand {
+        self.depth = Some("infinity");
+        self
+    }
+
+    /// Leave out values larger than `size` octets; the server reports the
+    /// size of the largest with a `METADATA LONGENTRIES` response code.
+    pub fn max_size(mut self, size: u32) -> GetMetadataCommand {
+        self.max_size = Some(size);
+        self
+    }
+
+    pub fn build(self) -> Command {
+        let mut args = vec![];
+        let mut literals = vec![];
+        args.extend(b"GETMETADATA ");
+        let mut options = vec![];
+        if let Some(size) = self.max_size {
+            options.push(format!("MAXSIZE {}", size));
+        }
+        if let Some(depth) = self.depth {
+            options.push(format!("DEPTH {}", depth));
+        }
+        if !options.is_empty() {
+            args.extend(format!("({}) ", options.join(" ")).as_bytes());
+        }
+        push_quoted(&mut args, &mut literals, &self.mailbox);
+        args.extend(b" (");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                args.push(b' ');
+            }
+            push_quoted(&mut args, &mut literals, entry);
+        }
+        args.push(b')');
+        Command { args, next_state: None, solicits: vec![ResponseKind::Metadata], literals }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum PatternPart {
+    Literal(String),
+    Any,
+    Level,
+}
+
+/// A LIST pattern made of literal mailbox-name text and wildcards. IMAP has
+/// no way to escape `%` and `*`, so literal occurrences are sent as `%`,
+/// which does not cross hierarchy levels, and the returned mailboxes must be
+/// checked with `matches()`; `Session::list_matching()` does so.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ListPattern {
+    parts: Vec<PatternPart>,
+}
+
+impl ListPattern {
+    pub fn new() -> ListPattern {
+        ListPattern::default()
+    }
+
+    /// Append `text`, which only matches itself.
+    pub fn literal(mut self, text: &str) -> ListPattern {
+        self.parts.push(PatternPart::Literal(text.to_string()));
+        self
+    }
+
+    /// Append a `*` wildcard, which matches anything, including hierarchy
+    /// delimiters.
+    pub fn any(mut self) -> ListPattern {
+        self.parts.push(PatternPart::Any);
+        self
+    }
+
+    /// Append a `%` wildcard, which matches anything except hierarchy
+    /// delimiters.
+    pub fn level(mut self) -> ListPattern {
+        self.parts.push(PatternPart::Level);
+        self
+    }
+
+    /// The pattern as sent to the server.
+    pub fn encode(&self) -> String {
+        let mut pattern = String::new();
+        for part in &self.parts {
+            match *part {
+                PatternPart::Literal(ref text) => pattern.extend(text.chars().map(|c| match c {
+                    '*' => '%',
+                    c => c,
+                })),
+                PatternPart::Any => pattern.push('*'),
+                PatternPart::Level => pattern.push('%'),
+            }
+        }
+        pattern
+    }
+
+    /// Whether the mailbox `name`, with hierarchy `delimiter`, matches.
+    pub fn matches(&self, name: &str, delimiter: Option<&str>) -> bool {
+        match_pattern(&self.parts, name, delimiter.filter(|d| !d.is_empty()))
+    }
+}
+
+fn match_pattern(parts: &[PatternPart], name: &str, delimiter: Option<&str>) -> bool {
+    let (part, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return name.is_empty(),
+    };
+    let mut ends = name.char_indices().map(|(i, _)| i).chain(Some(name.len()));
+    match *part {
+        PatternPart::Literal(ref text) => match name.strip_prefix(text.as_str()) {
+            Some(name) => match_pattern(rest, name, delimiter),
+            None => false,
+        },
+        PatternPart::Any => ends.any(|i| match_pattern(rest, &name[i..], delimiter)),
+        PatternPart::Level => ends
+            .take_while(|&i| !delimiter.is_some_and(|d| name[..i].contains(d)))
+            .any(|i| match_pattern(rest, &name[i..], delimiter)),
+    }
+}
+
+pub struct SearchCommand {
+    criteria: SearchCriteria,
+    returns: Vec<SearchReturn>,
+    charset: Option<String>,
+    uid: bool,
+    sources: Vec<SearchSource>,
+}
+
+impl SearchCommand {
+    /// Ask for the results in an ESEARCH response containing only the
+    /// requested data (RFC 4731).
+    pub fn returning(mut self, returns: &[SearchReturn]) -> SearchCommand {
+        self.returns.extend(returns);
+        self
+    }
+
+    /// Declare the charset of the search strings, which are then encoded
+    /// in it if it is ISO-8859-1, and in UTF-8 otherwise. By default,
+    /// `CHARSET UTF-8` is sent only if a string contains non-ASCII
+    /// characters. Such strings are always sent as literals.
+    pub fn charset(mut self, charset: &str) -> SearchCommand {
+        self.charset = Some(charset.to_string());
+        self
+    }
+
+    pub fn build(self) -> Command {
+        let SearchCommand { criteria, returns, charset, uid, sources } = self;
+        let charset = charset.or_else(|| match any_search_string(&criteria, &|s| !s.is_ascii()) {
+            true => Some("UTF-8".to_string()),
+            false => None,
+        });
+        let mut args = vec![];
+        let mut literals = vec![];
+        if !sources.is_empty() {
+            args.extend(b"ESEARCH ");
+            push_search_sources(&mut args, &mut literals, &sources);
+        } else if uid {
+            args.extend(b"UID SEARCH ");
+        } else {
+            args.extend(b"SEARCH ");
+        }
+        push_search_returns(&mut args, &returns);
+        if let Some(ref charset) = charset {
+            args.extend(format!("CHARSET {} ", charset).as_bytes());
+        }
+        let latin1 = charset.map(|charset| is_latin1(&charset)).unwrap_or(false);
+        push_search_key(&mut args, &mut literals, &criteria, latin1);
+        Command {
+            args,
+            next_state: None,
+            solicits: vec![ResponseKind::Search, ResponseKind::Esearch],
+            literals,
+        }
+    }
+}
+
+// Whether `charset` names ISO-8859-1, by one of its common names.
+pub(crate) fn is_latin1(charset: &str) -> bool {
+    ["ISO-8859-1", "ISO_8859-1", "LATIN1"].iter().any(|name| charset.eq_ignore_ascii_case(name))
+}
+
+/// A set of sequence numbers or UIDs in its compact wire form, such as
+/// `1:3,7`. Collecting numbers into it sorts them, drops duplicates and
+/// merges consecutive numbers into ranges.
+///
+/// ```ignore
+/// let set = uids.iter().copied().collect::<SequenceSet>();
+/// for chunk in set.chunks(1000) {
+///     session.uid_fetch(&chunk.to_string(), &[Attribute::Flags]).await?;
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SequenceSet {
+    ranges: Vec<RangeInclusive<u32>>,
+}
+
+impl SequenceSet {
+    /// The ranges of the set, in ascending order and not adjacent.
+    pub fn ranges(&self) -> &[RangeInclusive<u32>] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.ranges.binary_search_by(|range| match () {
+            _ if *range.end() < id => Ordering::Less,
+            _ if *range.start() > id => Ordering::Greater,
+            _ => Ordering::Equal,
+        }).is_ok()
+    }
+
+    // Parses the wire form of a set. Sets using `*` or `$` have no fixed
+    // numbers and give `None`.
+    pub(crate) fn parse(set: &str) -> Option<SequenceSet> {
+        let number = |n: &str| n.parse::<u32>().ok().filter(|&n| n > 0);
+        set.split(',')
+            .map(|element| match element.split_once(':') {
+                Some((first, last)) => {
+                    let (first, last) = (number(first)?, number(last)?);
+                    Some(first.min(last)..=first.max(last))
+                }
+                None => number(element).map(|n| n..=n),
+            })
+            .collect()
+    }
+
+    /// Split the set into sets whose wire form is at most `max_len` bytes,
+    /// as servers limit the length of command lines. A range longer than
+    /// that gets a set of its own.
+    pub fn chunks(&self, max_len: usize) -> Vec<SequenceSet> {
+        let mut chunks = vec![];
+        let mut current = SequenceSet::default();
+        let mut len = 0;
+        for range in &self.ranges {
+            let range_len = format_range(range).len();
+            if !current.is_empty() && len + 1 + range_len > max_len {
+                chunks.push(std::mem::take(&mut current));
+                len = 0;
+            }
+            if !current.is_empty() {
+                len += 1;
+            }
+            len += range_len;
+            current.ranges.push(range.clone());
+        }
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+        chunks
+    }
+}
+
+impl FromIterator<u32> for SequenceSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(ids: I) -> SequenceSet {
+        let mut ids = ids.into_iter().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut ranges: Vec<RangeInclusive<u32>> = vec![];
+        for id in ids {
+            match ranges.last_mut() {
+                Some(last) if last.end().checked_add(1) == Some(id) => *last = *last.start()..=id,
+                _ => ranges.push(id..=id),
+            }
+        }
+        SequenceSet { ranges }
+    }
+}
+
+// As in the wire form, a range such as `5..=3` stands for `3..=5`.
+impl FromIterator<RangeInclusive<u32>> for SequenceSet {
+    fn from_iter<I: IntoIterator<Item = RangeInclusive<u32>>>(ranges: I) -> SequenceSet {
+        let mut sorted = ranges.into_iter()
+            .map(|range| *range.start().min(range.end())..=*range.start().max(range.end()))
+            .collect::<Vec<_>>();
+        sorted.sort_unstable_by_key(|range| *range.start());
+        let mut ranges: Vec<RangeInclusive<u32>> = vec![];
+        for range in sorted {
+            match ranges.last_mut() {
+                Some(last) if last.end().saturating_add(1) >= *range.start() => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => ranges.push(range),
+            }
+        }
+        SequenceSet { ranges }
+    }
+}
+
+impl fmt::Display for SequenceSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(&format_range(range))?;
+        }
+        Ok(())
+    }
+}
+
+fn format_range(range: &RangeInclusive<u32>) -> String {
+    match range.start() == range.end() {
+        true => range.start().to_string(),
+        false => format!("{}:{}", range.start(), range.end()),
+    }
+}
+
+fn push_search_sources(args: &mut Vec<u8>, literals: &mut Vec<usize>, sources: &[SearchSource]) {
+    args.extend(b"IN (");
+    for (i, source) in sources.iter().enumerate() {
+        if i > 0 {
+            args.push(b' ');
+        }
+        let (name, mailboxes) = match *source {
+            SearchSource::Selected => ("selected", None),
+            SearchSource::SelectedDelayed => ("selected-delayed", None),
+            SearchSource::Inboxes => ("inboxes", None),
+            SearchSource::Personal => ("personal", None),
+            SearchSource::Subscribed => ("subscribed", None),
+            SearchSource::Subtree(ref mailboxes) => ("subtree", Some(mailboxes)),
+            SearchSource::SubtreeOne(ref mailboxes) => ("subtree-one", Some(mailboxes)),
+            SearchSource::Mailboxes(ref mailboxes) => ("mailboxes", Some(mailboxes)),
+        };
+        args.extend(name.as_bytes());
+        if let Some(mailboxes) = mailboxes {
+            args.push(b' ');
+            if mailboxes.len() != 1 {
+                args.push(b'(');
+            }
+            for (j, mailbox) in mailboxes.iter().enumerate() {
+                if j > 0 {
+                    args.push(b' ');
+                }
+                push_quoted(args, literals, mailbox);
+            }
+            if mailboxes.len() != 1 {
+                args.push(b')');
+            }
+        }
+    }
+    args.extend(b") ");
+}
+
+fn push_search_returns(args: &mut Vec<u8>, returns: &[SearchReturn]) {
+    if returns.is_empty() {
+        return;
+    }
+    args.extend(b"RETURN (");
+    for (i, ret) in returns.iter().enumerate() {
+        if i > 0 {
+            args.push(b' ');
+        }
+        match *ret {
+            SearchReturn::All => args.extend(b"ALL"),
+            SearchReturn::Count => args.extend(b"COUNT"),
+            SearchReturn::Max => args.extend(b"MAX"),
+            SearchReturn::Min => args.extend(b"MIN"),
+            SearchReturn::Partial(first, last) => {
+                args.extend(format!("PARTIAL {}:{}", first, last).as_bytes());
+            },
+            SearchReturn::Relevancy => args.extend(b"RELEVANCY"),
+            SearchReturn::Update => args.extend(b"UPDATE"),
+        }
+    }
+    args.extend(b") ");
+}
+
+pub struct SortCommand {
+    criteria: Vec<SortCriterion>,
+    search: SearchCriteria,
+    returns: Vec<SearchReturn>,
+    uid: bool,
+}
+
+impl SortCommand {
+    /// Ask for the results in an ESEARCH response containing only the
+    /// requested data (RFC 5267, section 3).
+    pub fn returning(mut self, returns: &[SearchReturn]) -> SortCommand {
+        self.returns.extend(returns);
+        self
+    }
+
+    pub fn build(self) -> Command {
+        let SortCommand { criteria, search, returns, uid } = self;
+        let mut args = vec![];
+        if uid {
+            args.extend(b"UID ");
+        }
+        args.extend(b"SORT ");
+        push_search_returns(&mut args, &returns);
+        args.push(b'(');
+        for (i, criterion) in criteria.iter().enumerate() {
+            if i > 0 {
+                args.push(b' ');
+            }
+            push_sort_criterion(&mut args, criterion);
+        }
+        args.extend(b") UTF-8 ");
+        let mut literals = vec![];
+        push_search_key(&mut args, &mut literals, &search, false);
+        Command {
+            args,
+            next_state: None,
+            solicits: vec![ResponseKind::Sort, ResponseKind::Esearch],
+            literals,
+        }
+    }
+}
+
+fn copy_command(command: &[u8], set: &str, mailbox: &str) -> Command {
+    let mut args = vec![];
+    let mut literals = vec![];
+    args.extend(command);
+    args.extend(set.as_bytes());
+    args.push(b' ');
+    push_quoted(&mut args, &mut literals, mailbox);
+    Command { args, next_state: None, solicits: vec![], literals }
+}
+
+fn store_annotation_command(command: &[u8], set: &str, entry: &str, values: &[(&str, Option<&str>)]) -> Command {
+    let mut args = vec![];
+    let mut literals = vec![];
+    args.extend(command);
+    args.extend(set.as_bytes());
+    args.extend(b" ANNOTATION (");
+    push_quoted(&mut args, &mut literals, entry);
+    args.extend(b" (");
+    for (i, &(attribute, value)) in values.iter().enumerate() {
+        if i > 0 {
+            args.push(b' ');
+        }
+        push_quoted(&mut args, &mut literals, attribute);
+        args.push(b' ');
+        push_nstring(&mut args, &mut literals, value);
+    }
+    args.extend(b"))");
+    Command { args, next_state: None, solicits: vec![ResponseKind::Fetch, ResponseKind::UidFetch], literals }
+}
+
+fn replace_command(command: &[u8], id: u32, mailbox: &str, message: &[u8]) -> Command {
+    let mut args = vec![];
+    let mut literals = vec![];
+    args.extend(command);
+    args.extend(id.to_string().as_bytes());
+    args.push(b' ');
+    push_quoted(&mut args, &mut literals, mailbox);
+    args.push(b' ');
+    push_literal(&mut args, &mut literals, message);
+    Command {
+        args,
+        next_state: None,
+        solicits: vec![ResponseKind::Expunge],
+        literals,
+    }
+}
+
+fn thread_command(command: &[u8], algorithm: ThreadAlgorithm, search: &SearchCriteria) -> Command {
+    let mut args = vec![];
+    args.extend(command);
+    args.extend(algorithm.as_str().as_bytes());
+    args.extend(b" UTF-8 ");
+    let mut literals = vec![];
+    push_search_key(&mut args, &mut literals, search, false);
+    Command {
+        args,
+        next_state: None,
+        solicits: vec![ResponseKind::Thread],
+        literals,
+    }
+}
+
+fn push_sort_criterion(args: &mut Vec<u8>, criterion: &SortCriterion) {
+    args.extend(match *criterion {
+        SortCriterion::Arrival => &b"ARRIVAL"[..],
+        SortCriterion::Cc => &b"CC"[..],
+        SortCriterion::Date => &b"DATE"[..],
+        SortCriterion::DisplayFrom => &b"DISPLAYFROM"[..],
+        SortCriterion::DisplayTo => &b"DISPLAYTO"[..],
+        SortCriterion::From => &b"FROM"[..],
+        SortCriterion::Relevancy => &b"RELEVANCY"[..],
+        SortCriterion::Reverse(ref criterion) => {
+            args.extend(b"REVERSE ");
+            return push_sort_criterion(args, criterion);
+        },
+        SortCriterion::Size => &b"SIZE"[..],
+        SortCriterion::Subject => &b"SUBJECT"[..],
+        SortCriterion::To => &b"TO"[..],
+    });
+}
+
+pub struct StoreCommand {
+    set: String,
+    store_type: StoreType,
+    flags: Vec<String>,
+    silent: bool,
+    unchanged_since: Option<u64>,
+    uid: bool,
+}
+
+impl StoreCommand {
+    /// Ask the server not to send back the updated flags.
+    pub fn silent(mut self) -> StoreCommand {
+        self.silent = true;
+        self
+    }
+
+    /// Only update messages whose mod-sequence is not greater than `seq`
+    /// (RFC 4551, section 3.2).
+    pub fn unchanged_since(mut self, seq: u64) -> StoreCommand {
+        self.unchanged_since = Some(seq);
+        self
+    }
+
+    pub fn build(self) -> Command {
+        let StoreCommand { set, store_type, flags, silent, unchanged_since, uid } = self;
+        let mut args = vec![];
+        if uid {
+            args.extend(b"UID ");
+        }
+        args.extend(b"STORE ");
+        args.extend(set.as_bytes());
+        if let Some(seq) = unchanged_since {
+            args.extend(b" (UNCHANGEDSINCE ");
+            args.extend(seq.to_string().as_bytes());
+            args.push(b')');
+        }
+        args.extend(match store_type {
+            StoreType::Replace => &b" FLAGS"[..],
+            StoreType::Add => &b" +FLAGS"[..],
+            StoreType::Remove => &b" -FLAGS"[..],
+        });
+        if silent {
+            args.extend(b".SILENT");
+        }
+        args.extend(b" (");
+        args.extend(flags.join(" ").as_bytes());
+        args.push(b')');
+        Command { args, next_state: None, solicits: vec![ResponseKind::Fetch, ResponseKind::UidFetch], literals: vec![] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::RangeInclusive;
+
+    use super::SequenceSet;
+
+    fn set(ids: &[u32]) -> SequenceSet {
+        ids.iter().copied().collect()
+    }
+
+    #[test]
+    fn sequence_set_from_ids() {
+        assert_eq!(set(&[]).to_string(), "");
+        assert!(set(&[]).is_empty());
+        assert_eq!(set(&[1, 2, 3, 7]).to_string(), "1:3,7");
+        assert_eq!(set(&[9, 3, 1, 2, 8]).to_string(), "1:3,8:9");
+        assert_eq!(set(&[5, 5, 4, 5, 4]).to_string(), "4:5");
+        assert_eq!(set(&[u32::MAX, 1, u32::MAX - 1]).to_string(), format!("1,{}:{}", u32::MAX - 1, u32::MAX));
+        assert_eq!(set(&[u32::MAX]).ranges(), [u32::MAX..=u32::MAX]);
+    }
+
+    #[test]
+    fn sequence_set_from_ranges() {
+        // Reversed ranges are taken as in the wire form `30:25`.
+        let reversed = RangeInclusive::new(30, 25);
+        let set = vec![9..=12, 1..=3, 4..=4, 11..=20, reversed].into_iter().collect::<SequenceSet>();
+        assert_eq!(set.to_string(), "1:4,9:20,25:30");
+        let set = vec![1..=u32::MAX, u32::MAX..=u32::MAX].into_iter().collect::<SequenceSet>();
+        assert_eq!(set.ranges(), [1..=u32::MAX]);
+        assert!(set.contains(u32::MAX));
+    }
+
+    #[test]
+    fn sequence_set_contains() {
+        let set = set(&[2, 3, 4, 10, 20, 21]);
+        for id in [2, 3, 4, 10, 20, 21] {
+            assert!(set.contains(id), "{}", id);
+        }
+        for id in [0, 1, 5, 9, 11, 19, 22, u32::MAX] {
+            assert!(!set.contains(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn sequence_set_chunks() {
+        let set = set(&[1, 2, 3, 5, 7, 8, 100, 1000]);
+        let chunks: Vec<_> = set.chunks(7).iter().map(SequenceSet::to_string).collect();
+        assert_eq!(chunks, ["1:3,5", "7:8,100", "1000"]);
+        // A range longer than the limit gets a chunk of its own.
+        let chunks: Vec<_> = set.chunks(2).iter().map(SequenceSet::to_string).collect();
+        assert_eq!(chunks, ["1:3", "5", "7:8", "100", "1000"]);
+        assert!(SequenceSet::default().chunks(10).is_empty());
+    }
+
+    #[test]
+    fn sequence_set_parse() {
+        assert_eq!(SequenceSet::parse("7,1:3,5:4").unwrap().to_string(), "1:5,7");
+        assert_eq!(SequenceSet::parse("4294967295").unwrap().ranges(), [u32::MAX..=u32::MAX]);
+        for set in ["", "1:*", "$", "0", "1,,2", "1:2:3", "4294967296", "a"] {
+            assert_eq!(SequenceSet::parse(set), None, "{}", set);
+        }
+    }
+}
@@ -1,193 +1,638 @@
-use futures::{Async, Future, Poll, Sink};
-use futures::stream::Stream;
-use futures::sink::Send;
-use futures_state_stream::{StateStream, StreamEvent};
+use bytes::{Buf, BytesMut};
 
-use native_tls::TlsConnector;
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::future;
+use futures::{ready, Stream, StreamExt};
 
-use std::io;
-use std::net::ToSocketAddrs;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use tokio_core::net::{TcpStream, TcpStreamNew};
-use tokio_io::AsyncRead;
-use tokio_core::reactor::Handle;
-use tokio_tls::{ConnectAsync, TlsConnectorExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use proto::*;
+use crate::connection::{Connection, Event};
+use crate::deflate::DeflateStream;
+use crate::error::Error;
+use crate::metrics::MetricsObserver;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+use crate::net::ConnectOptions;
+use crate::proto::*;
+use crate::record::Recorder;
+use crate::throttle::{Delay, RateLimit};
 
 pub mod builder;
-use self::builder::Command;
+pub mod session;
+use self::builder::{Command, CommandBuilder};
+use self::session::Mailbox;
 
+pub use crate::connection::IdGenerator;
+
+// Size of the chunks read from the stream.
+const READ_CHUNK: usize = 8192;
+
+/// Drives a `Connection` over an asynchronous stream. The stream only needs
+/// to implement the `tokio` I/O traits, which do not depend on the tokio
+/// runtime; connecting is supported for tokio (the `runtime-tokio`
+/// feature), for async-std (the `runtime-async-std` feature) and over any
+/// user-supplied `Transport`.
 pub struct Client {
-    transport: ImapTransport,
+    stream: ImapStream,
     state: ClientState,
 }
 
 impl Client {
-    pub fn connect(server: &str, handle: &Handle) -> ConnectFuture {
-        let addr = format!("{}:993", server);
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        let stream = TcpStream::connect(&addr, handle);
-        ConnectFuture::TcpConnecting(stream, server.to_string())
+    /// Connect to `server` on the IMAPS port, returning the client together
+    /// with the server greeting. If the server has several addresses, they
+    /// are tried in parallel after short delays (RFC 8305). Use
+    /// `ConnectOptions` to configure the socket.
+    #[cfg(feature = "runtime-tokio")]
+    pub async fn connect(server: &str) -> io::Result<(Client, ResponseData)> {
+        ConnectOptions::new().connect(server).await
     }
 
-    pub fn call(self, cmd: Command) -> ResponseStream {
-        let Client { transport, mut state } = self;
-        let request_id = state.request_ids.next().unwrap();
-        let (cmd_bytes, next_state) = cmd.to_parts();
-        let future = transport.send(Request(request_id.clone(), cmd_bytes));
-        ResponseStream::new(future, state, request_id, next_state)
+    /// Like `connect()`, but using the async-std runtime.
+    #[cfg(feature = "runtime-async-std")]
+    pub async fn connect_async_std(server: &str) -> io::Result<(Client, ResponseData)> {
+        ConnectOptions::new().connect_async_std(server).await
     }
-}
 
-pub struct ResponseStream {
-    future: Option<Send<ImapTransport>>,
-    transport: Option<ImapTransport>,
-    state: Option<ClientState>,
-    request_id: RequestId,
-    next_state: Option<State>,
-    done: bool,
-}
+    /// Set up a client on a user-supplied transport, waiting for the server
+    /// greeting. This works on any platform the tokio I/O traits do,
+    /// including wasm32, where the transport would typically be a bridge
+    /// from a WebSocket to the IMAP server. TLS, if any, is up to the
+    /// transport.
+    pub async fn connect_transport<T: Transport + 'static>(transport: T)
+                                                           -> io::Result<(Client, ResponseData)> {
+        Client::connect_stream(ImapStream::Transport(Box::new(transport))).await
+    }
 
-impl ResponseStream {
-    pub fn new(future: Send<ImapTransport>, state: ClientState,
-               request_id: RequestId, next_state: Option<State>) -> ResponseStream {
-        ResponseStream {
-            future: Some(future),
-            transport: None,
-            state: Some(state),
-            request_id: request_id,
-            next_state: next_state,
-            done: false,
+    /// Set up a client on an already established stream, waiting for the
+    /// server greeting. This is mostly useful for testing; see the
+    /// `testing` module.
+    pub async fn connect_stream(stream: ImapStream) -> io::Result<(Client, ResponseData)> {
+        let mut client = Client { stream, state: ClientState::new() };
+        let greeting = future::poll_fn(|cx| client.poll_greeting(cx)).await?;
+        debug!(greeting = ?greeting.parsed(), "received server greeting");
+        // A server that refuses the connection greets with BYE.
+        if let Some(text) = client.bye() {
+            return Err(Error::Bye { text: text.to_string() }.into());
         }
+        Ok((client, greeting))
     }
-}
 
-impl StateStream for ResponseStream {
-    type Item = ResponseData;
-    type State = Client;
-    type Error = io::Error;
-    fn poll(&mut self) -> Poll<StreamEvent<Self::Item, Self::State>, Self::Error> {
-        if self.future.is_some() {
-            let mut future = self.future.take().unwrap();
-            match future.poll() {
-                Ok(Async::Ready(transport)) => {
-                    self.transport = Some(transport);
-                },
-                Ok(Async::NotReady) => {
-                    self.future = Some(future);
-                    return Ok(Async::NotReady);
-                },
-                Err(e) => {
-                    return Err(e);
-                },
+    /// Issue `cmd`. Nothing is sent until the returned stream is polled.
+    /// In read-only mode, a command that could modify a mailbox is not sent
+    /// at all, and the stream yields `Error::ReadOnly`.
+    pub fn call(&mut self, cmd: Command) -> ResponseStream<'_> {
+        if let Err(err) = self.state.conn.permits(&cmd) {
+            let request_id = RequestId(Arc::from(""));
+            return ResponseStream { client: self, request_id, done: false, refused: Some(err) };
+        }
+        let request_id = self.state.conn.command(cmd);
+        ResponseStream { client: self, request_id, done: false, refused: None }
+    }
+
+    /// The reason the server gave for closing the connection, if it has
+    /// sent BYE, for example because the session was idle for too long.
+    /// Commands then fail with `Error::Bye`.
+    pub fn bye(&self) -> Option<&str> {
+        self.state.conn.bye()
+    }
+
+    /// The protocol state machine driven by this client.
+    pub fn connection(&self) -> &Connection {
+        &self.state.conn
+    }
+
+    /// Whether the server is known to support `capability`.
+    pub fn has(&self, capability: &Capability) -> bool {
+        self.state.conn.has(capability)
+    }
+
+    /// The cached capabilities of the server, if they are known.
+    pub fn capabilities(&self) -> Option<&[Capability]> {
+        self.state.conn.capabilities()
+    }
+
+    /// Whether `capability` has been enabled with the ENABLE command.
+    pub fn is_enabled(&self, capability: &Capability) -> bool {
+        self.state.conn.is_enabled(capability)
+    }
+
+    /// The state of the selected mailbox, kept up to date from all
+    /// responses, including unsolicited ones; see `Connection::mailbox()`.
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.state.conn.mailbox()
+    }
+
+    /// Make sure the capabilities of the server are known, by issuing a
+    /// CAPABILITY command only if they have not been cached.
+    pub async fn refresh_capabilities(&mut self) -> io::Result<()> {
+        if self.capabilities().is_none() {
+            self.call(CommandBuilder::capability()).drain().await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to unsolicited responses, such as EXISTS or FETCH flag
+    /// updates received while another command is running. Once subscribed,
+    /// these are no longer yielded from the `ResponseStream` of the command
+    /// during which they were received. Only the most recent subscriber
+    /// receives responses; dropping it restores the default behavior.
+    ///
+    /// At most `capacity` responses are buffered for the subscriber. While
+    /// the buffer is full, no further responses are read from the server.
+    pub fn unsolicited(&mut self, capacity: usize) -> UnsolicitedResponses {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.state.unsolicited = Some(tx);
+        rx
+    }
+
+    /// Log out (RFC 3501, section 6.1.3) and close the connection: once the
+    /// server has confirmed, the stream is shut down, which ends a TLS
+    /// session with a close_notify alert, and the server's end is read
+    /// until it has closed it as well.
+    pub async fn logout(mut self) -> io::Result<()> {
+        self.call(CommandBuilder::logout()).drain().await?;
+        future::poll_fn(|cx| Pin::new(&mut self.stream).poll_shutdown(cx)).await?;
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match future::poll_fn(|cx| Pin::new(&mut self.stream).poll_read(cx, &mut chunk)).await {
+                Ok(()) if chunk.filled().is_empty() => break,
+                Ok(()) => {},
+                // Not every server ends its TLS session properly.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
             }
         }
-        if !self.transport.is_some() {
-            return Ok(Async::NotReady);
+        debug!("logged out, connection closed");
+        Ok(())
+    }
+
+    /// Subscribe to the messages the server wants shown to the user, such
+    /// as `[ALERT]` responses (RFC 3501, section 7.1), whatever command they
+    /// are received with. Only the most recent subscriber receives them.
+    /// Alerts that do not fit in the buffer of `capacity` are dropped.
+    pub fn alerts(&mut self, capacity: usize) -> Alerts {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.state.alerts = Some(tx);
+        rx
+    }
+
+    /// Enable DEFLATE compression for the rest of the connection (RFC 4978).
+    /// The server must advertise the `COMPRESS=DEFLATE` capability.
+    pub async fn compress(mut self) -> io::Result<Client> {
+        let mut accepted = false;
+        {
+            let mut responses = self.call(CommandBuilder::compress());
+            while let Some(rsp) = responses.next().await {
+                if let Response::Done(_, ref status, ..) = *rsp?.parsed() {
+                    accepted = matches!(*status, Status::Ok);
+                }
+            }
         }
-        let mut transport = self.transport.take().unwrap();
-        if self.done {
-            let mut state = self.state.take().unwrap();
-            if self.next_state.is_some() {
-                state.state = self.next_state.take().unwrap();
+        if !accepted {
+            return Err(io::Error::other("server refused COMPRESS DEFLATE"));
+        }
+        debug!("DEFLATE compression enabled");
+        self.into_compressed()
+    }
+
+    fn into_compressed(self) -> Result<Client, io::Error> {
+        let Client { stream, mut state } = self;
+        let pending = state.conn.resume();
+        let stream = match stream {
+            ImapStream::Deflate(_) => {
+                return Err(Error::StateError("compression already active".into()).into());
+            },
+            stream => DeflateStream::new(stream, pending),
+        };
+        let stream = ImapStream::Deflate(Box::new(stream));
+        Ok(Client { stream, state })
+    }
+
+    /// Deliver the contents of fetched literals larger than `threshold` bytes
+    /// as a series of `Response::BodyChunk` responses, so that large message
+    /// bodies do not have to be buffered in memory completely.
+    pub fn stream_literals_over(mut self, threshold: usize) -> Client {
+        self.state.conn.stream_literals_over(threshold);
+        self
+    }
+
+    /// Write the contents of fetched literals larger than `threshold` bytes
+    /// to temporary files in `dir`, so that large messages do not have to
+    /// be held in memory; see `ImapCodec::spool_literals_over()`.
+    pub fn spool_literals_over(mut self, threshold: usize, dir: &Path) -> Client {
+        self.state.conn.spool_literals_over(threshold, dir);
+        self
+    }
+
+    /// Never modify mailboxes: open them with EXAMINE instead of SELECT, and
+    /// refuse commands such as STORE, EXPUNGE, DELETE and APPEND with
+    /// `Error::ReadOnly`; see `Connection::read_only()`.
+    pub fn read_only(mut self) -> Client {
+        self.state.conn.read_only();
+        self
+    }
+
+    /// Keep commands carrying sequence or UID sets, such as FETCH and STORE,
+    /// under `limit` bytes, as some servers reject longer lines. Larger sets
+    /// are split over several commands, whose results are merged.
+    pub fn limit_command_length(mut self, limit: usize) -> Client {
+        self.state.max_command_length = Some(limit);
+        self
+    }
+
+    /// The limit set with `limit_command_length()`, if any.
+    pub fn max_command_length(&self) -> Option<usize> {
+        self.state.max_command_length
+    }
+
+    /// The tag the next command will get; see `Connection::next_tag()`.
+    pub fn next_tag(&self) -> Option<&RequestId> {
+        self.state.conn.next_tag()
+    }
+
+    /// Fail with an error instead of buffering more than `limit` bytes of a
+    /// single incomplete response. Literals streamed or spooled because of
+    /// `stream_literals_over()` or `spool_literals_over()` do not count
+    /// against the limit.
+    pub fn limit_buffer(mut self, limit: usize) -> Client {
+        self.state.conn.limit_buffer(limit);
+        self
+    }
+
+    /// Accept responses from servers which end lines with a bare LF instead
+    /// of CRLF; see `ImapCodec::lenient()`.
+    pub fn lenient(mut self) -> Client {
+        self.state.conn.lenient();
+        self
+    }
+
+    /// Tolerate the `quirks` of the server, such as `Quirks::EXCHANGE`, in
+    /// further responses; see `Connection::tolerate()`.
+    pub fn tolerate(mut self, quirks: Quirks) -> Client {
+        self.state.conn.tolerate(quirks);
+        self
+    }
+
+    /// Take the tags of further commands from `ids`; see
+    /// `Connection::generate_tags()`.
+    pub fn generate_tags<I>(mut self, ids: I) -> Client where I: Iterator<Item = RequestId> + Send + 'static {
+        self.state.conn.generate_tags(ids);
+        self
+    }
+
+    /// Limit the rate at which commands are sent. `sleep` creates the timers
+    /// used to wait for the limit, such as `tokio::time::sleep()`.
+    pub fn rate_limit<S>(mut self, limit: RateLimit, sleep: S) -> Client
+    where S: Fn(Duration) -> Delay + Send + 'static {
+        self.state.conn.rate_limit(limit);
+        self.state.sleep = Some(Box::new(sleep));
+        self
+    }
+
+    /// Report activity on this connection to `observer`.
+    pub fn observe(mut self, observer: Arc<dyn MetricsObserver>) -> Client {
+        self.state.conn.observe(observer);
+        self
+    }
+
+    /// Record all further data exchanged with the server to `sink`, in the
+    /// format described in the `record` module. Credentials are redacted.
+    pub fn record_to<W: Write + Send + 'static>(mut self, sink: W) -> Client {
+        self.state.conn.record_to(Recorder::new(sink));
+        self
+    }
+
+    fn poll_greeting(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<ResponseData>> {
+        loop {
+            while let Some(event) = self.state.events.pop_front() {
+                if let Event::Greeting(rsp) = event {
+                    return Poll::Ready(Ok(rsp));
+                }
             }
-            let client = Client { transport, state };
-            return Ok(Async::Ready(StreamEvent::Done(client)));
+            ready!(self.poll_io(cx))?;
         }
+    }
+
+    // Writes any data the connection has to send, then reads from the
+    // stream and queues the resulting events.
+    fn poll_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let state = &mut self.state;
         loop {
-            match transport.poll() {
-                Ok(Async::Ready(Some(rsp))) => {
-                    if let Some(req_id) = rsp.request_id() {
-                        self.done = *req_id == self.request_id;
-                    };
-                    self.transport = Some(transport);
-                    return Ok(Async::Ready(StreamEvent::Next(rsp)));
-                },
-                Ok(Async::Ready(None)) | Ok(Async::NotReady) => {
-                    break;
-                },
-                Err(e) => {
-                    return Err(e);
-                },
+            if state.output.is_empty() {
+                match state.conn.transmit() {
+                    Some(output) => state.output = output,
+                    None => break,
+                }
+            }
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &state.output))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            state.output.advance(written);
+            state.needs_flush = true;
+        }
+        if state.needs_flush {
+            ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+            state.needs_flush = false;
+        }
+        if state.conn.is_paused() {
+            return Poll::Ready(Err(Error::StateError("transport change pending".into()).into()));
+        }
+        if let (Some(wait), Some(sleep)) = (state.conn.throttled_for(), &state.sleep) {
+            let delay = state.delay.get_or_insert_with(|| sleep(wait));
+            if delay.as_mut().poll(cx).is_ready() {
+                state.delay = None;
+                return Poll::Ready(Ok(()));
             }
         }
-        self.transport = Some(transport);
-        Ok(Async::NotReady)
+        let mut chunk = [0u8; READ_CHUNK];
+        let mut chunk = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut chunk))?;
+        if chunk.filled().is_empty() {
+            return Poll::Ready(Err(connection_closed(&state.conn)));
+        }
+        let events = state.conn.advance(chunk.filled())?;
+        state.report_alerts(&events);
+        state.events.extend(events);
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn connection_closed(conn: &Connection) -> io::Error {
+    match conn.bye() {
+        Some(text) => Error::Bye { text: text.to_string() }.into(),
+        None => Error::ConnectionClosed.into(),
+    }
+}
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub(crate) fn tls_error(err: native_tls::Error) -> io::Error {
+    Error::Tls(Box::new(err)).into()
+}
+
+pub type UnsolicitedResponses = Receiver<ResponseData>;
+
+pub type Alerts = Receiver<Alert>;
+
+/// A message from the server that should be shown to the user.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AlertKind {
+    Alert,
+    /// An operation failed because a quota was exceeded (RFC 5530).
+    OverQuota,
+}
+
+/// Yields the responses to a command, up to and including the tagged
+/// completion.
+///
+/// Dropping the stream before it is done is safe: the remaining responses
+/// to the command are discarded, so they are never attributed to the next
+/// command. This makes it possible to abandon a command, for example from a
+/// `tokio::select!` branch, without losing the connection.
+pub struct ResponseStream<'a> {
+    client: &'a mut Client,
+    request_id: RequestId,
+    done: bool,
+    // Why the command was not sent, reported as the only item.
+    refused: Option<Error>,
+}
+
+impl<'a> ResponseStream<'a> {
+    /// Discard the remaining responses to this command, and wait for it to
+    /// complete. Unsolicited responses are still delivered to the
+    /// subscriber, if any.
+    pub async fn drain(mut self) -> io::Result<()> {
+        while let Some(rsp) = self.next().await {
+            rsp?;
+        }
+        Ok(())
+    }
+
+    // Issue `cmd` once this stream's command is done, continuing with its
+    // responses.
+    pub(crate) fn then_call(&mut self, cmd: Command) {
+        assert!(self.done, "command still running");
+        if let Err(err) = self.client.state.conn.permits(&cmd) {
+            self.refused = Some(err);
+        } else {
+            self.request_id = self.client.state.conn.command(cmd);
+        }
+        self.done = false;
+    }
+
+    /// Answer a continuation request of the command this stream belongs
+    /// to; see `Connection::respond()`.
+    pub fn respond(&mut self, line: &[u8]) {
+        self.client.state.conn.respond(line);
+    }
+
+    /// End the IDLE command this stream belongs to; see
+    /// `Connection::idle_done()`.
+    pub fn idle_done(&mut self) {
+        self.client.state.conn.idle_done();
     }
 }
 
-pub enum ConnectFuture {
-    #[doc(hidden)]
-    TcpConnecting(TcpStreamNew, String),
-    #[doc(hidden)]
-    TlsHandshake(ConnectAsync<TcpStream>),
-    #[doc(hidden)]
-    ServerGreeting(Option<ImapTransport>),
+impl<'a> Stream for ResponseStream<'a> {
+    type Item = io::Result<ResponseData>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        if let Some(err) = this.refused.take() {
+            this.done = true;
+            return Poll::Ready(Some(Err(err.into())));
+        }
+        loop {
+            if let Some(rsp) = ready!(this.client.state.poll_pending(cx)) {
+                return Poll::Ready(Some(Ok(rsp)));
+            }
+            while let Some(event) = this.client.state.events.pop_front() {
+                let rsp = match event {
+                    Event::Response(id, rsp) => {
+                        if id != this.request_id {
+                            continue;
+                        }
+                        this.done = rsp.request_id() == Some(&id);
+                        return Poll::Ready(Some(Ok(rsp)));
+                    },
+                    Event::Greeting(rsp) | Event::Unsolicited(rsp) => rsp,
+                };
+                if let Some(rsp) = ready!(this.client.state.route_unsolicited(cx, rsp)) {
+                    return Poll::Ready(Some(Ok(rsp)));
+                }
+            }
+            // The command will not complete once the server has said BYE.
+            if let Some(text) = this.client.state.conn.bye() {
+                this.done = true;
+                return Poll::Ready(Some(Err(Error::Bye { text: text.to_string() }.into())));
+            }
+            ready!(this.client.poll_io(cx))?;
+        }
+    }
 }
 
-impl Future for ConnectFuture {
-    type Item = (Client, ResponseData);
-    type Error = io::Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut new = None;
-        if let ConnectFuture::TcpConnecting(ref mut future, ref domain) = *self {
-            let stream = try_ready!(future.poll());
-            let ctx = TlsConnector::builder().unwrap().build().unwrap();
-            let future = ctx.connect_async(domain, stream);
-            new = Some(ConnectFuture::TlsHandshake(future));
-        }
-        if new.is_some() {
-            *self = new.take().unwrap();
-        }
-        if let ConnectFuture::TlsHandshake(ref mut future) = *self {
-            let transport = try_ready!(future.map_err(|e| {
-                io::Error::new(io::ErrorKind::Other, e)
-            }).poll()).framed(ImapCodec::default());
-            new = Some(ConnectFuture::ServerGreeting(Some(transport)));
-        }
-        if new.is_some() {
-            *self = new.take().unwrap();
-        }
-        if let ConnectFuture::ServerGreeting(ref mut wrapped) = *self {
-            let msg = try_ready!(wrapped.as_mut().unwrap().poll()).unwrap();
-            return Ok(Async::Ready((Client {
-                transport: wrapped.take().unwrap(),
-                state: ClientState::new(),
-            }, msg)));
-        }
-        Ok(Async::NotReady)
+impl<'a> Drop for ResponseStream<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.state.conn.abandon(&self.request_id);
+        }
     }
 }
 
-pub struct ClientState {
-    state: State,
-    request_ids: IdGenerator,
+struct ClientState {
+    conn: Connection,
+    // Data taken from the connection that has not been written yet.
+    output: BytesMut,
+    needs_flush: bool,
+    events: VecDeque<Event>,
+    unsolicited: Option<Sender<ResponseData>>,
+    alerts: Option<Sender<Alert>>,
+    // Unsolicited response waiting for room in the subscriber's buffer.
+    pending: Option<ResponseData>,
+    sleep: Option<Box<dyn Fn(Duration) -> Delay + Send>>,
+    // Running while the next command is held back by the rate limit.
+    delay: Option<Delay>,
+    max_command_length: Option<usize>,
 }
 
 impl ClientState {
-    pub fn new() -> ClientState {
+    fn new() -> ClientState {
         ClientState {
-            state: State::NotAuthenticated,
-            request_ids: IdGenerator::new(),
+            conn: Connection::new(),
+            output: BytesMut::new(),
+            needs_flush: false,
+            events: VecDeque::new(),
+            unsolicited: None,
+            alerts: None,
+            pending: None,
+            sleep: None,
+            delay: None,
+            max_command_length: None,
         }
     }
-}
 
-pub struct IdGenerator {
-    next: u64,
-}
+    // Hands an unsolicited response to the subscriber, if there is one.
+    // Gives the response back if it should be yielded instead; if the
+    // subscriber's buffer is full, it is kept until there is room again.
+    fn route_unsolicited(&mut self, cx: &mut Context<'_>, mut rsp: ResponseData)
+                         -> Poll<Option<ResponseData>> {
+        let tx = match self.unsolicited {
+            Some(ref mut tx) => tx,
+            None => return Poll::Ready(Some(rsp)),
+        };
+        match tx.poll_ready(cx) {
+            Poll::Ready(Ok(())) => match tx.try_send(rsp) {
+                Ok(()) => return Poll::Ready(None),
+                Err(err) => {
+                    rsp = err.into_inner();
+                },
+            },
+            Poll::Ready(Err(_)) => {},
+            Poll::Pending => {
+                self.pending = Some(rsp);
+                return Poll::Pending;
+            },
+        }
+        self.unsolicited = None;
+        Poll::Ready(Some(rsp))
+    }
+
+    // Hands the alerts among the responses in `events` to the subscriber,
+    // if there is one.
+    fn report_alerts(&mut self, events: &[Event]) {
+        let tx = match self.alerts {
+            Some(ref mut tx) => tx,
+            None => return,
+        };
+        for event in events {
+            let rsp = match *event {
+                Event::Greeting(ref rsp) | Event::Response(_, ref rsp) | Event::Unsolicited(ref rsp) => rsp,
+            };
+            let (code, text) = match *rsp.parsed() {
+                Response::Data(_, Some(ref code), text) | Response::Done(_, _, Some(ref code), text) => (code, text),
+                _ => continue,
+            };
+            let kind = match *code {
+                ResponseCode::Alert => AlertKind::Alert,
+                ResponseCode::OverQuota => AlertKind::OverQuota,
+                _ => continue,
+            };
+            let alert = Alert { kind, text: text.unwrap_or("").to_string() };
+            if let Err(err) = tx.try_send(alert) {
+                if err.is_disconnected() {
+                    self.alerts = None;
+                    return;
+                }
+                warn!(alert = ?err.into_inner(), "alert subscriber full, dropping alert");
+            }
+        }
+    }
 
-impl IdGenerator {
-    pub fn new() -> IdGenerator {
-        IdGenerator { next: 0 }
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Option<ResponseData>> {
+        match self.pending.take() {
+            Some(rsp) => self.route_unsolicited(cx, rsp),
+            None => Poll::Ready(None),
+        }
     }
 }
 
-impl Iterator for IdGenerator {
-    type Item = RequestId;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.next += 1;
-        Some(RequestId(format!("A{:04}", self.next % 10000)))
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::{future, StreamExt};
+
+    use super::builder::CommandBuilder;
+    use super::Client;
+    use crate::proto::{ImapStream, Response, Status};
+    use crate::testing::MockServer;
+
+    // A command abandoned after its first response must not have its tagged
+    // completion attributed to the next command.
+    #[test]
+    fn dropped_command_does_not_complete_the_next() {
+        let (stream, server) = MockServer::new()
+            .send("* OK ready")
+            .expect("CAPABILITY")
+            .send("* CAPABILITY IMAP4rev1")
+            .done("OK capability done")
+            .expect("NOOP")
+            .send("* 3 EXISTS")
+            .done("OK noop done")
+            .run();
+        let client = async {
+            let (mut client, _) = Client::connect_stream(ImapStream::Memory(stream)).await.unwrap();
+            {
+                let mut responses = client.call(CommandBuilder::capability());
+                let first = responses.next().await.unwrap().unwrap();
+                assert!(matches!(*first.parsed(), Response::Capabilities(_)));
+            }
+            let mut responses = client.call(CommandBuilder::noop());
+            let mut texts = vec![];
+            while let Some(rsp) = responses.next().await {
+                let rsp = rsp.unwrap();
+                if let Response::Done(_, Status::Ok, _, text) = *rsp.parsed() {
+                    texts.push(text.unwrap_or("").to_string());
+                }
+            }
+            assert_eq!(texts, ["noop done"]);
+        };
+        let (served, ()) = block_on(future::join(server, client));
+        served.unwrap();
     }
 }
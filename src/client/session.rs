@@ -0,0 +1,1929 @@
+use futures::future::{self, Either};
+use futures::{pin_mut, ready, Stream, StreamExt};
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::str;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::proto::*;
+use crate::sasl::{self, Authenticator};
+use crate::utf7;
+
+use super::{Client, IdGenerator, ResponseStream};
+use super::builder::{AppendCommand, Command, CommandBuilder, FetchBuilderAttributes, FetchCommandAttributes};
+use super::builder::{any_search_string, is_latin1, GetMetadataCommand, ListCommand, ListPattern, SequenceSet};
+use super::builder::{FetchBuilderMessages, FetchBuilderModifiers};
+
+// How long to IDLE before restarting it, to stay clear of the 30 minute
+// inactivity timeout of servers (RFC 2177).
+const IDLE_RESTART: Duration = Duration::from_secs(29 * 60);
+
+/// The connection has not been authenticated yet.
+pub struct NotAuthenticated;
+/// The connection has been authenticated, but no mailbox is selected.
+pub struct Authenticated;
+/// A mailbox has been selected.
+pub struct Selected;
+
+/// Marker for the phases in which a mailbox can be selected.
+pub trait CanSelect {}
+impl CanSelect for Authenticated {}
+impl CanSelect for Selected {}
+
+/// Higher-level interface on top of `Client`, which aggregates the responses
+/// to a command into typed results. The type parameter tracks the protocol
+/// state of the connection (RFC 3501, section 3), so that commands can only
+/// be issued in the states where they are valid.
+pub struct Session<P> {
+    client: Client,
+    phase: PhantomData<P>,
+}
+
+pub type UnauthenticatedClient = Session<NotAuthenticated>;
+pub type AuthenticatedClient = Session<Authenticated>;
+pub type SelectedClient = Session<Selected>;
+
+impl<P> Session<P> {
+    fn from_client(client: Client) -> Session<P> {
+        Session { client, phase: PhantomData }
+    }
+
+    pub fn into_client(self) -> Client {
+        self.client
+    }
+
+    pub fn has(&self, capability: &Capability) -> bool {
+        self.client.has(capability)
+    }
+
+    /// Log out and close the connection; see `Client::logout()`.
+    pub async fn logout(self) -> io::Result<()> {
+        self.client.logout().await
+    }
+
+    /// The state of the selected mailbox; see `Client::mailbox()`.
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.client.mailbox()
+    }
+
+    /// The languages the server can use for its human-readable text, such
+    /// as that of errors (RFC 5255). Requires the LANGUAGE capability.
+    pub async fn languages(&mut self) -> io::Result<Vec<String>> {
+        self.run_language(&[]).await
+    }
+
+    /// Have the server use the first of the language `ranges` it supports
+    /// for its human-readable text, returning the language it chose. The
+    /// command fails if the server supports none of them.
+    pub async fn set_language(&mut self, ranges: &[&str]) -> io::Result<Option<String>> {
+        Ok(self.run_language(ranges).await?.into_iter().next())
+    }
+
+    async fn run_language(&mut self, ranges: &[&str]) -> io::Result<Vec<String>> {
+        let mut languages = vec![];
+        run(&mut self.client, CommandBuilder::language(ranges), "LANGUAGE", |rsp| {
+            if let Response::Language(ref tags) = *rsp.parsed() {
+                languages = tags.iter().map(|s| s.to_string()).collect();
+            }
+        }).await?;
+        Ok(languages)
+    }
+
+    // Mailbox names are exchanged in modified UTF-7, unless UTF8=ACCEPT has
+    // been enabled (RFC 6855, section 3).
+    fn encode_mailbox<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.client.is_enabled(&Capability::Utf8Accept) || name.is_ascii() && !name.contains('&') {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(utf7::encode(name))
+        }
+    }
+
+    fn decode_mailbox(&self, name: &str) -> String {
+        if self.client.is_enabled(&Capability::Utf8Accept) {
+            return name.to_string();
+        }
+        utf7::decode(name).unwrap_or_else(|| name.to_string())
+    }
+}
+
+impl Session<NotAuthenticated> {
+    pub fn new(client: Client) -> Session<NotAuthenticated> {
+        Session::from_client(client)
+    }
+
+    /// Start a session in the state announced by the greeting: if the
+    /// server greeted with PREAUTH, having authenticated the connection by
+    /// other means, no login is needed.
+    pub fn start(client: Client) -> SessionStart {
+        match *client.connection().state() {
+            State::NotAuthenticated => SessionStart::NotAuthenticated(Session::new(client)),
+            _ => SessionStart::PreAuthenticated(Session::from_client(client)),
+        }
+    }
+
+    /// Log in, then refresh the cached capabilities if the server did not
+    /// include them in its response. Nothing is sent if the server greeted
+    /// with PREAUTH; see `start()`.
+    pub async fn login(mut self, user_name: &str, password: &str)
+                       -> io::Result<Session<Authenticated>> {
+        if self.is_preauthenticated() {
+            return Ok(Session::from_client(self.client));
+        }
+        let cmd = CommandBuilder::login(user_name, password);
+        run(&mut self.client, cmd, "LOGIN", |_| {}).await?;
+        self.client.refresh_capabilities().await?;
+        Ok(Session::from_client(self.client))
+    }
+
+    /// Authenticate with the SASL mechanism implemented by `auth`, then
+    /// refresh the cached capabilities like `login()`. The initial response
+    /// is sent along with the command if the server supports SASL-IR, and
+    /// otherwise in answer to the first, empty, challenge. If `auth` fails,
+    /// the exchange is cancelled and its error returned. Like `login()`,
+    /// this does nothing if the server greeted with PREAUTH.
+    pub async fn authenticate<A: Authenticator>(mut self, mut auth: A) -> io::Result<Session<Authenticated>> {
+        if self.is_preauthenticated() {
+            return Ok(Session::from_client(self.client));
+        }
+        let mut initial = auth.initial_response();
+        let cmd = match initial {
+            Some(ref response) if self.client.has(&Capability::SaslIr) => {
+                let cmd = CommandBuilder::authenticate(auth.mechanism(), Some(response));
+                initial = None;
+                cmd
+            },
+            _ => CommandBuilder::authenticate(auth.mechanism(), None),
+        };
+        let mut responses = self.client.call(cmd);
+        let mut failure = None;
+        let mut error = None;
+        while let Some(rsp) = responses.next().await {
+            let rsp = rsp?;
+            check_done(rsp.parsed(), &mut failure);
+            if let Response::Continue { text, .. } = *rsp.parsed() {
+                let answer = match initial.take() {
+                    Some(response) => Ok(response),
+                    None => sasl::decode(text.unwrap_or("")).and_then(|challenge| auth.respond(&challenge)),
+                };
+                match answer {
+                    Ok(answer) => responses.respond(sasl::encode(&answer).as_bytes()),
+                    Err(err) => {
+                        responses.respond(b"*");
+                        error = Some(err);
+                    },
+                }
+            }
+        }
+        drop(responses);
+        if let Some(err) = error {
+            return Err(err);
+        }
+        if let Some(failure) = failure {
+            return Err(command_failed("AUTHENTICATE", failure));
+        }
+        self.client.refresh_capabilities().await?;
+        Ok(Session::from_client(self.client))
+    }
+
+    fn is_preauthenticated(&self) -> bool {
+        *self.client.connection().state() != State::NotAuthenticated
+    }
+}
+
+/// The session on a new connection, in the state announced by the greeting;
+/// see `Session::start()`.
+pub enum SessionStart {
+    NotAuthenticated(Session<NotAuthenticated>),
+    PreAuthenticated(Session<Authenticated>),
+}
+
+impl<P: CanSelect> Session<P> {
+    /// Select `mailbox` for read-write access.
+    pub async fn select(self, mailbox: &str) -> io::Result<(Session<Selected>, Mailbox)> {
+        let cmd = CommandBuilder::select(&self.encode_mailbox(mailbox));
+        self.open(cmd, "SELECT").await
+    }
+
+    /// Like `select()`, but also enabling CONDSTORE, so that the returned
+    /// `Mailbox` has the HIGHESTMODSEQ to start synchronizing from, or
+    /// `no_mod_seq` set. Requires the CONDSTORE capability.
+    pub async fn select_condstore(self, mailbox: &str) -> io::Result<(Session<Selected>, Mailbox)> {
+        let cmd = CommandBuilder::select_condstore(&self.encode_mailbox(mailbox));
+        self.open(cmd, "SELECT").await
+    }
+
+    /// Select `mailbox` for read-only access.
+    pub async fn examine(self, mailbox: &str) -> io::Result<(Session<Selected>, Mailbox)> {
+        let cmd = CommandBuilder::examine(&self.encode_mailbox(mailbox));
+        self.open(cmd, "EXAMINE").await
+    }
+
+    async fn open(mut self, cmd: Command, name: &str) -> io::Result<(Session<Selected>, Mailbox)> {
+        let mut mailbox = Mailbox::default();
+        run(&mut self.client, cmd, name, |rsp| mailbox.update(rsp.parsed())).await?;
+        Ok((Session::from_client(self.client), mailbox))
+    }
+
+    /// Select `mailbox` and retrieve everything that changed since `known`
+    /// using quick mailbox resynchronization (RFC 5162). QRESYNC is enabled
+    /// first if necessary.
+    ///
+    /// If the UIDVALIDITY of the mailbox no longer matches, the server does
+    /// not report any changes; compare `Resync::mailbox.uid_validity` to the
+    /// stored value to detect this.
+    pub async fn resync(mut self, mailbox: &str, known: &QresyncState)
+                        -> io::Result<(Session<Selected>, Resync)> {
+        if !self.client.is_enabled(&Capability::Qresync) {
+            let enable = CommandBuilder::enable(&["QRESYNC"]);
+            run(&mut self.client, enable, "ENABLE", |_| {}).await?;
+        }
+        let select = CommandBuilder::select_qresync(
+            &self.encode_mailbox(mailbox), known.uid_validity, known.mod_seq,
+            known.known_uids.as_deref());
+        let mut result = Resync::default();
+        run(&mut self.client, select, "SELECT", |rsp| result.update(rsp)).await?;
+        Ok((Session::from_client(self.client), result))
+    }
+
+    /// Store `message` in `mailbox` with the given `flags`, returning the
+    /// UIDVALIDITY of `mailbox` and the UID of the new message if the server
+    /// reports them (RFC 4315). Once UTF8=ACCEPT has been enabled, the
+    /// message may contain UTF-8 headers.
+    pub async fn append(&mut self, mailbox: &str, message: &[u8], flags: &[&str])
+                        -> io::Result<Option<(u32, u32)>> {
+        let mut cmd = CommandBuilder::append(&self.encode_mailbox(mailbox), message).flags(flags);
+        if self.client.is_enabled(&Capability::Utf8Accept) {
+            cmd = cmd.utf8();
+        }
+        self.run_append(cmd.build()).await
+    }
+
+    /// Like `append()`, but with `date` as the internal date of the message
+    /// instead of the time it is stored, to preserve the original delivery
+    /// date when migrating messages.
+    pub async fn append_dated(&mut self, mailbox: &str, message: &[u8], flags: &[&str], date: DateTime)
+                              -> io::Result<Option<(u32, u32)>> {
+        let mut cmd = CommandBuilder::append(&self.encode_mailbox(mailbox), message).flags(flags)
+            .internal_date(date);
+        if self.client.is_enabled(&Capability::Utf8Accept) {
+            cmd = cmd.utf8();
+        }
+        self.run_append(cmd.build()).await
+    }
+
+    /// Like `append()`, but with the message composed by the server from
+    /// `parts` (RFC 4469). Requires the CATENATE capability.
+    pub async fn append_catenate(&mut self, mailbox: &str, parts: &[CatenatePart], flags: &[&str])
+                                 -> io::Result<Option<(u32, u32)>> {
+        let cmd = CommandBuilder::append_catenate(&self.encode_mailbox(mailbox), parts).flags(flags).build();
+        self.run_append(cmd).await
+    }
+
+    /// Store all messages of `cmd` in one go (RFC 3502), returning the
+    /// UIDVALIDITY of the mailbox and the UIDs of the new messages if the
+    /// server reports them. `progress` is called with the number of
+    /// messages sent so far whenever the server asks for a literal of the
+    /// next one, and once all of them have been stored. With LITERAL+, the
+    /// server asks for nothing, so only the final call is made. Requires
+    /// the MULTIAPPEND capability when there is more than one message. The
+    /// mailbox name in `cmd` is sent as is.
+    pub async fn append_multiple<F>(&mut self, cmd: AppendCommand, mut progress: F)
+                                    -> io::Result<Option<(u32, Vec<u32>)>>
+            where F: FnMut(usize) {
+        let count = cmd.len();
+        let (cmd, starts) = cmd.build_indexed();
+        // Literals requested so far, and messages reported as sent.
+        let mut requested = 0;
+        let mut sent = 0;
+        let mut appended = None;
+        let limit = self.append_limit();
+        run(&mut self.client, cmd, "APPEND", |rsp| {
+            match *rsp.parsed() {
+                Response::Continue { .. } => {
+                    // Messages before the one the requested literal belongs to
+                    // have been sent completely.
+                    let done = starts.iter().skip(1).filter(|&&start| start <= requested).count();
+                    if done > sent {
+                        sent = done;
+                        progress(sent);
+                    }
+                    requested += 1;
+                },
+                Response::Done(_, Status::Ok, Some(ResponseCode::AppendUid(uid_validity, ref uids)), _) => {
+                    let uids = uids.iter().flat_map(|uids| uids.clone()).collect();
+                    appended = Some((uid_validity, uids));
+                },
+                _ => {},
+            }
+        }).await.map_err(|err| too_big(err, limit))?;
+        progress(count);
+        Ok(appended)
+    }
+
+    async fn run_append(&mut self, cmd: Command) -> io::Result<Option<(u32, u32)>> {
+        let limit = self.append_limit();
+        let mut appended = None;
+        run(&mut self.client, cmd, "APPEND", |rsp| {
+            appended = append_uid(rsp.parsed()).or(appended);
+        }).await.map_err(|err| too_big(err, limit))?;
+        Ok(appended)
+    }
+
+    // The maximum size of messages the server accepts, if it announced one
+    // with the APPENDLIMIT capability (RFC 7889).
+    fn append_limit(&self) -> Option<u64> {
+        self.client.capabilities()?.iter().find_map(|cap| match *cap {
+            Capability::AppendLimit(limit) => limit,
+            _ => None,
+        })
+    }
+
+    /// List the mailboxes matching `pattern`, relative to `reference`.
+    pub async fn list(&mut self, reference: &str, pattern: &str) -> io::Result<Vec<ListEntry>> {
+        let cmd = CommandBuilder::list(&self.encode_mailbox(reference), &self.encode_mailbox(pattern));
+        self.run_list(cmd).await
+    }
+
+    /// Like `list()` without a reference, but with a `pattern` that can
+    /// contain literal `%` and `*` characters, only returning the mailboxes
+    /// it matches exactly.
+    pub async fn list_matching(&mut self, pattern: &ListPattern) -> io::Result<Vec<ListEntry>> {
+        let mut entries = self.list("", &pattern.encode()).await?;
+        entries.retain(|entry| pattern.matches(&entry.name, entry.delimiter.as_deref()));
+        Ok(entries)
+    }
+
+    /// Like `list()`, but also returns the STATUS `items` of each mailbox in
+    /// the same round trip (RFC 5819). Requires the LIST-STATUS capability.
+    pub async fn list_status(&mut self, reference: &str, pattern: &str, items: &[StatusAttribute])
+                             -> io::Result<Vec<ListEntry>> {
+        let cmd = CommandBuilder::list(&self.encode_mailbox(reference), &self.encode_mailbox(pattern));
+        self.run_list(cmd.status(items)).await
+    }
+
+    /// List the mailboxes with a special use, such as the one for sent
+    /// messages (RFC 6154). Requires the SPECIAL-USE capability.
+    pub async fn list_special_use(&mut self) -> io::Result<Vec<ListEntry>> {
+        self.run_list(CommandBuilder::list("", "*").select_special_use()).await
+    }
+
+    /// Create `mailbox`.
+    pub async fn create(&mut self, mailbox: &str) -> io::Result<()> {
+        let cmd = CommandBuilder::create(&self.encode_mailbox(mailbox));
+        run(&mut self.client, cmd, "CREATE", |_| {}).await
+    }
+
+    /// Create `mailbox` for the special `uses` (RFC 6154). Requires the
+    /// CREATE-SPECIAL-USE capability; servers that cannot assign one of the
+    /// uses fail the command with a USEATTR response code.
+    pub async fn create_with_use(&mut self, mailbox: &str, uses: &[SpecialUse]) -> io::Result<()> {
+        let cmd = CommandBuilder::create_with_use(&self.encode_mailbox(mailbox), uses);
+        run(&mut self.client, cmd, "CREATE", |_| {}).await
+    }
+
+    async fn run_list(&mut self, cmd: ListCommand) -> io::Result<Vec<ListEntry>> {
+        let mut entries: Vec<ListEntry> = vec![];
+        run(&mut self.client, cmd.build(), "LIST", |rsp| {
+            match *rsp.parsed() {
+                Response::MailboxData(MailboxDatum::List { ref flags, delimiter, name }) => {
+                    entries.push(ListEntry {
+                        flags: flags.iter().map(|s| s.to_string()).collect(),
+                        delimiter: delimiter.map(|s| s.to_string()),
+                        name: name.to_string(),
+                        status: vec![],
+                    });
+                },
+                Response::MailboxData(MailboxDatum::Status { mailbox, ref status }) => {
+                    if let Some(entry) = entries.iter_mut().rev().find(|entry| entry.name == mailbox) {
+                        entry.status.extend(status);
+                    }
+                },
+                _ => {},
+            }
+        }).await?;
+        for entry in &mut entries {
+            entry.name = self.decode_mailbox(&entry.name);
+        }
+        Ok(entries)
+    }
+
+    /// Request the STATUS `items` of `mailbox`, which should not be the
+    /// selected mailbox.
+    pub async fn status(&mut self, mailbox: &str, items: &[StatusAttribute])
+                        -> io::Result<Vec<StatusAttributeValue>> {
+        let mut values = vec![];
+        let cmd = CommandBuilder::status(&self.encode_mailbox(mailbox), items);
+        run(&mut self.client, cmd, "STATUS", |rsp| {
+            if let Response::MailboxData(MailboxDatum::Status { ref status, .. }) = *rsp.parsed() {
+                values.extend(status);
+            }
+        }).await?;
+        Ok(values)
+    }
+
+    /// Authorize `urls` for access by others, such as a submission server
+    /// using BURL (RFC 4468), returning the URLAUTH-authorized URLs in the
+    /// same order. Each URL is given with its mechanism, usually `INTERNAL`.
+    pub async fn genurlauth(&mut self, urls: &[(&str, &str)]) -> io::Result<Vec<String>> {
+        let mut authorized = vec![];
+        run(&mut self.client, CommandBuilder::genurlauth(urls), "GENURLAUTH", |rsp| {
+            if let Response::GenUrlAuth(ref urls) = *rsp.parsed() {
+                authorized.extend(urls.iter().map(|s| s.to_string()));
+            }
+        }).await?;
+        Ok(authorized)
+    }
+
+    /// Fetch the contents of URLAUTH-authorized `urls`, paired with their
+    /// URL. The contents are `None` for URLs that could not be resolved.
+    pub async fn urlfetch(&mut self, urls: &[&str]) -> io::Result<Vec<(String, Option<Vec<u8>>)>> {
+        let mut fetched = vec![];
+        run(&mut self.client, CommandBuilder::urlfetch(urls), "URLFETCH", |rsp| {
+            if let Response::UrlFetch(ref results) = *rsp.parsed() {
+                fetched.extend(results.iter().map(|&(url, data)| (url.to_string(), data.map(|s| s.to_vec()))));
+            }
+        }).await?;
+        Ok(fetched)
+    }
+
+    /// Invalidate the URLAUTH access keys of `mailbox`, or of all mailboxes
+    /// if `None`, so that URLs authorized earlier stop working.
+    pub async fn resetkey(&mut self, mailbox: Option<&str>) -> io::Result<()> {
+        let cmd = CommandBuilder::resetkey(mailbox.map(|name| self.encode_mailbox(name)).as_deref(), &[]);
+        run(&mut self.client, cmd, "RESETKEY", |_| {}).await
+    }
+
+    /// Search the mailboxes of `sources` at once (RFC 7377), returning the
+    /// data requested in `returns` for each mailbox with matching messages,
+    /// with UIDs. Requires the MULTISEARCH capability.
+    pub async fn multisearch(&mut self, sources: &[SearchSource], criteria: &SearchCriteria,
+                             returns: &[SearchReturn]) -> io::Result<Vec<MailboxSearchResults>> {
+        let encode = |mailboxes: &Vec<String>| {
+            mailboxes.iter().map(|name| self.encode_mailbox(name).into_owned()).collect()
+        };
+        let sources = sources.iter().map(|source| match *source {
+            SearchSource::Subtree(ref mailboxes) => SearchSource::Subtree(encode(mailboxes)),
+            SearchSource::SubtreeOne(ref mailboxes) => SearchSource::SubtreeOne(encode(mailboxes)),
+            SearchSource::Mailboxes(ref mailboxes) => SearchSource::Mailboxes(encode(mailboxes)),
+            ref source => source.clone(),
+        }).collect::<Vec<_>>();
+        let cmd = CommandBuilder::multisearch(&sources, criteria).returning(returns).build();
+        let mut found = vec![];
+        run(&mut self.client, cmd, "ESEARCH", |rsp| {
+            if let Response::Esearch { mailbox: Some((mailbox, uid_validity)), ref results, .. } = *rsp.parsed() {
+                found.push((mailbox.to_string(), uid_validity, results.clone()));
+            }
+        }).await?;
+        Ok(found.into_iter().map(|(mailbox, uid_validity, results)| {
+            MailboxSearchResults { mailbox: self.decode_mailbox(&mailbox), uid_validity, results }
+        }).collect())
+    }
+
+    /// The values of the metadata `entries` of `mailbox`, or of the server
+    /// if `mailbox` is empty (RFC 5464), such as `/shared/comment`. Entries
+    /// without a value are left out. Requires the METADATA capability, or
+    /// METADATA-SERVER for the entries of the server.
+    pub async fn get_metadata(&mut self, mailbox: &str, entries: &[&str]) -> io::Result<Vec<(String, String)>> {
+        let cmd = CommandBuilder::getmetadata(&self.encode_mailbox(mailbox), entries);
+        self.run_getmetadata(cmd).await
+    }
+
+    /// Set the metadata entries of `mailbox`, or of the server if `mailbox`
+    /// is empty, to `values`. A value of `None` removes the entry.
+    pub async fn set_metadata(&mut self, mailbox: &str, values: &[(&str, Option<&str>)]) -> io::Result<()> {
+        let cmd = CommandBuilder::setmetadata(&self.encode_mailbox(mailbox), values);
+        run(&mut self.client, cmd, "SETMETADATA", |_| {}).await
+    }
+
+    /// The filter settings that servers keep in the vendor-specific server
+    /// entries `/shared/vendor/<vendor>/...`, such as the active Sieve
+    /// script, named relative to that prefix. `vendor` is the registered
+    /// vendor token, such as `vendor.example`. Requires METADATA-SERVER.
+    pub async fn filter_settings(&mut self, vendor: &str) -> io::Result<Vec<(String, String)>> {
+        let prefix = filter_entry(vendor, "");
+        let cmd = CommandBuilder::getmetadata("", &[prefix.trim_end_matches('/')]).descendants();
+        let values = self.run_getmetadata(cmd).await?;
+        Ok(values.into_iter().filter_map(|(entry, value)| {
+            entry.strip_prefix(&prefix).map(|name| (name.to_string(), value))
+        }).collect())
+    }
+
+    /// The filter setting `name` of `vendor`; see `filter_settings()`.
+    pub async fn filter_setting(&mut self, vendor: &str, name: &str) -> io::Result<Option<String>> {
+        let entry = filter_entry(vendor, name);
+        let values = self.run_getmetadata(CommandBuilder::getmetadata("", &[&entry])).await?;
+        Ok(values.into_iter().find(|(found, _)| found.eq_ignore_ascii_case(&entry)).map(|(_, value)| value))
+    }
+
+    /// Set the filter setting `name` of `vendor` to `value`, or remove it
+    /// if `None`; see `filter_settings()`.
+    pub async fn set_filter_setting(&mut self, vendor: &str, name: &str, value: Option<&str>) -> io::Result<()> {
+        self.set_metadata("", &[(&filter_entry(vendor, name), value)]).await
+    }
+
+    async fn run_getmetadata(&mut self, cmd: GetMetadataCommand) -> io::Result<Vec<(String, String)>> {
+        let mut values = vec![];
+        run(&mut self.client, cmd.build(), "GETMETADATA", |rsp| {
+            if let Response::Metadata { values: ref found, .. } = *rsp.parsed() {
+                values.extend(found.iter().filter_map(|&(entry, value)| {
+                    value.map(|value| (entry.to_string(), value.to_string()))
+                }));
+            }
+        }).await?;
+        Ok(values)
+    }
+
+    /// Log out of the current user while keeping the connection, so that
+    /// it can be reused for another user (RFC 8437). Any selected mailbox is
+    /// closed, and the cached capabilities are refreshed. Requires the
+    /// UNAUTHENTICATE capability.
+    pub async fn unauthenticate(mut self) -> io::Result<Session<NotAuthenticated>> {
+        let cmd = CommandBuilder::unauthenticate();
+        run(&mut self.client, cmd, "UNAUTHENTICATE", |_| {}).await?;
+        self.client.refresh_capabilities().await?;
+        Ok(Session::from_client(self.client))
+    }
+}
+
+impl Session<Authenticated> {
+    /// Enable UTF8=ACCEPT (RFC 6855), returning whether the server enabled
+    /// it. Mailbox names are then exchanged as UTF-8 instead of modified
+    /// UTF-7, and messages may be appended with UTF-8 headers.
+    pub async fn enable_utf8_accept(&mut self) -> io::Result<bool> {
+        let enable = CommandBuilder::enable(&["UTF8=ACCEPT"]);
+        run(&mut self.client, enable, "ENABLE", |_| {}).await?;
+        Ok(self.client.is_enabled(&Capability::Utf8Accept))
+    }
+
+    /// Enable UIDONLY (RFC 9586), returning whether the server enabled it.
+    /// The server then reports messages by UID in UIDFETCH responses, and
+    /// commands using sequence numbers fail with `Error::UidRequired`
+    /// without being sent.
+    pub async fn enable_uid_only(&mut self) -> io::Result<bool> {
+        let enable = CommandBuilder::enable(&["UIDONLY"]);
+        run(&mut self.client, enable, "ENABLE", |_| {}).await?;
+        Ok(self.client.is_enabled(&Capability::UidOnly))
+    }
+}
+
+/// Messages can be identified by UID or by sequence number. The `uid_`
+/// variants should be preferred: sequence numbers shift whenever a message
+/// is expunged, which another client can cause at any time.
+impl Session<Selected> {
+    /// Fetch `items` for the messages in the sequence set `set` (for
+    /// example, `1:10`). The returned stream yields one `Fetched` per
+    /// message; FETCH responses that do not carry any of the requested
+    /// items (such as unsolicited flag updates) are skipped. Sets too long
+    /// for `Client::limit_command_length()` are fetched with several
+    /// commands, unless they use `*` or `$`.
+    ///
+    /// Panics if `items` is empty.
+    pub fn fetch(&mut self, set: &str, items: Vec<Attribute>) -> FetchStream<'_> {
+        let cmds = self.split_commands(set, |set| fetch_command(set, &items).build());
+        self.fetch_stream(cmds, items)
+    }
+
+    /// Like `fetch()`, but for the messages in the UID set `set`. The UID
+    /// of each message is always included in the results.
+    pub fn uid_fetch(&mut self, set: &str, items: Vec<Attribute>) -> FetchStream<'_> {
+        let cmds = self.split_commands(set, |set| {
+            let mut cmd = CommandBuilder::uid_fetch().set(set).attr(Attribute::Uid);
+            for attr in items.iter().filter(|&attr| *attr != Attribute::Uid) {
+                cmd = cmd.attr(attr.clone());
+            }
+            cmd.build()
+        });
+        self.fetch_stream(cmds, items)
+    }
+
+    /// Like `fetch()`, but only for messages whose mod-sequence is greater
+    /// than `seq` (RFC 4551, section 3.3.1). The `MODSEQ` of each message
+    /// is always included in the results.
+    pub fn fetch_changed_since(&mut self, set: &str, mut items: Vec<Attribute>, seq: u64)
+                               -> FetchStream<'_> {
+        if !items.contains(&Attribute::ModSeq) {
+            items.push(Attribute::ModSeq);
+        }
+        let cmds = self.split_commands(set, |set| fetch_command(set, &items).changed_since(seq).build());
+        self.fetch_stream(cmds, items)
+    }
+
+    /// Add, remove or replace `flags` of the messages in the sequence set
+    /// `set`. The server is asked not to send back the updated flags. Sets
+    /// too long for `Client::limit_command_length()` are stored with several
+    /// commands, stopping at the first failure.
+    pub async fn store(&mut self, set: &str, store_type: StoreType, flags: &[&str]) -> io::Result<()> {
+        self.run_store(self.store_commands(false, set, store_type, flags)).await
+    }
+
+    /// Like `store()`, but for the messages in the UID set `set`.
+    pub async fn uid_store(&mut self, set: &str, store_type: StoreType, flags: &[&str]) -> io::Result<()> {
+        self.run_store(self.store_commands(true, set, store_type, flags)).await
+    }
+
+    /// Set attributes of the per-message annotation `entry` (RFC 5257) on
+    /// the messages in the UID set `set`; see
+    /// `CommandBuilder::store_annotation()`. Requires the
+    /// ANNOTATE-EXPERIMENT-1 capability.
+    pub async fn uid_store_annotation(&mut self, set: &str, entry: &str, values: &[(&str, Option<&str>)])
+                                      -> io::Result<()> {
+        let cmds = self.split_commands(set, |set| CommandBuilder::uid_store_annotation(set, entry, values));
+        for cmd in cmds {
+            run(&mut self.client, cmd, "STORE", |_| {}).await?;
+        }
+        Ok(())
+    }
+
+    fn store_commands(&self, uid: bool, set: &str, store_type: StoreType, flags: &[&str]) -> VecDeque<Command> {
+        self.split_commands(set, |set| match uid {
+            true => CommandBuilder::uid_store(set, store_type, flags).silent().build(),
+            false => CommandBuilder::store(set, store_type, flags).silent().build(),
+        })
+    }
+
+    async fn run_store(&mut self, cmds: VecDeque<Command>) -> io::Result<()> {
+        for cmd in cmds {
+            run(&mut self.client, cmd, "STORE", |_| {}).await?;
+        }
+        Ok(())
+    }
+
+    /// Find the sequence numbers of the messages matching `criteria`. Keys
+    /// from extensions, such as `SearchCriteria::Older` (RFC 5032), can
+    /// only be used if the server has the corresponding capability.
+    pub async fn search(&mut self, criteria: &SearchCriteria) -> io::Result<Vec<u32>> {
+        self.run_search_charset(criteria, false).await
+    }
+
+    /// Like `search()`, but returning UIDs.
+    pub async fn uid_search(&mut self, criteria: &SearchCriteria) -> io::Result<Vec<u32>> {
+        self.run_search_charset(criteria, true).await
+    }
+
+    /// Like `search()`, but only retrieving the data requested in `returns`
+    /// (RFC 4731). Requires the ESEARCH capability; use
+    /// `SearchReturn::Partial` to page through large results (RFC 9394).
+    pub async fn search_returning(&mut self, criteria: &SearchCriteria, returns: &[SearchReturn])
+                                  -> io::Result<SearchResults> {
+        let cmd = CommandBuilder::search(criteria).returning(returns).build();
+        self.run_esearch(cmd, "SEARCH").await
+    }
+
+    /// Like `search_returning()`, but with UIDs in the results.
+    pub async fn uid_search_returning(&mut self, criteria: &SearchCriteria, returns: &[SearchReturn])
+                                      -> io::Result<SearchResults> {
+        let cmd = CommandBuilder::uid_search(criteria).returning(returns).build();
+        self.run_esearch(cmd, "SEARCH").await
+    }
+
+    /// Find the UIDs of the messages matching `criteria` approximately
+    /// (RFC 6203), paired with their relevancy scores from 1 to 100, the
+    /// most relevant first. Requires the SEARCH=FUZZY and ESEARCH
+    /// capabilities.
+    pub async fn uid_search_fuzzy(&mut self, criteria: &SearchCriteria) -> io::Result<Vec<(u32, u8)>> {
+        let fuzzy = SearchCriteria::Fuzzy(Box::new(criteria.clone()));
+        let results = self.uid_search_returning(&fuzzy, &[SearchReturn::All, SearchReturn::Relevancy]).await?;
+        let uids = results.all.unwrap_or_default().into_iter().flatten();
+        let mut ranked = uids.zip(results.relevancy.unwrap_or_default()).collect::<Vec<_>>();
+        ranked.sort_by_key(|&(_, score)| cmp::Reverse(score));
+        Ok(ranked)
+    }
+
+    /// Find the sequence numbers of the messages matching `search`, ordered
+    /// by `criteria` (RFC 5256). The `DisplayFrom` and `DisplayTo` keys
+    /// require the SORT=DISPLAY capability (RFC 5957).
+    pub async fn sort(&mut self, criteria: &[SortCriterion], search: &SearchCriteria)
+                      -> io::Result<Vec<u32>> {
+        self.run_search(CommandBuilder::sort(criteria, search).build(), "SORT").await
+    }
+
+    /// Like `sort()`, but returning UIDs.
+    pub async fn uid_sort(&mut self, criteria: &[SortCriterion], search: &SearchCriteria)
+                          -> io::Result<Vec<u32>> {
+        self.run_search(CommandBuilder::uid_sort(criteria, search).build(), "SORT").await
+    }
+
+    /// Like `sort()`, but only retrieving the data requested in `returns`
+    /// (RFC 5267). Requires the ESORT capability.
+    pub async fn sort_returning(&mut self, criteria: &[SortCriterion], search: &SearchCriteria,
+                                returns: &[SearchReturn]) -> io::Result<SearchResults> {
+        let cmd = CommandBuilder::sort(criteria, search).returning(returns).build();
+        self.run_esearch(cmd, "SORT").await
+    }
+
+    /// Like `sort_returning()`, but with UIDs in the results.
+    pub async fn uid_sort_returning(&mut self, criteria: &[SortCriterion], search: &SearchCriteria,
+                                    returns: &[SearchReturn]) -> io::Result<SearchResults> {
+        let cmd = CommandBuilder::uid_sort(criteria, search).returning(returns).build();
+        self.run_esearch(cmd, "SORT").await
+    }
+
+    // Collects the results of SEARCH or SORT.
+    async fn run_search(&mut self, cmd: Command, name: &str) -> io::Result<Vec<u32>> {
+        let mut ids = vec![];
+        run(&mut self.client, cmd, name, |rsp| {
+            match *rsp.parsed() {
+                Response::Search(ref found) | Response::Sort(ref found) => ids.extend(found),
+                _ => {},
+            }
+        }).await?;
+        Ok(ids)
+    }
+
+    // Searches, sending non-ASCII strings in UTF-8. If the server rejects
+    // that with BADCHARSET but supports ISO-8859-1, retries in that charset
+    // where the strings can be encoded in it.
+    async fn run_search_charset(&mut self, criteria: &SearchCriteria, uid: bool) -> io::Result<Vec<u32>> {
+        let search = |charset: Option<&str>| {
+            let cmd = match uid {
+                true => CommandBuilder::uid_search(criteria),
+                false => CommandBuilder::search(criteria),
+            };
+            match charset {
+                Some(charset) => cmd.charset(charset).build(),
+                None => cmd.build(),
+            }
+        };
+        let err = match self.run_search(search(None), "SEARCH").await {
+            Ok(ids) => return Ok(ids),
+            Err(err) => err,
+        };
+        let supported = match err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+            Some(inner) => inner.supported_charsets().unwrap_or_default(),
+            None => return Err(err),
+        };
+        let latin1 = match supported.iter().find(|charset| is_latin1(charset)) {
+            Some(charset) => charset,
+            None => return Err(err),
+        };
+        if !any_search_string(criteria, &|s| !s.is_ascii())
+                || any_search_string(criteria, &|s| s.chars().any(|c| u32::from(c) > 0xff)) {
+            return Err(err);
+        }
+        self.run_search(search(Some(latin1)), "SEARCH").await
+    }
+
+    /// Like `uid_search_returning()`, but having the server keep the
+    /// results up to date (RFC 5267): changes are reported during IDLE as
+    /// `IdleEvent::SearchUpdated`, with the tag returned here, until the
+    /// search is passed to `cancel_update()`. Requires the CONTEXT=SEARCH
+    /// capability.
+    pub async fn uid_search_updating(&mut self, criteria: &SearchCriteria, returns: &[SearchReturn])
+                                     -> io::Result<(String, SearchResults)> {
+        let returns = [returns, &[SearchReturn::Update]].concat();
+        let cmd = CommandBuilder::uid_search(criteria).returning(&returns).build();
+        self.run_esearch_updating(cmd, "SEARCH").await
+    }
+
+    /// Like `uid_search_updating()`, but for a SORT, whose updates carry
+    /// the positions of the messages. Requires the CONTEXT=SORT capability.
+    pub async fn uid_sort_updating(&mut self, criteria: &[SortCriterion], search: &SearchCriteria,
+                                   returns: &[SearchReturn]) -> io::Result<(String, SearchResults)> {
+        let returns = [returns, &[SearchReturn::Update]].concat();
+        let cmd = CommandBuilder::uid_sort(criteria, search).returning(&returns).build();
+        self.run_esearch_updating(cmd, "SORT").await
+    }
+
+    /// Stop keeping the results of the search with `tag` up to date; see
+    /// `uid_search_updating()`.
+    pub async fn cancel_update(&mut self, tag: &str) -> io::Result<()> {
+        run(&mut self.client, CommandBuilder::cancel_update(&[tag]), "CANCELUPDATE", |_| {}).await
+    }
+
+    // Like `run_esearch()`, also returning the tag of the command, which
+    // identifies the updates to the results.
+    async fn run_esearch_updating(&mut self, cmd: Command, name: &str) -> io::Result<(String, SearchResults)> {
+        let mut tag = String::new();
+        let mut results = SearchResults::default();
+        run(&mut self.client, cmd, name, |rsp| {
+            match *rsp.parsed() {
+                Response::Esearch { results: ref found, .. } => results = found.clone(),
+                Response::Done(ref id, ..) => tag = id.0.to_string(),
+                _ => {},
+            }
+        }).await?;
+        Ok((tag, results))
+    }
+
+    async fn run_esearch(&mut self, cmd: Command, name: &str) -> io::Result<SearchResults> {
+        let mut results = SearchResults::default();
+        run(&mut self.client, cmd, name, |rsp| {
+            if let Response::Esearch { results: ref found, .. } = *rsp.parsed() {
+                results = found.clone();
+            }
+        }).await?;
+        Ok(results)
+    }
+
+    /// Arrange the messages matching `search` in threads using `algorithm`
+    /// (RFC 5256), identified by their sequence numbers. Requires the
+    /// matching `Capability::Thread`.
+    pub async fn thread(&mut self, algorithm: ThreadAlgorithm, search: &SearchCriteria)
+                        -> io::Result<Vec<Thread>> {
+        self.run_thread(CommandBuilder::thread(algorithm, search)).await
+    }
+
+    /// Like `thread()`, but identifying messages by UID.
+    pub async fn uid_thread(&mut self, algorithm: ThreadAlgorithm, search: &SearchCriteria)
+                            -> io::Result<Vec<Thread>> {
+        self.run_thread(CommandBuilder::uid_thread(algorithm, search)).await
+    }
+
+    async fn run_thread(&mut self, cmd: Command) -> io::Result<Vec<Thread>> {
+        let mut threads = vec![];
+        run(&mut self.client, cmd, "THREAD", |rsp| {
+            if let Response::Thread(ref found) = *rsp.parsed() {
+                threads.extend(found.iter().cloned());
+            }
+        }).await?;
+        Ok(threads)
+    }
+
+    /// Replace message `seq` with `message`, stored in `mailbox` (RFC 8508),
+    /// returning the UIDVALIDITY of `mailbox` and the UID of the new
+    /// message if the server reports them (RFC 4315). Requires the REPLACE
+    /// capability.
+    pub async fn replace(&mut self, seq: u32, mailbox: &str, message: &[u8])
+                         -> io::Result<Option<(u32, u32)>> {
+        let cmd = CommandBuilder::replace(seq, &self.encode_mailbox(mailbox), message);
+        self.run_replace(cmd).await
+    }
+
+    /// Like `replace()`, but for the message with UID `uid`.
+    pub async fn uid_replace(&mut self, uid: u32, mailbox: &str, message: &[u8])
+                             -> io::Result<Option<(u32, u32)>> {
+        let cmd = CommandBuilder::uid_replace(uid, &self.encode_mailbox(mailbox), message);
+        self.run_replace(cmd).await
+    }
+
+    async fn run_replace(&mut self, cmd: Command) -> io::Result<Option<(u32, u32)>> {
+        let mut appended = None;
+        run(&mut self.client, cmd, "REPLACE", |rsp| {
+            appended = append_uid(rsp.parsed()).or(appended);
+        }).await?;
+        Ok(appended)
+    }
+
+    /// Copy the messages in the sequence set `set` to `mailbox`, returning
+    /// the UIDVALIDITY of `mailbox` and the new UID of each copied message
+    /// by its old UID if the server reports them (RFC 4315).
+    pub async fn copy(&mut self, set: &str, mailbox: &str) -> io::Result<Option<(u32, Vec<(u32, u32)>)>> {
+        let cmd = CommandBuilder::copy(set, &self.encode_mailbox(mailbox));
+        self.run_copy(cmd).await
+    }
+
+    /// Like `copy()`, but for the messages in the UID set `set`.
+    pub async fn uid_copy(&mut self, set: &str, mailbox: &str) -> io::Result<Option<(u32, Vec<(u32, u32)>)>> {
+        let cmd = CommandBuilder::uid_copy(set, &self.encode_mailbox(mailbox));
+        self.run_copy(cmd).await
+    }
+
+    async fn run_copy(&mut self, cmd: Command) -> io::Result<Option<(u32, Vec<(u32, u32)>)>> {
+        let mut copied = None;
+        run(&mut self.client, cmd, "COPY", |rsp| {
+            copied = copy_uid(rsp.parsed()).or_else(|| copied.take());
+        }).await?;
+        Ok(copied)
+    }
+
+    /// Remove all messages marked `\Deleted` from the mailbox.
+    pub async fn expunge(&mut self) -> io::Result<()> {
+        run(&mut self.client, CommandBuilder::expunge(), "EXPUNGE", |_| {}).await
+    }
+
+    /// Like `expunge()`, but only removing the messages in the UID set
+    /// `set`, so that messages marked `\Deleted` by other clients are kept
+    /// (RFC 4315). Requires the UIDPLUS capability.
+    pub async fn uid_expunge(&mut self, set: &str) -> io::Result<()> {
+        run(&mut self.client, CommandBuilder::uid_expunge(set), "EXPUNGE", |_| {}).await
+    }
+
+    /// Move the messages in the UID set `set` to `mailbox`, returning the
+    /// UIDVALIDITY of `mailbox` and the new UID of each moved message by its
+    /// old UID if the server reports them (RFC 4315).
+    ///
+    /// Without the MOVE capability (RFC 6851), the messages are copied,
+    /// marked `\Deleted` and removed with UID EXPUNGE instead, which
+    /// requires UIDPLUS. The copies cannot be removed from `mailbox` again
+    /// without selecting it, so this fails before copying anything if
+    /// UIDPLUS is missing or the selected mailbox is read-only. If marking
+    /// or expunging the messages still fails, they are unmarked again.
+    pub async fn move_messages(&mut self, set: &str, mailbox: &str)
+                               -> io::Result<Option<(u32, Vec<(u32, u32)>)>> {
+        if !self.has(&Capability::Move) {
+            if !self.has(&Capability::UidPlus) {
+                return Err(Error::Unsupported("moving messages requires MOVE or UIDPLUS".into()).into());
+            }
+            if self.client.mailbox().is_some_and(|mailbox| mailbox.read_only) {
+                return Err(Error::StateError("cannot move messages out of a read-only mailbox".into()).into());
+            }
+        }
+        let mut cmds = self.move_commands(set, mailbox);
+        let mut copied = None;
+        if self.has(&Capability::Move) {
+            run(&mut self.client, cmds.remove(0), "MOVE", |rsp| {
+                copied = copy_uid(rsp.parsed()).or_else(|| copied.take());
+            }).await?;
+            return Ok(copied);
+        }
+        let copied = self.run_copy(cmds.remove(0)).await?;
+        let expunge = cmds.pop().expect("no EXPUNGE command");
+        let mut result = self.run_store(cmds.into()).await;
+        if result.is_ok() {
+            result = run(&mut self.client, expunge, "EXPUNGE", |_| {}).await;
+        }
+        if let Err(err) = result {
+            self.uid_store(set, StoreType::Remove, &["\\Deleted"]).await?;
+            return Err(err);
+        }
+        Ok(copied)
+    }
+
+    // The commands `move_messages()` issues if they succeed: a MOVE, or a
+    // COPY, the STOREs marking the messages and a UID EXPUNGE. None if the
+    // server supports neither MOVE nor UIDPLUS.
+    fn move_commands(&self, set: &str, mailbox: &str) -> Vec<Command> {
+        let mailbox = self.encode_mailbox(mailbox);
+        if self.has(&Capability::Move) {
+            return vec![CommandBuilder::uid_move(set, &mailbox)];
+        }
+        if !self.has(&Capability::UidPlus) {
+            return vec![];
+        }
+        let mut cmds = vec![CommandBuilder::uid_copy(set, &mailbox)];
+        cmds.extend(self.store_commands(true, set, StoreType::Add, &["\\Deleted"]));
+        cmds.push(CommandBuilder::uid_expunge(set));
+        cmds
+    }
+
+    /// Preview the commands of bulk operations such as `move_messages()`
+    /// without sending anything, for example to have a batch job reviewed
+    /// before it is run. The requests have placeholder tags; see `Plan`.
+    pub fn plan(&self) -> Plan<'_> {
+        Plan { session: self, tags: IdGenerator::new() }
+    }
+
+    /// List the attachments of message `uid`, from its BODYSTRUCTURE. Parts
+    /// that are text/plain or text/html are only included if their
+    /// Content-Disposition is `attachment`.
+    pub async fn attachments(&mut self, uid: u32) -> io::Result<Vec<Attachment>> {
+        let mut fetched = self.uid_fetch(&uid.to_string(), vec![Attribute::BodyStructure]);
+        let mut attachments = vec![];
+        while let Some(message) = fetched.next().await {
+            let message = message?;
+            if message.uid() != Some(uid) {
+                continue;
+            }
+            if let Some(body) = message.body_structure() {
+                attachments.clear();
+                collect_attachments(body, "", &mut attachments);
+            }
+        }
+        Ok(attachments)
+    }
+
+    /// Fetch the body section `section` of message `uid`, such as the
+    /// `section` of an `Attachment`, and write it to `sink` after undoing
+    /// its Content-Transfer-Encoding. Does not set the `\\Seen` flag.
+    pub async fn download_attachment<W: Write>(&mut self, uid: u32, section: &str, sink: &mut W)
+                                               -> io::Result<()> {
+        let mime = format!("{}.MIME", section);
+        let items = vec![Attribute::BodyPeek(mime.clone()), Attribute::BodyPeek(section.to_string())];
+        let mut fetched = self.uid_fetch(&uid.to_string(), items);
+        let mut decoded = None;
+        while let Some(message) = fetched.next().await {
+            let message = message?;
+            if message.uid() != Some(uid) {
+                continue;
+            }
+            // 8bit and binary parts need not be valid UTF-8.
+            let data = match message.body_section_bytes(section) {
+                Some(data) => data,
+                None => continue,
+            };
+            let header = message.body_section_bytes(&mime).map(String::from_utf8_lossy);
+            decoded = Some(match header.as_deref().and_then(transfer_encoding) {
+                Some(ref encoding) if encoding == "base64" => decode_base64(data)?,
+                Some(ref encoding) if encoding == "quoted-printable" => decode_quoted_printable(data),
+                _ => data.to_vec(),
+            });
+        }
+        match decoded {
+            Some(data) => sink.write_all(&data),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "body section not found")),
+        }
+    }
+
+    // Builds the commands for the set `set`, split so that each stays under
+    // the limit set with `Client::limit_command_length()`.
+    fn split_commands<F>(&self, set: &str, build: F) -> VecDeque<Command> where F: Fn(&str) -> Command {
+        // Sets using `*` or `$` cannot be split and are sent whole.
+        let (limit, parsed) = match (self.client.max_command_length(), SequenceSet::parse(set)) {
+            (Some(limit), Some(parsed)) => (limit, parsed),
+            _ => return VecDeque::from(vec![build(set)]),
+        };
+        // Room for the tag, the space after it and the CRLF.
+        let tag_len = self.client.next_tag().map(|tag| tag.0.len()).unwrap_or(0);
+        let overhead = build("").len() + tag_len + 3;
+        parsed.chunks(limit.saturating_sub(overhead)).iter().map(|chunk| build(&chunk.to_string())).collect()
+    }
+
+    fn fetch_stream(&mut self, mut cmds: VecDeque<Command>, items: Vec<Attribute>) -> FetchStream<'_> {
+        let first = cmds.pop_front().expect("no FETCH command");
+        FetchStream {
+            responses: self.client.call(first),
+            remaining: cmds,
+            items,
+            current: None,
+            failure: None,
+        }
+    }
+
+    /// Wait for changes to the mailbox with IDLE (RFC 2177), which the
+    /// returned stream yields as `IdleEvent`s until `Idle::done()` is
+    /// called. `mailbox` is kept up to date with the changes, and its
+    /// message count is used to tell how many messages are new.
+    ///
+    /// The changes arrive as unsolicited responses, so they are not seen
+    /// by the stream while there is an `unsolicited()` subscriber.
+    /// Dropping the stream ends IDLE without waiting for the server.
+    pub fn idle<'a>(&'a mut self, mailbox: &'a mut Mailbox) -> Idle<'a> {
+        Idle {
+            responses: self.client.call(CommandBuilder::idle()),
+            mailbox,
+            failure: None,
+        }
+    }
+
+    /// Wait for changes to the mailbox, returning them once there are any.
+    /// IDLE is used if the server supports it, and restarted every 29
+    /// minutes as RFC 2177 recommends; otherwise, NOOP is sent every
+    /// `interval`. `sleep` is the timer of the runtime, such as
+    /// `tokio::time::sleep`. `mailbox` is kept up to date as with `idle()`.
+    pub async fn watch<S, F>(&mut self, mailbox: &mut Mailbox, interval: Duration, mut sleep: S)
+                             -> io::Result<Vec<IdleEvent>>
+            where S: FnMut(Duration) -> F, F: Future<Output = ()> {
+        loop {
+            let events = if self.has(&Capability::Idle) {
+                self.idle_until_changed(mailbox, sleep(IDLE_RESTART)).await?
+            } else {
+                let mut events = vec![];
+                run(&mut self.client, CommandBuilder::noop(), "NOOP", |rsp| {
+                    events.extend(mailbox_event(mailbox, rsp.parsed()));
+                }).await?;
+                if events.is_empty() {
+                    sleep(interval).await;
+                }
+                events
+            };
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+
+    // Runs IDLE until the first change is reported or `timer` expires,
+    // returning the changes reported by then.
+    async fn idle_until_changed<F>(&mut self, mailbox: &mut Mailbox, timer: F) -> io::Result<Vec<IdleEvent>>
+            where F: Future<Output = ()> {
+        pin_mut!(timer);
+        let mut idle = self.idle(mailbox);
+        let mut events = vec![];
+        if let Either::Left((event, _)) = future::select(idle.next(), timer).await {
+            match event {
+                Some(Ok(IdleEvent::MailboxClosed)) => return Ok(vec![IdleEvent::MailboxClosed]),
+                Some(event) => events.push(event?),
+                None => return Ok(events),
+            }
+        }
+        events.extend(idle.done().await?);
+        Ok(events)
+    }
+
+    pub async fn close(mut self) -> io::Result<Session<Authenticated>> {
+        run(&mut self.client, CommandBuilder::close(), "CLOSE", |_| {}).await?;
+        Ok(Session::from_client(self.client))
+    }
+}
+
+fn fetch_command(set: &str, items: &[Attribute]) -> FetchCommandAttributes {
+    let mut attrs = items.iter().cloned();
+    let first = attrs.next().expect("FETCH requires at least one item");
+    let mut cmd = CommandBuilder::fetch().set(set).attr(first);
+    for attr in attrs {
+        cmd = cmd.attr(attr);
+    }
+    cmd
+}
+
+// The server metadata entry holding the filter setting `name` of `vendor`.
+fn filter_entry(vendor: &str, name: &str) -> String {
+    format!("/shared/vendor/{}/{}", vendor, name)
+}
+
+// The UIDVALIDITY and first UID from an APPENDUID response code.
+fn append_uid(rsp: &Response) -> Option<(u32, u32)> {
+    match *rsp {
+        Response::Data(Status::Ok, Some(ResponseCode::AppendUid(uid_validity, ref uids)), _) |
+        Response::Done(_, Status::Ok, Some(ResponseCode::AppendUid(uid_validity, ref uids)), _) => {
+            uids.first().map(|uid| (uid_validity, *uid.start()))
+        },
+        _ => None,
+    }
+}
+
+// The UIDVALIDITY and the pairs of source and destination UIDs from a
+// COPYUID response code.
+fn copy_uid(rsp: &Response) -> Option<(u32, Vec<(u32, u32)>)> {
+    match *rsp {
+        Response::Data(Status::Ok, Some(ResponseCode::CopyUid(uid_validity, ref source, ref destination)), _) |
+        Response::Done(_, Status::Ok, Some(ResponseCode::CopyUid(uid_validity, ref source, ref destination)), _) => {
+            let uids = source.iter().cloned().flatten().zip(destination.iter().cloned().flatten());
+            Some((uid_validity, uids.collect()))
+        },
+        _ => None,
+    }
+}
+
+/// The results of a search of several mailboxes for one of them; see
+/// `Session::multisearch()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MailboxSearchResults {
+    pub mailbox: String,
+    pub uid_validity: u32,
+    /// The results, with UIDs.
+    pub results: SearchResults,
+}
+
+/// The error returned when a command fails with a referral to another
+/// server or mailbox (RFC 2221, RFC 2193), for example because the user's
+/// mailboxes live on another server of a cluster. It is the inner error of
+/// the `io::Error` returned by the `Session` method; see `referral()`.
+#[derive(Debug)]
+pub struct Referral {
+    pub command: String,
+    pub url: ImapUrl,
+    pub text: String,
+}
+
+impl fmt::Display for Referral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed: {} (referred to {})", self.command, self.text, self.url)
+    }
+}
+
+impl error::Error for Referral {}
+
+/// The referral that caused a `Session` method to fail with `err`, if any.
+pub fn referral(err: &io::Error) -> Option<&Referral> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Referral>())
+}
+
+// A tagged completion response that is not OK.
+struct Failure {
+    bad: bool,
+    code: Option<String>,
+    text: String,
+    referral: Option<ImapUrl>,
+}
+
+// Records a tagged completion response that is not OK, and returns whether
+// `rsp` was a tagged completion at all.
+fn check_done(rsp: &Response, failure: &mut Option<Failure>) -> bool {
+    match *rsp {
+        Response::Done(_, ref status, ref code, text) => {
+            *failure = match *status {
+                Status::Ok => None,
+                _ => Some(Failure {
+                    bad: matches!(*status, Status::Bad),
+                    code: Error::code(code),
+                    text: text.unwrap_or("").to_string(),
+                    referral: match *code {
+                        Some(ResponseCode::Referral(url)) => ImapUrl::parse(url),
+                        _ => None,
+                    },
+                }),
+            };
+            true
+        },
+        _ => false,
+    }
+}
+
+fn command_failed(command: &str, failure: Failure) -> io::Error {
+    let Failure { bad, code, text, referral } = failure;
+    let command = command.to_string();
+    match referral {
+        Some(url) => io::Error::other(Referral { command, url, text }),
+        None if bad => Error::Bad { command, code, text }.into(),
+        None => Error::No { command, code, text }.into(),
+    }
+}
+
+// Turns a failure with a TOOBIG code into `Error::TooBig`.
+fn too_big(err: io::Error, limit: Option<u64>) -> io::Error {
+    match err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+        Some(Error::No { code: Some(code), .. }) if code == "TOOBIG" => {},
+        _ => return err,
+    }
+    match Error::from(err) {
+        Error::No { command, text, .. } => Error::TooBig { command, limit, text }.into(),
+        err => err.into(),
+    }
+}
+
+// Issues `cmd` and hands all responses to `handle`, failing if the command
+// does not complete successfully.
+async fn run<F>(client: &mut Client, cmd: Command, name: &str, mut handle: F) -> io::Result<()>
+        where F: FnMut(ResponseData) {
+    let mut responses = client.call(cmd);
+    let mut failure = None;
+    while let Some(rsp) = responses.next().await {
+        let rsp = rsp?;
+        check_done(rsp.parsed(), &mut failure);
+        handle(rsp);
+    }
+    match failure {
+        Some(failure) => Err(command_failed(name, failure)),
+        None => Ok(()),
+    }
+}
+
+/// The state of a mailbox, as reported by the server when selecting it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Mailbox {
+    pub flags: Vec<String>,
+    pub exists: u32,
+    pub recent: u32,
+    pub unseen: Option<u32>,
+    pub permanent_flags: Vec<String>,
+    /// Whether new keywords can be stored permanently, such as `$Forwarded`.
+    pub new_keywords: bool,
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    pub highest_mod_seq: Option<u64>, // RFC 4551, section 3.1.1
+    /// Whether the mailbox does not support mod-sequences, so that
+    /// CONDSTORE cannot be used with it (RFC 4551, section 3.1.2).
+    pub no_mod_seq: bool,
+    pub mailbox_id: Option<String>, // RFC 8474, section 4.1
+    pub read_only: bool,
+}
+
+impl Mailbox {
+    /// Update the mailbox state from a response sent by the server.
+    pub fn update(&mut self, rsp: &Response) {
+        match *rsp {
+            Response::MailboxData(MailboxDatum::Exists(num)) => {
+                self.exists = num;
+            },
+            Response::MailboxData(MailboxDatum::Recent(num)) => {
+                self.recent = num;
+            },
+            Response::Expunge(_) => {
+                self.exists = self.exists.saturating_sub(1);
+            },
+            // VANISHED (EARLIER) reports messages removed before the mailbox
+            // was selected, which are not counted by EXISTS.
+            Response::Vanished { earlier: false, ref uids } => {
+                let count: usize = uids.iter().map(|range| range.clone().count()).sum();
+                self.exists = self.exists.saturating_sub(count as u32);
+            },
+            Response::MailboxData(MailboxDatum::Flags(ref flags)) => {
+                self.flags = flags.iter().map(|s| s.to_string()).collect();
+            },
+            Response::Data(_, Some(ref code), _) |
+            Response::Done(_, _, Some(ref code), _) => {
+                self.update_code(code);
+            },
+            _ => {},
+        }
+    }
+
+    fn update_code(&mut self, code: &ResponseCode) {
+        match *code {
+            ResponseCode::HighestModSeq(seq) => {
+                self.highest_mod_seq = Some(seq);
+            },
+            ResponseCode::MailboxId(id) => {
+                self.mailbox_id = Some(id.to_string());
+            },
+            ResponseCode::NoModSeq => {
+                self.no_mod_seq = true;
+            },
+            ResponseCode::PermanentFlags(ref permanent) => {
+                self.permanent_flags = permanent.flags.iter().map(|flag| flag.to_string()).collect();
+                self.new_keywords = permanent.new_keywords;
+            },
+            ResponseCode::ReadOnly => {
+                self.read_only = true;
+            },
+            ResponseCode::ReadWrite => {
+                self.read_only = false;
+            },
+            ResponseCode::UidNext(uid) => {
+                self.uid_next = Some(uid);
+            },
+            ResponseCode::UidValidity(uid) => {
+                self.uid_validity = Some(uid);
+            },
+            ResponseCode::Unseen(num) => {
+                self.unseen = Some(num);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// A mailbox returned by the LIST command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListEntry {
+    pub flags: Vec<String>,
+    pub delimiter: Option<String>,
+    pub name: String,
+    /// The STATUS items returned with the mailbox, if any were requested.
+    pub status: Vec<StatusAttributeValue>,
+}
+
+impl ListEntry {
+    /// The special uses of the mailbox (RFC 6154).
+    pub fn special_use(&self) -> Vec<SpecialUse> {
+        self.flags.iter().filter_map(|flag| SpecialUse::from_flag(flag)).collect()
+    }
+}
+
+/// An attachment of a message, as returned by `Session::attachments()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Attachment {
+    /// The file name suggested by the Content-Disposition or Content-Type,
+    /// as sent.
+    pub filename: Option<String>,
+    /// The lowercase MIME type, such as `image/png`.
+    pub mime_type: String,
+    /// The size of the part as sent, before undoing its
+    /// Content-Transfer-Encoding.
+    pub size: u32,
+    /// The body section of the part, such as `2` or `1.3`.
+    pub section: String,
+}
+
+// Adds the attachments of `body`, whose body section is `section`, or ""
+// for the whole message.
+fn collect_attachments(body: &BodyStructure, section: &str, found: &mut Vec<Attachment>) {
+    let fields = match *body {
+        BodyStructure::Multipart { ref parts, .. } => {
+            for (i, part) in parts.iter().enumerate() {
+                let section = match section {
+                    "" => (i + 1).to_string(),
+                    _ => format!("{}.{}", section, i + 1),
+                };
+                collect_attachments(part, &section, found);
+            }
+            return;
+        },
+        BodyStructure::Single { ref fields, .. } => fields,
+    };
+    let mime_type = format!("{}/{}", fields.media_type, fields.media_subtype).to_ascii_lowercase();
+    let disposition = body.disposition();
+    let attached = disposition.is_some_and(|disposition| disposition.kind.eq_ignore_ascii_case("attachment"));
+    if !attached && (mime_type == "text/plain" || mime_type == "text/html") {
+        return;
+    }
+    let filename = disposition.and_then(|disposition| disposition.param("filename")).or_else(|| body.param("name"));
+    found.push(Attachment {
+        filename: filename.map(|name| name.to_string()),
+        mime_type,
+        size: fields.size,
+        section: if section.is_empty() { "1".to_string() } else { section.to_string() },
+    });
+}
+
+// Finds the lowercase Content-Transfer-Encoding in the MIME header `header`.
+fn transfer_encoding(header: &str) -> Option<String> {
+    header.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("Content-Transfer-Encoding") {
+            Some(value.trim().to_ascii_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+fn decode_base64(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut bits, mut len) = (0u32, 0);
+    for &c in data {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ if c.is_ascii_whitespace() => continue,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid base64 data")),
+        };
+        bits = bits << 6 | u32::from(value);
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+// Invalid escapes are kept as they are.
+fn decode_quoted_printable(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let rest = &bytes[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let Some(byte) = rest.get(..2).and_then(|hex| u8::from_str_radix(str::from_utf8(hex).ok()?, 16).ok()) {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(b'=');
+            i += 1;
+        }
+    }
+    decoded
+}
+
+/// All attributes returned for a single message by a FETCH command.
+/// Once UIDONLY is enabled, the server identifies messages by UID in
+/// UIDFETCH responses, and `seq` is 0.
+#[derive(Debug)]
+pub struct Fetched {
+    pub seq: u32,
+    uid_fetch: Option<u32>,
+    responses: Vec<ResponseData>,
+}
+
+impl Fetched {
+    fn new(rsp: ResponseData) -> Option<Fetched> {
+        let (seq, uid_fetch) = match *rsp.parsed() {
+            Response::Fetch(seq, _) => (seq, None),
+            Response::UidFetch(uid, _) => (0, Some(uid)),
+            _ => return None,
+        };
+        Some(Fetched { seq, uid_fetch, responses: vec![rsp] })
+    }
+
+    /// Adds `rsp` if it is about the same message, or returns it.
+    fn merge(&mut self, rsp: ResponseData) -> Option<ResponseData> {
+        let same = match *rsp.parsed() {
+            Response::Fetch(seq, _) => self.uid_fetch.is_none() && self.seq == seq,
+            Response::UidFetch(uid, _) => self.uid_fetch == Some(uid),
+            _ => false,
+        };
+        if !same {
+            return Some(rsp);
+        }
+        self.responses.push(rsp);
+        None
+    }
+
+    pub fn attributes(&self) -> Vec<&AttributeValue<'_>> {
+        let mut attrs = vec![];
+        for rsp in &self.responses {
+            if let Response::Fetch(_, ref values) | Response::UidFetch(_, ref values) = *rsp.parsed() {
+                attrs.extend(values.iter());
+            }
+        }
+        attrs
+    }
+
+    /// The literals of the message written to files because of
+    /// `Client::spool_literals_over()`, such as a large `BODY[]`; the
+    /// corresponding attributes are `NIL`.
+    pub fn spooled(&self) -> Vec<&SpooledLiteral> {
+        self.responses.iter().flat_map(|rsp| rsp.spooled()).collect()
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        if self.uid_fetch.is_some() {
+            return self.uid_fetch;
+        }
+        for attr in self.attributes() {
+            if let AttributeValue::Uid(uid) = *attr {
+                return Some(uid);
+            }
+        }
+        None
+    }
+
+    pub fn flags(&self) -> Vec<&str> {
+        for attr in self.attributes() {
+            if let AttributeValue::Flags(ref flags) = *attr {
+                return flags.to_vec();
+            }
+        }
+        vec![]
+    }
+
+    /// Whether the message has `flag`, such as `Flag::Forwarded`.
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags().into_iter().any(|s| Flag::from(s) == flag)
+    }
+
+    /// The attributes of the per-message annotation `entry` (RFC 5257),
+    /// such as `value.priv`, paired with their values.
+    pub fn annotation(&self, entry: &str) -> Option<&[(&str, Option<&str>)]> {
+        for attr in self.attributes() {
+            if let AttributeValue::Annotation(ref annotations) = *attr {
+                if let Some(annotation) = annotations.iter().find(|annotation| annotation.entry == entry) {
+                    return Some(&annotation.values);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn mod_seq(&self) -> Option<u64> {
+        for attr in self.attributes() {
+            if let AttributeValue::ModSeq(seq) = *attr {
+                return Some(seq);
+            }
+        }
+        None
+    }
+
+    /// The INTERNALDATE of the message, if it was fetched and is valid.
+    pub fn internal_date(&self) -> Option<DateTime> {
+        for attr in self.attributes() {
+            if let AttributeValue::InternalDate(date) = *attr {
+                return DateTime::parse(date);
+            }
+        }
+        None
+    }
+
+    /// The structure of the message, fetched as BODYSTRUCTURE or as BODY;
+    /// see `has_body_extensions()`.
+    pub fn body_structure(&self) -> Option<&BodyStructure<'_>> {
+        for attr in self.attributes() {
+            match *attr {
+                AttributeValue::BodyStructure(ref body) | AttributeValue::Body(ref body) => return Some(body),
+                _ => {},
+            }
+        }
+        None
+    }
+
+    /// Whether the structure of the message includes the extension data,
+    /// such as the dispositions of the parts, which is the case when it was
+    /// fetched as BODYSTRUCTURE, not as BODY.
+    pub fn has_body_extensions(&self) -> bool {
+        self.attributes().iter().any(|attr| matches!(*attr, AttributeValue::BodyStructure(_)))
+    }
+
+    pub fn envelope(&self) -> Option<&Envelope<'_>> {
+        for attr in self.attributes() {
+            if let AttributeValue::Envelope(ref envelope) = *attr {
+                return Some(envelope);
+            }
+        }
+        None
+    }
+
+    /// Contents of the body section `section` as text; use `""` for the
+    /// whole message. `None` if the contents are not valid UTF-8, such as
+    /// 8-bit or binary parts; see `body_section_bytes()`.
+    pub fn body_section(&self, section: &str) -> Option<&str> {
+        self.body_section_bytes(section).and_then(|data| str::from_utf8(data).ok())
+    }
+
+    /// Raw contents of the body section `section`.
+    pub fn body_section_bytes(&self, section: &str) -> Option<&[u8]> {
+        for attr in self.attributes() {
+            if let AttributeValue::BodySection { section: name, data, .. } = *attr {
+                if name.unwrap_or("") == section {
+                    return data;
+                }
+            }
+        }
+        None
+    }
+
+    /// Decode the whole message, fetched as `RFC822` or `BODY[]`, into its
+    /// text and attachments. Returns `None` if it was not fetched.
+    #[cfg(feature = "mime")]
+    pub fn parsed_body(&self) -> io::Result<Option<crate::mime::ParsedBody>> {
+        let raw = self.body_section_bytes("").or_else(|| {
+            self.attributes().into_iter().find_map(|attr| match *attr {
+                AttributeValue::Rfc822(data) => data,
+                _ => None,
+            })
+        });
+        raw.map(crate::mime::parse).transpose()
+    }
+}
+
+fn is_requested(value: &AttributeValue, items: &[Attribute]) -> bool {
+    items.iter().any(|item| matches!((item, value),
+        (&Attribute::Annotation { .. }, &AttributeValue::Annotation(_)) |
+        (&Attribute::BodySection(_), &AttributeValue::BodySection { .. }) |
+        (&Attribute::BodyPeek(_), &AttributeValue::BodySection { .. }) |
+        (&Attribute::BodyPeekPartial { .. }, &AttributeValue::BodySection { .. }) |
+        (&Attribute::Body, &AttributeValue::Body(_)) |
+        (&Attribute::BodyStructure, &AttributeValue::BodyStructure(_)) |
+        (&Attribute::Envelope, &AttributeValue::Envelope(_)) |
+        (&Attribute::Flags, &AttributeValue::Flags(_)) |
+        (&Attribute::InternalDate, &AttributeValue::InternalDate(_)) |
+        (&Attribute::ModSeq, &AttributeValue::ModSeq(_)) |
+        (&Attribute::Rfc822, &AttributeValue::Rfc822(_)) |
+        (&Attribute::Rfc822Size, &AttributeValue::Rfc822Size(_)) |
+        (&Attribute::Uid, &AttributeValue::Uid(_))
+    ))
+}
+
+/// The commands a bulk operation on the selected mailbox would issue, as
+/// returned by `Session::plan()`. Each method returns the requests in the
+/// order they would be sent if all of them succeed. Nothing is sent to the
+/// server.
+///
+/// The tags of the requests are placeholders, numbered from `A0001` like
+/// those of a new connection. The commands sent later get the next tags of
+/// the connection instead, so they cannot be matched to a plan by tag.
+///
+/// ```ignore
+/// for request in session.plan().move_messages("1:500", "Archive") {
+///     println!("{}", request);
+/// }
+/// ```
+pub struct Plan<'a> {
+    session: &'a Session<Selected>,
+    tags: IdGenerator,
+}
+
+impl<'a> Plan<'a> {
+    /// The requests of `Session::store()`.
+    pub fn store(&mut self, set: &str, store_type: StoreType, flags: &[&str]) -> Vec<Request> {
+        let cmds = self.session.store_commands(false, set, store_type, flags);
+        self.requests(cmds)
+    }
+
+    /// The requests of `Session::uid_store()`.
+    pub fn uid_store(&mut self, set: &str, store_type: StoreType, flags: &[&str]) -> Vec<Request> {
+        let cmds = self.session.store_commands(true, set, store_type, flags);
+        self.requests(cmds)
+    }
+
+    /// The request of `Session::expunge()`.
+    pub fn expunge(&mut self) -> Vec<Request> {
+        self.requests(vec![CommandBuilder::expunge()])
+    }
+
+    /// The request of `Session::uid_expunge()`.
+    pub fn uid_expunge(&mut self, set: &str) -> Vec<Request> {
+        self.requests(vec![CommandBuilder::uid_expunge(set)])
+    }
+
+    /// The requests of `Session::move_messages()`, which depend on the
+    /// capabilities of the server; none if it cannot move messages.
+    pub fn move_messages(&mut self, set: &str, mailbox: &str) -> Vec<Request> {
+        let cmds = self.session.move_commands(set, mailbox);
+        self.requests(cmds)
+    }
+
+    fn requests<I: IntoIterator<Item = Command>>(&mut self, cmds: I) -> Vec<Request> {
+        let tags = &mut self.tags;
+        cmds.into_iter().map(|cmd| cmd.into_request(tags.next().expect("tag generator exhausted"))).collect()
+    }
+}
+
+/// Yields the messages returned by a FETCH command. Dropping the stream
+/// before it is done abandons the command; see `ResponseStream`.
+pub struct FetchStream<'a> {
+    responses: ResponseStream<'a>,
+    // Commands for the rest of a split set, issued one after the other.
+    remaining: VecDeque<Command>,
+    items: Vec<Attribute>,
+    current: Option<Fetched>,
+    failure: Option<Failure>,
+}
+
+impl<'a> Stream for FetchStream<'a> {
+    type Item = io::Result<Fetched>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let rsp = match ready!(this.responses.poll_next_unpin(cx)) {
+                Some(rsp) => rsp?,
+                None => {
+                    if this.failure.is_none() {
+                        if let Some(cmd) = this.remaining.pop_front() {
+                            this.responses.then_call(cmd);
+                            continue;
+                        }
+                    }
+                    if let Some(fetched) = this.current.take() {
+                        return Poll::Ready(Some(Ok(fetched)));
+                    }
+                    if let Some(failure) = this.failure.take() {
+                        return Poll::Ready(Some(Err(command_failed("FETCH", failure))));
+                    }
+                    return Poll::Ready(None);
+                },
+            };
+            match *rsp.parsed() {
+                Response::Fetch(_, ref attrs) | Response::UidFetch(_, ref attrs) => {
+                    if !attrs.iter().any(|attr| is_requested(attr, &this.items)) {
+                        continue;
+                    }
+                },
+                ref rsp => {
+                    check_done(rsp, &mut this.failure);
+                    continue;
+                },
+            }
+            let rsp = match this.current {
+                Some(ref mut fetched) => match fetched.merge(rsp) {
+                    Some(rsp) => rsp,
+                    None => continue,
+                },
+                None => rsp,
+            };
+            let previous = mem::replace(&mut this.current, Fetched::new(rsp));
+            if let Some(fetched) = previous {
+                return Poll::Ready(Some(Ok(fetched)));
+            }
+        }
+    }
+}
+
+/// A change to the selected mailbox reported during IDLE.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IdleEvent {
+    /// `count` messages were added to the end of the mailbox.
+    NewMessages { count: u32 },
+    /// The message with sequence number `seq` was removed; the sequence
+    /// numbers of later messages have been decremented.
+    Expunged { seq: u32 },
+    /// Messages were removed, by UID, as reported once QRESYNC is enabled
+    /// (RFC 7162, section 3.2.10).
+    Vanished { uids: SequenceSet },
+    FlagsChanged { seq: u32, flags: Vec<String> },
+    /// The flags of the message with `uid` changed, as reported in a
+    /// UIDFETCH response once UIDONLY is enabled (RFC 9586).
+    UidFlagsChanged { uid: u32, flags: Vec<String> },
+    /// Messages joined or left the results of the search with `tag`; see
+    /// `Session::uid_search_updating()`. Identified by UID if `uid` is set.
+    SearchUpdated { tag: String, uid: bool, added: Vec<ContextUpdate>, removed: Vec<ContextUpdate> },
+    /// The server is closing the connection.
+    MailboxClosed,
+}
+
+/// Yields the changes to the selected mailbox during IDLE; see
+/// `Session::idle()`.
+pub struct Idle<'a> {
+    responses: ResponseStream<'a>,
+    mailbox: &'a mut Mailbox,
+    failure: Option<Failure>,
+}
+
+impl<'a> Idle<'a> {
+    /// End IDLE, and wait for the server to confirm it, returning the
+    /// changes reported in the meantime.
+    pub async fn done(mut self) -> io::Result<Vec<IdleEvent>> {
+        self.responses.idle_done();
+        let mut events = vec![];
+        while let Some(event) = self.next().await {
+            events.push(event?);
+        }
+        Ok(events)
+    }
+}
+
+// Applies `rsp` to `mailbox`, returning the change it reports, if any.
+fn mailbox_event(mailbox: &mut Mailbox, rsp: &Response) -> Option<IdleEvent> {
+    match *rsp {
+        Response::MailboxData(MailboxDatum::Exists(num)) => {
+            let count = num.saturating_sub(mailbox.exists);
+            mailbox.exists = num;
+            if count > 0 {
+                return Some(IdleEvent::NewMessages { count });
+            }
+        },
+        Response::Expunge(seq) => {
+            mailbox.update(rsp);
+            return Some(IdleEvent::Expunged { seq });
+        },
+        Response::Vanished { earlier: false, ref uids } => {
+            mailbox.update(rsp);
+            return Some(IdleEvent::Vanished { uids: uids.iter().cloned().collect() });
+        },
+        Response::Fetch(seq, ref attrs) => {
+            let flags = attrs.iter().find_map(|attr| match *attr {
+                AttributeValue::Flags(ref flags) => Some(flags.iter().map(|s| s.to_string()).collect()),
+                _ => None,
+            });
+            return flags.map(|flags| IdleEvent::FlagsChanged { seq, flags });
+        },
+        Response::UidFetch(uid, ref attrs) => {
+            let flags = attrs.iter().find_map(|attr| match *attr {
+                AttributeValue::Flags(ref flags) => Some(flags.iter().map(|s| s.to_string()).collect()),
+                _ => None,
+            });
+            return flags.map(|flags| IdleEvent::UidFlagsChanged { uid, flags });
+        },
+        Response::Esearch { tag: Some(tag), uid, ref results, .. } => {
+            if !results.add_to.is_empty() || !results.remove_from.is_empty() {
+                return Some(IdleEvent::SearchUpdated {
+                    tag: tag.to_string(),
+                    uid,
+                    added: results.add_to.clone(),
+                    removed: results.remove_from.clone(),
+                });
+            }
+        },
+        Response::Data(Status::Bye, ..) => return Some(IdleEvent::MailboxClosed),
+        ref rsp => mailbox.update(rsp),
+    }
+    None
+}
+
+impl<'a> Stream for Idle<'a> {
+    type Item = io::Result<IdleEvent>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let rsp = match ready!(this.responses.poll_next_unpin(cx)) {
+                Some(rsp) => rsp?,
+                None => {
+                    if let Some(failure) = this.failure.take() {
+                        return Poll::Ready(Some(Err(command_failed("IDLE", failure))));
+                    }
+                    return Poll::Ready(None);
+                },
+            };
+            check_done(rsp.parsed(), &mut this.failure);
+            if let Some(event) = mailbox_event(this.mailbox, rsp.parsed()) {
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+    }
+}
+
+/// Mailbox state stored by the client for resynchronization.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QresyncState {
+    pub uid_validity: u32,
+    pub mod_seq: u64,
+    /// UID set of the messages known to the client, such as `1:300`.
+    pub known_uids: Option<String>,
+}
+
+/// Changes in a mailbox since the last known state.
+#[derive(Debug, Default)]
+pub struct Resync {
+    pub mailbox: Mailbox,
+    /// UIDs of the messages removed since the known state.
+    pub vanished: SequenceSet,
+    pub changed: Vec<Fetched>,
+}
+
+impl Resync {
+    fn update(&mut self, rsp: ResponseData) {
+        self.mailbox.update(rsp.parsed());
+        match *rsp.parsed() {
+            Response::Vanished { ref uids, .. } => {
+                let known = self.vanished.ranges().iter().cloned();
+                self.vanished = known.chain(uids.iter().cloned()).collect();
+                return;
+            },
+            Response::Fetch(..) | Response::UidFetch(..) => {},
+            _ => return,
+        }
+        let rsp = match self.changed.last_mut() {
+            Some(fetched) => match fetched.merge(rsp) {
+                Some(rsp) => rsp,
+                None => return,
+            },
+            None => rsp,
+        };
+        self.changed.extend(Fetched::new(rsp));
+    }
+}
@@ -0,0 +1,510 @@
+use bytes::{Buf, BytesMut};
+
+use nom::Needed;
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::deflate::DeflateStream;
+use crate::error::Error;
+use crate::metrics::{self, MetricsObserver};
+use crate::parser;
+use crate::proto::{Quirks, Request, RequestId, Response};
+use crate::record::{Direction, Recorder};
+use crate::redact;
+
+/// A user-supplied stream to run the protocol over, such as a bridge from a
+/// WebSocket to an IMAP server. Any type implementing the tokio I/O traits
+/// qualifies; `futures` I/O types can be adapted with `tokio_util::compat`.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// A user-supplied stream to run the protocol over, such as a bridge from a
+/// WebSocket to an IMAP server. Streams built on browser APIs are not
+/// `Send`, so this is not required on wasm32.
+#[cfg(target_arch = "wasm32")]
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+#[cfg(target_arch = "wasm32")]
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
+
+pub enum ImapStream {
+    #[cfg(feature = "runtime-tokio")]
+    Tls(tokio_native_tls::TlsStream<tokio::net::TcpStream>),
+    #[cfg(feature = "runtime-async-std")]
+    AsyncStd(tokio_native_tls::TlsStream<tokio_util::compat::Compat<async_std::net::TcpStream>>),
+    Deflate(Box<DeflateStream<ImapStream>>), // RFC 4978
+    Memory(DuplexStream),
+    Transport(Box<dyn Transport>),
+}
+
+impl AsyncRead for ImapStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+                 -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            #[cfg(feature = "runtime-tokio")]
+            ImapStream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "runtime-async-std")]
+            ImapStream::AsyncStd(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            ImapStream::Deflate(ref mut s) => Pin::new(&mut **s).poll_read(cx, buf),
+            ImapStream::Memory(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            ImapStream::Transport(ref mut s) => Pin::new(&mut **s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ImapStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            #[cfg(feature = "runtime-tokio")]
+            ImapStream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "runtime-async-std")]
+            ImapStream::AsyncStd(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            ImapStream::Deflate(ref mut s) => Pin::new(&mut **s).poll_write(cx, buf),
+            ImapStream::Memory(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            ImapStream::Transport(ref mut s) => Pin::new(&mut **s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            #[cfg(feature = "runtime-tokio")]
+            ImapStream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "runtime-async-std")]
+            ImapStream::AsyncStd(ref mut s) => Pin::new(s).poll_flush(cx),
+            ImapStream::Deflate(ref mut s) => Pin::new(&mut **s).poll_flush(cx),
+            ImapStream::Memory(ref mut s) => Pin::new(s).poll_flush(cx),
+            ImapStream::Transport(ref mut s) => Pin::new(&mut **s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            #[cfg(feature = "runtime-tokio")]
+            ImapStream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "runtime-async-std")]
+            ImapStream::AsyncStd(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            ImapStream::Deflate(ref mut s) => Pin::new(&mut **s).poll_shutdown(cx),
+            ImapStream::Memory(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            ImapStream::Transport(ref mut s) => Pin::new(&mut **s).poll_shutdown(cx),
+        }
+    }
+}
+
+// What to do with FETCH literals over a threshold, instead of buffering
+// them with the rest of the response.
+enum LargeLiterals {
+    Stream(usize),
+    Spool(usize, PathBuf),
+}
+
+#[derive(Default)]
+pub struct ImapCodec {
+    decode_need_message_bytes: usize,
+    buffer_limit: Option<usize>,
+    quirks: Quirks,
+    large_literals: Option<LargeLiterals>,
+    // Start of the FETCH response containing the literal currently being
+    // streamed, and the number of literal bytes still to come.
+    streaming: Option<(BytesMut, usize)>,
+    // Literals of the current response spooled so far, the last one possibly
+    // still being written.
+    spooled: Vec<SpooledLiteral>,
+    recorder: Option<Recorder>,
+    observer: Option<Arc<dyn MetricsObserver>>,
+    // Bytes at the start of the next response which were already recorded
+    // while streaming a literal.
+    recorded_prefix: usize,
+}
+
+impl ImapCodec {
+    /// Deliver the contents of FETCH literals larger than `threshold` bytes
+    /// as a series of `Response::BodyChunk` items as they arrive, instead of
+    /// buffering the complete response. The FETCH response itself is
+    /// delivered afterwards, with `NIL` in place of the streamed literal.
+    pub fn stream_literals_over(mut self, threshold: usize) -> ImapCodec {
+        self.large_literals = Some(LargeLiterals::Stream(threshold));
+        self
+    }
+
+    /// Write the contents of FETCH literals larger than `threshold` bytes to
+    /// temporary files in `dir` as they arrive, instead of buffering them in
+    /// memory. The FETCH response is delivered with `NIL` in place of each
+    /// spooled literal, and the files are available from
+    /// `ResponseData::spooled()`. Replaces `stream_literals_over()`. The
+    /// files are written with blocking I/O.
+    pub fn spool_literals_over(mut self, threshold: usize, dir: &Path) -> ImapCodec {
+        self.large_literals = Some(LargeLiterals::Spool(threshold, dir.to_path_buf()));
+        self
+    }
+
+    /// Record all data sent and received through this codec.
+    pub fn record_to(mut self, recorder: Recorder) -> ImapCodec {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(direction, data);
+        }
+    }
+
+    /// Append the DONE ending an IDLE command (RFC 2177) to `dst`.
+    pub(crate) fn encode_idle_done(&mut self, dst: &mut BytesMut) {
+        self.encode_line(b"DONE", b"DONE", dst);
+    }
+
+    /// Append `answer` to a continuation request, such as a SASL response,
+    /// to `dst`. It is recorded redacted, as it may carry credentials.
+    pub(crate) fn encode_answer(&mut self, answer: &[u8], dst: &mut BytesMut) {
+        self.encode_line(answer, b"<redacted>", dst);
+    }
+
+    fn encode_line(&mut self, line: &[u8], recorded: &[u8], dst: &mut BytesMut) {
+        if self.recorder.is_some() {
+            self.record(Direction::Client, &[recorded, b"\r\n"].concat());
+        }
+        dst.extend_from_slice(line);
+        dst.extend_from_slice(b"\r\n");
+        if let Some(ref observer) = self.observer {
+            observer.bytes_sent(line.len() + 2);
+        }
+    }
+
+    /// Report activity on the connection to `observer`.
+    pub fn observe(mut self, observer: Arc<dyn MetricsObserver>) -> ImapCodec {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Fail decoding instead of buffering more than `limit` bytes for a
+    /// single response.
+    pub fn limit_buffer(mut self, limit: usize) -> ImapCodec {
+        self.buffer_limit = Some(limit);
+        self
+    }
+
+    /// Accept response lines ended by a bare LF instead of CRLF; see
+    /// `parser::parse_response_lenient()`.
+    pub fn lenient(mut self) -> ImapCodec {
+        self.quirks |= Quirks::BARE_LF;
+        self
+    }
+
+    /// Tolerate `quirks` in responses, instead of those tolerated so far.
+    pub fn tolerate(mut self, quirks: Quirks) -> ImapCodec {
+        self.quirks = quirks;
+        self
+    }
+
+    /// The quirks tolerated in responses.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    fn check_limit(&self, needed: usize) -> Result<(), io::Error> {
+        match self.buffer_limit {
+            Some(limit) if needed > limit => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("response exceeds buffer limit of {} bytes", limit)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn start_streaming(&mut self, buf: &mut BytesMut) -> io::Result<bool> {
+        let threshold = match self.large_literals {
+            Some(LargeLiterals::Stream(threshold)) | Some(LargeLiterals::Spool(threshold, _)) => threshold,
+            None => return Ok(false),
+        };
+        let (header, data, len) = match parser::open_literal(buf) {
+            Some(literal) => literal,
+            None => return Ok(false),
+        };
+        // Once UIDONLY is enabled (RFC 9586), messages come in UIDFETCH responses.
+        let is_fetch = buf.starts_with(b"* ") && [&b" FETCH "[..], b" UIDFETCH "].iter()
+            .any(|name| buf[..header].windows(name.len()).any(|w| w == *name));
+        if len <= threshold || !is_fetch {
+            return Ok(false);
+        }
+        if let Some(LargeLiterals::Spool(_, ref dir)) = self.large_literals {
+            self.spooled.push(SpooledLiteral::create(dir)?);
+        }
+        self.record(Direction::Server, &buf[..data]);
+        let prefix = buf.split_to(header);
+        buf.advance(data - header);
+        self.streaming = Some((prefix, len));
+        self.decode_need_message_bytes = 0;
+        Ok(true)
+    }
+
+    fn decode_chunk(&mut self, buf: &mut BytesMut) -> io::Result<Option<ResponseData>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let (prefix, remaining) = self.streaming.take().unwrap();
+        let len = cmp::min(remaining, buf.len());
+        let raw = buf.split_to(len);
+        if remaining > len {
+            self.streaming = Some((prefix, remaining - len));
+        } else {
+            // Put the start of the response back, replacing the literal.
+            self.recorded_prefix = prefix.len() + 3;
+            let mut restored = prefix;
+            restored.reserve(3 + buf.len());
+            restored.extend_from_slice(b"NIL");
+            restored.extend_from_slice(buf);
+            *buf = restored;
+        }
+        self.record(Direction::Server, &raw);
+        if let Some(ref observer) = self.observer {
+            observer.bytes_received(raw.len());
+        }
+        if let Some(LargeLiterals::Spool(..)) = self.large_literals {
+            self.spooled.last_mut().unwrap().write(&raw)?;
+            return match self.streaming {
+                Some(_) => Ok(None),
+                None => self.decode(buf),
+            };
+        }
+        let response = unsafe { mem::transmute::<Response<'_>, Response<'static>>(Response::BodyChunk(&raw[..])) };
+        Ok(Some(ResponseData { raw, response, spooled: vec![] }))
+    }
+}
+
+impl Decoder for ImapCodec {
+    type Item = ResponseData;
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut)
+             -> Result<Option<Self::Item>, io::Error> {
+        if self.streaming.is_some() {
+            return self.decode_chunk(buf);
+        }
+        if self.decode_need_message_bytes > buf.len() {
+            return Ok(None);
+        }
+        // The result of parsing, without references to `buf`.
+        let res = match parser::parse_response_quirks(buf, self.quirks) {
+            Ok((remaining, response)) => {
+                // This SHOULD be acceptable/safe: BytesMut storage memory is
+                // allocated on the heap and should not move. It will not be
+                // freed as long as we keep a reference alive, which we do
+                // by retaining a reference to the split buffer, below.
+                let response = unsafe { mem::transmute::<Response<'_>, Response<'static>>(response) };
+                Ok((response, buf.len() - remaining.len()))
+            },
+            Err(nom::Err::Incomplete(needed)) => Err(Some(needed)),
+            Err(err) => {
+                error!(error = %err, buffered = buf.len(), "failed to parse server response");
+                Err(None)
+            },
+        };
+        let (response, rsp_len) = match res {
+            Ok(parsed) => parsed,
+            Err(Some(Needed::Size(min))) => {
+                if self.start_streaming(buf)? {
+                    return self.decode_chunk(buf);
+                }
+                // nom reports the number of bytes still missing, not the
+                // length of the whole response.
+                let needed = buf.len() + min.get();
+                self.check_limit(needed)?;
+                self.decode_need_message_bytes = needed;
+                return Ok(None);
+            },
+            Err(Some(_)) => {
+                self.check_limit(buf.len())?;
+                return Ok(None);
+            },
+            Err(None) => {
+                if let Some(ref observer) = self.observer {
+                    observer.parse_failure();
+                }
+                let line = buf[..].split(|&b| b == b'\n').next().unwrap_or(&[]);
+                return Err(Error::Parse(String::from_utf8_lossy(line).trim_end().to_string()).into());
+            },
+        };
+        let raw = buf.split_to(rsp_len);
+        self.decode_need_message_bytes = 0;
+        self.record(Direction::Server, &raw[self.recorded_prefix..]);
+        self.recorded_prefix = 0;
+        if let Some(ref observer) = self.observer {
+            observer.bytes_received(rsp_len);
+        }
+        let rsp = ResponseData { raw, response, spooled: mem::take(&mut self.spooled) };
+        trace!(len = rsp_len, response = %redact::response(&rsp), "received response");
+        Ok(Some(rsp))
+    }
+}
+
+impl Encoder<Request> for ImapCodec {
+    type Error = io::Error;
+    fn encode(&mut self, msg: Request, dst: &mut BytesMut)
+             -> Result<(), io::Error> {
+        debug!(tag = %msg.0, command = %redact::command(&msg.1), "sending command");
+        if self.recorder.is_some() {
+            let line = format!("{} {}\r\n", msg.0, redact::command(&msg.1).preview(usize::MAX));
+            self.record(Direction::Client, line.as_bytes());
+        }
+        dst.extend_from_slice((msg.0).0.as_bytes());
+        dst.extend_from_slice(b" ");
+        dst.extend_from_slice(&msg.1);
+        dst.extend_from_slice(b"\r\n");
+        if let Some(ref observer) = self.observer {
+            observer.command_sent(&metrics::command_name(&msg.1));
+            observer.bytes_sent((msg.0).0.len() + msg.1.len() + 3);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseData {
+    raw: BytesMut,
+    // This reference is really scoped to the lifetime of the `raw`
+    // member, but unfortunately Rust does not allow that yet. It
+    // is transmuted to `'static` by the `Decoder`, instead, and
+    // references returned to callers of `ResponseData` are limited
+    // to the lifetime of the `ResponseData` struct.
+    pub response: Response<'static>,
+    spooled: Vec<SpooledLiteral>,
+}
+
+impl ResponseData {
+    pub fn request_id(&self) -> Option<&RequestId> {
+        match self.response {
+            Response::Done(ref req_id, ..) => Some(req_id),
+            _ => None,
+        }
+    }
+    /// The response as received, including the final CRLF.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+    pub fn parsed(&self) -> &Response<'_> {
+        unsafe { mem::transmute(&self.response) }
+    }
+    /// The literals of the response written to files because of
+    /// `ImapCodec::spool_literals_over()`, in the order they appear in it.
+    pub fn spooled(&self) -> &[SpooledLiteral] {
+        &self.spooled
+    }
+    /// Take the spooled literals, to keep them beyond the response.
+    pub fn take_spooled(&mut self) -> Vec<SpooledLiteral> {
+        mem::take(&mut self.spooled)
+    }
+}
+
+// Distinguishes the spool files of a process.
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A literal written to a temporary file by
+/// `ImapCodec::spool_literals_over()`. The file is removed when this is
+/// dropped, unless it was kept with `persist()`.
+#[derive(Debug)]
+pub struct SpooledLiteral {
+    file: File,
+    path: PathBuf,
+    len: u64,
+    keep: bool,
+}
+
+impl SpooledLiteral {
+    fn create(dir: &Path) -> io::Result<SpooledLiteral> {
+        loop {
+            let n = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("imap-literal-{}-{}", process::id(), n));
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(SpooledLiteral { file, path, len: 0, keep: false }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// The size of the literal in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Open the file for reading from the start.
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    /// Move the file to `path`, for example into a mail store on the same
+    /// file system, and keep it there.
+    pub fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.keep = true;
+        Ok(())
+    }
+}
+
+impl Drop for SpooledLiteral {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use tokio_util::codec::Decoder;
+
+    use super::ImapCodec;
+    use crate::proto::{AttributeValue, Response};
+
+    #[test]
+    fn streams_uidfetch_literals() {
+        let mut codec = ImapCodec::default().stream_literals_over(4);
+        let mut buf = BytesMut::from(&b"* 7 UIDFETCH (BODY[] {10}\r\n01234"[..]);
+        let chunk = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(*chunk.parsed(), Response::BodyChunk(b"01234")));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"56789)\r\n");
+        let chunk = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(*chunk.parsed(), Response::BodyChunk(b"56789")));
+        let rsp = codec.decode(&mut buf).unwrap().unwrap();
+        match *rsp.parsed() {
+            Response::UidFetch(7, ref attrs) => {
+                assert!(matches!(attrs[0], AttributeValue::BodySection { data: None, .. }));
+            },
+            ref rsp => panic!("unexpected response {:?}", rsp),
+        }
+        assert!(buf.is_empty());
+    }
+}
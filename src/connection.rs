@@ -0,0 +1,646 @@
+//! The client side of the protocol as a state machine, independent of any
+//! I/O or runtime.
+//!
+//! Data received from the server is fed to `Connection::advance()`, which
+//! returns the resulting events; data to send to the server is taken from
+//! `Connection::transmit()`. `Client` drives a `Connection` over an
+//! asynchronous stream, but it can just as well be driven by blocking I/O
+//! or by a test harness feeding it canned data.
+//!
+//! ```ignore
+//! let mut conn = Connection::new();
+//! let tag = conn.command(CommandBuilder::capability());
+//! socket.write_all(&conn.transmit().unwrap())?;
+//! let n = socket.read(&mut buf)?;
+//! for event in conn.advance(&buf[..n])? {
+//!     // ...
+//! }
+//! ```
+
+use bytes::BytesMut;
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::client::builder::Command;
+use crate::client::session::Mailbox;
+use crate::error::Error;
+use crate::metrics::{self, MetricsObserver};
+use crate::proto::*;
+use crate::record::Recorder;
+use crate::throttle::{RateLimit, Throttle};
+
+#[derive(Debug)]
+pub enum Event {
+    /// The greeting sent by the server when the connection was established.
+    Greeting(ResponseData),
+    /// A response to the command with the given tag. The last response to
+    /// a command is its tagged completion.
+    Response(RequestId, ResponseData),
+    /// A response that was not solicited by the running command, such as
+    /// an EXISTS update.
+    Unsolicited(ResponseData),
+}
+
+// Number of times a queued command may be overtaken by commands of a
+// higher priority before it is sent regardless.
+const MAX_OVERTAKEN: u32 = 8;
+
+/// How urgently a queued command should be sent; see
+/// `Connection::command_with_priority()`.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// Bulk work, such as fetching messages in the background.
+    Background,
+    #[default]
+    Normal,
+    /// Operations the user is waiting for, such as marking a message read.
+    Interactive,
+}
+
+// A command that has been issued, but not completed yet.
+struct InFlight {
+    request_id: RequestId,
+    name: String,
+    priority: Priority,
+    // How often commands queued later have been sent before this one.
+    overtaken: u32,
+    // Only set when there is an observer to report the latency to, since
+    // `Instant` is not available on every platform (wasm32-unknown-unknown).
+    started: Option<Instant>,
+    next_state: Option<State>,
+    solicits: Vec<ResponseKind>,
+    // Positions of the literals in the command; see `Command`.
+    literals: Vec<usize>,
+    // The parts of the command after each synchronizing literal's header,
+    // which are sent once the server asks for them.
+    continuations: VecDeque<BytesMut>,
+    abandoned: bool,
+    // For IDLE: whether the server has confirmed it, and whether it should
+    // be ended, as DONE may only be sent after the confirmation.
+    idling: bool,
+    idle_done: bool,
+}
+
+impl InFlight {
+    fn is_solicited(&self, rsp: &ResponseData) -> bool {
+        match rsp.parsed().kind() {
+            ResponseKind::BodyChunk | ResponseKind::Continue |
+            ResponseKind::Done | ResponseKind::Data => true,
+            kind => self.solicits.contains(&kind),
+        }
+    }
+}
+
+pub struct Connection {
+    codec: ImapCodec,
+    // Received data that has not been decoded yet.
+    input: BytesMut,
+    // Encoded commands that have not been taken by `transmit()` yet.
+    output: BytesMut,
+    greeted: bool,
+    paused: bool,
+    running: Option<InFlight>,
+    queued: VecDeque<(Request, InFlight)>,
+    state: State,
+    request_ids: Box<dyn Iterator<Item = RequestId> + Send>,
+    // Taken from `request_ids` ahead of time, so that its length is known.
+    next_tag: Option<RequestId>,
+    capabilities: Option<Vec<Capability>>,
+    enabled: Vec<Capability>,
+    mailbox: Option<Mailbox>,
+    bye: Option<String>,
+    throttle: Option<Throttle>,
+    observer: Option<Arc<dyn MetricsObserver>>,
+    read_only: bool,
+}
+
+impl Connection {
+    pub fn new() -> Connection {
+        let mut request_ids = IdGenerator::new();
+        let next_tag = request_ids.next();
+        Connection {
+            codec: ImapCodec::default(),
+            input: BytesMut::new(),
+            output: BytesMut::new(),
+            greeted: false,
+            paused: false,
+            running: None,
+            queued: VecDeque::new(),
+            state: State::NotAuthenticated,
+            request_ids: Box::new(request_ids),
+            next_tag,
+            capabilities: None,
+            enabled: vec![],
+            mailbox: None,
+            bye: None,
+            throttle: None,
+            observer: None,
+            read_only: false,
+        }
+    }
+
+    /// Queue `cmd`, returning its tag. Commands are sent one at a time: the
+    /// next one becomes available from `transmit()` once the running
+    /// command has completed.
+    pub fn command(&mut self, cmd: Command) -> RequestId {
+        self.command_with_priority(cmd, Priority::Normal)
+    }
+
+    /// Queue `cmd` like `command()`, but send it before queued commands of
+    /// a lower priority. Commands never overtake or are overtaken by one
+    /// that changes the state, such as SELECT, and a command is sent
+    /// regardless once it has been overtaken a few times, so that
+    /// background work is not starved.
+    pub fn command_with_priority(&mut self, cmd: Command, priority: Priority) -> RequestId {
+        let next_tag = self.request_ids.next();
+        let request_id = mem::replace(&mut self.next_tag, next_tag).expect("tag generator exhausted");
+        let (mut args, next_state, solicits, mut literals) = cmd.to_parts();
+        if self.read_only && args.starts_with(b"SELECT ") {
+            args.splice(..6, b"EXAMINE".iter().copied());
+            literals.iter_mut().for_each(|pos| *pos += 1);
+        }
+        let command = InFlight {
+            request_id: request_id.clone(),
+            name: metrics::command_name(&args),
+            priority,
+            overtaken: 0,
+            started: None,
+            next_state,
+            solicits,
+            literals,
+            continuations: VecDeque::new(),
+            abandoned: false,
+            idling: false,
+            idle_done: false,
+        };
+        self.queued.push_back((Request(request_id.clone(), args), command));
+        request_id
+    }
+
+    /// Stop reporting the responses to the command tagged `id`. A queued
+    /// command is not sent at all; if the command is already running, its
+    /// remaining responses are discarded, except for unsolicited ones.
+    pub fn abandon(&mut self, id: &RequestId) {
+        self.queued.retain(|(_, command)| command.request_id != *id);
+        if let Some(ref mut command) = self.running {
+            if command.request_id == *id {
+                debug!(tag = %id, "abandoning command in flight");
+                command.abandoned = true;
+                // An IDLE command only completes once it is ended.
+                self.idle_done();
+            }
+        }
+    }
+
+    /// End the IDLE command (RFC 2177) by sending DONE, once the server has
+    /// confirmed it, even if the command has not been sent yet. The command
+    /// then completes as usual.
+    pub fn idle_done(&mut self) {
+        let queued = self.queued.iter_mut().map(|(_, command)| command);
+        for command in self.running.iter_mut().chain(queued) {
+            if command.name == "IDLE" && !command.idle_done {
+                command.idle_done = true;
+                if command.idling {
+                    self.codec.encode_idle_done(&mut self.output);
+                }
+            }
+        }
+    }
+
+    /// Answer a continuation request of the running command with `line`,
+    /// such as a SASL response during AUTHENTICATE. The CRLF is added.
+    pub fn respond(&mut self, line: &[u8]) {
+        self.codec.encode_answer(line, &mut self.output);
+    }
+
+    /// Take the data that should be sent to the server next, if any. This
+    /// includes the rest of a command once the server has asked for the
+    /// contents of one of its literals.
+    pub fn transmit(&mut self) -> Option<BytesMut> {
+        let throttled = self.throttle.as_ref().map(|throttle| throttle.delay().is_some()).unwrap_or(false);
+        if self.running.is_none() && !self.paused && !throttled {
+            if let Some((mut request, mut command)) = self.next_queued() {
+                if let Some(ref mut throttle) = self.throttle {
+                    throttle.take(request.1.len());
+                }
+                self.sent(&mut command);
+                if self.has(&Capability::LiteralPlus) {
+                    // Non-synchronizing literals can be sent right away (RFC 7888).
+                    for &pos in command.literals.iter().rev() {
+                        request.1.insert(pos, b'+');
+                    }
+                    command.literals.clear();
+                }
+                let args_start = self.output.len() + (request.0).0.len() + 1;
+                self.codec.encode(request, &mut self.output)
+                    .expect("encoding a command cannot fail");
+                for &pos in command.literals.iter().rev() {
+                    let rest = self.output.split_off(args_start + pos + 3);
+                    command.continuations.push_front(rest);
+                }
+                self.running = Some(command);
+            }
+        }
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(self.output.split())
+        }
+    }
+
+    // Takes the queued command that should be sent next.
+    fn next_queued(&mut self) -> Option<(Request, InFlight)> {
+        // Commands up to the first one changing the state may be reordered.
+        let eligible = match self.queued.iter().position(|(_, command)| command.next_state.is_some()) {
+            Some(0) => 1,
+            Some(pos) => pos,
+            None => self.queued.len(),
+        };
+        let starved = self.queued.iter().take(eligible).position(|(_, command)| command.overtaken >= MAX_OVERTAKEN);
+        if let Some(next) = starved {
+            // Let the other commands start over, so that starved commands
+            // take turns with the ones overtaking them.
+            for (_, command) in self.queued.iter_mut() {
+                command.overtaken = 0;
+            }
+            return self.queued.remove(next);
+        }
+        let mut next = 0;
+        for (i, (_, command)) in self.queued.iter().enumerate().take(eligible) {
+            if command.priority > self.queued[next].1.priority {
+                next = i;
+            }
+        }
+        for (_, command) in self.queued.iter_mut().take(next) {
+            command.overtaken += 1;
+        }
+        self.queued.remove(next)
+    }
+
+    /// Process `input` received from the server, returning the events for
+    /// all responses that are now complete.
+    pub fn advance(&mut self, input: &[u8]) -> io::Result<Vec<Event>> {
+        self.input.extend_from_slice(input);
+        let mut events = vec![];
+        while !self.paused {
+            let rsp = match self.codec.decode(&mut self.input)? {
+                Some(rsp) => rsp,
+                None => break,
+            };
+            self.update(rsp.parsed());
+            if !self.greeted {
+                self.greeted = true;
+                // A server that authenticated the connection by other means,
+                // such as a pipe over SSH, greets with PREAUTH (RFC 3501,
+                // section 7.1.4).
+                if let Response::Data(Status::PreAuth, ..) = *rsp.parsed() {
+                    self.state = State::Authenticated;
+                }
+                if let Response::Data(_, _, Some(text)) = *rsp.parsed() {
+                    let quirks = self.codec.quirks() | Quirks::detect(text);
+                    self.codec = mem::take(&mut self.codec).tolerate(quirks);
+                }
+                events.push(Event::Greeting(rsp));
+                continue;
+            }
+            // The request for a literal is still reported to the command,
+            // so that the progress of long uploads can be followed.
+            if let Response::Continue { .. } = *rsp.parsed() {
+                let next = self.running.as_mut().and_then(|command| command.continuations.pop_front());
+                if let Some(next) = next {
+                    self.output.extend_from_slice(&next);
+                }
+                if let Some(ref mut command) = self.running {
+                    if command.name == "IDLE" && !command.idling {
+                        command.idling = true;
+                        if command.idle_done {
+                            self.codec.encode_idle_done(&mut self.output);
+                        }
+                    }
+                }
+            }
+            let command = match self.running {
+                Some(ref command) => command,
+                None => {
+                    if let Some(tag) = rsp.request_id() {
+                        self.unknown_tag(tag);
+                    }
+                    events.push(Event::Unsolicited(rsp));
+                    continue;
+                },
+            };
+            if rsp.request_id() == Some(&command.request_id) {
+                let command = self.running.take().unwrap();
+                self.complete(&command, &rsp);
+                if !command.abandoned {
+                    events.push(Event::Response(command.request_id, rsp));
+                }
+            } else if let Some(tag) = rsp.request_id() {
+                // A server echoing the wrong tag must not complete, or add
+                // to the results of, the running command.
+                self.unknown_tag(tag);
+                events.push(Event::Unsolicited(rsp));
+            } else if !command.is_solicited(&rsp) {
+                events.push(Event::Unsolicited(rsp));
+            } else if !command.abandoned {
+                events.push(Event::Response(command.request_id.clone(), rsp));
+            }
+        }
+        Ok(events)
+    }
+
+    /// Whether decoding has been paused because a command completed which
+    /// adds a new layer to the transport, as COMPRESS does (RFC 4978). Any
+    /// data received after its completion must go through the new layer;
+    /// see `resume()`.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Resume decoding after the transport has been changed, returning the
+    /// data that was received after the command that paused decoding. It
+    /// should be passed through the new transport layer before being fed
+    /// back to `advance()`.
+    pub fn resume(&mut self) -> BytesMut {
+        self.paused = false;
+        self.input.split()
+    }
+
+    /// How long until the next queued command may be sent, if it is held
+    /// back by the rate limit; see `rate_limit()`.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        if self.running.is_some() || self.paused || self.queued.is_empty() {
+            return None;
+        }
+        self.throttle.as_ref()?.delay()
+    }
+
+    /// Whether there are commands which have not completed yet.
+    pub fn is_busy(&self) -> bool {
+        self.running.is_some() || !self.queued.is_empty()
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Whether the server is known to support `capability`.
+    pub fn has(&self, capability: &Capability) -> bool {
+        match self.capabilities {
+            Some(ref caps) => caps.contains(capability),
+            None => false,
+        }
+    }
+
+    /// The cached capabilities of the server, if they are known.
+    pub fn capabilities(&self) -> Option<&[Capability]> {
+        self.capabilities.as_ref().map(|caps| &caps[..])
+    }
+
+    /// Whether `capability` has been enabled with the ENABLE command.
+    pub fn is_enabled(&self, capability: &Capability) -> bool {
+        self.enabled.contains(capability)
+    }
+
+    /// The text of the BYE response, once the server has announced that
+    /// it is closing the connection, other than in response to LOGOUT.
+    pub fn bye(&self) -> Option<&str> {
+        self.bye.as_deref()
+    }
+
+    /// The state of the selected mailbox, if any, as reported when it was
+    /// selected and updated from all responses since.
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.mailbox.as_ref()
+    }
+
+    /// Limit the rate at which commands are sent; `transmit()` holds back
+    /// commands until the limit allows them. Uses `Instant`, so this is not
+    /// available on wasm32-unknown-unknown.
+    pub fn rate_limit(&mut self, limit: RateLimit) {
+        self.throttle = Some(Throttle::new(limit));
+    }
+
+    /// Take the tags of further commands from `ids` instead of the default
+    /// `IdGenerator`. The tags must be unique among the commands in flight.
+    /// Each tag is taken from `ids` one command ahead, so that the length of
+    /// the next tag is known when splitting long commands.
+    pub fn generate_tags<I>(&mut self, ids: I) where I: Iterator<Item = RequestId> + Send + 'static {
+        self.request_ids = Box::new(ids);
+        self.next_tag = self.request_ids.next();
+    }
+
+    /// The tag the next command will get, if the generator has any left.
+    pub fn next_tag(&self) -> Option<&RequestId> {
+        self.next_tag.as_ref()
+    }
+
+    /// See `ImapCodec::stream_literals_over()`.
+    pub fn stream_literals_over(&mut self, threshold: usize) {
+        self.codec = mem::take(&mut self.codec).stream_literals_over(threshold);
+    }
+
+    /// See `ImapCodec::spool_literals_over()`.
+    pub fn spool_literals_over(&mut self, threshold: usize, dir: &Path) {
+        self.codec = mem::take(&mut self.codec).spool_literals_over(threshold, dir);
+    }
+
+    /// See `ImapCodec::limit_buffer()`.
+    pub fn limit_buffer(&mut self, limit: usize) {
+        self.codec = mem::take(&mut self.codec).limit_buffer(limit);
+    }
+
+    /// Guarantee that no mailbox is modified through this connection, for
+    /// tools such as backups: SELECT is sent as EXAMINE, and `permits()`
+    /// refuses commands such as STORE, EXPUNGE, DELETE and APPEND.
+    pub fn read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails with `Error::ReadOnly` if `cmd` could modify a mailbox or its
+    /// messages and the connection is read-only, or with
+    /// `Error::UidRequired` if `cmd` uses sequence numbers and UIDONLY is
+    /// enabled.
+    pub fn permits(&self, cmd: &Command) -> Result<(), Error> {
+        let name = cmd.name();
+        let uid = name.starts_with("UID ");
+        let verb = name.strip_prefix("UID ").unwrap_or(&name);
+        match verb {
+            "APPEND" | "COPY" | "CREATE" | "DELETE" | "EXPUNGE" | "MOVE" | "RENAME" | "REPLACE" |
+            "SETMETADATA" | "STORE" if self.read_only => Err(Error::ReadOnly { command: name }),
+            "COPY" | "FETCH" | "MOVE" | "REPLACE" | "SEARCH" | "SORT" | "STORE" | "THREAD"
+                if !uid && self.is_enabled(&Capability::UidOnly) =>
+            {
+                Err(Error::UidRequired { command: name })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// See `ImapCodec::lenient()`.
+    pub fn lenient(&mut self) {
+        self.codec = mem::take(&mut self.codec).lenient();
+    }
+
+    /// Tolerate `quirks` in further responses, instead of those detected
+    /// from the server greeting with `Quirks::detect()`.
+    pub fn tolerate(&mut self, quirks: Quirks) {
+        self.codec = mem::take(&mut self.codec).tolerate(quirks);
+    }
+
+    /// The quirks tolerated in responses.
+    pub fn quirks(&self) -> Quirks {
+        self.codec.quirks()
+    }
+
+    /// Record all further data exchanged with the server.
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.codec = mem::take(&mut self.codec).record_to(recorder);
+    }
+
+    /// Report activity on the connection to `observer`.
+    pub fn observe(&mut self, observer: Arc<dyn MetricsObserver>) {
+        self.codec = mem::take(&mut self.codec).observe(observer.clone());
+        self.observer = Some(observer);
+    }
+
+    fn sent(&mut self, command: &mut InFlight) {
+        let authenticating = self.state == State::NotAuthenticated &&
+            command.next_state == Some(State::Authenticated);
+        let unauthenticating = self.state != State::NotAuthenticated &&
+            command.next_state == Some(State::NotAuthenticated);
+        if authenticating || unauthenticating {
+            // Servers may advertise different capabilities after login
+            // (RFC 3501, section 6.2.3) and after UNAUTHENTICATE (RFC 8437).
+            self.capabilities = None;
+        }
+        if command.next_state == Some(State::Selected) {
+            // The selected mailbox is closed as soon as another one is
+            // selected, even if that fails (RFC 3501, section 6.3.1).
+            self.mailbox = Some(Mailbox::default());
+        }
+        if self.observer.is_some() {
+            command.started = Some(Instant::now());
+        }
+    }
+
+    fn complete(&mut self, command: &InFlight, rsp: &ResponseData) {
+        let status = match *rsp.parsed() {
+            Response::Done(_, ref status, ..) => status,
+            _ => return,
+        };
+        if let (Some(observer), Some(started)) = (self.observer.as_ref(), command.started) {
+            observer.command_completed(&command.name, status, started.elapsed());
+        }
+        if !matches!(*status, Status::Ok) {
+            if command.next_state == Some(State::Selected) {
+                self.mailbox = None;
+            }
+            return;
+        }
+        if let Some(state) = command.next_state {
+            if state == State::NotAuthenticated {
+                // UNAUTHENTICATE also ends any extensions enabled with ENABLE.
+                self.enabled.clear();
+            }
+            if state != State::Selected {
+                self.mailbox = None;
+            }
+            self.state = state;
+        }
+        if command.name == "COMPRESS" {
+            self.paused = true;
+        }
+    }
+
+    fn unknown_tag(&self, tag: &RequestId) {
+        warn!(tag = %tag.0, "response with a tag of no command in flight");
+        if let Some(ref observer) = self.observer {
+            observer.unknown_tag(&tag.0);
+        }
+    }
+
+    fn update(&mut self, rsp: &Response) {
+        match *rsp {
+            Response::Capabilities(ref caps) |
+            Response::Data(_, Some(ResponseCode::Capabilities(ref caps)), _) |
+            Response::Done(_, _, Some(ResponseCode::Capabilities(ref caps)), _) => {
+                self.capabilities = Some(caps.iter().map(|&cap| Capability::from(cap)).collect());
+            },
+            Response::Enabled(ref caps) => {
+                self.enabled.extend(caps.iter().map(|&cap| Capability::from(cap)));
+            },
+            // The BYE sent in response to LOGOUT is expected.
+            Response::Data(Status::Bye, _, text) if !self.running.iter().any(|command| command.name == "LOGOUT") => {
+                self.bye = Some(text.unwrap_or("").to_string());
+            },
+            _ => {},
+        }
+        if let Some(ref mut mailbox) = self.mailbox {
+            mailbox.update(rsp);
+        }
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Connection {
+        Connection::new()
+    }
+}
+
+/// Generates the tags of commands from a prefix and a counter, such as
+/// `A0001`.
+pub struct IdGenerator {
+    prefix: String,
+    width: usize,
+    next: u64,
+}
+
+impl IdGenerator {
+    pub fn new() -> IdGenerator {
+        IdGenerator::with_prefix("A", 4)
+    }
+
+    /// Generate tags made of `prefix` and a counter of `width` digits, which
+    /// wraps around once all digits are used.
+    ///
+    /// Panics if `prefix` contains characters not allowed in tags, or if
+    /// `width` is not between 1 and 19.
+    pub fn with_prefix(prefix: &str, width: usize) -> IdGenerator {
+        assert!(prefix.bytes().all(is_tag_char), "invalid tag prefix {:?}", prefix);
+        assert!((1..=19).contains(&width), "invalid tag counter width {}", width);
+        IdGenerator { prefix: prefix.to_string(), width, next: 0 }
+    }
+}
+
+// Whether `b` may appear in a tag (RFC 3501, section 9): any ASTRING-CHAR
+// other than "+".
+fn is_tag_char(b: u8) -> bool {
+    b.is_ascii_graphic() && !b"(){%*\"\\+".contains(&b)
+}
+
+impl Default for IdGenerator {
+    fn default() -> IdGenerator {
+        IdGenerator::new()
+    }
+}
+
+impl Iterator for IdGenerator {
+    type Item = RequestId;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next += 1;
+        let counter = self.next % 10u64.pow(self.width as u32);
+        Some(RequestId(format!("{}{:0width$}", self.prefix, counter, width = self.width).into()))
+    }
+}
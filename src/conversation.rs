@@ -0,0 +1,245 @@
+//! Conversations assembled from the THREAD results (RFC 5256) of one or
+//! more mailboxes, for rendering a threaded message list.
+//!
+//! ```ignore
+//! let mut conversations = Conversations::new();
+//! for mailbox in &["INBOX", "Sent"] {
+//!     session = session.examine(mailbox).await?.0;
+//!     let threads = session.uid_thread(ThreadAlgorithm::References, &SearchCriteria::All).await?;
+//!     conversations.add_with_email_ids(mailbox, &threads, &email_ids[mailbox]);
+//! }
+//! for entry in conversations.iter() {
+//!     println!("{:indent$}{:?}", "", entry.messages, indent = 2 * entry.depth);
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use crate::proto::Thread;
+
+/// A message in a mailbox.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MessageRef {
+    pub mailbox: String,
+    pub uid: u32,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    EmailId(String),
+    Uid(String, u32),
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    email_id: Option<String>,
+    // Empty for a message that is missing from the results, but has replies.
+    messages: Vec<MessageRef>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Threads of several mailboxes, merged into conversations. A message is
+/// identified by its mailbox and UID or, if known, by its EMAILID (RFC
+/// 8474), so that copies of a message in several mailboxes are merged.
+#[derive(Clone, Debug, Default)]
+pub struct Conversations {
+    nodes: Vec<Node>,
+    index: HashMap<Key, usize>,
+}
+
+/// A message of a conversation, as returned by `Conversations::iter()`.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<'a> {
+    /// The position of the conversation among all conversations.
+    pub conversation: usize,
+    /// The depth of the message in the conversation, 0 for its start.
+    pub depth: usize,
+    pub email_id: Option<&'a str>,
+    /// The copies of the message, in the order their mailboxes were added.
+    pub messages: &'a [MessageRef],
+}
+
+impl Conversations {
+    pub fn new() -> Conversations {
+        Conversations::default()
+    }
+
+    /// Add the threads of `mailbox`, which must be identified by UID, as
+    /// returned by `uid_thread()`.
+    pub fn add(&mut self, mailbox: &str, threads: &[Thread]) {
+        self.add_with_email_ids(mailbox, threads, &HashMap::new());
+    }
+
+    /// Like `add()`, with the EMAILIDs of messages of `mailbox` by UID.
+    pub fn add_with_email_ids(&mut self, mailbox: &str, threads: &[Thread], email_ids: &HashMap<u32, String>) {
+        for thread in threads {
+            self.insert(mailbox, thread, None, email_ids);
+        }
+    }
+
+    /// The number of conversations.
+    pub fn len(&self) -> usize {
+        self.nodes.iter().filter(|node| node.parent.is_none()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// All messages, conversation by conversation in the order they were
+    /// first added, each depth first.
+    pub fn iter(&self) -> Iter<'_> {
+        let roots = (0..self.nodes.len()).filter(|&i| self.nodes[i].parent.is_none());
+        let mut stack: Vec<_> = roots.enumerate().map(|(conversation, i)| (conversation, 0, i)).collect();
+        stack.reverse();
+        Iter { conversations: self, stack }
+    }
+
+    fn insert(&mut self, mailbox: &str, thread: &Thread, parent: Option<usize>,
+              email_ids: &HashMap<u32, String>) {
+        let node = match thread.id {
+            Some(uid) => {
+                let email_id = email_ids.get(&uid);
+                let key = match email_id {
+                    Some(id) => Key::EmailId(id.clone()),
+                    None => Key::Uid(mailbox.to_string(), uid),
+                };
+                let node = match self.index.get(&key) {
+                    Some(&node) => node,
+                    None => {
+                        self.index.insert(key, self.nodes.len());
+                        self.push(email_id.cloned())
+                    },
+                };
+                let message = MessageRef { mailbox: mailbox.to_string(), uid };
+                if !self.nodes[node].messages.contains(&message) {
+                    self.nodes[node].messages.push(message);
+                }
+                node
+            },
+            None => self.push(None),
+        };
+        if let Some(parent) = parent {
+            self.link(parent, node);
+        }
+        for child in &thread.children {
+            self.insert(mailbox, child, Some(node), email_ids);
+        }
+    }
+
+    fn push(&mut self, email_id: Option<String>) -> usize {
+        self.nodes.push(Node { email_id, messages: vec![], parent: None, children: vec![] });
+        self.nodes.len() - 1
+    }
+
+    // Makes `child` a reply to `parent`, unless it already is a reply, as
+    // the first mailbox to place a message wins.
+    fn link(&mut self, parent: usize, child: usize) {
+        if self.nodes[child].parent.is_some() {
+            return;
+        }
+        let mut ancestor = Some(parent);
+        while let Some(node) = ancestor {
+            if node == child {
+                return;
+            }
+            ancestor = self.nodes[node].parent;
+        }
+        self.nodes[child].parent = Some(parent);
+        self.nodes[parent].children.push(child);
+    }
+}
+
+/// Iterator over the messages of `Conversations`; see `Conversations::iter()`.
+pub struct Iter<'a> {
+    conversations: &'a Conversations,
+    stack: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Entry<'a>;
+    fn next(&mut self) -> Option<Entry<'a>> {
+        loop {
+            let (conversation, depth, i) = self.stack.pop()?;
+            let node = &self.conversations.nodes[i];
+            // Replies to a missing message take its place.
+            let child_depth = if node.messages.is_empty() { depth } else { depth + 1 };
+            self.stack.extend(node.children.iter().rev().map(|&child| (conversation, child_depth, child)));
+            if !node.messages.is_empty() {
+                return Some(Entry {
+                    conversation,
+                    depth,
+                    email_id: node.email_id.as_deref(),
+                    messages: &node.messages,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Conversations;
+    use crate::parser::parse_response;
+    use crate::proto::{Response, Thread};
+
+    fn threads(line: &str) -> Vec<Thread> {
+        match parse_response(format!("* THREAD {}\r\n", line).as_bytes()) {
+            Ok((_, Response::Thread(threads))) => threads,
+            rsp => panic!("unexpected response {:?}", rsp),
+        }
+    }
+
+    // The conversation, depth, mailboxes and UIDs of a message.
+    type Placed<'a> = (usize, usize, Vec<(&'a str, u32)>);
+
+    fn entries(conversations: &Conversations) -> Vec<Placed<'_>> {
+        conversations.iter().map(|entry| {
+            let messages = entry.messages.iter().map(|m| (&m.mailbox[..], m.uid)).collect();
+            (entry.conversation, entry.depth, messages)
+        }).collect()
+    }
+
+    // The example of RFC 5256, section 4.
+    #[test]
+    fn rfc_example() {
+        let mut conversations = Conversations::new();
+        conversations.add("INBOX", &threads("(2)(3 6 (4 23)(44 7 96))"));
+        assert_eq!(conversations.len(), 2);
+        let expected = [(0, 0, 2), (1, 0, 3), (1, 1, 6), (1, 2, 4), (1, 3, 23), (1, 2, 44), (1, 3, 7), (1, 4, 96)];
+        let expected: Vec<_> = expected.iter().map(|&(c, d, uid)| (c, d, vec![("INBOX", uid)])).collect();
+        assert_eq!(entries(&conversations), expected);
+    }
+
+    // Replies to a message missing from the results start the conversation.
+    #[test]
+    fn missing_parent() {
+        let mut conversations = Conversations::new();
+        conversations.add("INBOX", &threads("((3)(5 8))"));
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(entries(&conversations), [
+            (0, 0, vec![("INBOX", 3)]),
+            (0, 0, vec![("INBOX", 5)]),
+            (0, 1, vec![("INBOX", 8)]),
+        ]);
+    }
+
+    // Copies of a message in several mailboxes are merged by EMAILID.
+    #[test]
+    fn merge_by_email_id() {
+        let mut conversations = Conversations::new();
+        let inbox_ids: HashMap<_, _> = vec![(1, "M1".to_string()), (2, "M2".to_string())].into_iter().collect();
+        let sent_ids: HashMap<_, _> = vec![(7, "M2".to_string()), (8, "M3".to_string())].into_iter().collect();
+        conversations.add_with_email_ids("INBOX", &threads("(1 2)"), &inbox_ids);
+        conversations.add_with_email_ids("Sent", &threads("(7 8)"), &sent_ids);
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(entries(&conversations), [
+            (0, 0, vec![("INBOX", 1)]),
+            (0, 1, vec![("INBOX", 2), ("Sent", 7)]),
+            (0, 2, vec![("Sent", 8)]),
+        ]);
+    }
+}
@@ -0,0 +1,183 @@
+use bytes::{Buf, BytesMut};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use futures::ready;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Size of the chunks read from the underlying stream before inflating.
+const READ_CHUNK: usize = 4096;
+
+/// Wraps a stream in raw DEFLATE compression for both directions, as
+/// specified for the IMAP COMPRESS extension (RFC 4978).
+///
+/// Outgoing data is buffered in the compressor until the stream is flushed,
+/// at which point a sync flush is done so that the server can decompress the
+/// complete command without waiting for more data.
+pub struct DeflateStream<S> {
+    inner: S,
+    compress: Compress,
+    decompress: Decompress,
+    // Compressed data that has been read from `inner` but not yet inflated.
+    read_buf: BytesMut,
+    // Compressed data that has not yet been written to `inner`.
+    write_buf: Vec<u8>,
+    needs_sync: bool,
+}
+
+impl<S> DeflateStream<S> {
+    /// Create a compressed stream on top of `inner`. Any bytes that were
+    /// already read from `inner` after the server accepted the COMPRESS
+    /// command must be passed as `pending`, since they are compressed.
+    pub fn new(inner: S, pending: BytesMut) -> DeflateStream<S> {
+        DeflateStream {
+            inner,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            read_buf: pending,
+            write_buf: Vec::new(),
+            needs_sync: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+fn data_error<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid DEFLATE data")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+                 -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            // Even without further input, the inflater may hold output that
+            // did not fit into the last buffer.
+            let (before_in, before_out) = (this.decompress.total_in(), this.decompress.total_out());
+            let status = this.decompress.decompress(
+                &this.read_buf, buf.initialize_unfilled(), FlushDecompress::None,
+            ).map_err(data_error)?;
+            let consumed = (this.decompress.total_in() - before_in) as usize;
+            let produced = (this.decompress.total_out() - before_out) as usize;
+            this.read_buf.advance(consumed);
+            if produced > 0 {
+                buf.advance(produced);
+                return Poll::Ready(Ok(()));
+            }
+            if let Status::StreamEnd = status {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.read_buf.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.needs_sync {
+            loop {
+                self.write_buf.reserve(64);
+                self.compress.compress_vec(&[], &mut self.write_buf, FlushCompress::Sync)
+                    .map_err(data_error)?;
+                if self.write_buf.len() < self.write_buf.capacity() {
+                    break;
+                }
+            }
+            self.needs_sync = false;
+        }
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                      "failed to write compressed data")));
+            }
+            self.write_buf.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut consumed = 0;
+        while consumed < buf.len() {
+            this.write_buf.reserve(buf.len() - consumed + 64);
+            let before = this.compress.total_in();
+            this.compress.compress_vec(&buf[consumed..], &mut this.write_buf, FlushCompress::None)
+                .map_err(data_error)?;
+            consumed += (this.compress.total_in() - before) as usize;
+        }
+        this.needs_sync = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use futures::executor::block_on;
+    use futures::future;
+
+    use tokio::io::{self as aio, AsyncReadExt, AsyncWriteExt};
+
+    use super::{DeflateStream, READ_CHUNK};
+
+    // Data that inflates to much more than fits into one read still arrives
+    // in full, without waiting for more input.
+    #[test]
+    fn round_trip_large_payload() {
+        let payload = b"* 1 FETCH (FLAGS (\\Seen))\r\n".repeat(64 * READ_CHUNK);
+        let (client, server) = aio::duplex(READ_CHUNK);
+        let mut writer = DeflateStream::new(client, BytesMut::new());
+        let mut reader = DeflateStream::new(server, BytesMut::new());
+        let write = async {
+            writer.write_all(&payload).await.unwrap();
+            writer.flush().await.unwrap();
+        };
+        let read = async {
+            let mut received = vec![0; payload.len()];
+            for chunk in received.chunks_mut(1000) {
+                reader.read_exact(chunk).await.unwrap();
+            }
+            received
+        };
+        let ((), received) = block_on(future::join(write, read));
+        assert!(received == payload);
+    }
+}
@@ -0,0 +1,136 @@
+//! Downloads of large messages in chunks, which can be resumed on a new
+//! connection after the previous one was lost. The octets are written as
+//! received, so 8-bit and binary messages are preserved exactly, even when
+//! a chunk ends inside a multibyte character.
+//!
+//! ```ignore
+//! let mut download = match saved {
+//!     Some(state) => Download::resume(state),
+//!     None => Download::new(mailbox.uid_validity.unwrap(), uid),
+//! };
+//! download.run(&mut session, &mailbox, &mut file, |state| save(state)).await?;
+//! ```
+
+use futures::StreamExt;
+
+use std::io::{self, Write};
+
+use crate::client::session::{Mailbox, Selected, Session};
+use crate::proto::{Attribute, AttributeValue};
+
+/// The progress of a download, to persist in order to resume it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DownloadState {
+    pub uid_validity: u32,
+    pub uid: u32,
+    /// The RFC822.SIZE of the message, once known.
+    pub size: Option<u32>,
+    /// The number of octets received so far.
+    pub received: u32,
+}
+
+impl DownloadState {
+    pub fn is_complete(&self) -> bool {
+        self.size == Some(self.received)
+    }
+}
+
+/// Fetches a message with `BODY.PEEK[]<offset.length>`, one chunk at a time.
+pub struct Download {
+    state: DownloadState,
+    chunk_size: u32,
+}
+
+impl Download {
+    /// Download the message `uid` of a mailbox with the given UIDVALIDITY.
+    pub fn new(uid_validity: u32, uid: u32) -> Download {
+        Download::resume(DownloadState { uid_validity, uid, size: None, received: 0 })
+    }
+
+    /// Continue a download from a persisted `state`.
+    pub fn resume(state: DownloadState) -> Download {
+        Download { state, chunk_size: 64 * 1024 }
+    }
+
+    /// Set the number of octets to fetch at a time (64 KiB by default).
+    pub fn chunk_size(mut self, chunk_size: u32) -> Download {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn state(&self) -> &DownloadState {
+        &self.state
+    }
+
+    /// Fetch the rest of the message from `session`, where `mailbox` is the
+    /// selected mailbox, and write it to `sink`. `checkpoint` is called with
+    /// the new state after each chunk has been written, so that it can be
+    /// persisted. Fails if the UIDVALIDITY of `mailbox` changed, if the
+    /// message no longer exists, or if the server sends more or fewer octets
+    /// than the RFC822.SIZE of the message.
+    pub async fn run<W, F>(&mut self, session: &mut Session<Selected>, mailbox: &Mailbox, sink: &mut W,
+                           mut checkpoint: F) -> io::Result<()>
+            where W: Write, F: FnMut(&DownloadState) {
+        if mailbox.uid_validity != Some(self.state.uid_validity) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "UIDVALIDITY of the mailbox changed"));
+        }
+        while !self.state.is_complete() {
+            let (size, data) = self.fetch_chunk(session).await?;
+            if let Some(size) = size {
+                if self.state.size.is_some_and(|known| known != size) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "message size changed"));
+                }
+                self.state.size = Some(size);
+            }
+            let size = match self.state.size {
+                Some(size) => size,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "no RFC822.SIZE received")),
+            };
+            let received = self.state.received + data.len() as u32;
+            if received > size || data.is_empty() && received < size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message does not match its RFC822.SIZE"));
+            }
+            sink.write_all(&data)?;
+            self.state.received = received;
+            checkpoint(&self.state);
+        }
+        Ok(())
+    }
+
+    // Returns the RFC822.SIZE, if requested, and the raw contents of the
+    // next chunk, which must start at the requested offset.
+    async fn fetch_chunk(&self, session: &mut Session<Selected>) -> io::Result<(Option<u32>, Vec<u8>)> {
+        let mut items = vec![Attribute::BodyPeekPartial {
+            section: String::new(),
+            offset: self.state.received,
+            len: self.chunk_size,
+        }];
+        if self.state.size.is_none() {
+            items.push(Attribute::Rfc822Size);
+        }
+        let mut fetched = session.uid_fetch(&self.state.uid.to_string(), items);
+        let mut chunk = None;
+        while let Some(message) = fetched.next().await {
+            let message = message?;
+            if message.uid() != Some(self.state.uid) {
+                continue;
+            }
+            let mut size = None;
+            let mut data = vec![];
+            for attr in message.attributes() {
+                match *attr {
+                    AttributeValue::Rfc822Size(n) => size = Some(n),
+                    AttributeValue::BodySection { section: None, index, data: Some(contents) } => {
+                        if index != Some(self.state.received) || contents.len() > self.chunk_size as usize {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk does not match the request"));
+                        }
+                        data = contents.to_vec();
+                    },
+                    _ => {},
+                }
+            }
+            chunk = Some((size, data));
+        }
+        chunk.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "message no longer exists"))
+    }
+}
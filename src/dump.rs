@@ -0,0 +1,133 @@
+//! Parsing captured server output offline, to find out where the parser
+//! fails on the responses of a particular server.
+//!
+//! The capture must contain the data sent by the server, and nothing else,
+//! such as the output of `openssl s_client` with its own messages removed,
+//! or the server side of a TCP stream extracted from a packet capture.
+//!
+//! ```ignore
+//! for parsed in parse_session(File::open("capture.txt")?) {
+//!     match parsed {
+//!         Ok(parsed) => println!("{}: {:?}", parsed.offset, parsed.response.parsed()),
+//!         Err(err) => println!("{}", err),
+//!     }
+//! }
+//! ```
+
+use bytes::BytesMut;
+
+use tokio_util::codec::Decoder;
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::proto::{ImapCodec, ResponseData};
+
+// Size of the chunks read from the capture.
+const READ_CHUNK: usize = 8192;
+
+/// A response found in the capture, starting `offset` bytes into it.
+#[derive(Debug)]
+pub struct Parsed {
+    pub offset: usize,
+    pub response: ResponseData,
+}
+
+/// The part of the capture, `offset` bytes into it, where parsing failed.
+/// `line` is the line it starts with. If `incomplete` is set, the capture
+/// ends in the middle of a response instead. It serves as the inner error
+/// of an `io::Error`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Malformed {
+    pub offset: usize,
+    pub line: String,
+    pub incomplete: bool,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.incomplete {
+            write!(f, "incomplete response at byte {}: {}", self.offset, self.line)
+        } else {
+            write!(f, "malformed response at byte {}: {}", self.offset, self.line)
+        }
+    }
+}
+
+impl error::Error for Malformed {}
+
+/// Parse the server output captured in `reader`. The returned iterator
+/// yields the responses, and stops after the first part that cannot be
+/// parsed, which is reported as an error carrying a `Malformed`.
+pub fn parse_session<R: Read>(reader: R) -> SessionParser<R> {
+    SessionParser {
+        reader,
+        codec: ImapCodec::default(),
+        buf: BytesMut::new(),
+        offset: 0,
+        done: false,
+    }
+}
+
+/// Yields the responses in a capture; see `parse_session()`.
+pub struct SessionParser<R> {
+    reader: R,
+    codec: ImapCodec,
+    // Data read from the capture which has not been parsed yet.
+    buf: BytesMut,
+    // Offset of the start of `buf` in the capture.
+    offset: usize,
+    done: bool,
+}
+
+impl<R> SessionParser<R> {
+    fn malformed(&mut self, incomplete: bool) -> io::Error {
+        self.done = true;
+        let line = self.buf[..].split(|&b| b == b'\n').next().unwrap_or(&[]);
+        let malformed = Malformed {
+            offset: self.offset,
+            line: String::from_utf8_lossy(line).trim_end().to_string(),
+            incomplete,
+        };
+        io::Error::new(io::ErrorKind::InvalidData, malformed)
+    }
+}
+
+impl<R: Read> Iterator for SessionParser<R> {
+    type Item = io::Result<Parsed>;
+    fn next(&mut self) -> Option<io::Result<Parsed>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let buffered = self.buf.len();
+            match self.codec.decode(&mut self.buf) {
+                Ok(Some(response)) => {
+                    let offset = self.offset;
+                    self.offset += buffered - self.buf.len();
+                    return Some(Ok(Parsed { offset, response }));
+                },
+                Ok(None) => {},
+                Err(_) => return Some(Err(self.malformed(false))),
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            let read = match self.reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                },
+            };
+            if read == 0 {
+                if self.buf.is_empty() {
+                    self.done = true;
+                    return None;
+                }
+                return Some(Err(self.malformed(true)));
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
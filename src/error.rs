@@ -0,0 +1,145 @@
+//! Classes of failures. The client returns `io::Error`s, like the I/O
+//! traits it is built on; the errors it produces itself carry an `Error`,
+//! which `Error::from()` recovers, so that callers can match on it.
+//!
+//! ```ignore
+//! match session.create("Archive").await.map_err(Error::from) {
+//!     Err(Error::No { text, .. }) => println!("server refused: {}", text),
+//!     Err(Error::ConnectionClosed) | Err(Error::Bye { .. }) => reconnect().await?,
+//!     result => result?,
+//! }
+//! ```
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use bytes::BytesMut;
+
+use crate::client::session::Referral;
+use crate::proto::ResponseCode;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A response from the server could not be parsed.
+    Parse(String),
+    /// The TLS handshake failed.
+    Tls(Box<dyn error::Error + Send + Sync>),
+    Io(io::Error),
+    /// A command failed with a NO response. `code` is the response code,
+    /// such as `TRYCREATE`, if any.
+    No { command: String, code: Option<String>, text: String },
+    /// A message was too large to be stored, as indicated by a `TOOBIG`
+    /// code. `limit` is the maximum size the server announced, if any.
+    TooBig { command: String, limit: Option<u64>, text: String },
+    /// A command was rejected with a BAD response.
+    Bad { command: String, code: Option<String>, text: String },
+    /// The server closed the connection after a BYE response.
+    Bye { text: String },
+    Timeout,
+    /// The server closed the connection without a BYE response.
+    ConnectionClosed,
+    /// The operation is not possible in the current state of the client.
+    StateError(String),
+    /// The server does not support a required protocol version.
+    Unsupported(String),
+    /// The command would modify a mailbox, which the client refuses to do
+    /// in read-only mode; see `Client::read_only()`.
+    ReadOnly { command: String },
+    /// The command addresses messages by sequence number, which the server
+    /// rejects once UIDONLY is enabled; the UID form must be used instead.
+    UidRequired { command: String },
+}
+
+impl Error {
+    pub(crate) fn code(code: &Option<ResponseCode>) -> Option<String> {
+        let code = code.as_ref()?;
+        let mut dst = BytesMut::new();
+        crate::server::encode_code(code, &mut dst);
+        Some(String::from_utf8_lossy(&dst).into_owned())
+    }
+
+    /// The charsets the server supports, if a command such as SEARCH failed
+    /// with a `BADCHARSET` code; empty if the server did not list them. A
+    /// search can then be retried with one of these.
+    pub fn supported_charsets(&self) -> Option<Vec<String>> {
+        let code = match *self {
+            Error::No { code: Some(ref code), .. } | Error::Bad { code: Some(ref code), .. } => code,
+            _ => return None,
+        };
+        let list = match code.strip_prefix("BADCHARSET") {
+            Some(list) => list.trim_start().trim_start_matches('(').trim_end_matches(')'),
+            None => return None,
+        };
+        Some(list.split_whitespace().map(|charset| charset.trim_matches('"').to_string()).collect())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::Parse(ref msg) => write!(f, "invalid response: {}", msg),
+            Error::Tls(ref err) => write!(f, "TLS error: {}", err),
+            Error::Io(ref err) => err.fmt(f),
+            Error::No { ref command, ref text, .. } | Error::TooBig { ref command, ref text, .. } => {
+                write!(f, "{} failed: {}", command, text)
+            },
+            Error::Bad { ref command, ref text, .. } => write!(f, "{} rejected: {}", command, text),
+            Error::Bye { ref text } => write!(f, "connection closed by server: {}", text),
+            Error::Timeout => write!(f, "timed out"),
+            Error::ConnectionClosed => write!(f, "connection closed by server"),
+            Error::StateError(ref msg) | Error::Unsupported(ref msg) => f.write_str(msg),
+            Error::ReadOnly { ref command } => write!(f, "{} refused in read-only mode", command),
+            Error::UidRequired { ref command } => write!(f, "{} requires UIDs in UIDONLY mode", command),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Tls(ref err) => Some(&**err),
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::Parse(_) => io::ErrorKind::InvalidData,
+            Error::Bye { .. } | Error::ConnectionClosed => io::ErrorKind::UnexpectedEof,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::ReadOnly { .. } => io::ErrorKind::PermissionDenied,
+            Error::UidRequired { .. } => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Recovers the `Error` carried by `err`, or classifies it by its kind.
+/// A failure with a referral is a `No` with a `REFERRAL` code.
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let kind = err.kind();
+        if err.get_ref().map(|inner| inner.is::<Error>()).unwrap_or(false) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        if err.get_ref().map(|inner| inner.is::<Referral>()).unwrap_or(false) {
+            let referral = err.into_inner().unwrap().downcast::<Referral>().unwrap();
+            return Error::No {
+                command: referral.command,
+                code: Some(format!("REFERRAL {}", referral.url)),
+                text: referral.text,
+            };
+        }
+        match kind {
+            io::ErrorKind::TimedOut => Error::Timeout,
+            io::ErrorKind::UnexpectedEof => Error::ConnectionClosed,
+            _ => Error::Io(err),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Flag changes for many messages, sent as few STORE commands as possible.
+//!
+//! ```ignore
+//! let mut batch = FlagBatch::new();
+//! for uid in read {
+//!     batch.add(uid, "\\Seen");
+//! }
+//! batch.remove(42, "\\Flagged");
+//! batch.flush(&mut session).await?;
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+use crate::client::builder::SequenceSet;
+use crate::client::session::{Selected, Session};
+use crate::proto::StoreType;
+
+#[derive(Clone, Debug, Default)]
+struct Change {
+    replace: Option<BTreeSet<String>>,
+    add: BTreeSet<String>,
+    remove: BTreeSet<String>,
+}
+
+/// Pending flag changes for messages of the selected mailbox, by UID.
+#[derive(Clone, Debug, Default)]
+pub struct FlagBatch {
+    changes: BTreeMap<u32, Change>,
+}
+
+impl FlagBatch {
+    pub fn new() -> FlagBatch {
+        FlagBatch::default()
+    }
+
+    /// Add `flag` to message `uid`, undoing an earlier `remove()`.
+    pub fn add(&mut self, uid: u32, flag: &str) {
+        let change = self.changes.entry(uid).or_default();
+        match change.replace {
+            Some(ref mut flags) => {
+                flags.insert(flag.to_string());
+            },
+            None => {
+                change.remove.remove(flag);
+                change.add.insert(flag.to_string());
+            },
+        }
+    }
+
+    /// Remove `flag` from message `uid`, undoing an earlier `add()`.
+    pub fn remove(&mut self, uid: u32, flag: &str) {
+        let change = self.changes.entry(uid).or_default();
+        match change.replace {
+            Some(ref mut flags) => {
+                flags.remove(flag);
+            },
+            None => {
+                change.add.remove(flag);
+                change.remove.insert(flag.to_string());
+            },
+        }
+    }
+
+    /// Set the flags of message `uid` to `flags`, superseding earlier
+    /// changes.
+    pub fn replace(&mut self, uid: u32, flags: &[&str]) {
+        self.changes.insert(uid, Change {
+            replace: Some(flags.iter().map(|flag| flag.to_string()).collect()),
+            ..Change::default()
+        });
+    }
+
+    /// The number of messages with pending changes.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The STORE commands that apply the pending changes, as the UID set,
+    /// the type of change and the flags. Flags added to or removed from the
+    /// same messages are combined into one command, whose UID set coalesces
+    /// adjacent UIDs.
+    pub fn commands(&self) -> Vec<(String, StoreType, Vec<String>)> {
+        let mut replaced: BTreeMap<Vec<String>, Vec<u32>> = BTreeMap::new();
+        let mut added: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+        let mut removed: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+        for (&uid, change) in &self.changes {
+            if let Some(ref flags) = change.replace {
+                replaced.entry(flags.iter().cloned().collect()).or_default().push(uid);
+            }
+            for flag in &change.add {
+                added.entry(flag).or_default().push(uid);
+            }
+            for flag in &change.remove {
+                removed.entry(flag).or_default().push(uid);
+            }
+        }
+        let mut commands: Vec<_> = replaced.into_iter()
+            .map(|(flags, uids)| (uids.into_iter().collect::<SequenceSet>().to_string(), StoreType::Replace, flags))
+            .collect();
+        for (store_type, by_flag) in [(StoreType::Add, added), (StoreType::Remove, removed)] {
+            let mut by_set: BTreeMap<Vec<u32>, Vec<String>> = BTreeMap::new();
+            for (flag, uids) in by_flag {
+                by_set.entry(uids).or_default().push(flag.to_string());
+            }
+            commands.extend(by_set.into_iter()
+                .map(|(uids, flags)| (uids.into_iter().collect::<SequenceSet>().to_string(), store_type, flags)));
+        }
+        commands
+    }
+
+    /// Send the pending changes to `session`, returning the number of STORE
+    /// commands sent. The changes are only cleared once all commands have
+    /// succeeded; since they are idempotent, a failed flush can be retried.
+    pub async fn flush(&mut self, session: &mut Session<Selected>) -> io::Result<usize> {
+        let commands = self.commands();
+        for (set, store_type, flags) in &commands {
+            let flags: Vec<&str> = flags.iter().map(|flag| flag.as_str()).collect();
+            session.uid_store(set, *store_type, &flags).await?;
+        }
+        self.changes.clear();
+        Ok(commands.len())
+    }
+}
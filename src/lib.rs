@@ -1,16 +1,60 @@
-extern crate bytes;
-#[macro_use]
-extern crate futures;
-extern crate futures_state_stream;
-extern crate native_tls;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 #[macro_use]
-extern crate nom;
-extern crate tokio_core;
-extern crate tokio_io;
-extern crate tokio_tls;
+extern crate tracing;
 
+#[cfg(feature = "std")]
 pub mod client;
-mod parser;
+#[cfg(feature = "std")]
+mod codec;
+#[cfg(feature = "std")]
+pub mod connection;
+#[cfg(feature = "std")]
+pub mod conversation;
+#[cfg(feature = "std")]
+mod deflate;
+#[cfg(feature = "std")]
+pub mod download;
+#[cfg(feature = "std")]
+pub mod dump;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod flags;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "mime")]
+pub mod mime;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub mod net;
+#[cfg(feature = "std")]
+pub mod parallel;
 pub mod proto;
+#[cfg(feature = "std")]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod redact;
+#[cfg(feature = "std")]
+pub mod sasl;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod sync;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod throttle;
+#[cfg(feature = "std")]
+pub mod tree;
+#[cfg(feature = "std")]
+pub mod uid_map;
+#[cfg(feature = "std")]
+pub mod validity;
+
+pub use imap_proto::{parser, utf7};
 
-pub use client::Client;
+#[cfg(feature = "std")]
+pub use crate::client::Client;
+#[cfg(feature = "std")]
+pub use crate::client::session::{AuthenticatedClient, SelectedClient, Session, SessionStart, UnauthenticatedClient};
@@ -0,0 +1,42 @@
+//! Hooks for collecting metrics about a connection. See `Client::observe()`.
+
+use std::time::Duration;
+
+use crate::proto::Status;
+
+/// Receives notifications about the activity on a connection. All methods
+/// have empty default implementations, so implementors only need to
+/// override the ones they are interested in.
+pub trait MetricsObserver: Send + Sync {
+    /// A command named `command` (such as `FETCH`) was sent.
+    fn command_sent(&self, _command: &str) {}
+
+    /// The tagged completion for `command` was received `elapsed` after the
+    /// command was issued.
+    fn command_completed(&self, _command: &str, _status: &Status, _elapsed: Duration) {}
+
+    /// `len` bytes were written to the connection, before compression.
+    fn bytes_sent(&self, _len: usize) {}
+
+    /// `len` bytes were decoded from the connection, after decompression.
+    fn bytes_received(&self, _len: usize) {}
+
+    /// A response from the server could not be parsed.
+    fn parse_failure(&self) {}
+
+    /// A tagged response was received whose tag is not that of the command
+    /// in flight.
+    fn unknown_tag(&self, _tag: &str) {}
+}
+
+/// Name of the command in `args`, which excludes the tag. For UID commands,
+/// this includes the name of the underlying command, as in `UID FETCH`.
+pub fn command_name(args: &[u8]) -> String {
+    let cmd = String::from_utf8_lossy(args).to_ascii_uppercase();
+    let mut words = cmd.split(' ');
+    match (words.next(), words.next()) {
+        (Some("UID"), Some(name)) => format!("UID {}", name),
+        (Some(name), _) => name.to_string(),
+        (None, _) => String::new(),
+    }
+}
@@ -0,0 +1,88 @@
+//! Decoding of fetched messages with `mailparse`, enabled by the `mime`
+//! feature.
+//!
+//! ```ignore
+//! let mut messages = session.fetch("1", vec![Attribute::BodySection(String::new())]);
+//! while let Some(message) = messages.next().await {
+//!     if let Some(body) = message?.parsed_body()? {
+//!         println!("{}", body.text.join("\n"));
+//!     }
+//! }
+//! ```
+
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+
+use std::io;
+
+/// The decoded contents of a message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ParsedBody {
+    /// The text/plain parts that are meant to be displayed, in order.
+    pub text: Vec<String>,
+    /// The text/html parts that are meant to be displayed, in order.
+    pub html: Vec<String>,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttachmentInfo {
+    pub filename: Option<String>,
+    /// The lowercase MIME type, such as `image/png`.
+    pub mime_type: String,
+    /// The size of the decoded contents, in octets.
+    pub size: usize,
+    /// The Content-ID, for parts referred to by HTML parts.
+    pub content_id: Option<String>,
+    /// The body section of the part, such as `1.2`, to fetch it by itself.
+    pub section: String,
+}
+
+/// Decode the message `raw`, sorting its parts into text to display and
+/// attachments. Leaf parts that are text/plain or text/html are shown,
+/// unless their Content-Disposition is `attachment`; all other leaf parts
+/// are attachments.
+pub fn parse(raw: &[u8]) -> io::Result<ParsedBody> {
+    let mail = mailparse::parse_mail(raw).map_err(invalid)?;
+    let mut body = ParsedBody::default();
+    if mail.subparts.is_empty() {
+        add_part(&mail, "1".to_string(), &mut body)?;
+    } else {
+        add_subparts(&mail, "", &mut body)?;
+    }
+    Ok(body)
+}
+
+fn add_subparts(mail: &ParsedMail, prefix: &str, body: &mut ParsedBody) -> io::Result<()> {
+    for (i, part) in mail.subparts.iter().enumerate() {
+        let section = format!("{}{}", prefix, i + 1);
+        if part.subparts.is_empty() {
+            add_part(part, section, body)?;
+        } else {
+            add_subparts(part, &format!("{}.", section), body)?;
+        }
+    }
+    Ok(())
+}
+
+fn add_part(part: &ParsedMail, section: String, body: &mut ParsedBody) -> io::Result<()> {
+    let disposition = part.get_content_disposition();
+    let attached = disposition.disposition == DispositionType::Attachment;
+    match part.ctype.mimetype.as_str() {
+        "text/plain" if !attached => body.text.push(part.get_body().map_err(invalid)?),
+        "text/html" if !attached => body.html.push(part.get_body().map_err(invalid)?),
+        mime_type => {
+            body.attachments.push(AttachmentInfo {
+                filename: disposition.params.get("filename").or_else(|| part.ctype.params.get("name")).cloned(),
+                mime_type: mime_type.to_string(),
+                size: part.get_body_raw().map_err(invalid)?.len(),
+                content_id: part.headers.get_first_value("Content-ID"),
+                section,
+            });
+        },
+    }
+    Ok(())
+}
+
+fn invalid(err: mailparse::MailParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -0,0 +1,389 @@
+//! Establishing TCP connections to servers, with the options of the
+//! sockets set up as configured in `ConnectOptions`.
+//!
+//! If a server has several addresses, the attempts are raced as described
+//! in RFC 8305 ("Happy Eyeballs"), so that an unreachable address,
+//! typically IPv6 on a broken network, only delays the connection briefly.
+
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::client::{tls_error, Client};
+use crate::error::Error;
+use crate::proto::*;
+use crate::throttle::Delay;
+
+pub use native_tls::Identity;
+
+// Time to wait for an attempt before starting the next one in parallel
+// (RFC 8305, section 5).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// Time after which a single attempt is given up.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The addresses of a host, as looked up by a resolver set with
+/// `ConnectOptions::resolver()`.
+pub type Resolve = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// Options for the TCP connection to a server, for when the defaults of
+/// `Client::connect()` do not fit. Long-lived IDLE connections through
+/// NATs, for example, need keepalives to be kept open.
+///
+/// ```ignore
+/// let (client, greeting) = ConnectOptions::new()
+///     .keepalive(Duration::from_secs(120), Duration::from_secs(30))
+///     .nodelay(true)
+///     .connect("imap.example.com").await?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    local_addr: Option<IpAddr>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    interface: Option<String>,
+    keepalive: Option<(Duration, Duration)>,
+    nodelay: bool,
+    resolver: Option<Opaque<Resolver>>,
+    tls_name: Option<String>,
+    identity: Option<Opaque<Identity>>,
+    handshake_timeout: Option<Duration>,
+    require_imap4: bool,
+    #[cfg(feature = "danger")]
+    accept_invalid_certs: bool,
+    #[cfg(feature = "danger")]
+    accept_invalid_hostnames: bool,
+}
+
+type Resolver = Arc<dyn Fn(&str) -> Resolve + Send + Sync>;
+
+// An option whose value cannot be shown in the `Debug` output.
+#[derive(Clone)]
+struct Opaque<T>(T);
+
+impl<T> fmt::Debug for Opaque<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("..")
+    }
+}
+
+impl ConnectOptions {
+    /// The options used by `Client::connect()`.
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    /// Connect from the local address `addr`. Only the addresses of the
+    /// server in the same family are tried.
+    pub fn bind(mut self, addr: IpAddr) -> ConnectOptions {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Connect through the network interface `name`, such as `wlan0`.
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn interface(mut self, name: &str) -> ConnectOptions {
+        self.interface = Some(name.to_string());
+        self
+    }
+
+    /// Send TCP keepalive probes once the connection has been idle for
+    /// `idle`, repeated every `interval` while the server does not respond.
+    /// The interval is left to the system on platforms where it cannot be
+    /// set.
+    pub fn keepalive(mut self, idle: Duration, interval: Duration) -> ConnectOptions {
+        self.keepalive = Some((idle, interval));
+        self
+    }
+
+    /// Disable Nagle's algorithm, so that commands are sent without delay.
+    pub fn nodelay(mut self, nodelay: bool) -> ConnectOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Look up the addresses of servers with `resolver` instead of the
+    /// resolver of the system, which blocks a thread for each lookup. The
+    /// ports of the addresses returned are ignored.
+    ///
+    /// ```ignore
+    /// let options = ConnectOptions::new().resolver(move |host| {
+    ///     let (resolver, host) = (resolver.clone(), host.to_string());
+    ///     Box::pin(async move {
+    ///         let ips = resolver.lookup_ip(host).await?;
+    ///         Ok(ips.iter().map(|ip| SocketAddr::new(ip, 0)).collect())
+    ///     })
+    /// });
+    /// ```
+    pub fn resolver<R>(mut self, resolver: R) -> ConnectOptions
+            where R: Fn(&str) -> Resolve + Send + Sync + 'static {
+        self.resolver = Some(Opaque(Arc::new(resolver)));
+        self
+    }
+
+    /// Verify the certificate of the server against `name` instead of the
+    /// name connected to, for example when connecting to an IP address or
+    /// an internal load balancer. The name is also sent for SNI.
+    pub fn tls_name(mut self, name: &str) -> ConnectOptions {
+        self.tls_name = Some(name.to_string());
+        self
+    }
+
+    /// Present the client certificate `identity` during the TLS handshake,
+    /// for servers requiring mutual TLS, which then usually accept
+    /// `sasl::External` for authenticating.
+    pub fn identity(mut self, identity: Identity) -> ConnectOptions {
+        self.identity = Some(Opaque(identity));
+        self
+    }
+
+    /// Fail with `Error::Timeout` if the TLS handshake and the greeting of
+    /// the server together take longer than `timeout`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with `Error::Unsupported` unless the server supports IMAP4rev1
+    /// or IMAP4rev2, as announced in its greeting or, if that does not list
+    /// the capabilities, in response to CAPABILITY.
+    pub fn require_imap4(mut self, require: bool) -> ConnectOptions {
+        self.require_imap4 = require;
+        self
+    }
+
+    /// Accept any certificate, including expired and self-signed ones. This
+    /// makes the connection open to interception, so it should only be used
+    /// with test servers.
+    #[cfg(feature = "danger")]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> ConnectOptions {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Accept certificates issued for other names than the server. This
+    /// makes the connection open to interception, so it should only be used
+    /// with test servers; prefer `tls_name()`.
+    #[cfg(feature = "danger")]
+    pub fn danger_accept_invalid_hostnames(mut self, accept: bool) -> ConnectOptions {
+        self.accept_invalid_hostnames = accept;
+        self
+    }
+
+    /// Connect to `server` on the IMAPS port; see `Client::connect()`.
+    #[cfg(feature = "runtime-tokio")]
+    pub async fn connect(&self, server: &str) -> io::Result<(Client, ResponseData)> {
+        debug!(server, "resolving");
+        let addrs = match self.resolver {
+            Some(ref resolver) => (resolver.0)(server).await?,
+            None => tokio::net::lookup_host((server, 993)).await?.collect(),
+        };
+        let stream = race(self.order(addrs), |addr| self.connect_tokio(addr), |delay| {
+            Box::pin(tokio::time::sleep(delay))
+        }).await?;
+        let setup = async {
+            let stream = self.handshake(self.tls_name.as_deref().unwrap_or(server), stream).await?;
+            self.greet(ImapStream::Tls(stream)).await
+        };
+        match self.handshake_timeout {
+            Some(timeout) => within(setup, Box::pin(tokio::time::sleep(timeout))).await,
+            None => setup.await,
+        }
+    }
+
+    /// Like `connect()`, but using the async-std runtime.
+    #[cfg(feature = "runtime-async-std")]
+    pub async fn connect_async_std(&self, server: &str) -> io::Result<(Client, ResponseData)> {
+        use async_std::net::ToSocketAddrs;
+        use tokio_util::compat::FuturesAsyncReadCompatExt;
+        debug!(server, "resolving");
+        let addrs = match self.resolver {
+            Some(ref resolver) => (resolver.0)(server).await?,
+            None => (server, 993).to_socket_addrs().await?.collect(),
+        };
+        let stream = race(self.order(addrs), |addr| self.connect_async_std_addr(addr), |delay| {
+            Box::pin(async_std::task::sleep(delay))
+        }).await?;
+        let setup = async {
+            let stream = self.handshake(self.tls_name.as_deref().unwrap_or(server), stream.compat()).await?;
+            self.greet(ImapStream::AsyncStd(stream)).await
+        };
+        match self.handshake_timeout {
+            Some(timeout) => within(setup, Box::pin(async_std::task::sleep(timeout))).await,
+            None => setup.await,
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    async fn connect_tokio(&self, addr: SocketAddr) -> io::Result<tokio::net::TcpStream> {
+        let socket = self.socket(addr)?;
+        socket.set_nonblocking(true)?;
+        tokio::net::TcpSocket::from_std_stream(socket.into()).connect(addr).await
+    }
+
+    #[cfg(feature = "runtime-async-std")]
+    async fn connect_async_std_addr(&self, addr: SocketAddr) -> io::Result<async_std::net::TcpStream> {
+        if self.local_addr.is_none() && self.keepalive.is_none() && !self.has_interface() {
+            let stream = async_std::net::TcpStream::connect(addr).await?;
+            stream.set_nodelay(self.nodelay)?;
+            return Ok(stream);
+        }
+        // async-std cannot connect a socket set up beforehand, so it is
+        // connected on a blocking thread, which outlives a timed out attempt.
+        let socket = self.socket(addr)?;
+        let socket = async_std::task::spawn_blocking(move || {
+            socket.connect(&addr.into()).map(|()| socket)
+        }).await?;
+        Ok(std::net::TcpStream::from(socket).into())
+    }
+
+    #[cfg(feature = "runtime-async-std")]
+    fn has_interface(&self) -> bool {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        return self.interface.is_some();
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        return false;
+    }
+
+    // Creates a socket for connecting to `addr`, with the options applied.
+    fn socket(&self, addr: SocketAddr) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(local_addr) = self.local_addr {
+            socket.bind(&SocketAddr::new(local_addr, 0).into())?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(ref name) = self.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        if let Some((idle, interval)) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+            #[cfg(any(target_os = "android", target_os = "freebsd", target_os = "ios",
+                      target_os = "linux", target_os = "macos", target_os = "netbsd",
+                      target_os = "windows"))]
+            let keepalive = keepalive.with_interval(interval);
+            #[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "ios",
+                          target_os = "linux", target_os = "macos", target_os = "netbsd",
+                          target_os = "windows")))]
+            let _ = interval;
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        socket.set_tcp_nodelay(self.nodelay)?;
+        Ok(socket)
+    }
+
+    // Sets up TLS on `stream`, verifying the certificate against `name`.
+    async fn handshake<S>(&self, name: &str, stream: S) -> io::Result<tokio_native_tls::TlsStream<S>>
+            where S: AsyncRead + AsyncWrite + Unpin {
+        debug!(name, "TCP connected, starting TLS handshake");
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.0.clone());
+        }
+        #[cfg(feature = "danger")]
+        builder
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .danger_accept_invalid_hostnames(self.accept_invalid_hostnames);
+        let connector = builder.build().map_err(tls_error)?;
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect(name, stream).await
+            .map_err(tls_error)?;
+        debug!("TLS handshake complete");
+        Ok(stream)
+    }
+
+    // Waits for the greeting on `stream`, checking the protocol version if
+    // required. A BYE greeting fails with `Error::Bye`.
+    async fn greet(&self, stream: ImapStream) -> io::Result<(Client, ResponseData)> {
+        let (mut client, greeting) = Client::connect_stream(stream).await?;
+        if self.require_imap4 {
+            client.refresh_capabilities().await?;
+            if !client.has(&Capability::Imap4rev1) && !client.has(&Capability::Imap4rev2) {
+                let msg = "server supports neither IMAP4rev1 nor IMAP4rev2".to_string();
+                return Err(Error::Unsupported(msg).into());
+            }
+        }
+        Ok((client, greeting))
+    }
+
+    // Orders the addresses of the server for the connection attempts, on
+    // the IMAPS port, leaving out those the local address cannot reach.
+    fn order(&self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        for addr in &mut addrs {
+            addr.set_port(993);
+        }
+        if let Some(local_addr) = self.local_addr {
+            addrs.retain(|addr| addr.is_ipv6() == local_addr.is_ipv6());
+        }
+        interleave(addrs)
+    }
+}
+
+// Runs `future`, failing with `Error::Timeout` once `timeout` fires.
+async fn within<F, T>(future: F, timeout: Delay) -> io::Result<T> where F: Future<Output = io::Result<T>> {
+    match future::select(Box::pin(future), timeout).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(Error::Timeout.into()),
+    }
+}
+
+// Orders `addrs` for the connection attempts, alternating between IPv6 and
+// IPv4, starting with the family of the first address (RFC 8305, section 4).
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(SocketAddr::is_ipv6).unwrap_or(false);
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = vec![];
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+// Connects to the first of `addrs` that accepts the connection. The next
+// attempt is started once the previous one has failed, or has not completed
+// within `ATTEMPT_DELAY`; earlier attempts keep running.
+async fn race<S, C, F, T>(addrs: Vec<SocketAddr>, connect: C, sleep: T) -> io::Result<S>
+        where C: Fn(SocketAddr) -> F, F: Future<Output = io::Result<S>>, T: Fn(Duration) -> Delay {
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            debug!(%addr, "connecting");
+            let attempt = Box::pin(connect(addr));
+            let timeout = sleep(ATTEMPT_TIMEOUT);
+            attempts.push(async move {
+                match future::select(attempt, timeout).await {
+                    Either::Left((result, _)) => (addr, result),
+                    Either::Right(_) => (addr, Err(io::ErrorKind::TimedOut.into())),
+                }
+            });
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+            }));
+        }
+        match future::select(attempts.next(), sleep(ATTEMPT_DELAY)).await {
+            Either::Left((Some((_, Ok(stream))), _)) => return Ok(stream),
+            Either::Left((Some((addr, Err(err))), _)) => {
+                debug!(%addr, error = %err, "connection attempt failed");
+                last_error = Some(err);
+            },
+            Either::Left((None, _)) | Either::Right(_) => {},
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Fetching many messages over several connections at once, which is the
+//! core of copying whole mailboxes between servers.
+//!
+//! ```ignore
+//! // Each session has the mailbox selected.
+//! let mut sessions = vec![first, second, third];
+//! let mut bodies = Box::pin(fetch_bodies(&mut sessions, &uids, 3));
+//! while let Some(message) = bodies.next().await {
+//!     let (uid, body) = message?;
+//!     target.append("INBOX", &body, &[]).await?;
+//! }
+//! ```
+
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::{self, FuturesUnordered};
+use futures::{future, Stream, StreamExt};
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::client::builder::SequenceSet;
+use crate::client::session::{Selected, Session};
+use crate::error::Error;
+use crate::proto::Attribute;
+
+// Number of messages fetched with one command.
+const SHARD_SIZE: usize = 100;
+// Number of times a shard is retried after it failed.
+const MAX_RETRIES: u32 = 2;
+
+// Messages fetched with one command; failed shards are retried with the
+// messages that have not been received yet.
+struct Shard {
+    uids: Vec<u32>,
+    attempts: u32,
+}
+
+/// Fetch the contents of the messages with `uids` over up to `concurrency`
+/// of `sessions`, which must all have the same mailbox selected. The UIDs
+/// are split into shards which are fetched in parallel, and messages are
+/// yielded as they arrive, in no particular order. Messages that no longer
+/// exist are left out.
+///
+/// A shard that fails is retried a few times; if its connection was closed,
+/// the remaining connections take over. Messages that could not be fetched
+/// in the end are reported by an error.
+///
+/// Panics if `concurrency` is zero.
+pub fn fetch_bodies<'a>(sessions: &'a mut [Session<Selected>], uids: &[u32], concurrency: usize)
+                        -> impl Stream<Item = io::Result<(u32, Vec<u8>)>> + 'a {
+    assert!(concurrency > 0, "concurrency must be positive");
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+    let mut shards: VecDeque<Shard> = uids.chunks(SHARD_SIZE)
+        .map(|uids| Shard { uids: uids.to_vec(), attempts: 0 })
+        .collect();
+    let (tx, rx) = mpsc::unbounded();
+    let work = async move {
+        let mut idle: Vec<&mut Session<Selected>> = sessions.iter_mut().take(concurrency).collect();
+        let mut running = FuturesUnordered::new();
+        let mut last_error = None;
+        loop {
+            while !idle.is_empty() && !shards.is_empty() {
+                let session = idle.pop().unwrap();
+                running.push(fetch_shard(session, shards.pop_front().unwrap(), tx.clone()));
+            }
+            let (session, mut shard, result) = match running.next().await {
+                Some(done) => done,
+                None => break,
+            };
+            let err = match result {
+                Ok(()) => {
+                    idle.push(session);
+                    continue;
+                },
+                Err(err) => err,
+            };
+            shard.attempts += 1;
+            warn!(error = %err, attempts = shard.attempts, "fetching messages failed");
+            if is_command_failure(&err) {
+                idle.push(session);
+            }
+            if shard.uids.is_empty() {
+                continue;
+            } else if shard.attempts > MAX_RETRIES {
+                let _ = tx.unbounded_send(Err(err));
+            } else {
+                shards.push_back(shard);
+                last_error = Some(err);
+            }
+        }
+        // Left over when all connections have failed.
+        if !shards.is_empty() {
+            let err = last_error.unwrap_or_else(|| Error::ConnectionClosed.into());
+            let _ = tx.unbounded_send(Err(err));
+        }
+    };
+    let work = stream::once(work).filter_map(|()| future::ready(None));
+    stream::select(rx, work)
+}
+
+async fn fetch_shard(session: &mut Session<Selected>, mut shard: Shard,
+                     tx: UnboundedSender<io::Result<(u32, Vec<u8>)>>)
+                     -> (&mut Session<Selected>, Shard, io::Result<()>) {
+    let result = fetch_messages(session, &mut shard, &tx).await;
+    (session, shard, result)
+}
+
+// Fetches the messages of `shard`, removing each one from it once sent.
+async fn fetch_messages(session: &mut Session<Selected>, shard: &mut Shard,
+                        tx: &UnboundedSender<io::Result<(u32, Vec<u8>)>>) -> io::Result<()> {
+    let set = shard.uids.iter().copied().collect::<SequenceSet>();
+    let mut fetched = session.uid_fetch(&set.to_string(), vec![Attribute::BodyPeek(String::new())]);
+    while let Some(message) = fetched.next().await {
+        let message = message?;
+        let uid = match message.uid() {
+            Some(uid) => uid,
+            None => continue,
+        };
+        // Bodies are kept as raw octets, as 8-bit and binary messages are
+        // not valid UTF-8.
+        let body = message.body_section_bytes("").map(<[u8]>::to_vec);
+        if let (Some(body), Some(pos)) = (body, shard.uids.iter().position(|&known| known == uid)) {
+            shard.uids.remove(pos);
+            let _ = tx.unbounded_send(Ok((uid, body)));
+        }
+    }
+    Ok(())
+}
+
+// Whether `err` is the failure of a command, after which the connection
+// can still be used.
+fn is_command_failure(err: &io::Error) -> bool {
+    let inner = err.get_ref().and_then(|inner| inner.downcast_ref::<Error>());
+    matches!(inner, Some(Error::No { .. }) | Some(Error::Bad { .. }))
+}
@@ -0,0 +1,87 @@
+//! Recording of the data exchanged with a server, for reproducing problems
+//! offline. See `Client::record_to()` and `testing::replay()`.
+//!
+//! A recording consists of records, each made up of a header line with the
+//! direction (`C` for data sent by the client, `S` for data sent by the
+//! server) and the length of the data, followed by the data itself and a
+//! newline. Credentials sent by the client are replaced before recording.
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Client,
+    Server,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Shared handle to the sink a recording is written to.
+#[derive(Clone)]
+pub struct Recorder {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(sink: W) -> Recorder {
+        Recorder { sink: Arc::new(Mutex::new(Box::new(sink))) }
+    }
+
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let mut sink = self.sink.lock().unwrap();
+        let prefix = match direction {
+            Direction::Client => 'C',
+            Direction::Server => 'S',
+        };
+        let res = writeln!(sink, "{} {}", prefix, data.len())
+            .and_then(|_| sink.write_all(data))
+            .and_then(|_| sink.write_all(b"\n"))
+            .and_then(|_| sink.flush());
+        if let Err(err) = res {
+            warn!(error = %err, "failed to write session recording");
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Recorder")
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read all records from a recording.
+pub fn read_recording<R: Read>(reader: R) -> io::Result<Vec<Record>> {
+    let mut reader = io::BufReader::new(reader);
+    let mut records = vec![];
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(records);
+        }
+        let mut parts = header.trim_end().splitn(2, ' ');
+        let direction = match parts.next() {
+            Some("C") => Direction::Client,
+            Some("S") => Direction::Server,
+            _ => return Err(invalid("invalid record direction")),
+        };
+        let len = parts.next()
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid("invalid record length"))?;
+        let mut data = vec![0; len + 1];
+        reader.read_exact(&mut data)?;
+        if data.pop() != Some(b'\n') {
+            return Err(invalid("missing record terminator"));
+        }
+        records.push(Record { direction, data });
+    }
+}
@@ -0,0 +1,125 @@
+//! Rendering commands and responses for logs, with credentials masked and
+//! literals shortened, so that the logs can be shared safely.
+//!
+//! ```ignore
+//! println!("sending {}", redact::request(&request));
+//! println!("received {}", redact::response(&rsp).preview(16));
+//! ```
+//!
+//! The arguments of LOGIN and AUTHENTICATE are masked, except for the user
+//! name or mechanism, as are the access tokens of URLAUTH URLs (RFC 4467).
+
+use std::cmp;
+use std::fmt;
+
+use crate::proto::{Request, RequestId, ResponseData};
+
+/// Number of bytes of each literal shown by default.
+pub const DEFAULT_PREVIEW: usize = 64;
+
+/// Displays a command or response with credentials masked, and literals cut
+/// off after the preview length, followed by their size.
+pub struct Redacted<'a> {
+    tag: Option<&'a RequestId>,
+    data: &'a [u8],
+    is_command: bool,
+    preview: usize,
+}
+
+/// Render `req`, including its tag.
+pub fn request(req: &Request) -> Redacted<'_> {
+    Redacted { tag: Some(&req.0), data: &req.1, is_command: true, preview: DEFAULT_PREVIEW }
+}
+
+/// Render `rsp` as received from the server, without the final CRLF.
+pub fn response(rsp: &ResponseData) -> Redacted<'_> {
+    let raw = rsp.raw();
+    let data = raw.strip_suffix(b"\r\n").unwrap_or(raw);
+    Redacted { tag: None, data, is_command: false, preview: DEFAULT_PREVIEW }
+}
+
+// Render the command `args`, without a tag.
+pub(crate) fn command(args: &[u8]) -> Redacted<'_> {
+    Redacted { tag: None, data: args, is_command: true, preview: DEFAULT_PREVIEW }
+}
+
+impl<'a> Redacted<'a> {
+    /// Show at most `len` bytes of each literal; `usize::MAX` shows them in
+    /// full.
+    pub fn preview(mut self, len: usize) -> Redacted<'a> {
+        self.preview = len;
+        self
+    }
+}
+
+impl<'a> fmt::Display for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(tag) = self.tag {
+            write!(f, "{} ", tag)?;
+        }
+        if self.is_command {
+            let cmd = String::from_utf8_lossy(self.data);
+            let mut words = cmd.splitn(3, ' ');
+            let name = words.next().unwrap_or("");
+            if name.eq_ignore_ascii_case("LOGIN") || name.eq_ignore_ascii_case("AUTHENTICATE") {
+                // Keep the user name or mechanism, which is useful for debugging.
+                let first = words.next().unwrap_or("");
+                return write!(f, "{} {} <redacted>", name, first);
+            }
+        }
+        let mut rest = self.data;
+        while let Some((start, len)) = find_literal(rest) {
+            write_text(f, &rest[..start])?;
+            let end = cmp::min(start + len, rest.len());
+            let contents = &rest[start..end];
+            if contents.len() > self.preview {
+                f.write_str(&String::from_utf8_lossy(&contents[..self.preview]))?;
+                write!(f, "...<{} bytes>", contents.len())?;
+            } else {
+                f.write_str(&String::from_utf8_lossy(contents))?;
+            }
+            rest = &rest[end..];
+        }
+        write_text(f, rest)
+    }
+}
+
+// Finds the first literal in `data`, returning the position of its contents
+// and its length.
+fn find_literal(data: &[u8]) -> Option<(usize, usize)> {
+    let mut from = 0;
+    while let Some(pos) = data[from..].windows(3).position(|w| w == b"}\r\n") {
+        let end = from + pos;
+        from = end + 3;
+        let open = match data[..end].iter().rposition(|&b| b == b'{') {
+            Some(open) => open,
+            None => continue,
+        };
+        let digits = data[open + 1..end].strip_suffix(b"+").unwrap_or(&data[open + 1..end]);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            continue;
+        }
+        if let Some(len) = std::str::from_utf8(digits).ok().and_then(|n| n.parse().ok()) {
+            return Some((end + 3, len));
+        }
+    }
+    None
+}
+
+// Writes `text`, masking the access tokens of URLAUTH URLs.
+fn write_text(f: &mut fmt::Formatter, text: &[u8]) -> fmt::Result {
+    let text = String::from_utf8_lossy(text);
+    let mut rest = &text[..];
+    while let Some(pos) = rest.to_ascii_uppercase().find(";URLAUTH=") {
+        let (before, after) = rest.split_at(pos + ";URLAUTH=".len());
+        f.write_str(before)?;
+        let end = after.find(['"', ' ', ')', '>', '\r']).unwrap_or(after.len());
+        let mut parts = after[..end].splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(access), Some(mechanism), Some(_)) => write!(f, "{}:{}:<redacted>", access, mechanism)?,
+            _ => f.write_str(&after[..end])?,
+        }
+        rest = &after[end..];
+    }
+    f.write_str(rest)
+}
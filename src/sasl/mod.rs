@@ -0,0 +1,329 @@
+//! SASL mechanisms (RFC 4422) for `Session::authenticate()`.
+//!
+//! ```ignore
+//! let identity = Identity::from_pkcs12(&der, "password")?;
+//! let (client, _) = ConnectOptions::new().identity(identity).connect("imap.example.com").await?;
+//! let session = Session::new(client).authenticate(External::new()).await?;
+//! ```
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+#[cfg(feature = "legacy")]
+use md5::Digest;
+
+#[cfg(feature = "legacy")]
+use std::convert::TryFrom;
+use std::io;
+
+#[cfg(feature = "ntlm")]
+mod ntlm;
+#[cfg(feature = "ntlm")]
+pub use self::ntlm::Ntlm;
+
+/// A SASL mechanism, which computes the responses of the client.
+pub trait Authenticator {
+    /// The name of the mechanism, such as `EXTERNAL`.
+    fn mechanism(&self) -> &str;
+
+    /// The response sent without waiting for a challenge, if the mechanism
+    /// starts with one.
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Answer the (decoded) `challenge` of the server. An error cancels the
+    /// exchange.
+    fn respond(&mut self, challenge: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// The EXTERNAL mechanism (RFC 4422, appendix A), which authenticates with
+/// credentials established outside of IMAP, typically the client
+/// certificate of the TLS connection; see `ConnectOptions::identity()`.
+#[derive(Clone, Debug, Default)]
+pub struct External {
+    authzid: String,
+}
+
+impl External {
+    /// Act as the user the credentials belong to.
+    pub fn new() -> External {
+        External::default()
+    }
+
+    /// Act as the user `authzid` instead, if the credentials allow it.
+    pub fn as_user(authzid: &str) -> External {
+        External { authzid: authzid.to_string() }
+    }
+}
+
+impl Authenticator for External {
+    fn mechanism(&self) -> &str {
+        "EXTERNAL"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        Some(self.authzid.as_bytes().to_vec())
+    }
+
+    fn respond(&mut self, _: &[u8]) -> io::Result<Vec<u8>> {
+        Err(invalid("unexpected challenge for EXTERNAL"))
+    }
+}
+
+/// The ANONYMOUS mechanism (RFC 4505), for servers offering public access,
+/// such as archives. The trace information, usually an email address, is
+/// only logged by the server.
+#[derive(Clone, Debug, Default)]
+pub struct Anonymous {
+    trace: String,
+}
+
+impl Anonymous {
+    /// Send `trace` as trace information; it is cut off after 255
+    /// characters, the maximum allowed.
+    pub fn new(trace: &str) -> Anonymous {
+        Anonymous { trace: trace.chars().take(255).collect() }
+    }
+}
+
+impl Authenticator for Anonymous {
+    fn mechanism(&self) -> &str {
+        "ANONYMOUS"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        Some(self.trace.as_bytes().to_vec())
+    }
+
+    fn respond(&mut self, _: &[u8]) -> io::Result<Vec<u8>> {
+        Err(invalid("unexpected challenge for ANONYMOUS"))
+    }
+}
+
+/// The GSS-SPNEGO mechanism (RFC 4178), for Kerberos or NTLM through the
+/// security services of the system, as accepted by Exchange servers. The
+/// tokens are computed by `step`, typically wrapping a GSSAPI or SSPI
+/// security context, which is passed the decoded challenges of the server,
+/// starting with an empty one.
+pub struct GssSpnego<F> {
+    step: F,
+}
+
+impl<F> GssSpnego<F> where F: FnMut(&[u8]) -> io::Result<Vec<u8>> {
+    pub fn new(step: F) -> GssSpnego<F> {
+        GssSpnego { step }
+    }
+}
+
+impl<F> Authenticator for GssSpnego<F> where F: FnMut(&[u8]) -> io::Result<Vec<u8>> {
+    fn mechanism(&self) -> &str {
+        "GSS-SPNEGO"
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> io::Result<Vec<u8>> {
+        (self.step)(challenge)
+    }
+}
+
+/// The DIGEST-MD5 mechanism (RFC 2831), still offered by some older
+/// servers. It has been obsoleted (RFC 6331), so it is only available with
+/// the `legacy` feature. The server is authenticated as well, by the
+/// `rspauth` it sends back.
+#[cfg(feature = "legacy")]
+#[derive(Clone, Debug)]
+pub struct DigestMd5 {
+    user: String,
+    password: String,
+    host: String,
+    authzid: Option<String>,
+    // The `rspauth` expected from the server, once the first challenge has
+    // been answered.
+    rspauth: Option<String>,
+}
+
+#[cfg(feature = "legacy")]
+impl DigestMd5 {
+    /// Authenticate as `user` with `password` on the server `host`, the
+    /// name connected to.
+    pub fn new(user: &str, password: &str, host: &str) -> DigestMd5 {
+        DigestMd5 {
+            user: user.to_string(),
+            password: password.to_string(),
+            host: host.to_string(),
+            authzid: None,
+            rspauth: None,
+        }
+    }
+
+    /// Act as the user `authzid`, if `user` is allowed to.
+    pub fn as_user(mut self, authzid: &str) -> DigestMd5 {
+        self.authzid = Some(authzid.to_string());
+        self
+    }
+
+    // Answers the first challenge, `challenge`, with the client nonce
+    // `cnonce`.
+    fn answer(&mut self, challenge: &[u8], cnonce: &str) -> io::Result<Vec<u8>> {
+        let challenge = String::from_utf8_lossy(challenge);
+        let fields = digest_fields(&challenge);
+        let field = |name: &str| fields.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| &value[..]);
+        let nonce = field("nonce").ok_or_else(|| invalid("DIGEST-MD5 challenge without nonce"))?;
+        let offers_auth = field("qop").map(|qop| qop.split(',').any(|qop| qop.trim() == "auth")).unwrap_or(true);
+        let is = |name: &str, value: &str| field(name).map(|v| v.eq_ignore_ascii_case(value)).unwrap_or(false);
+        if !offers_auth || !is("algorithm", "md5-sess") {
+            return Err(invalid("unsupported DIGEST-MD5 challenge"));
+        }
+        let realm = field("realm").unwrap_or("");
+        let utf8 = is("charset", "utf-8");
+        let encode = |text: &str| -> io::Result<Vec<u8>> {
+            if utf8 {
+                return Ok(text.as_bytes().to_vec());
+            }
+            // Without the charset, the server expects ISO 8859-1.
+            text.chars().map(|c| u8::try_from(c).ok())
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid("DIGEST-MD5 credentials not representable in ISO 8859-1"))
+        };
+
+        let digest_uri = format!("imap/{}", self.host);
+
+        let mut secret = encode(&self.user)?;
+        secret.push(b':');
+        secret.extend(encode(realm)?);
+        secret.push(b':');
+        secret.extend(encode(&self.password)?);
+        let mut a1 = md5::Md5::digest(&secret).to_vec();
+        a1.extend(format!(":{}:{}", nonce, cnonce).as_bytes());
+        if let Some(ref authzid) = self.authzid {
+            a1.push(b':');
+            a1.extend(authzid.as_bytes());
+        }
+        let a1 = hex(&md5::Md5::digest(&a1));
+        let kd = |a2: &str| {
+            let a2 = hex(&md5::Md5::digest(a2.as_bytes()));
+            hex(&md5::Md5::digest(format!("{}:{}:00000001:{}:auth:{}", a1, nonce, cnonce, a2).as_bytes()))
+        };
+        self.rspauth = Some(kd(&format!(":{}", digest_uri)));
+
+        let mut response = format!(
+            "username={},realm={},nonce={},cnonce={},nc=00000001,qop=auth,digest-uri={},response={}",
+            quote(&self.user), quote(realm), quote(nonce), quote(cnonce), quote(&digest_uri),
+            kd(&format!("AUTHENTICATE:{}", digest_uri)),
+        );
+        if utf8 {
+            response.push_str(",charset=utf-8");
+        }
+        if let Some(ref authzid) = self.authzid {
+            response.push_str(&format!(",authzid={}", quote(authzid)));
+        }
+        Ok(response.into_bytes())
+    }
+}
+
+#[cfg(feature = "legacy")]
+impl Authenticator for DigestMd5 {
+    fn mechanism(&self) -> &str {
+        "DIGEST-MD5"
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> io::Result<Vec<u8>> {
+        let expected = match self.rspauth {
+            Some(ref expected) => expected,
+            None => {
+                let mut random = [0u8; 16];
+                getrandom::fill(&mut random).map_err(|err| io::Error::other(err.to_string()))?;
+                return self.answer(challenge, &hex(&random));
+            },
+        };
+        let challenge = String::from_utf8_lossy(challenge);
+        let fields = digest_fields(&challenge);
+        match fields.iter().find(|(key, _)| key.eq_ignore_ascii_case("rspauth")) {
+            Some((_, rspauth)) if rspauth == expected => Ok(vec![]),
+            _ => Err(invalid("DIGEST-MD5 server authentication failed")),
+        }
+    }
+}
+
+// Splits a DIGEST-MD5 challenge into its fields, unquoting the values.
+#[cfg(feature = "legacy")]
+fn digest_fields(challenge: &str) -> Vec<(String, String)> {
+    let mut fields = vec![];
+    let mut chars = challenge.chars().peekable();
+    loop {
+        while chars.peek().map(|&c| c == ',' || c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if key.is_empty() {
+            return fields;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        fields.push((key.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+#[cfg(feature = "legacy")]
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(feature = "legacy")]
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Encodes a response for sending.
+pub(crate) fn encode(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+// Decodes a challenge received from the server.
+pub(crate) fn decode(text: &str) -> io::Result<Vec<u8>> {
+    STANDARD.decode(text.trim()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(all(test, feature = "legacy"))]
+mod tests {
+    use super::{digest_fields, Authenticator, DigestMd5};
+
+    // The example exchange of RFC 2831, section 4.
+    #[test]
+    fn digest_md5_example() {
+        let challenge = "realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",qop=\"auth\",\
+                         algorithm=md5-sess,charset=utf-8";
+        for challenge in [challenge.to_string(), challenge.replace("utf-8", "UTF-8")] {
+            let mut mechanism = DigestMd5::new("chris", "secret", "elwood.innosoft.com");
+            let answer = mechanism.answer(challenge.as_bytes(), "OA6MHXh6VqTrRk").unwrap();
+            let fields = digest_fields(&String::from_utf8(answer).unwrap());
+            let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| &value[..]);
+            assert_eq!(field("response"), Some("d388dad90d4bbd760a152321f2143af7"));
+            assert_eq!(field("digest-uri"), Some("imap/elwood.innosoft.com"));
+            assert_eq!(field("charset"), Some("utf-8"));
+            let verified = mechanism.respond(b"rspauth=ea40f60335c427b5527b84dbabcdfffd").unwrap();
+            assert!(verified.is_empty());
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! The NTLM mechanism with NTLMv2 responses (MS-NLMP), as offered by
+//! Exchange servers with LOGIN disabled (MS-OXIMAP4).
+
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+use std::convert::TryInto;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{invalid, Authenticator};
+
+const SIGNATURE: &[u8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+const FLAGS: u32 = NEGOTIATE_UNICODE | NEGOTIATE_OEM | REQUEST_TARGET | NEGOTIATE_NTLM |
+    NEGOTIATE_ALWAYS_SIGN | NEGOTIATE_EXTENDED_SESSIONSECURITY | NEGOTIATE_TARGET_INFO |
+    NEGOTIATE_128 | NEGOTIATE_56;
+
+// Attributes in the target information of the challenge.
+const MSV_AV_EOL: u16 = 0;
+const MSV_AV_TIMESTAMP: u16 = 7;
+
+// Seconds from the FILETIME epoch (1601) to the Unix epoch.
+const FILETIME_OFFSET: u64 = 11_644_473_600;
+
+/// The NTLM mechanism, which is only available with the `ntlm` feature.
+/// Only NTLMv2 responses are supported; the older ones are too weak to be
+/// worth sending.
+#[derive(Clone, Debug)]
+pub struct Ntlm {
+    user: String,
+    domain: String,
+    password: String,
+}
+
+impl Ntlm {
+    /// Authenticate as `user`, which may be qualified as `DOMAIN\user`,
+    /// with `password`.
+    pub fn new(user: &str, password: &str) -> Ntlm {
+        let (domain, user) = match user.split_once('\\') {
+            Some((domain, user)) => (domain, user),
+            None => ("", user),
+        };
+        Ntlm { user: user.to_string(), domain: domain.to_string(), password: password.to_string() }
+    }
+
+    // Builds the AUTHENTICATE_MESSAGE answering the CHALLENGE_MESSAGE
+    // `challenge`, with the client challenge `nonce`, at the FILETIME `now`.
+    fn authenticate(&self, challenge: &[u8], nonce: [u8; 8], now: u64) -> io::Result<Vec<u8>> {
+        if challenge.len() < 48 || &challenge[..8] != SIGNATURE || u32_at(challenge, 8) != 2 {
+            return Err(invalid("invalid NTLM challenge"));
+        }
+        let flags = u32_at(challenge, 20);
+        if flags & NEGOTIATE_UNICODE == 0 {
+            return Err(invalid("NTLM server without Unicode support"));
+        }
+        let server_nonce = &challenge[24..32];
+        let target_info = match flags & NEGOTIATE_TARGET_INFO {
+            0 => &[][..],
+            _ => security_buffer(challenge, 40).ok_or_else(|| invalid("invalid NTLM challenge"))?,
+        };
+        let timestamp = server_time(target_info);
+
+        let mut identity = utf16(&self.user.to_uppercase());
+        identity.extend(utf16(&self.domain));
+        let key = hmac_md5(&Md4::digest(utf16(&self.password)), &[&identity]);
+
+        let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+        blob.extend(&timestamp.unwrap_or(now).to_le_bytes());
+        blob.extend(&nonce);
+        blob.extend(&[0; 4]);
+        blob.extend(target_info);
+        blob.extend(&[0; 4]);
+        let mut nt_response = hmac_md5(&key, &[server_nonce, &blob]).to_vec();
+        nt_response.extend(&blob);
+        // The LMv2 response is left out when the server sends its time.
+        let lm_response = match timestamp {
+            Some(_) => vec![0; 24],
+            None => {
+                let mut response = hmac_md5(&key, &[server_nonce, &nonce]).to_vec();
+                response.extend(&nonce);
+                response
+            },
+        };
+
+        let payloads = [
+            &lm_response[..],
+            &nt_response[..],
+            &utf16(&self.domain)[..],
+            &utf16(&self.user)[..],
+            &[][..], // workstation
+            &[][..], // session key
+        ];
+        let mut msg = SIGNATURE.to_vec();
+        msg.extend(&3u32.to_le_bytes());
+        let mut offset = 64;
+        for payload in &payloads {
+            let len = payload.len() as u16;
+            msg.extend(&len.to_le_bytes());
+            msg.extend(&len.to_le_bytes());
+            msg.extend(&(offset as u32).to_le_bytes());
+            offset += payload.len();
+        }
+        msg.extend(&(FLAGS & flags & !NEGOTIATE_OEM).to_le_bytes());
+        for payload in &payloads {
+            msg.extend(*payload);
+        }
+        Ok(msg)
+    }
+}
+
+impl Authenticator for Ntlm {
+    fn mechanism(&self) -> &str {
+        "NTLM"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        // The NEGOTIATE_MESSAGE, without domain or workstation.
+        let mut msg = SIGNATURE.to_vec();
+        msg.extend(&1u32.to_le_bytes());
+        msg.extend(&FLAGS.to_le_bytes());
+        msg.extend(&[0; 16]);
+        Some(msg)
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0; 8];
+        getrandom::fill(&mut nonce).map_err(|err| io::Error::other(err.to_string()))?;
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now = (since_epoch.as_secs() + FILETIME_OFFSET) * 10_000_000 + u64::from(since_epoch.subsec_nanos() / 100);
+        self.authenticate(challenge, nonce, now)
+    }
+}
+
+// Finds the server time in the target information, as a FILETIME.
+fn server_time(mut target_info: &[u8]) -> Option<u64> {
+    while target_info.len() >= 4 {
+        let id = u16::from_le_bytes([target_info[0], target_info[1]]);
+        let len = usize::from(u16::from_le_bytes([target_info[2], target_info[3]]));
+        let value = target_info.get(4..4 + len)?;
+        match id {
+            MSV_AV_EOL => return None,
+            MSV_AV_TIMESTAMP => return Some(u64::from_le_bytes(value.try_into().ok()?)),
+            _ => target_info = &target_info[4 + len..],
+        }
+    }
+    None
+}
+
+fn security_buffer(msg: &[u8], pos: usize) -> Option<&[u8]> {
+    let len = usize::from(u16::from_le_bytes([msg[pos], msg[pos + 1]]));
+    let offset = u32_at(msg, pos + 4) as usize;
+    msg.get(offset..offset.checked_add(len)?)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], data: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for data in data {
+        mac.update(data);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{security_buffer, utf16, Ntlm};
+
+    // The NTLMv2 example of MS-NLMP, section 4.2.4.
+    #[test]
+    fn ntlm_v2_test_vectors() {
+        let challenge = [
+            0x4e, 0x54, 0x4c, 0x4d, 0x53, 0x53, 0x50, 0x00, 0x02, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x0c, 0x00,
+            0x38, 0x00, 0x00, 0x00, 0x33, 0x82, 0x8a, 0xe2, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x24, 0x00, 0x44, 0x00, 0x00, 0x00,
+            0x06, 0x00, 0x70, 0x17, 0x00, 0x00, 0x00, 0x0f, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00,
+            0x65, 0x00, 0x72, 0x00, 0x02, 0x00, 0x0c, 0x00, 0x44, 0x00, 0x6f, 0x00, 0x6d, 0x00, 0x61, 0x00,
+            0x69, 0x00, 0x6e, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00,
+            0x65, 0x00, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let target_info = &challenge[0x44..];
+        let msg = Ntlm::new("Domain\\User", "Password").authenticate(&challenge, [0xaa; 8], 0).unwrap();
+
+        let lm_response = security_buffer(&msg, 12).unwrap();
+        assert_eq!(lm_response, [
+            0x86, 0xc3, 0x50, 0x97, 0xac, 0x9c, 0xec, 0x10, 0x25, 0x54, 0x76, 0x4a, 0x57, 0xcc, 0xcc, 0x19,
+            0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+        ]);
+        let nt_response = security_buffer(&msg, 20).unwrap();
+        assert_eq!(nt_response[..16], [
+            0x68, 0xcd, 0x0a, 0xb8, 0x51, 0xe5, 0x1c, 0x96, 0xaa, 0xbc, 0x92, 0x7b, 0xeb, 0xef, 0x6a, 0x1c,
+        ]);
+        let mut blob = vec![0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        blob.extend(&[0xaa; 8]);
+        blob.extend(&[0; 4]);
+        blob.extend(target_info);
+        blob.extend(&[0; 4]);
+        assert_eq!(nt_response[16..], blob[..]);
+        assert_eq!(security_buffer(&msg, 28).unwrap(), &utf16("Domain")[..]);
+        assert_eq!(security_buffer(&msg, 36).unwrap(), &utf16("User")[..]);
+    }
+}
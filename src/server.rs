@@ -0,0 +1,800 @@
+use bytes::BytesMut;
+
+use std::io;
+use std::ops::RangeInclusive;
+
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::parser::{self, Line};
+use crate::proto::*;
+
+/// Codec for the server side of a connection: decodes commands sent by a
+/// client and encodes responses to send back.
+#[derive(Default)]
+pub struct ServerCodec {
+    // Offset of the last synchronizing literal announced to the caller.
+    announced: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum ClientMessage {
+    Command(Request),
+    /// The client ended an IDLE command (RFC 2177).
+    IdleDone,
+    /// The client announced a synchronizing literal of the given size, and
+    /// is waiting for a continuation request before sending its contents.
+    Literal(usize),
+}
+
+impl Decoder for ServerCodec {
+    type Item = ClientMessage;
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut)
+             -> Result<Option<Self::Item>, io::Error> {
+        match parser::scan_line(buf) {
+            Line::Complete(len) => {
+                self.announced = None;
+                let line = buf.split_to(len);
+                if line.eq_ignore_ascii_case(&b"DONE\r\n"[..]) {
+                    return Ok(Some(ClientMessage::IdleDone));
+                }
+                match parser::parse_command(&line) {
+                    Some(req) => Ok(Some(ClientMessage::Command(req))),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               "invalid command line")),
+                }
+            },
+            Line::OpenLiteral(header, data, len) => {
+                let synchronizing = buf[data - 4] != b'+';
+                if !synchronizing || data < buf.len() || self.announced == Some(header) {
+                    return Ok(None);
+                }
+                self.announced = Some(header);
+                Ok(Some(ClientMessage::Literal(len)))
+            },
+            Line::Incomplete => Ok(None),
+            Line::Invalid => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                "invalid literal length")),
+        }
+    }
+}
+
+impl<'a> Encoder<Response<'a>> for ServerCodec {
+    type Error = io::Error;
+    fn encode(&mut self, msg: Response<'a>, dst: &mut BytesMut)
+             -> Result<(), io::Error> {
+        encode_response(&msg, dst);
+        Ok(())
+    }
+}
+
+fn encode_response(rsp: &Response, dst: &mut BytesMut) {
+    match *rsp {
+        Response::BodyChunk(data) => {
+            dst.extend_from_slice(data);
+            return;
+        },
+        Response::Capabilities(ref caps) => {
+            dst.extend_from_slice(b"* CAPABILITY");
+            for cap in caps {
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(cap.as_bytes());
+            }
+        },
+        Response::Continue { ref code, text } => {
+            dst.extend_from_slice(b"+ ");
+            encode_resp_text(code, text, dst);
+        },
+        Response::Done(ref tag, ref status, ref code, text) => {
+            dst.extend_from_slice(tag.0.as_bytes());
+            dst.extend_from_slice(b" ");
+            encode_status(status, dst);
+            dst.extend_from_slice(b" ");
+            encode_resp_text(code, text, dst);
+        },
+        Response::Data(ref status, ref code, text) => {
+            dst.extend_from_slice(b"* ");
+            encode_status(status, dst);
+            dst.extend_from_slice(b" ");
+            encode_resp_text(code, text, dst);
+        },
+        Response::Enabled(ref caps) => {
+            dst.extend_from_slice(b"* ENABLED");
+            for cap in caps {
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(cap.as_bytes());
+            }
+        },
+        Response::Expunge(num) => {
+            dst.extend_from_slice(format!("* {} EXPUNGE", num).as_bytes());
+        },
+        Response::Fetch(num, ref attrs) | Response::UidFetch(num, ref attrs) => {
+            let name = match *rsp {
+                Response::UidFetch(..) => "UIDFETCH",
+                _ => "FETCH",
+            };
+            dst.extend_from_slice(format!("* {} {} (", num, name).as_bytes());
+            for (i, attr) in attrs.iter().enumerate() {
+                if i > 0 {
+                    dst.extend_from_slice(b" ");
+                }
+                encode_attribute(attr, dst);
+            }
+            dst.extend_from_slice(b")");
+        },
+        Response::MailboxData(MailboxDatum::Exists(num)) => {
+            dst.extend_from_slice(format!("* {} EXISTS", num).as_bytes());
+        },
+        Response::MailboxData(MailboxDatum::Flags(ref flags)) => {
+            dst.extend_from_slice(b"* FLAGS ");
+            encode_list(flags, dst);
+        },
+        Response::MailboxData(MailboxDatum::Recent(num)) => {
+            dst.extend_from_slice(format!("* {} RECENT", num).as_bytes());
+        },
+        Response::MailboxData(MailboxDatum::List { ref flags, delimiter, name }) => {
+            dst.extend_from_slice(b"* LIST ");
+            encode_list(flags, dst);
+            dst.extend_from_slice(b" ");
+            encode_nstring(delimiter, dst);
+            dst.extend_from_slice(b" ");
+            encode_nstring(Some(name), dst);
+        },
+        Response::MailboxData(MailboxDatum::Status { mailbox, ref status }) => {
+            dst.extend_from_slice(b"* STATUS ");
+            encode_nstring(Some(mailbox), dst);
+            dst.extend_from_slice(b" (");
+            for (i, value) in status.iter().enumerate() {
+                if i > 0 {
+                    dst.extend_from_slice(b" ");
+                }
+                encode_status_attribute(value, dst);
+            }
+            dst.extend_from_slice(b")");
+        },
+        Response::Search(ref ids) => {
+            dst.extend_from_slice(b"* SEARCH");
+            for id in ids {
+                dst.extend_from_slice(format!(" {}", id).as_bytes());
+            }
+        },
+        Response::GenUrlAuth(ref urls) => {
+            dst.extend_from_slice(b"* GENURLAUTH");
+            for url in urls {
+                dst.extend_from_slice(b" ");
+                encode_nstring(Some(url), dst);
+            }
+        },
+        Response::Language(ref languages) => {
+            dst.extend_from_slice(b"* LANGUAGE ");
+            encode_list(languages, dst);
+        },
+        Response::Metadata { mailbox, ref values } => {
+            dst.extend_from_slice(b"* METADATA ");
+            encode_nstring(Some(mailbox), dst);
+            dst.extend_from_slice(b" (");
+            for (i, &(entry, value)) in values.iter().enumerate() {
+                if i > 0 {
+                    dst.extend_from_slice(b" ");
+                }
+                encode_nstring(Some(entry), dst);
+                dst.extend_from_slice(b" ");
+                encode_nstring(value, dst);
+            }
+            dst.extend_from_slice(b")");
+        },
+        Response::UrlFetch(ref results) => {
+            dst.extend_from_slice(b"* URLFETCH");
+            for &(url, data) in results {
+                dst.extend_from_slice(b" ");
+                encode_nstring(Some(url), dst);
+                dst.extend_from_slice(b" ");
+                encode_nstring_bytes(data, dst);
+            }
+        },
+        Response::Sort(ref ids) => {
+            dst.extend_from_slice(b"* SORT");
+            for id in ids {
+                dst.extend_from_slice(format!(" {}", id).as_bytes());
+            }
+        },
+        Response::Thread(ref threads) => {
+            dst.extend_from_slice(b"* THREAD");
+            if !threads.is_empty() {
+                dst.extend_from_slice(b" ");
+            }
+            for thread in threads {
+                encode_thread(thread, dst);
+            }
+        },
+        Response::Esearch { tag, mailbox, uid, ref results } => {
+            dst.extend_from_slice(b"* ESEARCH");
+            if let Some(tag) = tag {
+                dst.extend_from_slice(b" (TAG ");
+                encode_nstring(Some(tag), dst);
+                if let Some((mailbox, uid_validity)) = mailbox {
+                    dst.extend_from_slice(b" MAILBOX ");
+                    encode_nstring(Some(mailbox), dst);
+                    dst.extend_from_slice(format!(" UIDVALIDITY {}", uid_validity).as_bytes());
+                }
+                dst.extend_from_slice(b")");
+            }
+            if uid {
+                dst.extend_from_slice(b" UID");
+            }
+            if let Some(min) = results.min {
+                dst.extend_from_slice(format!(" MIN {}", min).as_bytes());
+            }
+            if let Some(max) = results.max {
+                dst.extend_from_slice(format!(" MAX {}", max).as_bytes());
+            }
+            if let Some(ref all) = results.all {
+                dst.extend_from_slice(b" ALL ");
+                encode_sequence_set(all, dst);
+            }
+            if let Some(count) = results.count {
+                dst.extend_from_slice(format!(" COUNT {}", count).as_bytes());
+            }
+            if let Some(ref partial) = results.partial {
+                let range = format!(" PARTIAL ({}:{} ", partial.first, partial.last);
+                dst.extend_from_slice(range.as_bytes());
+                if partial.set.is_empty() {
+                    dst.extend_from_slice(b"NIL");
+                } else {
+                    encode_sequence_set(&partial.set, dst);
+                }
+                dst.extend_from_slice(b")");
+            }
+            if let Some(ref scores) = results.relevancy {
+                let scores = scores.iter().map(|score| score.to_string()).collect::<Vec<_>>();
+                dst.extend_from_slice(format!(" RELEVANCY ({})", scores.join(" ")).as_bytes());
+            }
+            for &(name, updates) in &[("ADDTO", &results.add_to), ("REMOVEFROM", &results.remove_from)] {
+                if updates.is_empty() {
+                    continue;
+                }
+                dst.extend_from_slice(format!(" {} (", name).as_bytes());
+                for (i, update) in updates.iter().enumerate() {
+                    if i > 0 {
+                        dst.extend_from_slice(b" ");
+                    }
+                    dst.extend_from_slice(format!("{} ", update.position).as_bytes());
+                    encode_sequence_set(&update.set, dst);
+                }
+                dst.extend_from_slice(b")");
+            }
+        },
+        Response::Vanished { earlier, ref uids } => {
+            dst.extend_from_slice(b"* VANISHED ");
+            if earlier {
+                dst.extend_from_slice(b"(EARLIER) ");
+            }
+            encode_sequence_set(uids, dst);
+        },
+    }
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn encode_sequence_set(set: &[RangeInclusive<u32>], dst: &mut BytesMut) {
+    for (i, range) in set.iter().enumerate() {
+        if i > 0 {
+            dst.extend_from_slice(b",");
+        }
+        if range.start() == range.end() {
+            dst.extend_from_slice(range.start().to_string().as_bytes());
+        } else {
+            dst.extend_from_slice(format!("{}:{}", range.start(), range.end()).as_bytes());
+        }
+    }
+}
+
+// Writes a chain of single replies as a list of numbers, as in
+// `(3 6 (4 23)(44 7 96))`.
+fn encode_thread(thread: &Thread, dst: &mut BytesMut) {
+    dst.extend_from_slice(b"(");
+    let mut node = thread;
+    let mut first = true;
+    loop {
+        if let Some(id) = node.id {
+            if !first {
+                dst.extend_from_slice(b" ");
+            }
+            dst.extend_from_slice(id.to_string().as_bytes());
+            first = false;
+            if node.children.len() == 1 {
+                node = &node.children[0];
+                continue;
+            }
+        }
+        if !node.children.is_empty() && !first {
+            dst.extend_from_slice(b" ");
+        }
+        for child in &node.children {
+            encode_thread(child, dst);
+        }
+        break;
+    }
+    dst.extend_from_slice(b")");
+}
+
+fn encode_status_attribute(value: &StatusAttributeValue, dst: &mut BytesMut) {
+    let (name, num) = match *value {
+        StatusAttributeValue::HighestModSeq(num) => ("HIGHESTMODSEQ", num),
+        StatusAttributeValue::Messages(num) => ("MESSAGES", u64::from(num)),
+        StatusAttributeValue::Recent(num) => ("RECENT", u64::from(num)),
+        StatusAttributeValue::UidNext(num) => ("UIDNEXT", u64::from(num)),
+        StatusAttributeValue::UidValidity(num) => ("UIDVALIDITY", u64::from(num)),
+        StatusAttributeValue::Unseen(num) => ("UNSEEN", u64::from(num)),
+    };
+    dst.extend_from_slice(format!("{} {}", name, num).as_bytes());
+}
+
+fn encode_status(status: &Status, dst: &mut BytesMut) {
+    dst.extend_from_slice(match *status {
+        Status::Ok => &b"OK"[..],
+        Status::No => &b"NO"[..],
+        Status::Bad => &b"BAD"[..],
+        Status::PreAuth => &b"PREAUTH"[..],
+        Status::Bye => &b"BYE"[..],
+    });
+}
+
+fn encode_resp_text(code: &Option<ResponseCode>, text: Option<&str>, dst: &mut BytesMut) {
+    if let Some(ref code) = *code {
+        dst.extend_from_slice(b"[");
+        encode_code(code, dst);
+        dst.extend_from_slice(b"]");
+        if text.is_some() {
+            dst.extend_from_slice(b" ");
+        }
+    }
+    if let Some(text) = text {
+        dst.extend_from_slice(text.as_bytes());
+    }
+}
+
+pub(crate) fn encode_code(code: &ResponseCode, dst: &mut BytesMut) {
+    match *code {
+        ResponseCode::Alert => dst.extend_from_slice(b"ALERT"),
+        ResponseCode::AppendUid(uid_validity, ref uids) => {
+            dst.extend_from_slice(format!("APPENDUID {} ", uid_validity).as_bytes());
+            encode_sequence_set(uids, dst);
+        },
+        ResponseCode::BadCharset(ref charsets) => {
+            dst.extend_from_slice(b"BADCHARSET");
+            if !charsets.is_empty() {
+                dst.extend_from_slice(b" ");
+                encode_list(charsets, dst);
+            }
+        },
+        ResponseCode::BadUrl(url) => {
+            dst.extend_from_slice(b"BADURL ");
+            dst.extend_from_slice(url.as_bytes());
+        },
+        ResponseCode::Capabilities(ref caps) => {
+            dst.extend_from_slice(b"CAPABILITY");
+            for cap in caps {
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(cap.as_bytes());
+            }
+        },
+        ResponseCode::CompressionActive => dst.extend_from_slice(b"COMPRESSIONACTIVE"),
+        ResponseCode::CopyUid(uid_validity, ref source, ref destination) => {
+            dst.extend_from_slice(format!("COPYUID {} ", uid_validity).as_bytes());
+            encode_sequence_set(source, dst);
+            dst.extend_from_slice(b" ");
+            encode_sequence_set(destination, dst);
+        },
+        ResponseCode::HighestModSeq(seq) => {
+            dst.extend_from_slice(format!("HIGHESTMODSEQ {}", seq).as_bytes());
+        },
+        ResponseCode::MailboxId(id) => {
+            dst.extend_from_slice(format!("MAILBOXID ({})", id).as_bytes());
+        },
+        ResponseCode::Modified(set) => {
+            dst.extend_from_slice(b"MODIFIED ");
+            dst.extend_from_slice(set.as_bytes());
+        },
+        ResponseCode::NoModSeq => dst.extend_from_slice(b"NOMODSEQ"),
+        ResponseCode::OverQuota => dst.extend_from_slice(b"OVERQUOTA"),
+        ResponseCode::PermanentFlags(ref permanent) => {
+            let mut flags = permanent.flags.iter().map(Flag::as_str).collect::<Vec<_>>();
+            if permanent.new_keywords {
+                flags.push("\\*");
+            }
+            dst.extend_from_slice(b"PERMANENTFLAGS ");
+            encode_list(&flags, dst);
+        },
+        ResponseCode::PrivacyRequired => dst.extend_from_slice(b"PRIVACYREQUIRED"),
+        ResponseCode::ReadOnly => dst.extend_from_slice(b"READ-ONLY"),
+        ResponseCode::ReadWrite => dst.extend_from_slice(b"READ-WRITE"),
+        ResponseCode::Referral(url) => {
+            dst.extend_from_slice(b"REFERRAL ");
+            dst.extend_from_slice(url.as_bytes());
+        },
+        ResponseCode::TooBig => dst.extend_from_slice(b"TOOBIG"),
+        ResponseCode::TryCreate => dst.extend_from_slice(b"TRYCREATE"),
+        ResponseCode::UseAttr => dst.extend_from_slice(b"USEATTR"),
+        ResponseCode::UidRequired => dst.extend_from_slice(b"UIDREQUIRED"),
+        ResponseCode::UidNext(uid) => {
+            dst.extend_from_slice(format!("UIDNEXT {}", uid).as_bytes());
+        },
+        ResponseCode::UidValidity(uid) => {
+            dst.extend_from_slice(format!("UIDVALIDITY {}", uid).as_bytes());
+        },
+        ResponseCode::Unseen(num) => {
+            dst.extend_from_slice(format!("UNSEEN {}", num).as_bytes());
+        },
+    }
+}
+
+fn encode_attribute(attr: &AttributeValue, dst: &mut BytesMut) {
+    match *attr {
+        AttributeValue::Annotation(ref annotations) => {
+            dst.extend_from_slice(b"ANNOTATION (");
+            for (i, annotation) in annotations.iter().enumerate() {
+                if i > 0 {
+                    dst.extend_from_slice(b" ");
+                }
+                encode_nstring(Some(annotation.entry), dst);
+                if annotation.values.is_empty() {
+                    continue;
+                }
+                dst.extend_from_slice(b" (");
+                for (j, &(attribute, value)) in annotation.values.iter().enumerate() {
+                    if j > 0 {
+                        dst.extend_from_slice(b" ");
+                    }
+                    encode_nstring(Some(attribute), dst);
+                    dst.extend_from_slice(b" ");
+                    encode_nstring(value, dst);
+                }
+                dst.extend_from_slice(b")");
+            }
+            dst.extend_from_slice(b")");
+        },
+        AttributeValue::BodySection { section, index, data } => {
+            dst.extend_from_slice(b"BODY[");
+            dst.extend_from_slice(section.unwrap_or("").as_bytes());
+            dst.extend_from_slice(b"]");
+            if let Some(index) = index {
+                dst.extend_from_slice(format!("<{}>", index).as_bytes());
+            }
+            dst.extend_from_slice(b" ");
+            encode_nstring_bytes(data, dst);
+        },
+        AttributeValue::Body(ref body) => {
+            dst.extend_from_slice(b"BODY ");
+            encode_body_structure(body, false, dst);
+        },
+        AttributeValue::BodyStructure(ref body) => {
+            dst.extend_from_slice(b"BODYSTRUCTURE ");
+            encode_body_structure(body, true, dst);
+        },
+        AttributeValue::Envelope(ref env) => {
+            dst.extend_from_slice(b"ENVELOPE ");
+            encode_envelope(env, dst);
+        },
+        AttributeValue::Flags(ref flags) => {
+            dst.extend_from_slice(b"FLAGS ");
+            encode_list(flags, dst);
+        },
+        AttributeValue::InternalDate(date) => {
+            dst.extend_from_slice(b"INTERNALDATE ");
+            encode_nstring(Some(date), dst);
+        },
+        AttributeValue::ModSeq(seq) => {
+            dst.extend_from_slice(format!("MODSEQ ({})", seq).as_bytes());
+        },
+        AttributeValue::Rfc822(data) => {
+            dst.extend_from_slice(b"RFC822 ");
+            encode_nstring_bytes(data, dst);
+        },
+        AttributeValue::Rfc822Size(size) => {
+            dst.extend_from_slice(format!("RFC822.SIZE {}", size).as_bytes());
+        },
+        AttributeValue::Uid(uid) => {
+            dst.extend_from_slice(format!("UID {}", uid).as_bytes());
+        },
+    }
+}
+
+fn encode_envelope(env: &Envelope, dst: &mut BytesMut) {
+    dst.extend_from_slice(b"(");
+    encode_nstring(env.date, dst);
+    dst.extend_from_slice(b" ");
+    encode_nstring(env.subject, dst);
+    for addrs in &[&env.from, &env.sender, &env.reply_to, &env.to, &env.cc, &env.bcc] {
+        dst.extend_from_slice(b" ");
+        encode_addresses(addrs, dst);
+    }
+    dst.extend_from_slice(b" ");
+    encode_nstring(env.in_reply_to, dst);
+    dst.extend_from_slice(b" ");
+    encode_nstring(env.message_id, dst);
+    dst.extend_from_slice(b")");
+}
+
+// Encodes `body`, with the extension data if `extensible`, as for
+// BODYSTRUCTURE.
+fn encode_body_structure(body: &BodyStructure, extensible: bool, dst: &mut BytesMut) {
+    dst.extend_from_slice(b"(");
+    match *body {
+        BodyStructure::Multipart { ref parts, subtype, ref params, ref disposition } => {
+            for part in parts {
+                encode_body_structure(part, extensible, dst);
+            }
+            dst.extend_from_slice(b" ");
+            encode_nstring(Some(subtype), dst);
+            if !extensible {
+                dst.extend_from_slice(b")");
+                return;
+            }
+            dst.extend_from_slice(b" ");
+            encode_body_params(params, dst);
+            dst.extend_from_slice(b" ");
+            encode_disposition(disposition, dst);
+        },
+        BodyStructure::Single { ref fields, lines, ref message, md5, ref disposition } => {
+            for value in &[fields.media_type, fields.media_subtype] {
+                encode_nstring(Some(value), dst);
+                dst.extend_from_slice(b" ");
+            }
+            encode_body_params(&fields.params, dst);
+            for value in &[fields.id, fields.description, Some(fields.encoding)] {
+                dst.extend_from_slice(b" ");
+                encode_nstring(*value, dst);
+            }
+            dst.extend_from_slice(format!(" {}", fields.size).as_bytes());
+            if let Some((ref envelope, ref body)) = *message {
+                dst.extend_from_slice(b" ");
+                encode_envelope(envelope, dst);
+                dst.extend_from_slice(b" ");
+                encode_body_structure(body, extensible, dst);
+            }
+            if let Some(lines) = lines {
+                dst.extend_from_slice(format!(" {}", lines).as_bytes());
+            }
+            if !extensible {
+                dst.extend_from_slice(b")");
+                return;
+            }
+            dst.extend_from_slice(b" ");
+            encode_nstring(md5, dst);
+            dst.extend_from_slice(b" ");
+            encode_disposition(disposition, dst);
+        },
+    }
+    dst.extend_from_slice(b")");
+}
+
+fn encode_body_params(params: &[(&str, &str)], dst: &mut BytesMut) {
+    if params.is_empty() {
+        dst.extend_from_slice(b"NIL");
+        return;
+    }
+    dst.extend_from_slice(b"(");
+    for (i, &(key, value)) in params.iter().enumerate() {
+        if i > 0 {
+            dst.extend_from_slice(b" ");
+        }
+        encode_nstring(Some(key), dst);
+        dst.extend_from_slice(b" ");
+        encode_nstring(Some(value), dst);
+    }
+    dst.extend_from_slice(b")");
+}
+
+fn encode_disposition(disposition: &Option<ContentDisposition>, dst: &mut BytesMut) {
+    match *disposition {
+        Some(ref disposition) => {
+            dst.extend_from_slice(b"(");
+            encode_nstring(Some(disposition.kind), dst);
+            dst.extend_from_slice(b" ");
+            encode_body_params(&disposition.params, dst);
+            dst.extend_from_slice(b")");
+        },
+        None => dst.extend_from_slice(b"NIL"),
+    }
+}
+
+fn encode_addresses(addrs: &Option<Vec<Address>>, dst: &mut BytesMut) {
+    let addrs = match *addrs {
+        Some(ref addrs) => addrs,
+        None => {
+            dst.extend_from_slice(b"NIL");
+            return;
+        },
+    };
+    dst.extend_from_slice(b"(");
+    for addr in addrs {
+        dst.extend_from_slice(b"(");
+        encode_nstring(addr.name, dst);
+        dst.extend_from_slice(b" ");
+        encode_nstring(addr.adl, dst);
+        dst.extend_from_slice(b" ");
+        encode_nstring(addr.mailbox, dst);
+        dst.extend_from_slice(b" ");
+        encode_nstring(addr.host, dst);
+        dst.extend_from_slice(b")");
+    }
+    dst.extend_from_slice(b")");
+}
+
+fn encode_list(items: &[&str], dst: &mut BytesMut) {
+    dst.extend_from_slice(b"(");
+    dst.extend_from_slice(items.join(" ").as_bytes());
+    dst.extend_from_slice(b")");
+}
+
+// Uses a quoted string where possible, and a literal otherwise.
+fn encode_nstring(s: Option<&str>, dst: &mut BytesMut) {
+    encode_nstring_bytes(s.map(str::as_bytes), dst);
+}
+
+// Data containing NUL is sent as a literal8 (RFC 3516).
+fn encode_nstring_bytes(s: Option<&[u8]>, dst: &mut BytesMut) {
+    let s = match s {
+        Some(s) => s,
+        None => {
+            dst.extend_from_slice(b"NIL");
+            return;
+        },
+    };
+    if s.contains(&0) {
+        dst.extend_from_slice(format!("~{{{}}}\r\n", s.len()).as_bytes());
+        dst.extend_from_slice(s);
+        return;
+    }
+    if s.iter().any(|&c| c == b'\r' || c == b'\n' || c >= 0x80) {
+        dst.extend_from_slice(format!("{{{}}}\r\n", s.len()).as_bytes());
+        dst.extend_from_slice(s);
+        return;
+    }
+    // Values from quoted strings in responses keep their escapes, so only
+    // unescaped quotes and a trailing backslash are escaped.
+    dst.extend_from_slice(b"\"");
+    let mut bytes = s.iter();
+    while let Some(&c) = bytes.next() {
+        match c {
+            b'\\' => {
+                dst.extend_from_slice(b"\\");
+                dst.extend_from_slice(&[*bytes.next().unwrap_or(&b'\\')]);
+            },
+            b'"' => dst.extend_from_slice(b"\\\""),
+            c => dst.extend_from_slice(&[c]),
+        }
+    }
+    dst.extend_from_slice(b"\"");
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{ClientMessage, ServerCodec};
+    use crate::parser;
+    use crate::proto::{Response, Thread};
+
+    fn decode(codec: &mut ServerCodec, buf: &mut BytesMut) -> Option<ClientMessage> {
+        codec.decode(buf).unwrap()
+    }
+
+    fn encode(rsp: Response) -> BytesMut {
+        let mut dst = BytesMut::new();
+        ServerCodec::default().encode(rsp, &mut dst).unwrap();
+        dst
+    }
+
+    fn thread(id: u32, children: Vec<Thread>) -> Thread {
+        Thread { id: Some(id), children }
+    }
+
+    #[test]
+    fn decode_synchronizing_literal() {
+        let mut codec = ServerCodec::default();
+        let mut buf = BytesMut::from(&b"a1 LOGIN {4}\r\n"[..]);
+        assert!(matches!(decode(&mut codec, &mut buf), Some(ClientMessage::Literal(4))));
+        // The literal is only announced once, however often the caller
+        // decodes before its contents arrive.
+        assert!(decode(&mut codec, &mut buf).is_none());
+        buf.extend_from_slice(b"us");
+        assert!(decode(&mut codec, &mut buf).is_none());
+        buf.extend_from_slice(b"er pass\r\n");
+        match decode(&mut codec, &mut buf) {
+            Some(ClientMessage::Command(req)) => {
+                assert_eq!(&*req.0 .0, "a1");
+                assert_eq!(req.1, b"LOGIN {4}\r\nuser pass");
+            },
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_non_synchronizing_literal() {
+        let mut codec = ServerCodec::default();
+        let mut buf = BytesMut::from(&b"a2 APPEND INBOX {5+}\r\n"[..]);
+        assert!(decode(&mut codec, &mut buf).is_none());
+        buf.extend_from_slice(b"hello\r\na3 NOOP\r\n");
+        match decode(&mut codec, &mut buf) {
+            Some(ClientMessage::Command(req)) => {
+                assert_eq!(&*req.0 .0, "a2");
+                assert_eq!(req.1, b"APPEND INBOX {5+}\r\nhello");
+            },
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        match decode(&mut codec, &mut buf) {
+            Some(ClientMessage::Command(req)) => assert_eq!(req.1, b"NOOP"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn decode_idle_done() {
+        let mut codec = ServerCodec::default();
+        let mut buf = BytesMut::from(&b"a4 IDLE\r\nDO"[..]);
+        assert!(matches!(decode(&mut codec, &mut buf), Some(ClientMessage::Command(_))));
+        assert!(decode(&mut codec, &mut buf).is_none());
+        buf.extend_from_slice(b"NE\r\ndone\r\n");
+        assert!(matches!(decode(&mut codec, &mut buf), Some(ClientMessage::IdleDone)));
+        assert!(matches!(decode(&mut codec, &mut buf), Some(ClientMessage::IdleDone)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut buf = BytesMut::from(&b"a5 LOGIN {99999999999999999999999}\r\n"[..]);
+        assert!(ServerCodec::default().decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"a6\r\n"[..]);
+        assert!(ServerCodec::default().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_parse_round_trip() {
+        let lines: &[&[u8]] = &[
+            b"* CAPABILITY IMAP4rev1 IDLE\r\n",
+            b"a1 OK [APPENDUID 38505 3955:3957] APPEND completed\r\n",
+            b"+ Ready for literal data\r\n",
+            b"* 23 EXISTS\r\n",
+            b"* STATUS \"INBOX\" (MESSAGES 3 UIDNEXT 5)\r\n",
+            b"* 12 FETCH (UID 7 FLAGS (\\Seen) BODY[TEXT]<0> {7}\r\nhi\r\nyou)\r\n",
+            b"* 3 FETCH (BODY[] ~{3}\r\na\0b)\r\n",
+            b"* 4 FETCH (RFC822 \"say \\\"hi\\\"\")\r\n",
+            b"* ESEARCH (TAG \"a5\") UID MIN 2 ALL 2,4:7,9 COUNT 5\r\n",
+            b"* VANISHED (EARLIER) 41,43:116\r\n",
+            b"* THREAD (2)(3 6 (4 23)(44 7 96))\r\n",
+            b"* THREAD\r\n",
+        ];
+        for &line in lines {
+            let (rest, rsp) = parser::parse_response(line).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(&encode(rsp)[..], line, "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn encode_thread_round_trip() {
+        // The example of RFC 5256, section 4, and a thread whose first
+        // message is missing.
+        let threads = vec![
+            thread(2, vec![]),
+            thread(3, vec![thread(6, vec![
+                thread(4, vec![thread(23, vec![])]),
+                thread(44, vec![thread(7, vec![thread(96, vec![])])]),
+            ])]),
+            Thread { id: None, children: vec![thread(3, vec![]), thread(5, vec![])] },
+        ];
+        let encoded = encode(Response::Thread(threads.clone()));
+        assert_eq!(&encoded[..], &b"* THREAD (2)(3 6 (4 23)(44 7 96))((3)(5))\r\n"[..]);
+        match parser::parse_response(&encoded) {
+            Ok((rest, Response::Thread(parsed))) => {
+                assert!(rest.is_empty());
+                assert_eq!(parsed, threads);
+            },
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+    }
+}
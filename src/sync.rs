@@ -0,0 +1,175 @@
+//! Synchronization of a mailbox with a local copy, using the cheapest
+//! strategy the server supports: QRESYNC (RFC 5162), CONDSTORE (RFC 4551),
+//! or comparing the UIDs of all messages.
+//!
+//! ```ignore
+//! let (session, changes) = sync::sync(session, "INBOX", stored.as_ref()).await?;
+//! for uid in &changes.expunged {
+//!     store.remove(*uid);
+//! }
+//! for message in changes.new.iter().chain(&changes.changed) {
+//!     store.set_flags(message.uid, &message.flags);
+//! }
+//! stored = Some(changes.state);
+//! ```
+
+use futures::StreamExt;
+
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::client::builder::SequenceSet;
+use crate::client::session::{CanSelect, Fetched, Mailbox, QresyncState, Selected, Session};
+use crate::proto::{Attribute, Capability};
+
+/// The state of a mailbox to persist between synchronizations.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncState {
+    pub uid_validity: u32,
+    /// The HIGHESTMODSEQ of the mailbox, if the server supports CONDSTORE.
+    pub highest_mod_seq: Option<u64>,
+    /// The UIDs of all messages in the mailbox, in ascending order.
+    pub uids: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    Qresync,
+    Condstore,
+    /// Fetch the UIDs and flags of all messages.
+    Full,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MessageState {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub mod_seq: Option<u64>,
+}
+
+impl MessageState {
+    fn from_fetched(fetched: &Fetched) -> Option<MessageState> {
+        Some(MessageState {
+            uid: fetched.uid()?,
+            flags: fetched.flags().iter().map(|s| s.to_string()).collect(),
+            mod_seq: fetched.mod_seq(),
+        })
+    }
+}
+
+/// The changes in a mailbox since the last synchronization.
+#[derive(Clone, Debug)]
+pub struct Changeset {
+    pub strategy: Strategy,
+    /// Whether the UIDVALIDITY of the mailbox changed, so that everything
+    /// known about it must be discarded. All messages are then new.
+    pub invalidated: bool,
+    /// Messages that were not known before.
+    pub new: Vec<MessageState>,
+    /// Known messages whose flags may have changed. With `Strategy::Full`,
+    /// these are all known messages that still exist.
+    pub changed: Vec<MessageState>,
+    /// UIDs of known messages that no longer exist.
+    pub expunged: Vec<u32>,
+    pub mailbox: Mailbox,
+    /// The state to persist for the next synchronization.
+    pub state: SyncState,
+}
+
+/// Select `mailbox` and determine what changed since `known`, or list all
+/// its messages if there is no known state.
+pub async fn sync<P: CanSelect>(session: Session<P>, mailbox: &str, known: Option<&SyncState>)
+                                -> io::Result<(Session<Selected>, Changeset)> {
+    let resumable = known.and_then(|known| known.highest_mod_seq.map(|seq| (known, seq)));
+    if let Some((known, mod_seq)) = resumable {
+        if session.has(&Capability::Qresync) {
+            let qresync = QresyncState {
+                uid_validity: known.uid_validity,
+                mod_seq,
+                known_uids: match known.uids.iter().copied().collect::<SequenceSet>() {
+                    set if set.is_empty() => None,
+                    set => Some(set.to_string()),
+                },
+            };
+            let (session, resync) = session.resync(mailbox, &qresync).await?;
+            if resync.mailbox.uid_validity == Some(known.uid_validity) {
+                let fetched = resync.changed.iter().filter_map(MessageState::from_fetched).collect();
+                let changes = diff(Strategy::Qresync, known, resync.mailbox, fetched, &resync.vanished);
+                return Ok((session, changes));
+            }
+            return full(session, resync.mailbox, Some(known)).await;
+        }
+    }
+    let (mut session, selected) = session.select(mailbox).await?;
+    let current = match (resumable, selected.uid_validity, selected.highest_mod_seq) {
+        (Some((known, _)), Some(uid_validity), Some(_)) if uid_validity == known.uid_validity => known,
+        _ => return full(session, selected, known).await,
+    };
+    let mod_seq = current.highest_mod_seq.unwrap_or(0);
+    if selected.exists == 0 {
+        let changes = diff(Strategy::Condstore, current, selected, vec![], &current.uids.iter().copied().collect());
+        return Ok((session, changes));
+    }
+    let items = vec![Attribute::Uid, Attribute::Flags];
+    let fetched = fetch_all(session.fetch_changed_since("1:*", items, mod_seq)).await?;
+    // Expunged messages can only be found by listing all UIDs.
+    let present = fetch_all(session.fetch("1:*", vec![Attribute::Uid])).await?;
+    let present: BTreeSet<u32> = present.iter().map(|message| message.uid).collect();
+    let expunged = current.uids.iter().filter(|uid| !present.contains(uid)).cloned().collect::<SequenceSet>();
+    let changes = diff(Strategy::Condstore, current, selected, fetched, &expunged);
+    Ok((session, changes))
+}
+
+// Lists all messages of the selected mailbox.
+async fn full(mut session: Session<Selected>, selected: Mailbox, known: Option<&SyncState>)
+              -> io::Result<(Session<Selected>, Changeset)> {
+    let fetched = if selected.exists > 0 {
+        fetch_all(session.fetch("1:*", vec![Attribute::Uid, Attribute::Flags])).await?
+    } else {
+        vec![]
+    };
+    let empty = SyncState::default();
+    let (known, invalidated) = match known {
+        Some(known) if selected.uid_validity == Some(known.uid_validity) => (known, false),
+        Some(_) => (&empty, true),
+        None => (&empty, false),
+    };
+    let present: BTreeSet<u32> = fetched.iter().map(|message| message.uid).collect();
+    let expunged = known.uids.iter().filter(|uid| !present.contains(uid)).cloned().collect::<SequenceSet>();
+    let mut changes = diff(Strategy::Full, known, selected, fetched, &expunged);
+    changes.invalidated = invalidated;
+    Ok((session, changes))
+}
+
+async fn fetch_all<S>(mut stream: S) -> io::Result<Vec<MessageState>>
+        where S: futures::Stream<Item = io::Result<Fetched>> + Unpin {
+    let mut messages = vec![];
+    while let Some(fetched) = stream.next().await {
+        messages.extend(MessageState::from_fetched(&fetched?));
+    }
+    Ok(messages)
+}
+
+// Sorts `fetched` into new and changed messages, and computes the new state.
+fn diff(strategy: Strategy, known: &SyncState, mailbox: Mailbox, fetched: Vec<MessageState>,
+        expunged: &SequenceSet) -> Changeset {
+    let mut uids: BTreeSet<u32> = known.uids.iter().cloned().collect();
+    let removed: BTreeSet<u32> = uids.iter().filter(|&&uid| expunged.contains(uid)).cloned().collect();
+    uids.retain(|uid| !removed.contains(uid));
+    let (changed, new): (Vec<_>, Vec<_>) = fetched.into_iter().partition(|message| uids.contains(&message.uid));
+    uids.extend(new.iter().map(|message| message.uid));
+    let state = SyncState {
+        uid_validity: mailbox.uid_validity.unwrap_or(known.uid_validity),
+        highest_mod_seq: mailbox.highest_mod_seq,
+        uids: uids.into_iter().collect(),
+    };
+    Changeset {
+        strategy,
+        invalidated: false,
+        new,
+        changed,
+        expunged: removed.into_iter().collect(),
+        mailbox,
+        state,
+    }
+}
@@ -0,0 +1,330 @@
+//! Helpers for testing code built on this crate without a live server.
+//!
+//! `MockServer` runs a scripted IMAP server on one end of an in-memory
+//! stream; the other end can be handed to `Client::connect_stream()`.
+//!
+//! ```ignore
+//! let (stream, server) = MockServer::new()
+//!     .send("* OK [CAPABILITY IMAP4rev1 IDLE] ready")
+//!     .expect("LOGIN \"user\" \"secret\"")
+//!     .done("OK logged in")
+//!     .run();
+//! tokio::spawn(async { server.await.unwrap() });
+//! let (client, greeting) = Client::connect_stream(ImapStream::Memory(stream)).await?;
+//! ```
+
+use bytes::BytesMut;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read};
+
+use tokio::io::{self as aio, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_util::codec::Decoder;
+
+use crate::parser;
+use crate::record::{self, Direction};
+use crate::server::{ClientMessage, ServerCodec};
+
+// Buffer size of the in-memory stream between the client and the server.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+enum Step {
+    Send(Vec<u8>),
+    Expect(String),
+    ExpectAny,
+    ExpectIdleDone,
+    ExpectLine(String),
+    ExpectAnyLine,
+    Done(String),
+    // A recorded continuation request, which is not sent again if it asked
+    // for a literal the mock server has already asked for itself.
+    Continue(Vec<u8>),
+}
+
+/// Script for a mock server. Steps are executed in order.
+pub struct MockServer {
+    steps: VecDeque<Step>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockServer {
+    pub fn new() -> MockServer {
+        MockServer { steps: VecDeque::new() }
+    }
+
+    /// Send a response line; the CRLF is added automatically.
+    pub fn send(self, line: &str) -> MockServer {
+        let mut raw = line.as_bytes().to_vec();
+        raw.extend(b"\r\n");
+        self.send_raw(&raw)
+    }
+
+    /// Send raw bytes, for example a response containing a literal.
+    pub fn send_raw(mut self, raw: &[u8]) -> MockServer {
+        self.steps.push_back(Step::Send(raw.to_vec()));
+        self
+    }
+
+    /// Wait for a command and check that it matches `command`, which
+    /// excludes the tag. Synchronizing literals in the command are accepted
+    /// automatically.
+    pub fn expect(mut self, command: &str) -> MockServer {
+        self.steps.push_back(Step::Expect(command.to_string()));
+        self
+    }
+
+    /// Wait for a command without checking its contents.
+    pub fn expect_any(mut self) -> MockServer {
+        self.steps.push_back(Step::ExpectAny);
+        self
+    }
+
+    /// Wait for the client to end an IDLE command.
+    pub fn expect_idle_done(mut self) -> MockServer {
+        self.steps.push_back(Step::ExpectIdleDone);
+        self
+    }
+
+    /// Wait for a line that is not a command, such as the answer to a SASL
+    /// challenge, and check that it matches `line`, which excludes the CRLF.
+    pub fn expect_line(mut self, line: &str) -> MockServer {
+        self.steps.push_back(Step::ExpectLine(line.to_string()));
+        self
+    }
+
+    /// Wait for a line that is not a command without checking its contents.
+    pub fn expect_any_line(mut self) -> MockServer {
+        self.steps.push_back(Step::ExpectAnyLine);
+        self
+    }
+
+    /// Send a tagged completion for the last command received, such as
+    /// `OK done`.
+    pub fn done(mut self, text: &str) -> MockServer {
+        self.steps.push_back(Step::Done(text.to_string()));
+        self
+    }
+
+    /// Start the server. Returns the client end of the stream and a future
+    /// which must be polled to run the script; it fails if the client sends
+    /// something unexpected.
+    pub fn run(self) -> (DuplexStream, impl Future<Output = io::Result<()>>) {
+        let (client, server) = aio::duplex(BUFFER_SIZE);
+        (client, serve(server, self.steps))
+    }
+}
+
+/// Build a mock server which replays the server side of a recording made
+/// with `Client::record_to()`. Commands sent by the client are not checked,
+/// since credentials have been redacted from the recording; the recorded
+/// server data is sent verbatim, relying on the client generating the same
+/// tags as in the original session. Continuation requests for literals are
+/// sent by the mock server itself, so the recorded ones are skipped.
+pub fn replay<R: Read>(reader: R) -> io::Result<MockServer> {
+    let records = record::read_recording(reader)?;
+    let mut server = MockServer::new();
+    // The greeting is not recorded when recording starts after connecting.
+    if records.first().map(|r| r.direction) != Some(Direction::Server) {
+        server = server.send("* OK replaying recorded session");
+    }
+    for record in records {
+        server = match record.direction {
+            Direction::Client => match record.data.as_slice() {
+                b"DONE\r\n" => server.expect_idle_done(),
+                data if parser::parse_command(data).is_some() => server.expect_any(),
+                _ => server.expect_any_line(),
+            },
+            Direction::Server if record.data.starts_with(b"+") => {
+                server.steps.push_back(Step::Continue(record.data));
+                server
+            },
+            Direction::Server => server.send_raw(&record.data),
+        };
+    }
+    Ok(server)
+}
+
+struct Connection {
+    stream: DuplexStream,
+    codec: ServerCodec,
+    buf: BytesMut,
+    // Continuation requests sent for literals of the last command.
+    literals: usize,
+}
+
+impl Connection {
+    async fn next_message(&mut self) -> io::Result<ClientMessage> {
+        self.literals = 0;
+        loop {
+            match self.codec.decode(&mut self.buf)? {
+                Some(ClientMessage::Literal(_)) => {
+                    self.stream.write_all(b"+ Ready for literal data\r\n").await?;
+                    self.literals += 1;
+                },
+                Some(msg) => return Ok(msg),
+                None => {
+                    if self.stream.read_buf(&mut self.buf).await? == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "client closed the connection"));
+                    }
+                },
+            }
+        }
+    }
+
+    async fn next_line(&mut self) -> io::Result<BytesMut> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let mut line = self.buf.split_to(pos + 2);
+                line.truncate(pos);
+                return Ok(line);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "client closed the connection"));
+            }
+        }
+    }
+}
+
+fn unexpected(expected: &str, msg: &ClientMessage) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("expected {}, received {:?}", expected, msg))
+}
+
+async fn serve(stream: DuplexStream, steps: VecDeque<Step>) -> io::Result<()> {
+    let mut conn = Connection { stream, codec: ServerCodec::default(), buf: BytesMut::new(), literals: 0 };
+    let mut last_tag = None;
+    for step in steps {
+        match step {
+            Step::Send(raw) => {
+                conn.stream.write_all(&raw).await?;
+            },
+            Step::Done(text) => {
+                let tag = last_tag.as_deref().unwrap_or("*");
+                conn.stream.write_all(format!("{} {}\r\n", tag, text).as_bytes()).await?;
+            },
+            Step::Continue(raw) => match conn.literals {
+                0 => conn.stream.write_all(&raw).await?,
+                _ => conn.literals -= 1,
+            },
+            Step::Expect(command) => match conn.next_message().await? {
+                ClientMessage::Command(ref req) if req.1 == command.as_bytes() => {
+                    last_tag = Some((req.0).0.clone());
+                },
+                msg => return Err(unexpected(&command, &msg)),
+            },
+            Step::ExpectAny => {
+                if let ClientMessage::Command(req) = conn.next_message().await? {
+                    last_tag = Some((req.0).0);
+                }
+            },
+            Step::ExpectIdleDone => match conn.next_message().await? {
+                ClientMessage::IdleDone => {},
+                msg => return Err(unexpected("DONE", &msg)),
+            },
+            Step::ExpectAnyLine => {
+                conn.next_line().await?;
+            },
+            Step::ExpectLine(expected) => {
+                let line = conn.next_line().await?;
+                if line != expected.as_bytes() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("expected {}, received {:?}", expected, line)));
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::{future, StreamExt};
+
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{replay, MockServer};
+    use crate::record::{self, Direction};
+    use crate::client::builder::CommandBuilder;
+    use crate::client::Client;
+    use crate::proto::{ImapStream, MailboxDatum, Response};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Appends a message and idles until a new one arrives, returning the
+    // responses received.
+    async fn append_and_idle(mut client: Client) -> Vec<String> {
+        let mut received = vec![];
+        let mut responses = client.call(CommandBuilder::append("INBOX", b"Hello").build());
+        while let Some(rsp) = responses.next().await {
+            received.push(String::from_utf8_lossy(rsp.unwrap().raw()).into_owned());
+        }
+        drop(responses);
+        let mut responses = client.call(CommandBuilder::idle());
+        while let Some(rsp) = responses.next().await {
+            let rsp = rsp.unwrap();
+            if let Response::MailboxData(MailboxDatum::Exists(_)) = *rsp.parsed() {
+                responses.idle_done();
+            }
+            received.push(String::from_utf8_lossy(rsp.raw()).into_owned());
+        }
+        received
+    }
+
+    // A recording of IDLE and of a synchronizing literal plays back as it
+    // was recorded.
+    #[test]
+    fn replay_round_trip() {
+        let (stream, server) = MockServer::new()
+            .send("* OK ready")
+            .expect("APPEND \"INBOX\" {5}\r\nHello")
+            .done("OK appended")
+            .expect("IDLE")
+            .send("+ idling")
+            .send("* 4 EXISTS")
+            .expect_idle_done()
+            .done("OK idle done")
+            .run();
+        let recording = SharedBuf::default();
+        let sink = recording.clone();
+        let client = async move {
+            let (client, _) = Client::connect_stream(ImapStream::Memory(stream)).await.unwrap();
+            append_and_idle(client.record_to(sink)).await
+        };
+        let (served, recorded) = block_on(future::join(server, client));
+        served.unwrap();
+
+        let recording = recording.0.lock().unwrap().clone();
+        let records = record::read_recording(&recording[..]).unwrap();
+        assert!(records.iter().any(|r| r.direction == Direction::Client && r.data == b"DONE\r\n"));
+        let (stream, server) = replay(&recording[..]).unwrap().run();
+        let client = async move {
+            let (client, _) = Client::connect_stream(ImapStream::Memory(stream)).await.unwrap();
+            append_and_idle(client).await
+        };
+        let (served, replayed) = block_on(future::join(server, client));
+        served.unwrap();
+        assert_eq!(replayed, recorded);
+    }
+}
@@ -0,0 +1,101 @@
+//! Limiting the rate at which commands are sent, to stay clear of the
+//! throttling and abuse detection of large providers. See
+//! `Client::rate_limit()`.
+//!
+//! ```ignore
+//! let limit = RateLimit::commands(5.0, 20).bytes(1_000_000);
+//! let client = client.rate_limit(limit, |delay| Box::pin(tokio::time::sleep(delay)));
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+/// A timer future, as returned by the sleep function of the runtime.
+pub type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Limits on the average rate of commands and, optionally, of the bytes
+/// sent, enforced with token buckets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    commands_per_second: f64,
+    burst: u32,
+    bytes_per_second: Option<u64>,
+}
+
+impl RateLimit {
+    /// Send at most `per_second` commands per second on average, and at
+    /// most `burst` at once after a quiet period.
+    ///
+    /// Panics if `per_second` is not positive or `burst` is zero.
+    pub fn commands(per_second: f64, burst: u32) -> RateLimit {
+        assert!(per_second > 0.0 && burst > 0, "invalid rate limit");
+        RateLimit { commands_per_second: per_second, burst, bytes_per_second: None }
+    }
+
+    /// Also send at most `per_second` bytes per second on average, and at
+    /// most as many at once. A command larger than that is still sent, but
+    /// delays the following ones accordingly.
+    pub fn bytes(mut self, per_second: u64) -> RateLimit {
+        assert!(per_second > 0, "invalid rate limit");
+        self.bytes_per_second = Some(per_second);
+        self
+    }
+}
+
+// The state of the token buckets of a `RateLimit`.
+pub(crate) struct Throttle {
+    limit: RateLimit,
+    commands: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: RateLimit) -> Throttle {
+        Throttle {
+            limit,
+            commands: f64::from(limit.burst),
+            bytes: limit.bytes_per_second.unwrap_or(0) as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    // How long to wait before the next command may be sent.
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        let (commands, bytes) = self.refilled(Instant::now());
+        let mut wait = 0.0f64;
+        if commands < 1.0 {
+            wait = (1.0 - commands) / self.limit.commands_per_second;
+        }
+        if let Some(per_second) = self.limit.bytes_per_second {
+            if bytes < 0.0 {
+                wait = wait.max(-bytes / per_second as f64);
+            }
+        }
+        match wait > 0.0 {
+            true => Some(Duration::from_secs_f64(wait)),
+            false => None,
+        }
+    }
+
+    // Accounts for a command of `len` bytes being sent.
+    pub(crate) fn take(&mut self, len: usize) {
+        let now = Instant::now();
+        let (commands, bytes) = self.refilled(now);
+        self.commands = commands - 1.0;
+        self.bytes = bytes - len as f64;
+        self.updated = now;
+    }
+
+    fn refilled(&self, now: Instant) -> (f64, f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let limit = &self.limit;
+        let commands = (self.commands + elapsed * limit.commands_per_second).min(f64::from(limit.burst));
+        let bytes = match limit.bytes_per_second {
+            Some(per_second) => (self.bytes + elapsed * per_second as f64).min(per_second as f64),
+            None => 0.0,
+        };
+        (commands, bytes)
+    }
+}
@@ -0,0 +1,161 @@
+//! The mailbox hierarchy, assembled from the results of LIST.
+//!
+//! ```ignore
+//! let tree = MailboxTree::new(session.list("", "*").await?);
+//! for (depth, node) in tree.iter() {
+//!     println!("{:indent$}{}", "", node.label, indent = 2 * depth);
+//! }
+//! ```
+
+use crate::client::session::ListEntry;
+use crate::proto::SpecialUse;
+
+/// The mailboxes of an account, arranged by their hierarchy.
+#[derive(Clone, Debug, Default)]
+pub struct MailboxTree {
+    roots: Vec<MailboxNode>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MailboxNode {
+    /// The full name of the mailbox.
+    pub name: String,
+    /// The last level of the name, to display.
+    pub label: String,
+    /// The LIST result for the mailbox, or `None` for a level of the
+    /// hierarchy that was implied by the names of its children.
+    pub entry: Option<ListEntry>,
+    pub children: Vec<MailboxNode>,
+}
+
+// The order of special-use mailboxes, after INBOX and before the others.
+const SPECIAL_USE_ORDER: [SpecialUse; 7] = [
+    SpecialUse::Drafts, SpecialUse::Sent, SpecialUse::Archive, SpecialUse::Junk,
+    SpecialUse::Trash, SpecialUse::All, SpecialUse::Flagged,
+];
+
+impl MailboxTree {
+    /// Arrange `entries` by splitting their names at their hierarchy
+    /// delimiters. Levels that were not listed themselves are added as
+    /// placeholders.
+    pub fn new(entries: Vec<ListEntry>) -> MailboxTree {
+        let mut roots = vec![];
+        for entry in entries {
+            let labels: Vec<String> = match entry.delimiter {
+                Some(ref delimiter) if !delimiter.is_empty() => {
+                    entry.name.split(delimiter.as_str()).map(|s| s.to_string()).collect()
+                },
+                _ => vec![entry.name.clone()],
+            };
+            let delimiter = entry.delimiter.clone().unwrap_or_default();
+            let mut level = &mut roots;
+            let mut name = String::new();
+            for (i, label) in labels.iter().enumerate() {
+                if i > 0 {
+                    name.push_str(&delimiter);
+                }
+                name.push_str(label);
+                let pos = match level.iter().position(|node: &MailboxNode| same_name(&node.name, &name)) {
+                    Some(pos) => pos,
+                    None => {
+                        level.push(MailboxNode {
+                            name: name.clone(),
+                            label: label.clone(),
+                            entry: None,
+                            children: vec![],
+                        });
+                        level.len() - 1
+                    },
+                };
+                if i == labels.len() - 1 {
+                    level[pos].entry = Some(entry.clone());
+                }
+                level = &mut level[pos].children;
+            }
+        }
+        sort(&mut roots);
+        MailboxTree { roots }
+    }
+
+    pub fn roots(&self) -> &[MailboxNode] {
+        &self.roots
+    }
+
+    /// The mailbox with the full name `name`.
+    pub fn find(&self, name: &str) -> Option<&MailboxNode> {
+        self.iter().map(|(_, node)| node).find(|node| same_name(&node.name, name))
+    }
+
+    /// The first mailbox, in display order, with the special use `usage`.
+    pub fn find_special_use(&self, usage: SpecialUse) -> Option<&MailboxNode> {
+        self.iter().map(|(_, node)| node).find(|node| node.special_use().contains(&usage))
+    }
+
+    /// All mailboxes in display order, depth first, with their depth in
+    /// the hierarchy.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { stack: self.roots.iter().rev().map(|node| (0, node)).collect() }
+    }
+}
+
+impl MailboxNode {
+    /// Whether the mailbox can be selected, as opposed to only containing
+    /// other mailboxes.
+    pub fn is_selectable(&self) -> bool {
+        match self.entry {
+            Some(ref entry) => !entry.flags.iter().any(|flag| {
+                flag.eq_ignore_ascii_case("\\Noselect") || flag.eq_ignore_ascii_case("\\NonExistent")
+            }),
+            None => false,
+        }
+    }
+
+    pub fn special_use(&self) -> Vec<SpecialUse> {
+        self.entry.as_ref().map(|entry| entry.special_use()).unwrap_or_default()
+    }
+
+    fn is_inbox(&self) -> bool {
+        self.name.eq_ignore_ascii_case("INBOX")
+    }
+
+    fn rank(&self) -> usize {
+        if self.is_inbox() {
+            return 0;
+        }
+        let uses = self.special_use();
+        match SPECIAL_USE_ORDER.iter().position(|usage| uses.contains(usage)) {
+            Some(pos) => pos + 1,
+            None => SPECIAL_USE_ORDER.len() + 1,
+        }
+    }
+}
+
+/// Iterator over the mailboxes of a `MailboxTree`; see `MailboxTree::iter()`.
+pub struct Iter<'a> {
+    stack: Vec<(usize, &'a MailboxNode)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (usize, &'a MailboxNode);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, node) = self.stack.pop()?;
+        self.stack.extend(node.children.iter().rev().map(|child| (depth + 1, child)));
+        Some((depth, node))
+    }
+}
+
+// INBOX is case-insensitive; all other names are not (RFC 3501, section 5.1).
+fn same_name(a: &str, b: &str) -> bool {
+    a == b || a.eq_ignore_ascii_case("INBOX") && b.eq_ignore_ascii_case("INBOX")
+}
+
+fn sort(nodes: &mut [MailboxNode]) {
+    nodes.sort_by(|a, b| {
+        a.rank().cmp(&b.rank())
+            .then_with(|| a.label.to_lowercase().cmp(&b.label.to_lowercase()))
+            .then_with(|| a.label.cmp(&b.label))
+    });
+    for node in nodes {
+        sort(&mut node.children);
+    }
+}
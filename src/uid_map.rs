@@ -0,0 +1,140 @@
+//! The mapping between the sequence numbers and UIDs of the messages in
+//! the selected mailbox, kept up to date from the responses the server
+//! sends, so that an EXPUNGE can be attributed to a message without
+//! fetching anything.
+//!
+//! ```ignore
+//! let mut uids = UidMap::load(&mut session, mailbox.exists).await?;
+//! // For every response received while the mailbox is selected:
+//! for uid in uids.update(rsp.parsed()) {
+//!     store.remove(uid);
+//! }
+//! ```
+
+use futures::StreamExt;
+
+use std::io;
+
+use crate::client::session::{Selected, Session};
+use crate::proto::{Attribute, AttributeValue, MailboxDatum, Response};
+
+/// The UIDs of the messages in a mailbox, by sequence number.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UidMap {
+    // The UID of message `seq` is at index `seq - 1`, if it is known.
+    uids: Vec<Option<u32>>,
+    // Whether a message may have vanished before its UID was known.
+    stale: bool,
+}
+
+impl UidMap {
+    pub fn new() -> UidMap {
+        UidMap::default()
+    }
+
+    /// Set up the map from the UIDs of all messages, in ascending order.
+    pub fn from_uids(uids: &[u32]) -> UidMap {
+        UidMap { uids: uids.iter().map(|&uid| Some(uid)).collect(), stale: false }
+    }
+
+    /// Fetch the UIDs of all `exists` messages in the selected mailbox.
+    pub async fn load(session: &mut Session<Selected>, exists: u32) -> io::Result<UidMap> {
+        let mut map = UidMap::new();
+        if exists == 0 {
+            return Ok(map);
+        }
+        map.uids.resize(exists as usize, None);
+        let mut fetched = session.fetch("1:*", vec![Attribute::Uid]);
+        while let Some(message) = fetched.next().await {
+            let message = message?;
+            if let Some(uid) = message.uid() {
+                map.set(message.seq, uid);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Apply an EXISTS, EXPUNGE, VANISHED or FETCH response, returning the
+    /// UIDs of the messages it removed. Messages removed before their UID
+    /// was known are not included; if a VANISHED response names such a
+    /// message, `needs_reload()` becomes true.
+    pub fn update(&mut self, rsp: &Response) -> Vec<u32> {
+        match *rsp {
+            Response::MailboxData(MailboxDatum::Exists(num)) => {
+                self.uids.resize(num as usize, None);
+            },
+            Response::Expunge(seq) if seq >= 1 && seq as usize <= self.uids.len() => {
+                return self.uids.remove(seq as usize - 1).into_iter().collect();
+            },
+            Response::Vanished { earlier: false, ref uids } => {
+                let mut removed = vec![];
+                self.uids.retain(|uid| match *uid {
+                    Some(uid) if uids.iter().any(|range| range.contains(&uid)) => {
+                        removed.push(uid);
+                        false
+                    },
+                    _ => true,
+                });
+                let vanished: u64 = uids.iter()
+                    .map(|range| (u64::from(*range.end()) + 1).saturating_sub(u64::from(*range.start())))
+                    .sum();
+                if vanished > removed.len() as u64 && self.uids.contains(&None) {
+                    self.stale = true;
+                }
+                return removed;
+            },
+            Response::Fetch(seq, ref attrs) => {
+                for attr in attrs {
+                    if let AttributeValue::Uid(uid) = *attr {
+                        self.set(seq, uid);
+                    }
+                }
+            },
+            _ => {},
+        }
+        vec![]
+    }
+
+    /// The UID of message `seq`, if known.
+    pub fn uid(&self, seq: u32) -> Option<u32> {
+        *self.uids.get((seq as usize).checked_sub(1)?)?
+    }
+
+    /// The sequence number of the message with `uid`, if known.
+    pub fn seq(&self, uid: u32) -> Option<u32> {
+        self.uids.iter().position(|&known| known == Some(uid)).map(|i| i as u32 + 1)
+    }
+
+    /// The sequence numbers of the messages whose UID is not known yet,
+    /// such as new messages reported by EXISTS. Fetching their UIDs fills
+    /// in the map.
+    pub fn unknown(&self) -> Vec<u32> {
+        (1..).zip(&self.uids).filter(|(_, uid)| uid.is_none()).map(|(seq, _)| seq).collect()
+    }
+
+    /// Whether a message vanished before its UID was known. Its slot
+    /// cannot be told apart from those of other messages whose UID is not
+    /// known, so sequence numbers may be off until the map is loaded again.
+    pub fn needs_reload(&self) -> bool {
+        self.stale
+    }
+
+    /// The number of messages in the mailbox.
+    pub fn len(&self) -> usize {
+        self.uids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty()
+    }
+
+    fn set(&mut self, seq: u32, uid: u32) {
+        if seq == 0 {
+            return;
+        }
+        if seq as usize > self.uids.len() {
+            self.uids.resize(seq as usize, None);
+        }
+        self.uids[seq as usize - 1] = Some(uid);
+    }
+}
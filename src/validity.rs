@@ -0,0 +1,77 @@
+//! Detection of UIDVALIDITY changes (RFC 3501, section 2.3.1.1). When the
+//! UIDVALIDITY of a mailbox changes, its UIDs may have been reassigned, so
+//! everything cached about its messages has to be discarded.
+//!
+//! The tracker outlives sessions, so that changes are also detected after
+//! reconnecting.
+//!
+//! ```ignore
+//! let (session, mailbox) = session.select("INBOX").await?;
+//! if let Some(invalidated) = tracker.check("INBOX", &mailbox) {
+//!     cache.clear(&invalidated.mailbox);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use crate::client::session::Mailbox;
+
+/// The UIDVALIDITY last seen for each mailbox.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UidValidityTracker {
+    known: HashMap<String, u32>,
+}
+
+/// The UIDVALIDITY of a mailbox has changed, invalidating the cached UIDs
+/// of its messages. It can also serve as the inner error of an
+/// `io::Error`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CacheInvalidated {
+    pub mailbox: String,
+    pub old: u32,
+    pub new: u32,
+}
+
+impl fmt::Display for CacheInvalidated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UIDVALIDITY of {} changed from {} to {}", self.mailbox, self.old, self.new)
+    }
+}
+
+impl error::Error for CacheInvalidated {}
+
+impl UidValidityTracker {
+    pub fn new() -> UidValidityTracker {
+        UidValidityTracker::default()
+    }
+
+    /// Record the UIDVALIDITY of `mailbox`, such as one stored along with a
+    /// cache, without checking it.
+    pub fn insert(&mut self, mailbox: &str, uid_validity: u32) {
+        self.known.insert(mailbox.to_string(), uid_validity);
+    }
+
+    pub fn get(&self, mailbox: &str) -> Option<u32> {
+        self.known.get(mailbox).cloned()
+    }
+
+    /// Record the UIDVALIDITY reported when selecting `mailbox`, returning
+    /// the change if it differs from the one recorded before. Servers that
+    /// do not report a UIDVALIDITY cannot be checked.
+    pub fn check(&mut self, mailbox: &str, selected: &Mailbox) -> Option<CacheInvalidated> {
+        let new = selected.uid_validity?;
+        let old = self.known.insert(mailbox.to_string(), new)?;
+        if old == new {
+            return None;
+        }
+        warn!(mailbox, old, new, "UIDVALIDITY changed");
+        Some(CacheInvalidated { mailbox: mailbox.to_string(), old, new })
+    }
+
+    /// The recorded UIDVALIDITY of each mailbox, for persisting them.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> + '_ {
+        self.known.iter().map(|(mailbox, &uid_validity)| (mailbox.as_str(), uid_validity))
+    }
+}